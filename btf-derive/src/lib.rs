@@ -0,0 +1,157 @@
+//! `#[derive(Btf)]`, describe a `#[repr(C)]` type in BTF.
+//!
+//! The derive implements `libbpf::btf::BtfTypeInfo` and places an entry in the
+//! `btf_types` linker section, collected by `libbpf::linked_btf_types!`.
+//! Structs and unions need named fields, enums need to be fieldless with a
+//! `C` or integer representation. `#[btf(name = "task_struct")]` sets the BTF
+//! name, which CO-RE uses to match the types of a program, it defaults to the
+//! Rust name.
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use syn::{parse_macro_input, Attribute, Data, DeriveInput, Error, Fields, Ident, LitStr, Result};
+
+#[proc_macro_derive(Btf, attributes(btf))]
+pub fn derive_btf(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+/// The integer types and `C` of `#[repr(...)]`
+fn repr(attrs: &[Attribute]) -> Result<Vec<Ident>> {
+    let mut reprs = Vec::new();
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("repr")) {
+        attr.parse_nested_meta(|meta| {
+            if let Some(ident) = meta.path.get_ident() {
+                reprs.push(ident.clone());
+            }
+            // skip the arguments of align(N) and packed(N)
+            if meta.input.peek(syn::token::Paren) {
+                let _ = meta.input.parse::<proc_macro2::Group>()?;
+            }
+            Ok(())
+        })?;
+    }
+    Ok(reprs)
+}
+
+/// The name given by `#[btf(name = "...")]`
+fn btf_name(attrs: &[Attribute]) -> Result<Option<LitStr>> {
+    let mut name = None;
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("btf")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("name") {
+                name = Some(meta.value()?.parse()?);
+                Ok(())
+            } else {
+                Err(meta.error("unknown btf attribute"))
+            }
+        })?;
+    }
+    Ok(name)
+}
+
+fn expand(input: DeriveInput) -> Result<proc_macro2::TokenStream> {
+    let ident = &input.ident;
+    if !input.generics.params.is_empty() {
+        return Err(Error::new_spanned(
+            &input.generics,
+            "Btf can not be derived for generic types",
+        ));
+    }
+    let reprs = repr(&input.attrs)?;
+    let is_c = reprs.iter().any(|repr| repr == "C");
+    let name =
+        btf_name(&input.attrs)?.unwrap_or_else(|| LitStr::new(&ident.to_string(), ident.span()));
+
+    let fields = |fields: &Fields| -> Result<Vec<proc_macro2::TokenStream>> {
+        let named = match fields {
+            Fields::Named(named) => &named.named,
+            _ => {
+                return Err(Error::new_spanned(
+                    fields,
+                    "Btf needs named fields, BTF members have C names",
+                ))
+            }
+        };
+        Ok(named
+            .iter()
+            .map(|field| {
+                let field_ident = field.ident.as_ref().unwrap();
+                let field_name = field_ident.to_string();
+                let ty = &field.ty;
+                quote! {
+                    ::libbpf::btf::BtfField {
+                        name: #field_name,
+                        offset: ::core::mem::offset_of!(Self, #field_ident),
+                        ty: ::libbpf::btf::BtfBuilder::add::<#ty>,
+                    }
+                }
+            })
+            .collect())
+    };
+
+    let body = match &input.data {
+        Data::Struct(data) => {
+            if !is_c {
+                return Err(Error::new(
+                    Span::call_site(),
+                    "Btf needs #[repr(C)] for the layout to be stable",
+                ));
+            }
+            let fields = fields(&data.fields)?;
+            quote! { builder.add_struct::<Self>(#name, &[#(#fields),*]) }
+        }
+        Data::Union(data) => {
+            if !is_c {
+                return Err(Error::new(
+                    Span::call_site(),
+                    "Btf needs #[repr(C)] for the layout to be stable",
+                ));
+            }
+            let fields = fields(&Fields::Named(data.fields.clone()))?;
+            quote! { builder.add_union::<Self>(#name, &[#(#fields),*]) }
+        }
+        Data::Enum(data) => {
+            let int = reprs.iter().find(|repr| *repr != "C");
+            if !is_c && int.is_none() {
+                return Err(Error::new(
+                    Span::call_site(),
+                    "Btf needs #[repr(C)] or an integer representation",
+                ));
+            }
+            // a C enum is an int
+            let signed = int.map_or(true, |int| int.to_string().starts_with('i'));
+            let mut values = Vec::new();
+            for variant in &data.variants {
+                if !matches!(variant.fields, Fields::Unit) {
+                    return Err(Error::new_spanned(
+                        variant,
+                        "Btf can only be derived for fieldless enums",
+                    ));
+                }
+                let variant_ident = &variant.ident;
+                let variant_name = variant_ident.to_string();
+                values.push(quote! { (#variant_name, Self::#variant_ident as i64) });
+            }
+            quote! { builder.add_enum::<Self>(#name, #signed, &[#(#values),*]) }
+        }
+    };
+
+    Ok(quote! {
+        impl ::libbpf::btf::BtfTypeInfo for #ident {
+            fn add_btf_type(builder: &mut ::libbpf::btf::BtfBuilder) -> u32 {
+                #body
+            }
+        }
+
+        const _: () = {
+            #[used]
+            #[link_section = "btf_types"]
+            static ENTRY: ::libbpf::btf::BtfTypeEntry =
+                ::libbpf::btf::BtfTypeEntry(::libbpf::btf::BtfBuilder::add::<#ident>);
+        };
+    })
+}
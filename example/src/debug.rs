@@ -10,9 +10,12 @@ use crate::kprobe::{PtRegs, DEBUG_KPROBE_LIST};
 pub fn debug_handler(trap_context: &mut TrapFrame) {
     println!("<debug_handler>");
     let pc = *trap_context.index(TrapFrameArgs::SEPC);
+    if crate::watchpoint::hw_breakpoint_handler(trap_context, pc) {
+        return;
+    }
     let kprobe = DEBUG_KPROBE_LIST.lock().get(&pc).map(|k| k.clone());
     if let Some(kprobe) = kprobe {
-        kprobe.call_post_handler(&PtRegs::from(trap_context.clone()));
+        kprobe.call_post_handler(&PtRegs::new(trap_context, pc));
         let tf = trap_context.rflags.get_bit(8);
         info!("tf: {}", tf);
         info!("clear x86 single step");
@@ -20,6 +23,11 @@ pub fn debug_handler(trap_context: &mut TrapFrame) {
         trap_context.rflags.set_bit(8, false);
         // recover pc
         *trap_context.index_mut(TrapFrameArgs::SEPC) = kprobe.return_address();
+    } else if trap_context.rflags.get_bit(8) {
+        // the stepped instruction left the copy by itself, like the `ret` of a
+        // return probe, so the pc is already right
+        info!("single step left the kprobe copy at pc {:#x}", pc);
+        trap_context.rflags.set_bit(8, false);
     } else {
         info!("There is no kprobe in pc {:#x}", pc);
         // trap_context.rip += 1; // skip ebreak instruction
@@ -7,6 +7,18 @@ use polyhal::{TrapFrame, TrapFrameArgs};
 use crate::kprobe::{PtRegs, BREAK_KPROBE_LIST};
 
 pub fn ebreak_handler(trap_context: &mut TrapFrame) {
+    #[cfg(target_arch = "riscv64")]
+    {
+        let pc = *trap_context.index(TrapFrameArgs::SEPC) - 2;
+        // SAFETY: pc is the instruction that raised the exception
+        if unsafe { kprobe::is_trigger_hit(pc) } {
+            crate::watchpoint::trigger_handler(trap_context, pc);
+            return;
+        }
+        if crate::watchpoint::single_step_done(trap_context, pc) {
+            return;
+        }
+    }
     let break_addr = if cfg!(target_arch = "x86_64") {
         *trap_context.index(TrapFrameArgs::SEPC) - 1
     } else if cfg!(target_arch = "riscv64") {
@@ -21,7 +33,7 @@ pub fn ebreak_handler(trap_context: &mut TrapFrame) {
 
     let kprobe = BREAK_KPROBE_LIST.lock().get(&break_addr).map(|k| k.clone());
     if let Some(kprobe) = kprobe {
-        kprobe.call_pre_handler(&PtRegs::from(trap_context.clone()));
+        kprobe.call_pre_handler(&PtRegs::new(trap_context, break_addr));
         // set single step
         #[cfg(target_arch = "x86_64")]
         {
@@ -38,7 +50,7 @@ pub fn ebreak_handler(trap_context: &mut TrapFrame) {
             let run = run_list.get(&break_addr);
             if let Some(kprobe) = run {
                 println!("The kprobe which pc {:#x} is in run list", break_addr);
-                kprobe.call_post_handler(&PtRegs::from(trap_context.clone()));
+                kprobe.call_post_handler(&PtRegs::new(trap_context, break_addr));
                 let next_inst = kprobe.return_address();
                 info!("set sepc: {:#x}", next_inst);
                 *trap_context.index_mut(TrapFrameArgs::SEPC) = next_inst;
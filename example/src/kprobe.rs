@@ -1,8 +1,14 @@
-use alloc::{collections::BTreeMap, string::ToString, sync::Arc};
+use alloc::{collections::BTreeMap, string::ToString, sync::Arc, vec::Vec};
 
-use kprobe::{Kprobe, KprobeBuilder, KprobeOps, ProbeArgs};
+use kprobe::{
+    probe_event::ProbeEvent, unwind::unwind, Kprobe, KprobeBuilder, KprobeOps, ProbeArgs,
+};
+use libbpf::{
+    helpers::{bpf_get_stack, HelperEnv, PERF_MAX_STACK_DEPTH},
+    map::BpfMapRegistry,
+};
 use polyhal::{hart_id, TrapFrame};
-use spin::Mutex;
+use spin::{mutex::SpinMutex, Mutex};
 
 pub static BREAK_KPROBE_LIST: Mutex<BTreeMap<usize, Arc<Kprobe>>> = Mutex::new(BTreeMap::new());
 pub static DEBUG_KPROBE_LIST: Mutex<BTreeMap<usize, Arc<Kprobe>>> = Mutex::new(BTreeMap::new());
@@ -24,21 +30,78 @@ pub struct PtRegs {
     pub r13: usize,
     pub r14: usize,
     pub r15: usize,
+    pub rsp: usize,
+    /// The instruction that raised the trap
+    pub pc: usize,
 }
 
 #[cfg(any(target_arch = "riscv64", target_arch = "loongarch64"))]
 pub struct PtRegs {
     pub x: [usize; 32],
+    /// The instruction that raised the trap
+    pub pc: usize,
 }
 
+#[cfg(target_arch = "riscv64")]
+const REGISTER_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
 impl ProbeArgs for PtRegs {
     fn as_any(&self) -> &dyn core::any::Any {
         self
     }
+
+    fn break_address(&self) -> usize {
+        self.pc
+    }
+
+    fn debug_address(&self) -> usize {
+        self.pc
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn register(&self, name: &str) -> Option<usize> {
+        let value = match name {
+            "ax" => self.rax,
+            "cx" => self.rcx,
+            "dx" => self.rdx,
+            "bx" => self.rbx,
+            "bp" => self.rbp,
+            "si" => self.rsi,
+            "di" => self.rdi,
+            "r8" => self.r8,
+            "r9" => self.r9,
+            "r10" => self.r10,
+            "r11" => self.r11,
+            "r12" => self.r12,
+            "r13" => self.r13,
+            "r14" => self.r14,
+            "r15" => self.r15,
+            "sp" => self.rsp,
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    #[cfg(target_arch = "riscv64")]
+    fn register(&self, name: &str) -> Option<usize> {
+        let idx = REGISTER_NAMES.iter().position(|reg| *reg == name)?;
+        Some(self.x[idx])
+    }
+
+    #[cfg(target_arch = "loongarch64")]
+    fn register(&self, name: &str) -> Option<usize> {
+        let idx = name.strip_prefix('r')?.parse::<usize>().ok()?;
+        self.x.get(idx).copied()
+    }
 }
 #[cfg(target_arch = "x86_64")]
-impl From<TrapFrame> for PtRegs {
-    fn from(tf: TrapFrame) -> Self {
+impl PtRegs {
+    /// The registers of a trap raised by the instruction at `pc`
+    pub fn new(tf: &TrapFrame, pc: usize) -> Self {
         Self {
             rax: tf.rax,
             rcx: tf.rcx,
@@ -55,21 +118,53 @@ impl From<TrapFrame> for PtRegs {
             r13: tf.r13,
             r14: tf.r14,
             r15: tf.r15,
+            rsp: tf.rsp,
+            pc,
         }
     }
 }
 
 #[cfg(target_arch = "riscv64")]
-impl From<TrapFrame> for PtRegs {
-    fn from(tf: TrapFrame) -> Self {
-        Self { x: tf.x }
+impl PtRegs {
+    /// The registers of a trap raised by the instruction at `pc`
+    pub fn new(tf: &TrapFrame, pc: usize) -> Self {
+        Self { x: tf.x, pc }
     }
 }
 
 #[cfg(target_arch = "loongarch64")]
-impl From<TrapFrame> for PtRegs {
-    fn from(tf: TrapFrame) -> Self {
-        Self { x: tf.regs }
+impl PtRegs {
+    /// The registers of a trap raised by the instruction at `pc`
+    pub fn new(tf: &TrapFrame, pc: usize) -> Self {
+        Self { x: tf.regs, pc }
+    }
+}
+
+/// The maps of the BPF programs attached to the probes
+static BPF_MAPS: BpfMapRegistry<SpinMutex<()>> = BpfMapRegistry::new();
+
+/// The host state of the BPF helpers, programs attached to kprobes get the
+/// [`PtRegs`] of the probe as their context
+pub struct BpfEnv;
+
+impl HelperEnv for BpfEnv {
+    type Lock = SpinMutex<()>;
+
+    fn maps() -> &'static BpfMapRegistry<Self::Lock> {
+        &BPF_MAPS
+    }
+
+    fn stack_trace(ctx: u64, ips: &mut [u64]) -> usize {
+        // SAFETY: the context of a kprobe program is the PtRegs of the probe
+        let regs = unsafe { &*(ctx as *const PtRegs) };
+        let mut addresses = [0; PERF_MAX_STACK_DEPTH];
+        let max = ips.len().min(addresses.len());
+        // SAFETY: the example kernel is built with frame pointers
+        let len = unsafe { unwind(regs, &mut addresses[..max]) };
+        for (ip, address) in ips.iter_mut().zip(&addresses[..len]) {
+            *ip = *address as u64;
+        }
+        len
     }
 }
 
@@ -117,3 +212,149 @@ pub fn test_kprobe() {
     DEBUG_KPROBE_LIST.lock().remove(&debug_address);
     detect_func(1, 2);
 }
+
+pub fn test_probe_event() {
+    let event = "p:myprobe detect_func x=$arg1:u64 y=$arg2:x64 ret=$stack0:x64"
+        .parse::<ProbeEvent>()
+        .unwrap();
+    println!("probe event: {}", event);
+    let kprobe = event
+        .kprobe_builder(
+            |symbol| (symbol == "detect_func").then_some(detect_func as usize),
+            read_kernel_memory,
+            |record| println!("{}", record),
+        )
+        .unwrap()
+        .build()
+        .install();
+
+    let kprobe = Arc::new(kprobe);
+    BREAK_KPROBE_LIST
+        .lock()
+        .insert(detect_func as usize, kprobe.clone());
+    let debug_address = kprobe.debug_address();
+    DEBUG_KPROBE_LIST.lock().insert(debug_address, kprobe);
+    detect_func(3, 4);
+
+    BREAK_KPROBE_LIST.lock().remove(&(detect_func as usize));
+    DEBUG_KPROBE_LIST.lock().remove(&debug_address);
+
+    let event = "r:myret detect_func $retval".parse::<ProbeEvent>().unwrap();
+    println!("probe event: {}", event);
+    let kprobes = event
+        .kretprobe_builders(
+            |symbol| (symbol == "detect_func").then_some(detect_func as usize),
+            detect_func_exits,
+            read_kernel_memory,
+            |record| println!("{}", record),
+        )
+        .unwrap()
+        .into_iter()
+        .map(|builder| Arc::new(builder.build().install()))
+        .collect::<Vec<_>>();
+    for kprobe in &kprobes {
+        BREAK_KPROBE_LIST
+            .lock()
+            .insert(kprobe.kprobe_address(), kprobe.clone());
+        DEBUG_KPROBE_LIST
+            .lock()
+            .insert(kprobe.debug_address(), kprobe.clone());
+    }
+    detect_func(7, 8);
+
+    for kprobe in &kprobes {
+        BREAK_KPROBE_LIST.lock().remove(&kprobe.kprobe_address());
+        DEBUG_KPROBE_LIST.lock().remove(&kprobe.debug_address());
+    }
+}
+
+/// How many bytes of `detect_func` are searched for its exit point
+const DETECT_FUNC_WINDOW: usize = 256;
+
+/// The example kernel has no symbol sizes. `detect_func` is small and has a
+/// single epilogue, so its first return instruction is its only exit point.
+fn detect_func_exits(address: usize) -> Vec<usize> {
+    // SAFETY: the window lies in the kernel text
+    let code = unsafe { core::slice::from_raw_parts(address as *const u8, DETECT_FUNC_WINDOW) };
+    kprobe::return_instructions(address, code)
+        .into_iter()
+        .take(1)
+        .collect()
+}
+
+/// How far above the reader's own frame the kernel stack may be read
+const STACK_READ_LIMIT: usize = 4096;
+
+/// The example kernel can not recover from faults, so the probe definitions may
+/// only read the stack the probe handler runs on, above the reader's own frame.
+fn read_kernel_memory(address: usize, buf: &mut [u8]) -> bool {
+    let marker = 0u8;
+    let low = &marker as *const u8 as usize;
+    let readable = address >= low
+        && address
+            .checked_add(buf.len())
+            .is_some_and(|end| end <= low + STACK_READ_LIMIT);
+    if readable {
+        // SAFETY: the range lies within the live kernel stack
+        unsafe {
+            core::ptr::copy_nonoverlapping(address as *const u8, buf.as_mut_ptr(), buf.len())
+        };
+    }
+    readable
+}
+
+/// The stack seen by the `bpf_get_stack` helper from a probe on `detect_func`
+static PROBED_STACK: Mutex<[u64; 4]> = Mutex::new([0; 4]);
+
+#[inline(never)]
+fn call_detect_func() -> usize {
+    detect_func(5, 6)
+}
+
+pub fn test_stack_trace() {
+    let pre_handler = |regs: &dyn ProbeArgs| {
+        let pt_regs = regs.as_any().downcast_ref::<PtRegs>().unwrap();
+        let mut stack = PROBED_STACK.lock();
+        let size = core::mem::size_of_val(&*stack) as u64;
+        // call the helper as a kprobe program would, with the registers as
+        // its context
+        let copied = bpf_get_stack::<BpfEnv>(
+            pt_regs as *const PtRegs as u64,
+            stack.as_mut_ptr() as u64,
+            size,
+            0,
+            0,
+        );
+        println!(
+            "bpf_get_stack copied {} bytes: {:#x?}",
+            copied as i64, *stack
+        );
+    };
+    let kprobe = KprobeBuilder::new()
+        .symbol("detect_func".to_string())
+        .symbol_addr(detect_func as usize)
+        .offset(0)
+        .pre_handler(pre_handler)
+        .post_handler(|_: &dyn ProbeArgs| {})
+        .fault_handler(|_: &dyn ProbeArgs| {})
+        .build()
+        .install();
+
+    let kprobe = Arc::new(kprobe);
+    BREAK_KPROBE_LIST
+        .lock()
+        .insert(detect_func as usize, kprobe.clone());
+    let debug_address = kprobe.debug_address();
+    DEBUG_KPROBE_LIST.lock().insert(debug_address, kprobe);
+    call_detect_func();
+
+    BREAK_KPROBE_LIST.lock().remove(&(detect_func as usize));
+    DEBUG_KPROBE_LIST.lock().remove(&debug_address);
+
+    let stack = PROBED_STACK.lock();
+    assert_eq!(stack[0], detect_func as usize as u64);
+    // the probe hits the first instruction, before detect_func saves its own
+    // frame, so the first return address is in the caller of call_detect_func
+    assert_ne!(stack[1], 0);
+    println!("test_stack_trace passed");
+}
@@ -13,6 +13,8 @@ mod frame;
 mod logging;
 mod ebreak;
 mod kprobe;
+#[cfg(any(target_arch = "x86_64", target_arch = "riscv64"))]
+mod watchpoint;
 
 #[cfg(target_arch = "x86_64")]
 mod debug;
@@ -91,6 +93,10 @@ fn main(hartid: usize) {
         frame::add_frame_range(start, start + size);
     });
     kprobe::test_kprobe();
+    kprobe::test_probe_event();
+    kprobe::test_stack_trace();
+    #[cfg(any(target_arch = "x86_64", target_arch = "riscv64"))]
+    watchpoint::test_hw_breakpoint();
 
     unsafe {
         #[cfg(target_arch = "riscv64")]
@@ -0,0 +1,134 @@
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+#[cfg(target_arch = "riscv64")]
+use core::ops::IndexMut;
+
+#[cfg(target_arch = "x86_64")]
+use bit_field::BitField;
+
+use kprobe::{
+    HwBreakpoint, HwBreakpointBuilder, HwBreakpointError, HwBreakpointOps, HwBreakpointType,
+    ProbeArgs,
+};
+#[cfg(any(target_arch = "x86_64", target_arch = "riscv64"))]
+use polyhal::TrapFrame;
+#[cfg(target_arch = "riscv64")]
+use polyhal::TrapFrameArgs;
+use spin::Mutex;
+
+use crate::kprobe::PtRegs;
+
+/// Installed hardware breakpoints, indexed by their debug register slot
+pub static HW_BREAKPOINT_LIST: Mutex<BTreeMap<usize, Arc<HwBreakpoint>>> =
+    Mutex::new(BTreeMap::new());
+
+/// Breakpoints disarmed while the access that hit them is single stepped
+#[cfg(target_arch = "riscv64")]
+static STEPPING: Mutex<Vec<Arc<HwBreakpoint>>> = Mutex::new(Vec::new());
+
+static mut WATCHED_VALUE: usize = 0;
+
+/// Call the handlers of the breakpoints that triggered and return them
+fn call_handlers(regs: &PtRegs, fault_address: usize) -> Vec<Arc<HwBreakpoint>> {
+    let hits = kprobe::hw_breakpoint_hits();
+    let list = HW_BREAKPOINT_LIST.lock();
+    let mut triggered = Vec::new();
+    for (slot, breakpoint) in list.iter() {
+        if hits & (1 << slot) != 0 || breakpoint.contains(fault_address) {
+            breakpoint.call_handler(regs);
+            triggered.push(breakpoint.clone());
+        }
+    }
+    triggered
+}
+
+/// Call the handlers of the breakpoints hit by the instruction at `pc`, return
+/// false if none did.
+///
+/// An instruction breakpoint faults before its instruction runs, so `RF` is
+/// set to execute it once without hitting the breakpoint again.
+#[cfg(target_arch = "x86_64")]
+pub fn hw_breakpoint_handler(trap_context: &mut TrapFrame, pc: usize) -> bool {
+    // the hit slots come from DR6, there is no fault address
+    let triggered = call_handlers(&PtRegs::new(trap_context, pc), 0);
+    if triggered
+        .iter()
+        .any(|breakpoint| breakpoint.ty() == HwBreakpointType::Execute)
+    {
+        // rflags.RF
+        trap_context.rflags.set_bit(16, true);
+    }
+    !triggered.is_empty()
+}
+
+/// Handle a trigger hit by the instruction at `pc`.
+///
+/// riscv triggers fire before the access, so the breakpoints are disarmed and
+/// the access is single stepped out of line, see [`single_step_done`].
+#[cfg(target_arch = "riscv64")]
+pub fn trigger_handler(trap_context: &mut TrapFrame, pc: usize) {
+    let fault_address = riscv::register::stval::read();
+    let triggered = call_handlers(&PtRegs::new(trap_context, pc), fault_address);
+    for breakpoint in &triggered {
+        breakpoint.set_enabled(false);
+    }
+    let Some(breakpoint) = triggered.first() else {
+        // a trigger we do not know about, retry the access
+        println!("There is no hw breakpoint at pc {:#x}", pc);
+        *trap_context.index_mut(TrapFrameArgs::SEPC) = pc;
+        return;
+    };
+    // SAFETY: pc hit the trigger and this kernel runs on one hart
+    let step_addr = unsafe { breakpoint.single_step_address(pc) };
+    *trap_context.index_mut(TrapFrameArgs::SEPC) = step_addr;
+    *STEPPING.lock() = triggered;
+}
+
+/// Re-arm the breakpoints once their access has been single stepped, return
+/// false if `break_addr` does not end a step.
+#[cfg(target_arch = "riscv64")]
+pub fn single_step_done(trap_context: &mut TrapFrame, break_addr: usize) -> bool {
+    let mut stepping = STEPPING.lock();
+    match stepping.first() {
+        Some(breakpoint) if breakpoint.debug_address() == break_addr => {
+            *trap_context.index_mut(TrapFrameArgs::SEPC) = breakpoint.return_address();
+        }
+        _ => return false,
+    }
+    for breakpoint in stepping.drain(..) {
+        breakpoint.set_enabled(true);
+    }
+    true
+}
+
+pub fn test_hw_breakpoint() {
+    let handler = |_regs: &dyn ProbeArgs| {
+        println!("call hw breakpoint handler, the value is written");
+    };
+    // SAFETY: only the address is taken
+    let address = unsafe { core::ptr::addr_of!(WATCHED_VALUE) } as usize;
+    let breakpoint = HwBreakpointBuilder::new()
+        .symbol("WATCHED_VALUE".into())
+        .address(address)
+        .size(core::mem::size_of::<usize>())
+        .ty(HwBreakpointType::Write)
+        .handler(handler)
+        .build()
+        .install();
+    let breakpoint = match breakpoint {
+        Ok(breakpoint) => breakpoint,
+        Err(HwBreakpointError::Unavailable) => {
+            println!("hardware breakpoints are not available, skip the test");
+            return;
+        }
+        Err(err) => panic!("install hw breakpoint failed: {:?}", err),
+    };
+    let slot = breakpoint.slot().unwrap();
+    HW_BREAKPOINT_LIST.lock().insert(slot, Arc::new(breakpoint));
+    unsafe {
+        core::ptr::write_volatile(core::ptr::addr_of_mut!(WATCHED_VALUE), 1);
+    }
+    HW_BREAKPOINT_LIST.lock().remove(&slot);
+    unsafe {
+        core::ptr::write_volatile(core::ptr::addr_of_mut!(WATCHED_VALUE), 2);
+    }
+}
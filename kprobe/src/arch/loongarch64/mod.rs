@@ -1,12 +1,28 @@
+use alloc::vec::Vec;
 use core::ops::{Deref, DerefMut};
 
-use crate::{KprobeBasic, KprobeBuilder, KprobeOps};
+use crate::{unwind::FrameLayout, KprobeBasic, KprobeBuilder, KprobeOps};
 
 // #define BRK_KPROBE_BP		10	/* Kprobe break */
 // #define BRK_KPROBE_SSTEPBP	11	/* Kprobe single step break */
 const BRK_KPROBE_BP: u64 = 10;
 const BRK_KPROBE_SSTEPBP: u64 = 11;
 const EBREAK_INST: u32 = 0x002a0000;
+const RET_INST: u32 = 0x4c000020; // jirl zero, ra, 0
+
+/// Registers carrying the function arguments, in order (`$argN` in probe definitions)
+pub const ARG_REGISTERS: &[&str] = &["r4", "r5", "r6", "r7", "r8", "r9", "r10", "r11"];
+/// Register carrying the function return value (`$retval` in probe definitions)
+pub const RETVAL_REGISTER: &str = "r4";
+/// Stack pointer register (`$stack` in probe definitions)
+pub const STACK_POINTER_REGISTER: &str = "r3";
+/// Frame pointer register, where the unwinder starts
+pub const FRAME_POINTER_REGISTER: &str = "r22";
+/// Frame records: `fp` (`r22`) points just above the saved `ra` and `fp`
+pub const FRAME_LAYOUT: FrameLayout = FrameLayout {
+    prev_fp: -16,
+    return_address: -8,
+};
 
 #[derive(Debug)]
 pub struct Kprobe {
@@ -91,3 +107,14 @@ impl Drop for Kprobe {
         );
     }
 }
+
+/// The addresses of the return instructions (`jirl $zero, $ra, 0`) in `code`,
+/// the instructions of a function loaded at `address`. These are the exit points
+/// a return probe is installed on.
+pub fn return_instructions(address: usize, code: &[u8]) -> Vec<usize> {
+    code.chunks_exact(4)
+        .enumerate()
+        .filter(|(_, inst)| u32::from_le_bytes((*inst).try_into().unwrap()) == RET_INST)
+        .map(|(idx, _)| address + idx * 4)
+        .collect()
+}
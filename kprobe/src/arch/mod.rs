@@ -1,4 +1,4 @@
-use alloc::{boxed::Box, string::String};
+use alloc::{boxed::Box, format, string::String};
 use core::{any::Any, fmt::Debug};
 
 #[cfg(target_arch = "riscv64")]
@@ -20,6 +20,13 @@ pub trait ProbeArgs: Send {
     fn as_any(&self) -> &dyn Any;
     fn break_address(&self) -> usize;
     fn debug_address(&self) -> usize;
+    /// Read a general purpose register by its name (e.g. `a0`, `sp`, `di`).
+    ///
+    /// This is used by the fetch arguments of text probe definitions, the default
+    /// implementation knows no register.
+    fn register(&self, _name: &str) -> Option<usize> {
+        None
+    }
 }
 
 pub trait KprobeOps: Send {
@@ -33,12 +40,14 @@ pub trait KprobeOps: Send {
     fn debug_address(&self) -> usize;
 }
 
+type HandlerFn = dyn Fn(&dyn ProbeArgs) + Send + Sync;
+
 pub struct ProbeHandler {
-    func: Box<fn(&dyn ProbeArgs)>,
+    func: Box<HandlerFn>,
 }
 
 impl ProbeHandler {
-    pub fn new(func: fn(&dyn ProbeArgs)) -> Self {
+    pub fn new(func: impl Fn(&dyn ProbeArgs) + Send + Sync + 'static) -> Self {
         ProbeHandler {
             func: Box::new(func),
         }
@@ -83,17 +92,17 @@ impl KprobeBuilder {
         self
     }
 
-    pub fn pre_handler(mut self, func: fn(&dyn ProbeArgs)) -> Self {
+    pub fn pre_handler(mut self, func: impl Fn(&dyn ProbeArgs) + Send + Sync + 'static) -> Self {
         self.pre_handler = Some(ProbeHandler::new(func));
         self
     }
 
-    pub fn post_handler(mut self, func: fn(&dyn ProbeArgs)) -> Self {
+    pub fn post_handler(mut self, func: impl Fn(&dyn ProbeArgs) + Send + Sync + 'static) -> Self {
         self.post_handler = Some(ProbeHandler::new(func));
         self
     }
 
-    pub fn fault_handler(mut self, func: fn(&dyn ProbeArgs)) -> Self {
+    pub fn fault_handler(mut self, func: impl Fn(&dyn ProbeArgs) + Send + Sync + 'static) -> Self {
         self.fault_handler = Some(ProbeHandler::new(func));
         self
     }
@@ -155,3 +164,160 @@ impl From<KprobeBuilder> for KprobeBasic {
         }
     }
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HwBreakpointType {
+    /// Trigger when the instruction at the address is executed
+    Execute,
+    /// Trigger when the address is written
+    Write,
+    /// Trigger when the address is read
+    Read,
+    /// Trigger when the address is read or written
+    ReadWrite,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HwBreakpointError {
+    /// The watched size is not 1, 2, 4 or 8 bytes
+    InvalidSize(usize),
+    /// The address is not aligned to the watched size
+    UnalignedAddress(usize),
+    /// The architecture can not watch this kind of access
+    UnsupportedType(HwBreakpointType),
+    /// All debug registers are in use
+    NoFreeSlot,
+    /// The debug registers can not be accessed from the current privilege mode
+    Unavailable,
+}
+
+pub trait HwBreakpointOps: Send {
+    /// Program a free debug register of the current cpu with the breakpoint.
+    ///
+    /// Only the cpu calling `install` is armed, the others never trap on the
+    /// address.
+    fn install(self) -> Result<Self, HwBreakpointError>
+    where
+        Self: Sized;
+    /// The debug register used by the breakpoint, `None` before installation
+    fn slot(&self) -> Option<usize>;
+    /// Temporarily disarm or re-arm an installed breakpoint
+    fn set_enabled(&self, enabled: bool);
+}
+
+pub struct HwBreakpointBuilder {
+    symbol: Option<String>,
+    address: Option<usize>,
+    size: Option<usize>,
+    ty: Option<HwBreakpointType>,
+    handler: Option<ProbeHandler>,
+}
+
+impl HwBreakpointBuilder {
+    pub fn new() -> Self {
+        HwBreakpointBuilder {
+            symbol: None,
+            address: None,
+            size: None,
+            ty: None,
+            handler: None,
+        }
+    }
+
+    pub fn symbol(mut self, symbol: String) -> Self {
+        self.symbol = Some(symbol);
+        self
+    }
+
+    pub fn address(mut self, address: usize) -> Self {
+        self.address = Some(address);
+        self
+    }
+
+    /// The number of watched bytes, defaults to the size of a pointer
+    pub fn size(mut self, size: usize) -> Self {
+        self.size = Some(size);
+        self
+    }
+
+    pub fn ty(mut self, ty: HwBreakpointType) -> Self {
+        self.ty = Some(ty);
+        self
+    }
+
+    pub fn handler(mut self, func: impl Fn(&dyn ProbeArgs) + Send + Sync + 'static) -> Self {
+        self.handler = Some(ProbeHandler::new(func));
+        self
+    }
+}
+
+pub struct HwBreakpointBasic {
+    symbol: String,
+    address: usize,
+    size: usize,
+    ty: HwBreakpointType,
+    handler: ProbeHandler,
+}
+
+impl Debug for HwBreakpointBasic {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("HwBreakpoint")
+            .field("symbol", &self.symbol)
+            .field("address", &self.address)
+            .field("size", &self.size)
+            .field("ty", &self.ty)
+            .finish()
+    }
+}
+
+impl HwBreakpointBasic {
+    pub fn call_handler(&self, trap_frame: &dyn ProbeArgs) {
+        self.handler.call(trap_frame);
+    }
+
+    pub fn symbol(&self) -> &str {
+        &self.symbol
+    }
+
+    pub fn address(&self) -> usize {
+        self.address
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn ty(&self) -> HwBreakpointType {
+        self.ty
+    }
+
+    /// Whether an access to `address` falls into the watched range
+    pub fn contains(&self, address: usize) -> bool {
+        // a range running past the end of the address space ends there
+        let end = self.address.checked_add(self.size);
+        address >= self.address && end.map_or(true, |end| address < end)
+    }
+
+    fn check(&self) -> Result<(), HwBreakpointError> {
+        if !matches!(self.size, 1 | 2 | 4 | 8) {
+            return Err(HwBreakpointError::InvalidSize(self.size));
+        }
+        if self.address % self.size != 0 {
+            return Err(HwBreakpointError::UnalignedAddress(self.address));
+        }
+        Ok(())
+    }
+}
+
+impl From<HwBreakpointBuilder> for HwBreakpointBasic {
+    fn from(value: HwBreakpointBuilder) -> Self {
+        let address = value.address.unwrap();
+        HwBreakpointBasic {
+            symbol: value.symbol.unwrap_or_else(|| format!("{:#x}", address)),
+            address,
+            size: value.size.unwrap_or(core::mem::size_of::<usize>()),
+            ty: value.ty.unwrap(),
+            handler: value.handler.unwrap(),
+        }
+    }
+}
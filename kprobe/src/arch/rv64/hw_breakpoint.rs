@@ -0,0 +1,283 @@
+//! Hardware breakpoints on the riscv64 trigger module.
+//!
+//! Each trigger is selected through `tselect` and programmed as an address
+//! match (`mcontrol`) trigger through `tdata1`/`tdata2`. The trigger CSRs are
+//! only accessible from M-mode and an S-mode kernel can not probe for them, so
+//! they stay untouched until the kernel calls [`enable_trigger_csrs`]. Before
+//! that [`HwBreakpointOps::install`] fails with [`HwBreakpointError::Unavailable`].
+//!
+//! A matching access raises a breakpoint exception *before* it is performed,
+//! with `stval` holding the accessed address. [`is_trigger_hit`] tells it apart
+//! from an `ebreak`. The handler disarms the breakpoint and single steps the
+//! access out of line like a kprobe does: the instruction is copied to
+//! [`HwBreakpoint::single_step_address`] followed by a `c.ebreak` at
+//! [`HwBreakpoint::debug_address`], where the breakpoint is re-armed and the
+//! execution resumes at [`HwBreakpoint::return_address`].
+use core::{
+    arch::asm,
+    cell::UnsafeCell,
+    fmt::Debug,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use super::{C_EBREAK_INST, EBREAK_INST};
+use crate::{
+    HwBreakpointBasic, HwBreakpointBuilder, HwBreakpointError, HwBreakpointOps, HwBreakpointType,
+};
+
+/// Upper bound of triggers probed through `tselect`
+const MAX_TRIGGERS: usize = 16;
+
+const MCONTROL_TYPE: usize = 2 << 60;
+const MCONTROL_TYPE_MASK: usize = 0xf << 60;
+const MCONTROL_HIT: usize = 1 << 20;
+const MCONTROL_MATCH_NAPOT: usize = 1 << 7;
+const MCONTROL_M: usize = 1 << 6;
+const MCONTROL_S: usize = 1 << 4;
+const MCONTROL_EXECUTE: usize = 1 << 2;
+const MCONTROL_STORE: usize = 1 << 1;
+const MCONTROL_LOAD: usize = 1 << 0;
+const MCONTROL_ACCESS_MASK: usize = MCONTROL_EXECUTE | MCONTROL_STORE | MCONTROL_LOAD;
+
+static TRIGGER_CSRS: AtomicBool = AtomicBool::new(false);
+
+/// Let the hardware breakpoints use the trigger CSRs.
+///
+/// # Safety
+/// The trigger CSRs are M-mode CSRs, the caller has to run in M-mode or on
+/// firmware that emulates the accesses. Otherwise every access raises an
+/// illegal instruction exception.
+pub unsafe fn enable_trigger_csrs() {
+    TRIGGER_CSRS.store(true, Ordering::Release);
+}
+
+/// Whether the trigger CSRs may be used, see [`enable_trigger_csrs`]
+pub fn hw_breakpoint_available() -> bool {
+    TRIGGER_CSRS.load(Ordering::Acquire)
+}
+
+pub struct HwBreakpoint {
+    basic: HwBreakpointBasic,
+    slot: Option<usize>,
+    tdata1: usize,
+    /// The access that hit the trigger followed by a `c.ebreak`
+    step: UnsafeCell<[u16; 3]>,
+    step_len: AtomicUsize,
+    return_address: AtomicUsize,
+}
+
+// The step buffer is only written by the breakpoint handler while the trigger
+// is disarmed, see `single_step_address`.
+unsafe impl Sync for HwBreakpoint {}
+
+impl Debug for HwBreakpoint {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("HwBreakpoint")
+            .field("basic", &self.basic)
+            .field("slot", &self.slot)
+            .field("tdata1", &self.tdata1)
+            .finish()
+    }
+}
+
+impl Deref for HwBreakpoint {
+    type Target = HwBreakpointBasic;
+
+    fn deref(&self) -> &Self::Target {
+        &self.basic
+    }
+}
+
+impl DerefMut for HwBreakpoint {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.basic
+    }
+}
+
+impl HwBreakpointBuilder {
+    pub fn build(self) -> HwBreakpoint {
+        HwBreakpoint {
+            basic: HwBreakpointBasic::from(self),
+            slot: None,
+            tdata1: 0,
+            step: UnsafeCell::new([0; 3]),
+            step_len: AtomicUsize::new(0),
+            return_address: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl HwBreakpoint {
+    /// Copy the instruction at `pc` out of line, followed by a `c.ebreak`, and
+    /// return the address to single step it from. Call it with the breakpoint
+    /// disarmed.
+    ///
+    /// # Safety
+    /// `pc` has to be the instruction that hit the trigger, and the breakpoint
+    /// must not be stepped on two harts at once.
+    pub unsafe fn single_step_address(&self, pc: usize) -> usize {
+        let step = self.step.get() as *mut u16;
+        let low = core::ptr::read(pc as *const u16);
+        let len = if low & 0b11 == 0b11 { 2 } else { 1 };
+        core::ptr::copy_nonoverlapping(pc as *const u16, step, len);
+        core::ptr::write(step.add(len), C_EBREAK_INST as u16);
+        asm!("fence.i");
+        self.step_len.store(len * 2, Ordering::Release);
+        self.return_address.store(pc + len * 2, Ordering::Release);
+        step as usize
+    }
+
+    /// The `c.ebreak` that ends the single step, where the breakpoint is re-armed
+    pub fn debug_address(&self) -> usize {
+        self.step.get() as usize + self.step_len.load(Ordering::Acquire)
+    }
+
+    /// The instruction after the stepped access
+    pub fn return_address(&self) -> usize {
+        self.return_address.load(Ordering::Acquire)
+    }
+}
+
+/// Tell a breakpoint exception raised by a trigger from one raised by an
+/// `ebreak`, both have the same cause. Always false while the triggers are not
+/// [available](hw_breakpoint_available).
+///
+/// # Safety
+/// `pc` has to be the instruction that raised the exception.
+pub unsafe fn is_trigger_hit(pc: usize) -> bool {
+    if !hw_breakpoint_available() {
+        return false;
+    }
+    let low = core::ptr::read(pc as *const u16);
+    if low & 0b11 != 0b11 {
+        return low != C_EBREAK_INST as u16;
+    }
+    core::ptr::read_unaligned(pc as *const u32) != EBREAK_INST
+}
+
+/// Select a trigger, return false if it does not exist
+fn select(slot: usize) -> bool {
+    let selected: usize;
+    unsafe {
+        asm!("csrw 0x7a0, {}", in(reg) slot);
+        asm!("csrr {}, 0x7a0", out(reg) selected);
+    }
+    selected == slot
+}
+
+fn read_tdata1() -> usize {
+    let value;
+    unsafe { asm!("csrr {}, 0x7a1", out(reg) value) };
+    value
+}
+
+fn write_tdata1(value: usize) {
+    unsafe { asm!("csrw 0x7a1, {}", in(reg) value) };
+}
+
+fn write_tdata2(value: usize) {
+    unsafe { asm!("csrw 0x7a2, {}", in(reg) value) };
+}
+
+/// Return the bitmask of triggers whose `hit` bit is set and clear it.
+///
+/// The `hit` bit is optional, when the hardware does not implement it the
+/// handler can find the breakpoint by matching `stval` with
+/// [`HwBreakpointBasic::contains`].
+pub fn hw_breakpoint_hits() -> usize {
+    if !hw_breakpoint_available() {
+        return 0;
+    }
+    let mut hits = 0;
+    for slot in 0..MAX_TRIGGERS {
+        if !select(slot) {
+            break;
+        }
+        let tdata1 = read_tdata1();
+        if tdata1 & MCONTROL_TYPE_MASK == MCONTROL_TYPE && tdata1 & MCONTROL_HIT != 0 {
+            write_tdata1(tdata1 & !MCONTROL_HIT);
+            hits |= 1 << slot;
+        }
+    }
+    hits
+}
+
+impl HwBreakpointOps for HwBreakpoint {
+    fn install(mut self) -> Result<Self, HwBreakpointError> {
+        if !hw_breakpoint_available() {
+            return Err(HwBreakpointError::Unavailable);
+        }
+        self.check()?;
+        let access = match self.ty() {
+            HwBreakpointType::Execute => MCONTROL_EXECUTE,
+            HwBreakpointType::Write => MCONTROL_STORE,
+            HwBreakpointType::Read => MCONTROL_LOAD,
+            HwBreakpointType::ReadWrite => MCONTROL_LOAD | MCONTROL_STORE,
+        };
+        // a naturally aligned range is matched as NAPOT: the low bits of tdata2
+        // are set up to the bit below the size of the range
+        let (match_mode, tdata2) = match self.size() {
+            1 => (0, self.address()),
+            size => (MCONTROL_MATCH_NAPOT, self.address() | (size / 2 - 1)),
+        };
+        let tdata1 = MCONTROL_TYPE | match_mode | MCONTROL_M | MCONTROL_S | access;
+        for slot in 0..MAX_TRIGGERS {
+            if !select(slot) {
+                break;
+            }
+            let old = read_tdata1();
+            if old & MCONTROL_TYPE_MASK != MCONTROL_TYPE || old & MCONTROL_ACCESS_MASK != 0 {
+                continue;
+            }
+            write_tdata1(MCONTROL_TYPE);
+            write_tdata2(tdata2);
+            write_tdata1(tdata1);
+            // the trigger may not support the requested match, check it stuck
+            if read_tdata1() & MCONTROL_ACCESS_MASK != access {
+                write_tdata1(MCONTROL_TYPE);
+                continue;
+            }
+            self.slot = Some(slot);
+            self.tdata1 = tdata1;
+            log::trace!(
+                "HwBreakpoint::install: address: {:#x}, symbol: {}, slot: {}",
+                self.address(),
+                self.symbol(),
+                slot
+            );
+            return Ok(self);
+        }
+        Err(HwBreakpointError::NoFreeSlot)
+    }
+
+    fn slot(&self) -> Option<usize> {
+        self.slot
+    }
+
+    fn set_enabled(&self, enabled: bool) {
+        if let Some(slot) = self.slot {
+            select(slot);
+            if enabled {
+                write_tdata1(self.tdata1);
+            } else {
+                write_tdata1(self.tdata1 & !MCONTROL_ACCESS_MASK);
+            }
+        }
+    }
+}
+
+impl Drop for HwBreakpoint {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot {
+            select(slot);
+            write_tdata1(MCONTROL_TYPE);
+            write_tdata2(0);
+            log::trace!(
+                "HwBreakpoint::uninstall: address: {:#x}, slot: {}",
+                self.address(),
+                slot
+            );
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use alloc::vec::Vec;
 use core::{
     arch::riscv64::sfence_vma_all,
     fmt::Debug,
@@ -6,9 +7,28 @@ use core::{
 
 use raki::{decode::Decode, Isa};
 
-use crate::{KprobeBasic, KprobeBuilder, KprobeOps};
+mod hw_breakpoint;
+pub use hw_breakpoint::*;
+
+use crate::{unwind::FrameLayout, KprobeBasic, KprobeBuilder, KprobeOps};
 const EBREAK_INST: u32 = 0x00100073; // ebreak
 const C_EBREAK_INST: u32 = 0x9002; // c.ebreak
+const RET_INST: u32 = 0x00008067; // jalr zero, 0(ra)
+const C_RET_INST: u16 = 0x8082; // c.jr ra
+
+/// Registers carrying the function arguments, in order (`$argN` in probe definitions)
+pub const ARG_REGISTERS: &[&str] = &["a0", "a1", "a2", "a3", "a4", "a5", "a6", "a7"];
+/// Register carrying the function return value (`$retval` in probe definitions)
+pub const RETVAL_REGISTER: &str = "a0";
+/// Stack pointer register (`$stack` in probe definitions)
+pub const STACK_POINTER_REGISTER: &str = "sp";
+/// Frame pointer register, where the unwinder starts
+pub const FRAME_POINTER_REGISTER: &str = "s0";
+/// Frame records: `s0` points just above the saved `ra` and `s0`
+pub const FRAME_LAYOUT: FrameLayout = FrameLayout {
+    prev_fp: -16,
+    return_address: -8,
+};
 
 #[derive(Debug)]
 pub struct Kprobe {
@@ -127,3 +147,30 @@ impl Drop for Kprobe {
         );
     }
 }
+
+/// The addresses of the return instructions (`ret` and `c.jr ra`) in `code`, the
+/// instructions of a function loaded at `address`. These are the exit points a
+/// return probe is installed on.
+pub fn return_instructions(address: usize, code: &[u8]) -> Vec<usize> {
+    let mut exits = Vec::new();
+    let mut offset = 0;
+    while offset + 2 <= code.len() {
+        let inst_16 = u16::from_le_bytes([code[offset], code[offset + 1]]);
+        // the two lowest bits of a 32 bit instruction are set
+        if inst_16 & 0b11 != 0b11 {
+            if inst_16 == C_RET_INST {
+                exits.push(address + offset);
+            }
+            offset += 2;
+            continue;
+        }
+        let Some(bytes) = code.get(offset..offset + 4) else {
+            break;
+        };
+        if u32::from_le_bytes(bytes.try_into().unwrap()) == RET_INST {
+            exits.push(address + offset);
+        }
+        offset += 4;
+    }
+    exits
+}
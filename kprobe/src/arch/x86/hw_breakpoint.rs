@@ -0,0 +1,178 @@
+//! Hardware breakpoints on the x86_64 debug registers.
+//!
+//! DR0-DR3 hold the watched addresses, DR7 enables them and selects the access
+//! type and length, DR6 reports which of them triggered the `#DB` exception.
+//! Data breakpoints trap after the access completed, instruction breakpoints
+//! fault before the instruction runs, so the debug handler must set `RF` in
+//! `rflags` to resume from them.
+//!
+//! The debug registers are per cpu, so a breakpoint only traps on the cpu that
+//! installed it.
+use core::{
+    arch::asm,
+    fmt::Debug,
+    ops::{Deref, DerefMut},
+};
+
+use crate::{
+    HwBreakpointBasic, HwBreakpointBuilder, HwBreakpointError, HwBreakpointOps, HwBreakpointType,
+};
+
+const SLOT_NUM: usize = 4;
+const DR6_HIT_MASK: usize = 0xf;
+
+pub struct HwBreakpoint {
+    basic: HwBreakpointBasic,
+    slot: Option<usize>,
+}
+
+impl Debug for HwBreakpoint {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("HwBreakpoint")
+            .field("basic", &self.basic)
+            .field("slot", &self.slot)
+            .finish()
+    }
+}
+
+impl Deref for HwBreakpoint {
+    type Target = HwBreakpointBasic;
+
+    fn deref(&self) -> &Self::Target {
+        &self.basic
+    }
+}
+
+impl DerefMut for HwBreakpoint {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.basic
+    }
+}
+
+impl HwBreakpointBuilder {
+    pub fn build(self) -> HwBreakpoint {
+        HwBreakpoint {
+            basic: HwBreakpointBasic::from(self),
+            slot: None,
+        }
+    }
+}
+
+fn read_dr6() -> usize {
+    let value;
+    unsafe { asm!("mov {}, dr6", out(reg) value) };
+    value
+}
+
+fn write_dr6(value: usize) {
+    unsafe { asm!("mov dr6, {}", in(reg) value) };
+}
+
+fn read_dr7() -> usize {
+    let value;
+    unsafe { asm!("mov {}, dr7", out(reg) value) };
+    value
+}
+
+fn write_dr7(value: usize) {
+    unsafe { asm!("mov dr7, {}", in(reg) value) };
+}
+
+fn write_address(slot: usize, address: usize) {
+    unsafe {
+        match slot {
+            0 => asm!("mov dr0, {}", in(reg) address),
+            1 => asm!("mov dr1, {}", in(reg) address),
+            2 => asm!("mov dr2, {}", in(reg) address),
+            3 => asm!("mov dr3, {}", in(reg) address),
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// The local enable bit of a slot in DR7
+fn enable_bit(slot: usize) -> usize {
+    1 << (slot * 2)
+}
+
+/// The R/W and LEN fields of a slot in DR7
+fn control_bits(
+    slot: usize,
+    ty: HwBreakpointType,
+    size: usize,
+) -> Result<usize, HwBreakpointError> {
+    let rw = match ty {
+        HwBreakpointType::Execute => 0b00,
+        HwBreakpointType::Write => 0b01,
+        HwBreakpointType::ReadWrite => 0b11,
+        HwBreakpointType::Read => return Err(HwBreakpointError::UnsupportedType(ty)),
+    };
+    // instruction breakpoints must use a length of one byte
+    let len = match (ty, size) {
+        (HwBreakpointType::Execute, _) | (_, 1) => 0b00,
+        (_, 2) => 0b01,
+        (_, 4) => 0b11,
+        _ => 0b10,
+    };
+    Ok((rw | len << 2) << (16 + slot * 4))
+}
+
+/// Return the bitmask of slots that triggered the current debug exception and
+/// clear it in DR6
+pub fn hw_breakpoint_hits() -> usize {
+    let dr6 = read_dr6();
+    write_dr6(dr6 & !DR6_HIT_MASK);
+    dr6 & DR6_HIT_MASK
+}
+
+impl HwBreakpointOps for HwBreakpoint {
+    fn install(mut self) -> Result<Self, HwBreakpointError> {
+        self.check()?;
+        let dr7 = read_dr7();
+        let slot = (0..SLOT_NUM)
+            .find(|slot| dr7 & (0b11 << (slot * 2)) == 0)
+            .ok_or(HwBreakpointError::NoFreeSlot)?;
+        let control = control_bits(slot, self.ty(), self.size())?;
+        let control_mask = 0xf << (16 + slot * 4);
+        write_address(slot, self.address());
+        write_dr7((dr7 & !control_mask) | control | enable_bit(slot));
+        self.slot = Some(slot);
+        log::trace!(
+            "HwBreakpoint::install: address: {:#x}, symbol: {}, slot: {}",
+            self.address(),
+            self.symbol(),
+            slot
+        );
+        Ok(self)
+    }
+
+    fn slot(&self) -> Option<usize> {
+        self.slot
+    }
+
+    fn set_enabled(&self, enabled: bool) {
+        if let Some(slot) = self.slot {
+            let dr7 = read_dr7();
+            if enabled {
+                write_dr7(dr7 | enable_bit(slot));
+            } else {
+                write_dr7(dr7 & !enable_bit(slot));
+            }
+        }
+    }
+}
+
+impl Drop for HwBreakpoint {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot {
+            let control_mask = 0xf << (16 + slot * 4);
+            write_dr7(read_dr7() & !(control_mask | enable_bit(slot)));
+            write_address(slot, 0);
+            log::trace!(
+                "HwBreakpoint::uninstall: address: {:#x}, slot: {}",
+                self.address(),
+                slot
+            );
+        }
+    }
+}
@@ -1,15 +1,33 @@
-use alloc::string::ToString;
+use alloc::{string::ToString, vec::Vec};
 use core::{
     fmt::Debug,
     ops::{Deref, DerefMut},
 };
 
 use yaxpeax_arch::LengthedInstruction;
+use yaxpeax_x86::amd64::Opcode;
 
-use crate::{KprobeBasic, KprobeBuilder, KprobeOps};
+mod hw_breakpoint;
+pub use hw_breakpoint::*;
+
+use crate::{unwind::FrameLayout, KprobeBasic, KprobeBuilder, KprobeOps};
 
 const EBREAK_INST: u8 = 0xcc; // x86_64: 0xcc
 
+/// Registers carrying the function arguments, in order (`$argN` in probe definitions)
+pub const ARG_REGISTERS: &[&str] = &["di", "si", "dx", "cx", "r8", "r9"];
+/// Register carrying the function return value (`$retval` in probe definitions)
+pub const RETVAL_REGISTER: &str = "ax";
+/// Stack pointer register (`$stack` in probe definitions)
+pub const STACK_POINTER_REGISTER: &str = "sp";
+/// Frame pointer register, where the unwinder starts
+pub const FRAME_POINTER_REGISTER: &str = "bp";
+/// Frame records: `rbp` points to the saved `rbp`, followed by the return address
+pub const FRAME_LAYOUT: FrameLayout = FrameLayout {
+    prev_fp: 0,
+    return_address: 8,
+};
+
 pub struct Kprobe {
     basic: KprobeBasic,
     old_instruction: [u8; 15],
@@ -116,3 +134,22 @@ impl Drop for Kprobe {
         );
     }
 }
+
+/// The addresses of the return instructions in `code`, the instructions of a
+/// function loaded at `address`. These are the exit points a return probe is
+/// installed on.
+pub fn return_instructions(address: usize, code: &[u8]) -> Vec<usize> {
+    let decoder = yaxpeax_x86::amd64::InstDecoder::default();
+    let mut exits = Vec::new();
+    let mut offset = 0;
+    while offset < code.len() {
+        let Ok(inst) = decoder.decode_slice(&code[offset..]) else {
+            break;
+        };
+        if inst.opcode() == Opcode::RETURN {
+            exits.push(address + offset);
+        }
+        offset += inst.len().to_const() as usize;
+    }
+    exits
+}
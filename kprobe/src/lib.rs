@@ -1,8 +1,10 @@
 #![cfg_attr(target_arch = "riscv64", feature(riscv_ext_intrinsics))]
 #![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_main)]
 extern crate alloc;
 
 mod arch;
+pub mod probe_event;
+pub mod unwind;
 
 pub use arch::*;
@@ -0,0 +1,1134 @@
+//! Probe definitions in the text syntax of Linux's `kprobe_events`.
+//!
+//! ```text
+//! p[:[GRP/]EVENT] SYM[+offs]|MEMADDR [FETCHARGS]
+//! r[MAXACTIVE][:[GRP/]EVENT] SYM[+0]|MEMADDR [FETCHARGS]
+//!
+//! FETCHARGS: [NAME=]FETCHARG[:TYPE]
+//!   %REG                   register
+//!   @ADDR                  memory at ADDR
+//!   @SYM[+|-offs]          memory at SYM +|- offs
+//!   $stackN                Nth entry of the stack (N >= 0)
+//!   $stack                 stack address
+//!   $argN                  Nth function argument (N >= 1)
+//!   $retval                return value (return probes only)
+//!   +|-[u]OFFS(FETCHARG)   memory at FETCHARG +|- OFFS
+//!   \IMM                   immediate value
+//! TYPE: u8/u16/u32/u64, s8/s16/s32/s64, x8/x16/x32/x64, string
+//! ```
+//!
+//! For example `p:myprobe detect_func+4 x=%a0:u64 s=+0($arg2):string` or
+//! `r:myret detect_func $retval`.
+//!
+//! A return probe is a kprobe on each exit point of the function, the return
+//! instructions found by the host (see [`ProbeEvent::kretprobe_builders`]).
+//! `$retval` reads [`RETVAL_REGISTER`] there, `MAXACTIVE` is accepted for
+//! compatibility and ignored.
+//!
+//! Memory fetchers read through a [`MemoryReader`] supplied by the host, so a bad
+//! address in a definition shows up as [`FetchValue::Fault`] instead of a fault in
+//! the probe handler.
+use alloc::{
+    boxed::Box,
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use core::{
+    fmt::{Display, Formatter},
+    str::FromStr,
+};
+
+use crate::{KprobeBuilder, ProbeArgs, ARG_REGISTERS, RETVAL_REGISTER, STACK_POINTER_REGISTER};
+
+/// Group used when the definition does not name one
+pub const DEFAULT_GROUP: &str = "kprobes";
+/// Maximum number of bytes read for a `string` fetch argument
+pub const MAX_STRING_SIZE: usize = 256;
+
+/// Reads kernel memory for the memory fetchers: fills `buf` with the bytes at
+/// `address` and returns `false` if any of them is not mapped.
+///
+/// Hosts check the address against their page tables or use a copy routine that
+/// recovers from faults.
+pub type MemoryReader = dyn Fn(usize, &mut [u8]) -> bool + Send + Sync;
+
+/// Receives the arguments recorded by each hit of a probe
+type RecordFn = dyn Fn(ProbeRecord) + Send + Sync;
+/// The pre-handler shared by the kprobes of one probe event
+type EventHandler = dyn Fn(&dyn ProbeArgs) + Send + Sync;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProbeEventError {
+    /// The definition is empty
+    Empty,
+    /// The definition does not start with `p` or `r`
+    UnknownProbeType(String),
+    /// The group or event name is not a valid identifier
+    InvalidName(String),
+    /// The probed location can not be parsed
+    InvalidLocation(String),
+    /// A fetch argument can not be parsed
+    InvalidFetchArg(String),
+    /// The type of a fetch argument is unknown or can not be used with its fetcher
+    InvalidType(String),
+    /// Two fetch arguments have the same name
+    DuplicateArgName(String),
+    /// A symbol can not be resolved to an address
+    UnresolvedSymbol(String),
+    /// The probe is built by the builder of the other probe kind
+    KindMismatch(ProbeKind),
+    /// The host found no exit point for the function of a return probe
+    NoExitPoint(String),
+}
+
+impl Display for ProbeEventError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ProbeEventError::Empty => write!(f, "empty probe definition"),
+            ProbeEventError::UnknownProbeType(ty) => write!(f, "unknown probe type: {}", ty),
+            ProbeEventError::InvalidName(name) => write!(f, "invalid name: {}", name),
+            ProbeEventError::InvalidLocation(loc) => write!(f, "invalid probe location: {}", loc),
+            ProbeEventError::InvalidFetchArg(arg) => write!(f, "invalid fetch argument: {}", arg),
+            ProbeEventError::InvalidType(ty) => write!(f, "invalid fetch type: {}", ty),
+            ProbeEventError::DuplicateArgName(name) => {
+                write!(f, "duplicate fetch argument name: {}", name)
+            }
+            ProbeEventError::UnresolvedSymbol(symbol) => {
+                write!(f, "can not resolve symbol: {}", symbol)
+            }
+            ProbeEventError::KindMismatch(kind) => {
+                write!(f, "can not build {} probes with this builder", kind)
+            }
+            ProbeEventError::NoExitPoint(symbol) => write!(f, "no exit point in: {}", symbol),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeKind {
+    /// `p`, fires when the probed instruction is reached
+    Entry,
+    /// `r[MAXACTIVE]`, fires when the probed function returns
+    Return { max_active: Option<usize> },
+}
+
+impl Display for ProbeKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ProbeKind::Entry => write!(f, "entry"),
+            ProbeKind::Return { .. } => write!(f, "return"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProbeLocation {
+    Symbol { symbol: String, offset: usize },
+    Address(usize),
+}
+
+impl Display for ProbeLocation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ProbeLocation::Symbol { symbol, offset } if *offset == 0 => write!(f, "{}", symbol),
+            ProbeLocation::Symbol { symbol, offset } => write!(f, "{}+{:#x}", symbol, offset),
+            ProbeLocation::Address(address) => write!(f, "{:#x}", address),
+        }
+    }
+}
+
+/// Where the value of a fetch argument comes from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FetchOp {
+    /// `%REG`
+    Register(String),
+    /// `$stackN`
+    Stack(usize),
+    /// `$stack`
+    StackPointer,
+    /// `$argN`, starting from 1
+    Argument(usize),
+    /// `$retval`
+    ReturnValue,
+    /// `@ADDR`
+    Memory(usize),
+    /// `@SYM[+|-offs]`, turned into [`FetchOp::Memory`] when the probe is built
+    Symbol { symbol: String, offset: isize },
+    /// `+|-[u]OFFS(FETCHARG)`
+    Deref { offset: isize, base: Box<FetchOp> },
+    /// `\IMM`
+    Immediate(u64),
+}
+
+impl FetchOp {
+    fn is_memory(&self) -> bool {
+        matches!(
+            self,
+            FetchOp::Stack(_) | FetchOp::Memory(_) | FetchOp::Symbol { .. } | FetchOp::Deref { .. }
+        )
+    }
+
+    /// Whether the fetcher reads `$retval`, which is only set in return probes
+    fn uses_return_value(&self) -> bool {
+        match self {
+            FetchOp::ReturnValue => true,
+            FetchOp::Deref { base, .. } => base.uses_return_value(),
+            _ => false,
+        }
+    }
+
+    /// The address read by a memory fetcher
+    fn address(&self, regs: &dyn ProbeArgs, read: &MemoryReader) -> Option<usize> {
+        match self {
+            FetchOp::Stack(n) => regs
+                .register(STACK_POINTER_REGISTER)
+                .map(|sp| sp + n * core::mem::size_of::<usize>()),
+            FetchOp::Memory(address) => Some(*address),
+            FetchOp::Deref { offset, base } => base
+                .value(regs, core::mem::size_of::<usize>(), read)
+                .map(|base| (base as usize).wrapping_add_signed(*offset)),
+            _ => None,
+        }
+    }
+
+    /// The raw value of the fetcher, `size` bytes wide
+    fn value(&self, regs: &dyn ProbeArgs, size: usize, read: &MemoryReader) -> Option<u64> {
+        if self.is_memory() {
+            return self
+                .address(regs, read)
+                .and_then(|address| read_memory(read, address, size));
+        }
+        let value = match self {
+            FetchOp::Register(name) => regs.register(name)? as u64,
+            FetchOp::StackPointer => regs.register(STACK_POINTER_REGISTER)? as u64,
+            FetchOp::Argument(n) => regs.register(ARG_REGISTERS.get(n.checked_sub(1)?)?)? as u64,
+            FetchOp::ReturnValue => regs.register(RETVAL_REGISTER)? as u64,
+            FetchOp::Immediate(value) => *value,
+            _ => unreachable!(),
+        };
+        Some(truncate(value, size))
+    }
+
+    fn resolve(&mut self, lookup: &dyn Fn(&str) -> Option<usize>) -> Result<(), ProbeEventError> {
+        match self {
+            FetchOp::Symbol { symbol, offset } => {
+                let address = lookup(symbol)
+                    .ok_or_else(|| ProbeEventError::UnresolvedSymbol(symbol.clone()))?;
+                *self = FetchOp::Memory(address.wrapping_add_signed(*offset));
+            }
+            FetchOp::Deref { base, .. } => base.resolve(lookup)?,
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+impl Display for FetchOp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            FetchOp::Register(name) => write!(f, "%{}", name),
+            FetchOp::Stack(n) => write!(f, "$stack{}", n),
+            FetchOp::StackPointer => write!(f, "$stack"),
+            FetchOp::Argument(n) => write!(f, "$arg{}", n),
+            FetchOp::ReturnValue => write!(f, "$retval"),
+            FetchOp::Memory(address) => write!(f, "@{:#x}", address),
+            FetchOp::Symbol { symbol, offset } if *offset == 0 => write!(f, "@{}", symbol),
+            FetchOp::Symbol { symbol, offset } => write!(f, "@{}{:+}", symbol, offset),
+            FetchOp::Deref { offset, base } => write!(f, "{:+}({})", offset, base),
+            FetchOp::Immediate(value) => write!(f, "\\{}", value),
+        }
+    }
+}
+
+/// How a fetched value is read and printed, sizes are in bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FetchType {
+    Unsigned(usize),
+    Signed(usize),
+    Hex(usize),
+    String,
+}
+
+impl FetchType {
+    /// The type used when a fetch argument does not name one
+    pub const DEFAULT: FetchType = FetchType::Hex(core::mem::size_of::<usize>());
+}
+
+impl FromStr for FetchType {
+    type Err = ProbeEventError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "string" {
+            return Ok(FetchType::String);
+        }
+        let invalid = || ProbeEventError::InvalidType(s.to_string());
+        let size = |bits: &str| match bits {
+            "8" => Ok(1),
+            "16" => Ok(2),
+            "32" => Ok(4),
+            "64" => Ok(8),
+            _ => Err(invalid()),
+        };
+        if let Some(bits) = s.strip_prefix('u') {
+            Ok(FetchType::Unsigned(size(bits)?))
+        } else if let Some(bits) = s.strip_prefix('s') {
+            Ok(FetchType::Signed(size(bits)?))
+        } else if let Some(bits) = s.strip_prefix('x') {
+            Ok(FetchType::Hex(size(bits)?))
+        } else {
+            Err(invalid())
+        }
+    }
+}
+
+impl Display for FetchType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            FetchType::Unsigned(size) => write!(f, "u{}", size * 8),
+            FetchType::Signed(size) => write!(f, "s{}", size * 8),
+            FetchType::Hex(size) => write!(f, "x{}", size * 8),
+            FetchType::String => write!(f, "string"),
+        }
+    }
+}
+
+/// A named argument recorded each time the probe fires
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FetchArg {
+    pub name: String,
+    pub op: FetchOp,
+    pub ty: FetchType,
+}
+
+impl FetchArg {
+    /// Fetch the argument from the register context of a hit, memory is read
+    /// through `read`
+    pub fn fetch(&self, regs: &dyn ProbeArgs, read: &MemoryReader) -> FetchValue {
+        let value = match self.ty {
+            FetchType::String => {
+                let value = self
+                    .op
+                    .address(regs, read)
+                    .and_then(|address| read_string(read, address));
+                return value.map_or(FetchValue::Fault, FetchValue::String);
+            }
+            FetchType::Unsigned(size) | FetchType::Signed(size) | FetchType::Hex(size) => {
+                self.op.value(regs, size, read)
+            }
+        };
+        match (value, self.ty) {
+            (None, _) => FetchValue::Fault,
+            (Some(value), FetchType::Signed(size)) => {
+                let shift = 64 - size * 8;
+                FetchValue::Signed(((value << shift) as i64) >> shift)
+            }
+            (Some(value), FetchType::Hex(_)) => FetchValue::Hex(value),
+            (Some(value), _) => FetchValue::Unsigned(value),
+        }
+    }
+}
+
+impl Display for FetchArg {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}={}:{}", self.name, self.op, self.ty)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FetchValue {
+    Unsigned(u64),
+    Signed(i64),
+    Hex(u64),
+    String(String),
+    /// The register is unknown or the memory could not be read
+    Fault,
+}
+
+impl Display for FetchValue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            FetchValue::Unsigned(value) => write!(f, "{}", value),
+            FetchValue::Signed(value) => write!(f, "{}", value),
+            FetchValue::Hex(value) => write!(f, "{:#x}", value),
+            FetchValue::String(value) => write!(f, "\"{}\"", value),
+            FetchValue::Fault => write!(f, "(fault)"),
+        }
+    }
+}
+
+/// The arguments recorded by one hit of a probe
+#[derive(Debug, Clone)]
+pub struct ProbeRecord {
+    pub event: String,
+    pub location: ProbeLocation,
+    pub address: usize,
+    pub args: Vec<(String, FetchValue)>,
+}
+
+impl Display for ProbeRecord {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}: ({})", self.event, self.location)?;
+        for (name, value) in &self.args {
+            write!(f, " {}={}", name, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// A probe definition parsed from the `kprobe_events` syntax
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProbeEvent {
+    pub kind: ProbeKind,
+    pub group: String,
+    pub event: String,
+    pub location: ProbeLocation,
+    pub args: Vec<FetchArg>,
+}
+
+impl ProbeEvent {
+    /// Fetch all arguments of the probe from the register context of a hit
+    pub fn record(&self, regs: &dyn ProbeArgs, read: &MemoryReader) -> ProbeRecord {
+        ProbeRecord {
+            event: self.event.clone(),
+            location: self.location.clone(),
+            address: regs.break_address(),
+            args: self
+                .args
+                .iter()
+                .map(|arg| (arg.name.clone(), arg.fetch(regs, read)))
+                .collect(),
+        }
+    }
+
+    /// Create a [`KprobeBuilder`] for an entry probe whose pre-handler records
+    /// the arguments and passes them to `record`.
+    ///
+    /// `lookup` resolves the symbols used by the location and the `@SYM` fetchers,
+    /// `read` is the [`MemoryReader`] used by the memory fetchers.
+    pub fn kprobe_builder(
+        &self,
+        lookup: impl Fn(&str) -> Option<usize>,
+        read: impl Fn(usize, &mut [u8]) -> bool + Send + Sync + 'static,
+        record: impl Fn(ProbeRecord) + Send + Sync + 'static,
+    ) -> Result<KprobeBuilder, ProbeEventError> {
+        if self.kind != ProbeKind::Entry {
+            return Err(ProbeEventError::KindMismatch(self.kind));
+        }
+        let (symbol, symbol_addr, offset) = self.resolve_location(&lookup)?;
+        let handler = self.handler(&lookup, Arc::new(read), Arc::new(record))?;
+        Ok(probe_builder(symbol, symbol_addr, offset, handler))
+    }
+
+    /// Create the [`KprobeBuilder`]s of a return probe, one on each exit point of
+    /// the function. Their pre-handlers record the arguments and pass them to
+    /// `record`.
+    ///
+    /// `exits` returns the addresses of the return instructions of the function
+    /// starting at the given address, e.g. found with
+    /// [`return_instructions`](crate::return_instructions). `lookup` and `read`
+    /// are used as in [`ProbeEvent::kprobe_builder`].
+    pub fn kretprobe_builders(
+        &self,
+        lookup: impl Fn(&str) -> Option<usize>,
+        exits: impl Fn(usize) -> Vec<usize>,
+        read: impl Fn(usize, &mut [u8]) -> bool + Send + Sync + 'static,
+        record: impl Fn(ProbeRecord) + Send + Sync + 'static,
+    ) -> Result<Vec<KprobeBuilder>, ProbeEventError> {
+        if self.kind == ProbeKind::Entry {
+            return Err(ProbeEventError::KindMismatch(self.kind));
+        }
+        let (symbol, symbol_addr, _) = self.resolve_location(&lookup)?;
+        let handler = self.handler(&lookup, Arc::new(read), Arc::new(record))?;
+        let exits = exits(symbol_addr);
+        if exits.is_empty() {
+            return Err(ProbeEventError::NoExitPoint(symbol));
+        }
+        exits
+            .into_iter()
+            .map(|exit| {
+                let offset = exit
+                    .checked_sub(symbol_addr)
+                    .ok_or_else(|| ProbeEventError::InvalidLocation(format!("{:#x}", exit)))?;
+                Ok(probe_builder(
+                    symbol.clone(),
+                    symbol_addr,
+                    offset,
+                    handler.clone(),
+                ))
+            })
+            .collect()
+    }
+
+    /// The symbol, its address and the offset of the probed instruction
+    fn resolve_location(
+        &self,
+        lookup: &dyn Fn(&str) -> Option<usize>,
+    ) -> Result<(String, usize, usize), ProbeEventError> {
+        match &self.location {
+            ProbeLocation::Symbol { symbol, offset } => {
+                let address = lookup(symbol)
+                    .ok_or_else(|| ProbeEventError::UnresolvedSymbol(symbol.clone()))?;
+                Ok((symbol.clone(), address, *offset))
+            }
+            ProbeLocation::Address(address) => Ok((format!("{:#x}", address), *address, 0)),
+        }
+    }
+
+    /// The pre-handler shared by the kprobes of the event
+    fn handler(
+        &self,
+        lookup: &dyn Fn(&str) -> Option<usize>,
+        read: Arc<MemoryReader>,
+        record: Arc<RecordFn>,
+    ) -> Result<Arc<EventHandler>, ProbeEventError> {
+        let mut event = self.clone();
+        for arg in event.args.iter_mut() {
+            arg.op.resolve(lookup)?;
+        }
+        Ok(Arc::new(move |regs: &dyn ProbeArgs| {
+            record(event.record(regs, &*read))
+        }))
+    }
+}
+
+fn probe_builder(
+    symbol: String,
+    symbol_addr: usize,
+    offset: usize,
+    handler: Arc<EventHandler>,
+) -> KprobeBuilder {
+    KprobeBuilder::new()
+        .symbol(symbol)
+        .symbol_addr(symbol_addr)
+        .offset(offset)
+        .pre_handler(move |regs| handler(regs))
+        .post_handler(|_| {})
+}
+
+impl FromStr for ProbeEvent {
+    type Err = ProbeEventError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut tokens = s.split_whitespace();
+        let head = tokens.next().ok_or(ProbeEventError::Empty)?;
+        let (ty, name) = match head.split_once(':') {
+            Some((ty, name)) => (ty, Some(name)),
+            None => (head, None),
+        };
+        let kind = if ty == "p" {
+            ProbeKind::Entry
+        } else {
+            // `r[MAXACTIVE]`
+            let unknown = || ProbeEventError::UnknownProbeType(ty.to_string());
+            let max_active = ty.strip_prefix('r').ok_or_else(unknown)?;
+            if !max_active.chars().all(|c| c.is_ascii_digit()) {
+                return Err(unknown());
+            }
+            ProbeKind::Return {
+                max_active: match max_active {
+                    "" => None,
+                    _ => Some(max_active.parse().map_err(|_| unknown())?),
+                },
+            }
+        };
+
+        let token = tokens
+            .next()
+            .ok_or_else(|| ProbeEventError::InvalidLocation(String::new()))?;
+        let location = parse_location(token)?;
+        // a return probe fires when the function returns, not at an instruction
+        if let (ProbeKind::Return { .. }, ProbeLocation::Symbol { offset, .. }) = (kind, &location)
+        {
+            if *offset != 0 {
+                return Err(ProbeEventError::InvalidLocation(token.to_string()));
+            }
+        }
+
+        let (group, event) = match name.map(|name| name.split_once('/')) {
+            Some(Some((group, event))) => (group, event),
+            Some(None) => ("", name.unwrap()),
+            None => ("", ""),
+        };
+        let group = if group.is_empty() {
+            DEFAULT_GROUP
+        } else {
+            group
+        };
+        let event = if event.is_empty() {
+            default_event_name(kind, &location)
+        } else {
+            event.to_string()
+        };
+        if !is_identifier(group) {
+            return Err(ProbeEventError::InvalidName(group.to_string()));
+        }
+        if !is_identifier(&event) {
+            return Err(ProbeEventError::InvalidName(event));
+        }
+
+        let mut args: Vec<FetchArg> = Vec::new();
+        for (idx, token) in tokens.enumerate() {
+            let arg = parse_fetch_arg(token, idx + 1, kind)?;
+            if args.iter().any(|a| a.name == arg.name) {
+                return Err(ProbeEventError::DuplicateArgName(arg.name));
+            }
+            args.push(arg);
+        }
+
+        Ok(ProbeEvent {
+            kind,
+            group: group.to_string(),
+            event,
+            location,
+            args,
+        })
+    }
+}
+
+impl Display for ProbeEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self.kind {
+            ProbeKind::Entry => write!(f, "p")?,
+            ProbeKind::Return { max_active: None } => write!(f, "r")?,
+            ProbeKind::Return {
+                max_active: Some(max_active),
+            } => write!(f, "r{}", max_active)?,
+        }
+        write!(f, ":{}/{} {}", self.group, self.event, self.location)?;
+        for arg in &self.args {
+            write!(f, " {}", arg)?;
+        }
+        Ok(())
+    }
+}
+
+fn default_event_name(kind: ProbeKind, location: &ProbeLocation) -> String {
+    let prefix = match kind {
+        ProbeKind::Entry => 'p',
+        ProbeKind::Return { .. } => 'r',
+    };
+    match location {
+        ProbeLocation::Symbol { symbol, offset } => format!("{}_{}_{}", prefix, symbol, offset),
+        ProbeLocation::Address(address) => format!("{}_0x{:x}", prefix, address),
+    }
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn parse_number(s: &str) -> Option<usize> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// Parse `+offs` or `-offs`
+fn parse_signed_offset(s: &str) -> Option<isize> {
+    if let Some(offset) = s.strip_prefix('+') {
+        parse_number(offset).map(|offset| offset as isize)
+    } else {
+        parse_number(s.strip_prefix('-')?).map(|offset| -(offset as isize))
+    }
+}
+
+fn parse_location(s: &str) -> Result<ProbeLocation, ProbeEventError> {
+    let invalid = || ProbeEventError::InvalidLocation(s.to_string());
+    if s.starts_with(|c: char| c.is_ascii_digit()) {
+        return parse_number(s)
+            .map(ProbeLocation::Address)
+            .ok_or_else(invalid);
+    }
+    let (symbol, offset) = match s.split_once('+') {
+        Some((symbol, offset)) => (symbol, parse_number(offset).ok_or_else(invalid)?),
+        None => (s, 0),
+    };
+    if !is_identifier(symbol) {
+        return Err(invalid());
+    }
+    Ok(ProbeLocation::Symbol {
+        symbol: symbol.to_string(),
+        offset,
+    })
+}
+
+fn parse_fetch_arg(s: &str, idx: usize, kind: ProbeKind) -> Result<FetchArg, ProbeEventError> {
+    let (name, fetch) = match s.split_once('=') {
+        Some((name, fetch)) => (name.to_string(), fetch),
+        None => (format!("arg{}", idx), s),
+    };
+    if !is_identifier(&name) {
+        return Err(ProbeEventError::InvalidName(name));
+    }
+    let (fetch, ty) = match fetch.rsplit_once(':') {
+        Some((fetch, ty)) => (fetch, ty.parse()?),
+        None => (fetch, FetchType::DEFAULT),
+    };
+    let op = parse_fetch_op(fetch)?;
+    if kind == ProbeKind::Entry && op.uses_return_value() {
+        return Err(ProbeEventError::InvalidFetchArg(fetch.to_string()));
+    }
+    if ty == FetchType::String && !op.is_memory() {
+        return Err(ProbeEventError::InvalidType(format!("{}:string", fetch)));
+    }
+    Ok(FetchArg { name, op, ty })
+}
+
+fn parse_fetch_op(s: &str) -> Result<FetchOp, ProbeEventError> {
+    let invalid = || ProbeEventError::InvalidFetchArg(s.to_string());
+    if let Some(register) = s.strip_prefix('%') {
+        if register.is_empty() || !register.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(invalid());
+        }
+        return Ok(FetchOp::Register(register.to_string()));
+    }
+    if let Some(address) = s.strip_prefix('@') {
+        if address.starts_with(|c: char| c.is_ascii_digit()) {
+            return parse_number(address)
+                .map(FetchOp::Memory)
+                .ok_or_else(invalid);
+        }
+        let (symbol, offset) = match address.find(['+', '-']) {
+            Some(pos) => (
+                &address[..pos],
+                parse_signed_offset(&address[pos..]).ok_or_else(invalid)?,
+            ),
+            None => (address, 0),
+        };
+        if !is_identifier(symbol) {
+            return Err(invalid());
+        }
+        return Ok(FetchOp::Symbol {
+            symbol: symbol.to_string(),
+            offset,
+        });
+    }
+    if let Some(var) = s.strip_prefix('$') {
+        return match var {
+            "stack" => Ok(FetchOp::StackPointer),
+            "retval" => Ok(FetchOp::ReturnValue),
+            _ => {
+                if let Some(n) = var.strip_prefix("stack") {
+                    n.parse().map(FetchOp::Stack).map_err(|_| invalid())
+                } else if let Some(n) = var.strip_prefix("arg") {
+                    match n.parse() {
+                        Ok(n) if n >= 1 => Ok(FetchOp::Argument(n)),
+                        _ => Err(invalid()),
+                    }
+                } else {
+                    Err(invalid())
+                }
+            }
+        };
+    }
+    if let Some(imm) = s.strip_prefix('\\') {
+        return parse_number(imm)
+            .map(|imm| FetchOp::Immediate(imm as u64))
+            .ok_or_else(invalid);
+    }
+    if s.starts_with(['+', '-']) {
+        let (offset, base) = s
+            .strip_suffix(')')
+            .and_then(|s| s.split_once('('))
+            .ok_or_else(invalid)?;
+        // `u` marks a user space address, both are read the same way here
+        let (sign, offset) = offset.split_at(1);
+        let offset = offset.strip_prefix('u').unwrap_or(offset);
+        let offset = parse_signed_offset(&format!("{}{}", sign, offset)).ok_or_else(invalid)?;
+        let base = parse_fetch_op(base)?;
+        return Ok(FetchOp::Deref {
+            offset,
+            base: Box::new(base),
+        });
+    }
+    Err(invalid())
+}
+
+fn truncate(value: u64, size: usize) -> u64 {
+    if size >= 8 {
+        value
+    } else {
+        value & ((1u64 << (size * 8)) - 1)
+    }
+}
+
+/// Read `size` bytes at `address`, all supported targets are little endian
+fn read_memory(read: &MemoryReader, address: usize, size: usize) -> Option<u64> {
+    let mut bytes = [0u8; 8];
+    let size = size.min(bytes.len());
+    (address != 0 && read(address, &mut bytes[..size])).then(|| u64::from_le_bytes(bytes))
+}
+
+/// Read a NUL terminated string at `address`, at most [`MAX_STRING_SIZE`] bytes.
+/// The string ends early at the first byte that can not be read.
+fn read_string(read: &MemoryReader, address: usize) -> Option<String> {
+    if address == 0 {
+        return None;
+    }
+    let mut bytes = Vec::new();
+    for i in 0..MAX_STRING_SIZE {
+        let mut byte = [0u8];
+        if !read(address.wrapping_add(i), &mut byte) {
+            if i == 0 {
+                return None;
+            }
+            break;
+        }
+        if byte[0] == 0 {
+            break;
+        }
+        bytes.push(byte[0]);
+    }
+    Some(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{string::ToString, vec, vec::Vec};
+    use core::{
+        any::Any,
+        sync::atomic::{AtomicBool, Ordering},
+    };
+
+    use super::*;
+    use crate::KprobeBasic;
+
+    const SP: usize = 0x1000;
+
+    const RETVAL: usize = 0x2a;
+
+    /// Registers named after [`ARG_REGISTERS`] hold 1, 2, 3..., the return
+    /// register holds [`RETVAL`] unless it also carries an argument
+    struct Regs;
+
+    impl ProbeArgs for Regs {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+        fn break_address(&self) -> usize {
+            0x100
+        }
+        fn debug_address(&self) -> usize {
+            0x104
+        }
+        fn register(&self, name: &str) -> Option<usize> {
+            if name == STACK_POINTER_REGISTER {
+                return Some(SP);
+            }
+            ARG_REGISTERS
+                .iter()
+                .position(|r| *r == name)
+                .map(|idx| idx + 1)
+                .or((name == RETVAL_REGISTER).then_some(RETVAL))
+        }
+    }
+
+    /// Mapped memory is `SP..SP + 16` holding `0x8877665544332211, 0x200b`, the
+    /// string "hi" at 0x2000 and "hello" at 0x200b, ending at the unmapped 0x2010.
+    fn read(address: usize, buf: &mut [u8]) -> bool {
+        let mut memory = vec![0u8; 0x1010];
+        memory[..8].copy_from_slice(&0x8877665544332211u64.to_le_bytes());
+        memory[8..16].copy_from_slice(&0x200bu64.to_le_bytes());
+        memory[0x1000..0x1002].copy_from_slice(b"hi");
+        memory[0x100b..0x1010].copy_from_slice(b"hello");
+        let Some(start) = address.checked_sub(SP) else {
+            return false;
+        };
+        let end = start + buf.len();
+        if !(end <= 16 || (start >= 0x1000 && end <= memory.len())) {
+            return false;
+        }
+        buf.copy_from_slice(&memory[start..end]);
+        true
+    }
+
+    fn parse(s: &str) -> Result<ProbeEvent, ProbeEventError> {
+        s.parse()
+    }
+
+    fn fetch(arg: &str) -> FetchValue {
+        let event = parse(&format!("p detect_func {}", arg)).unwrap();
+        event.args[0].fetch(&Regs, &read)
+    }
+
+    #[test]
+    fn parse_head() {
+        let event = parse("p:grp/ev detect_func+0x10").unwrap();
+        assert_eq!(event.group, "grp");
+        assert_eq!(event.event, "ev");
+        assert_eq!(
+            event.location,
+            ProbeLocation::Symbol {
+                symbol: "detect_func".to_string(),
+                offset: 0x10
+            }
+        );
+        assert!(event.args.is_empty());
+
+        let event = parse("p:ev 0xffff0000").unwrap();
+        assert_eq!(event.group, DEFAULT_GROUP);
+        assert_eq!(event.location, ProbeLocation::Address(0xffff0000));
+
+        let event = parse("p detect_func+4").unwrap();
+        assert_eq!(event.event, "p_detect_func_4");
+        assert_eq!(parse("p 4096").unwrap().event, "p_0x1000");
+    }
+
+    #[test]
+    fn parse_return_probe() {
+        let event = parse("r:myret detect_func $retval").unwrap();
+        assert_eq!(event.kind, ProbeKind::Return { max_active: None });
+        assert_eq!(event.event, "myret");
+        assert_eq!(event.args[0].op, FetchOp::ReturnValue);
+
+        let event = parse("r10 detect_func+0 +8($retval):u8").unwrap();
+        assert_eq!(
+            event.kind,
+            ProbeKind::Return {
+                max_active: Some(10)
+            }
+        );
+        assert_eq!(event.event, "r_detect_func_0");
+        assert_eq!(
+            event.to_string(),
+            "r10:kprobes/r_detect_func_0 detect_func arg1=+8($retval):u8"
+        );
+        assert_eq!(parse(&event.to_string()).unwrap(), event);
+        assert_eq!(parse("r 0x1000").unwrap().event, "r_0x1000");
+    }
+
+    #[test]
+    fn parse_fetch_args() {
+        let event = parse(
+            "p f %a0 x=$arg2:u32 $stack $stack3:s16 @0x10:x8 y=@sym-8 +8(+0($arg1)):string \\42",
+        )
+        .unwrap();
+        let args = event
+            .args
+            .iter()
+            .map(|arg| (arg.name.as_str(), arg.op.clone(), arg.ty))
+            .collect::<Vec<_>>();
+        let deref = |offset, base| FetchOp::Deref {
+            offset,
+            base: Box::new(base),
+        };
+        assert_eq!(
+            args,
+            vec![
+                (
+                    "arg1",
+                    FetchOp::Register("a0".to_string()),
+                    FetchType::DEFAULT
+                ),
+                ("x", FetchOp::Argument(2), FetchType::Unsigned(4)),
+                ("arg3", FetchOp::StackPointer, FetchType::DEFAULT),
+                ("arg4", FetchOp::Stack(3), FetchType::Signed(2)),
+                ("arg5", FetchOp::Memory(0x10), FetchType::Hex(1)),
+                (
+                    "y",
+                    FetchOp::Symbol {
+                        symbol: "sym".to_string(),
+                        offset: -8
+                    },
+                    FetchType::DEFAULT
+                ),
+                (
+                    "arg7",
+                    deref(8, deref(0, FetchOp::Argument(1))),
+                    FetchType::String
+                ),
+                ("arg8", FetchOp::Immediate(42), FetchType::DEFAULT),
+            ]
+        );
+    }
+
+    #[test]
+    fn display_round_trip() {
+        let event = parse("p:grp/ev f+0x4 x=$arg1:s32 y=-0x8(%sp):u8 z=@sym+16:string").unwrap();
+        assert_eq!(
+            event.to_string(),
+            "p:grp/ev f+0x4 x=$arg1:s32 y=-8(%sp):u8 z=@sym+16:string"
+        );
+        assert_eq!(parse(&event.to_string()).unwrap(), event);
+    }
+
+    #[test]
+    fn fetch_types() {
+        for (ty, expected) in [
+            ("u8", FetchType::Unsigned(1)),
+            ("u16", FetchType::Unsigned(2)),
+            ("s32", FetchType::Signed(4)),
+            ("x64", FetchType::Hex(8)),
+            ("string", FetchType::String),
+        ] {
+            assert_eq!(ty.parse::<FetchType>(), Ok(expected));
+            assert_eq!(expected.to_string(), ty);
+        }
+        for ty in ["u", "u12", "i32", "x128", "str"] {
+            assert_eq!(
+                ty.parse::<FetchType>(),
+                Err(ProbeEventError::InvalidType(ty.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn fetch_values() {
+        assert_eq!(fetch("$arg3:u64"), FetchValue::Unsigned(3));
+        assert_eq!(fetch("$stack"), FetchValue::Hex(SP as u64));
+        assert_eq!(fetch("$stack0:u8"), FetchValue::Unsigned(0x11));
+        assert_eq!(fetch("$stack0:u32"), FetchValue::Unsigned(0x44332211));
+        assert_eq!(fetch("$stack0:s8"), FetchValue::Signed(0x11));
+        assert_eq!(fetch("+7($stack):s8"), FetchValue::Signed(-0x78));
+        assert_eq!(fetch("$stack1:x64"), FetchValue::Hex(0x200b));
+        assert_eq!(fetch("@0x2000:string"), FetchValue::String("hi".into()));
+        assert_eq!(
+            fetch("+2(+8($stack)):string"),
+            FetchValue::String("llo".into())
+        );
+        assert_eq!(fetch("\\0x10:u16"), FetchValue::Unsigned(0x10));
+
+        let event = parse("r f $retval:u64").unwrap();
+        let retval = Regs.register(RETVAL_REGISTER).unwrap() as u64;
+        assert_eq!(
+            event.args[0].fetch(&Regs, &read),
+            FetchValue::Unsigned(retval)
+        );
+    }
+
+    #[test]
+    fn fetch_faults() {
+        assert_eq!(fetch("%nope"), FetchValue::Fault);
+        assert_eq!(fetch("$arg20"), FetchValue::Fault);
+        assert_eq!(fetch("$stack2"), FetchValue::Fault);
+        assert_eq!(fetch("@0x10:u64"), FetchValue::Fault);
+        assert_eq!(fetch("+0(\\0):string"), FetchValue::Fault);
+        // an unreadable byte ends the string
+        assert_eq!(
+            fetch("+0(+8($stack)):string"),
+            FetchValue::String("hello".into())
+        );
+    }
+
+    #[test]
+    fn record() {
+        let event = parse("p:ev f a=$arg1:u8 b=$stack1").unwrap();
+        let record = event.record(&Regs, &read);
+        assert_eq!(record.address, 0x100);
+        assert_eq!(record.to_string(), "ev: (f) a=1 b=0x200b");
+    }
+
+    #[test]
+    fn parse_errors() {
+        use ProbeEventError::*;
+        let cases = [
+            ("", Empty),
+            ("q f", UnknownProbeType("q".into())),
+            ("rx:ev f", UnknownProbeType("rx".into())),
+            ("r1x f", UnknownProbeType("r1x".into())),
+            ("r+1 f", UnknownProbeType("r+1".into())),
+            ("r f+4", InvalidLocation("f+4".into())),
+            ("p:1ev f", InvalidName("1ev".into())),
+            ("p:g-1/ev f", InvalidName("g-1".into())),
+            ("p", InvalidLocation(String::new())),
+            ("p f+x", InvalidLocation("f+x".into())),
+            ("p 0xzz", InvalidLocation("0xzz".into())),
+            ("p f 1x=$arg1", InvalidName("1x".into())),
+            ("p f $arg0", InvalidFetchArg("$arg0".into())),
+            ("p f $retval", InvalidFetchArg("$retval".into())),
+            ("p f +8($retval):u8", InvalidFetchArg("+8($retval)".into())),
+            ("r f $retvalx", InvalidFetchArg("$retvalx".into())),
+            ("p f $stackx", InvalidFetchArg("$stackx".into())),
+            ("p f %", InvalidFetchArg("%".into())),
+            ("p f @sym+x", InvalidFetchArg("@sym+x".into())),
+            ("p f +8($arg1", InvalidFetchArg("+8($arg1".into())),
+            ("p f +8(x)", InvalidFetchArg("x".into())),
+            ("p f $arg1:u7", InvalidType("u7".into())),
+            ("p f $arg1:string", InvalidType("$arg1:string".into())),
+            ("p f a=$arg1 a=$arg2", DuplicateArgName("a".into())),
+        ];
+        for (s, error) in cases {
+            assert_eq!(parse(s), Err(error), "{}", s);
+        }
+    }
+
+    #[test]
+    fn kprobe_builder_resolves_symbols() {
+        let event = parse("p missing").unwrap();
+        let error = event.kprobe_builder(|_| None, read, |_| {}).err();
+        assert_eq!(
+            error,
+            Some(ProbeEventError::UnresolvedSymbol("missing".into()))
+        );
+
+        let event = parse("p f x=@sym").unwrap();
+        let lookup = |s: &str| (s == "f").then_some(0x100);
+        let error = event.kprobe_builder(lookup, read, |_| {}).err();
+        assert_eq!(error, Some(ProbeEventError::UnresolvedSymbol("sym".into())));
+
+        let mut op = event.args[0].op.clone();
+        op.resolve(&|_| Some(0x1008)).unwrap();
+        assert_eq!(op, FetchOp::Memory(0x1008));
+    }
+
+    #[test]
+    fn kretprobe_builders_use_exit_points() {
+        let lookup = |s: &str| (s == "f").then_some(0x100);
+        let event = parse("r f $retval").unwrap();
+        let builders = event
+            .kretprobe_builders(lookup, |addr| vec![addr + 0x10, addr + 0x24], read, |_| {})
+            .unwrap();
+        let addresses = builders
+            .into_iter()
+            .map(|builder| KprobeBasic::from(builder).kprobe_address())
+            .collect::<Vec<_>>();
+        assert_eq!(addresses, vec![0x110, 0x124]);
+
+        let error = event
+            .kretprobe_builders(lookup, |_| vec![], read, |_| {})
+            .err();
+        assert_eq!(error, Some(ProbeEventError::NoExitPoint("f".into())));
+        let error = event
+            .kretprobe_builders(lookup, |_| vec![0x80], read, |_| {})
+            .err();
+        assert_eq!(error, Some(ProbeEventError::InvalidLocation("0x80".into())));
+        let error = event.kprobe_builder(lookup, read, |_| {}).err();
+        assert_eq!(error, Some(ProbeEventError::KindMismatch(event.kind)));
+
+        let event = parse("p f").unwrap();
+        let error = event
+            .kretprobe_builders(lookup, |_| vec![0x100], read, |_| {})
+            .err();
+        assert_eq!(error, Some(ProbeEventError::KindMismatch(ProbeKind::Entry)));
+    }
+
+    #[test]
+    fn kretprobe_records_return_value() {
+        let recorded = Arc::new(AtomicBool::new(false));
+        let called = recorded.clone();
+        let expected = format!(
+            "myret: (f) arg1={}",
+            Regs.register(RETVAL_REGISTER).unwrap()
+        );
+        let event = parse("r:myret f $retval:u64").unwrap();
+        let builders = event
+            .kretprobe_builders(
+                |_| Some(0x100),
+                |addr| vec![addr],
+                read,
+                move |record| {
+                    assert_eq!(record.to_string(), expected);
+                    called.store(true, Ordering::Relaxed);
+                },
+            )
+            .unwrap();
+        let probe = KprobeBasic::from(builders.into_iter().next().unwrap());
+        probe.call_pre_handler(&Regs);
+        assert!(recorded.load(Ordering::Relaxed));
+    }
+}
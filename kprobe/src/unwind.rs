@@ -0,0 +1,157 @@
+//! A frame-pointer unwinder walking the stack of a probed function.
+//!
+//! With frame pointers, each function saves the frame pointer of its caller and
+//! its return address in a frame record found from its own frame pointer. The
+//! layout of the record depends on the architecture and is given by
+//! [`FRAME_LAYOUT`](crate::FRAME_LAYOUT), starting from the register named by
+//! [`FRAME_POINTER_REGISTER`](crate::FRAME_POINTER_REGISTER).
+use core::mem::align_of;
+
+use crate::{ProbeArgs, FRAME_LAYOUT, FRAME_POINTER_REGISTER};
+
+/// Where a frame record stores its values, relative to the frame pointer
+#[derive(Debug, Clone, Copy)]
+pub struct FrameLayout {
+    /// Offset of the frame pointer of the caller
+    pub prev_fp: isize,
+    /// Offset of the return address into the caller
+    pub return_address: isize,
+}
+
+/// Fill `ips` with the probed address followed by the return addresses of the
+/// stack, innermost first, and return the number of addresses stored.
+///
+/// The walk stops at a null or misaligned frame pointer, at a null return
+/// address, or when the next frame is not above the current one.
+///
+/// # Safety
+///
+/// The kernel is built with frame pointers and the frame records linked from
+/// the frame pointer of `regs` are readable.
+pub unsafe fn unwind(regs: &dyn ProbeArgs, ips: &mut [usize]) -> usize {
+    let Some(first) = ips.first_mut() else {
+        return 0;
+    };
+    *first = regs.break_address();
+    let mut len = 1;
+    let Some(mut fp) = regs.register(FRAME_POINTER_REGISTER) else {
+        return len;
+    };
+    while len < ips.len() {
+        if fp == 0 || fp % align_of::<usize>() != 0 {
+            break;
+        }
+        let return_address =
+            core::ptr::read(fp.wrapping_add_signed(FRAME_LAYOUT.return_address) as *const usize);
+        let prev_fp = core::ptr::read(fp.wrapping_add_signed(FRAME_LAYOUT.prev_fp) as *const usize);
+        if return_address == 0 {
+            break;
+        }
+        ips[len] = return_address;
+        len += 1;
+        // the stack grows down, the frames of the callers are above
+        if prev_fp <= fp {
+            break;
+        }
+        fp = prev_fp;
+    }
+    len
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{vec, vec::Vec};
+    use core::{any::Any, mem::size_of};
+
+    use super::*;
+
+    /// The words between two frame records
+    const FRAME_WORDS: usize = 4;
+
+    struct Regs {
+        fp: Option<usize>,
+    }
+
+    impl ProbeArgs for Regs {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+        fn break_address(&self) -> usize {
+            0x100
+        }
+        fn debug_address(&self) -> usize {
+            0x104
+        }
+        fn register(&self, name: &str) -> Option<usize> {
+            (name == FRAME_POINTER_REGISTER).then_some(self.fp?)
+        }
+    }
+
+    /// A stack whose frame records return to 0x1000, 0x2000..., each linked to
+    /// the one above it and the last one to a null frame pointer
+    struct Stack {
+        memory: Vec<usize>,
+    }
+
+    impl Stack {
+        fn new(frames: usize) -> Self {
+            let mut stack = Stack {
+                memory: vec![0; (frames + 2) * FRAME_WORDS],
+            };
+            for frame in 0..frames {
+                let prev_fp = if frame + 1 < frames {
+                    stack.fp(frame + 1)
+                } else {
+                    0
+                };
+                stack.set(frame, FRAME_LAYOUT.prev_fp, prev_fp);
+                stack.set(frame, FRAME_LAYOUT.return_address, (frame + 1) * 0x1000);
+            }
+            stack
+        }
+
+        /// The frame pointer of a frame, with room for negative offsets below
+        fn fp(&self, frame: usize) -> usize {
+            self.memory.as_ptr() as usize + (frame + 1) * FRAME_WORDS * size_of::<usize>()
+        }
+
+        fn set(&mut self, frame: usize, offset: isize, value: usize) {
+            let index = ((frame + 1) * FRAME_WORDS)
+                .wrapping_add_signed(offset / size_of::<usize>() as isize);
+            self.memory[index] = value;
+        }
+
+        fn unwind(&self, fp: Option<usize>, depth: usize) -> Vec<usize> {
+            let mut ips = vec![0; depth];
+            // SAFETY: the frame records are in `memory`
+            let len = unsafe { unwind(&Regs { fp }, &mut ips) };
+            ips.truncate(len);
+            ips
+        }
+    }
+
+    #[test]
+    fn walk() {
+        let stack = Stack::new(3);
+        let fp = Some(stack.fp(0));
+        assert_eq!(stack.unwind(fp, 8), [0x100, 0x1000, 0x2000, 0x3000]);
+        // truncated to the size of the buffer
+        assert_eq!(stack.unwind(fp, 2), [0x100, 0x1000]);
+        assert_eq!(stack.unwind(fp, 0), []);
+    }
+
+    #[test]
+    fn stops() {
+        let mut stack = Stack::new(3);
+        // only the probed address without a usable frame pointer
+        assert_eq!(stack.unwind(None, 8), [0x100]);
+        assert_eq!(stack.unwind(Some(0), 8), [0x100]);
+        assert_eq!(stack.unwind(Some(stack.fp(0) + 1), 8), [0x100]);
+        // a frame linked below itself
+        stack.set(1, FRAME_LAYOUT.prev_fp, stack.fp(0));
+        assert_eq!(stack.unwind(Some(stack.fp(0)), 8), [0x100, 0x1000, 0x2000]);
+        // a null return address
+        stack.set(1, FRAME_LAYOUT.return_address, 0);
+        assert_eq!(stack.unwind(Some(stack.fp(0)), 8), [0x100, 0x1000]);
+    }
+}
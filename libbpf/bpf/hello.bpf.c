@@ -1,15 +1,51 @@
-#include <linux/bpf.h>
-#include <bpf/bpf_helpers.h>
-
-int counter = 0;
-int counter2 = 1;
-SEC("xdp")
-int hello(void *ctx) {
-    bpf_printk("Hello World %d", counter2);
-//    bpf_printk("xxxxx yyyyy");
-    counter++;
-    counter2++;
-    return counter2 + counter;
-}
-
+#include <linux/bpf.h>
+#include <bpf/bpf_helpers.h>
+
+int counter = 0;
+int counter2 = 1;
+
+struct {
+    __uint(type, BPF_MAP_TYPE_HASH);
+    __uint(max_entries, 16);
+    __type(key, __u32);
+    __type(value, __u64);
+} calls SEC(".maps");
+
+struct {
+    __uint(type, BPF_MAP_TYPE_PROG_ARRAY);
+    __uint(max_entries, 4);
+    __uint(key_size, sizeof(__u32));
+    __uint(value_size, sizeof(__u32));
+} jmp_table SEC(".maps");
+
+static __attribute__((noinline)) int add(int a, int b) {
+    return a + b;
+}
+
+SEC("xdp")
+int hello(void *ctx) {
+    bpf_printk("Hello World %d", counter2);
+//    bpf_printk("xxxxx yyyyy");
+    counter++;
+    counter2++;
+
+    __u32 key = 0;
+    __u64 one = 1;
+    __u64 *value = bpf_map_lookup_elem(&calls, &key);
+    if (value)
+        (*value)++;
+    else
+        bpf_map_update_elem(&calls, &key, &one, BPF_ANY);
+
+    // continues below if the table is empty
+    bpf_tail_call(ctx, &jmp_table, 0);
+    return add(counter2, counter);
+}
+
+SEC("xdp")
+int hello_tail(void *ctx) {
+    bpf_printk("Hello from a tail call, counter %d", counter);
+    return counter;
+}
+
 char LICENSE[] SEC("license") = "Dual BSD/GPL";
\ No newline at end of file
@@ -0,0 +1,413 @@
+//! Build a BTF blob describing the types of the host, used as the target BTF
+//! of CO-RE relocations and to format map values.
+//!
+//! Kernel types implement [`BtfTypeInfo`], usually with `#[derive(Btf)]` from
+//! the `btf-derive` crate. The derive also places a [`BtfTypeEntry`] in the
+//! `btf_types` linker section so that every annotated type can be collected at
+//! boot:
+//!
+//! ```ignore
+//! let btf = BtfBuilder::from_entries(libbpf::linked_btf_types!()).build();
+//! let bpf = BpfLoader::new()
+//!     .elf(obj)
+//!     .target_btf(&btf)
+//!     .load(&mut registry)?;
+//! ```
+//!
+//! With a custom linker script the section must be kept and delimited, e.g.
+//! `__start_btf_types = .; KEEP(*(btf_types)) __stop_btf_types = .;`.
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::any::TypeId;
+
+use super::{
+    BtfEnumValue, BtfMember, BtfType, BTF_HEADER_LEN, BTF_INT_BOOL, BTF_INT_SIGNED, BTF_KIND_ARRAY,
+    BTF_KIND_ENUM, BTF_KIND_ENUM64, BTF_KIND_INT, BTF_KIND_PTR, BTF_KIND_STRUCT, BTF_KIND_UNION,
+    BTF_MAGIC,
+};
+
+/// A type that can describe itself in BTF
+pub trait BtfTypeInfo: 'static {
+    /// Add the type and the types it references to `builder`, return its id.
+    /// Use [`BtfBuilder::add`] to reference other types so that each type is
+    /// added once.
+    fn add_btf_type(builder: &mut BtfBuilder) -> u32;
+}
+
+/// A type to add to the target BTF, the derive places one in the `btf_types`
+/// section for each annotated type
+#[derive(Clone, Copy)]
+#[repr(transparent)]
+pub struct BtfTypeEntry(pub fn(&mut BtfBuilder) -> u32);
+
+/// A member of a struct or an union, `offset` is in bytes
+pub struct BtfField {
+    pub name: &'static str,
+    pub offset: usize,
+    pub ty: fn(&mut BtfBuilder) -> u32,
+}
+
+/// The BTF types of the host, see the [module](self) documentation
+#[derive(Debug, Default)]
+pub struct BtfBuilder {
+    /// Types from id 1
+    types: Vec<BtfType>,
+    ids: BTreeMap<TypeId, u32>,
+}
+
+impl BtfBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the types of the entries, e.g. those of the `btf_types` section
+    pub fn from_entries(entries: &[BtfTypeEntry]) -> Self {
+        let mut builder = Self::new();
+        for entry in entries {
+            (entry.0)(&mut builder);
+        }
+        builder
+    }
+
+    /// Add `T` if it is not already there, return its id
+    pub fn add<T: BtfTypeInfo>(&mut self) -> u32 {
+        match self.ids.get(&TypeId::of::<T>()) {
+            Some(id) => *id,
+            None => T::add_btf_type(self),
+        }
+    }
+
+    /// Append the type of `T`
+    fn push<T: 'static>(&mut self, ty: BtfType) -> u32 {
+        self.types.push(ty);
+        let id = self.types.len() as u32;
+        self.ids.insert(TypeId::of::<T>(), id);
+        id
+    }
+
+    pub fn add_int<T: 'static>(&mut self, name: &str, signed: bool, bool: bool) -> u32 {
+        let size = core::mem::size_of::<T>() as u32;
+        let encoding = if bool {
+            BTF_INT_BOOL
+        } else if signed {
+            BTF_INT_SIGNED
+        } else {
+            0
+        };
+        self.push::<T>(BtfType::Int {
+            name: name.to_string(),
+            size,
+            encoding,
+            offset: 0,
+            bits: (size * 8) as u8,
+        })
+    }
+
+    /// Add a pointer to `ty`, 0 being `void`
+    pub fn add_ptr<T: 'static>(&mut self, ty: u32) -> u32 {
+        self.push::<T>(BtfType::Ptr { ty })
+    }
+
+    pub fn add_array<T: 'static, E: BtfTypeInfo>(&mut self, nelems: u32) -> u32 {
+        let elem_ty = self.add::<E>();
+        let index_ty = self.add::<u32>();
+        self.push::<T>(BtfType::Array {
+            elem_ty,
+            index_ty,
+            nelems,
+        })
+    }
+
+    /// Add a struct, its id is known before its members are added so that they
+    /// can point to it
+    pub fn add_struct<T: 'static>(&mut self, name: &str, fields: &[BtfField]) -> u32 {
+        let id = self.push::<T>(BtfType::Struct {
+            name: name.to_string(),
+            size: core::mem::size_of::<T>() as u32,
+            members: Vec::new(),
+        });
+        let fields = self.members(fields);
+        if let BtfType::Struct { members, .. } = &mut self.types[id as usize - 1] {
+            *members = fields;
+        }
+        id
+    }
+
+    pub fn add_union<T: 'static>(&mut self, name: &str, fields: &[BtfField]) -> u32 {
+        let id = self.push::<T>(BtfType::Union {
+            name: name.to_string(),
+            size: core::mem::size_of::<T>() as u32,
+            members: Vec::new(),
+        });
+        let fields = self.members(fields);
+        if let BtfType::Union { members, .. } = &mut self.types[id as usize - 1] {
+            *members = fields;
+        }
+        id
+    }
+
+    fn members(&mut self, fields: &[BtfField]) -> Vec<BtfMember> {
+        fields
+            .iter()
+            .map(|field| BtfMember {
+                name: field.name.to_string(),
+                ty: (field.ty)(self),
+                bit_offset: field.offset as u32 * 8,
+                bitfield_size: 0,
+            })
+            .collect()
+    }
+
+    /// Add a fieldless enum, an 8-byte enum is an `ENUM64`
+    pub fn add_enum<T: 'static>(
+        &mut self,
+        name: &str,
+        signed: bool,
+        values: &[(&str, i64)],
+    ) -> u32 {
+        let size = core::mem::size_of::<T>() as u32;
+        let name = name.to_string();
+        let values = values
+            .iter()
+            .map(|(name, value)| BtfEnumValue {
+                name: name.to_string(),
+                value: *value,
+            })
+            .collect();
+        let ty = if size == 8 {
+            BtfType::Enum64 {
+                name,
+                size,
+                signed,
+                values,
+            }
+        } else {
+            BtfType::Enum {
+                name,
+                size,
+                signed,
+                values,
+            }
+        };
+        self.push::<T>(ty)
+    }
+
+    /// Encode the types, the result can be given to
+    /// [`BpfLoader::target_btf`](crate::loader::BpfLoader::target_btf)
+    pub fn build(&self) -> Vec<u8> {
+        let mut strings = StringSection::default();
+        let mut types = Vec::new();
+        let put = |types: &mut Vec<u8>, value: u32| types.extend_from_slice(&value.to_le_bytes());
+        let info = |kind: u8, vlen: usize, kind_flag: bool| {
+            (kind_flag as u32) << 31 | (kind as u32) << 24 | vlen as u32
+        };
+        for ty in &self.types {
+            match ty {
+                BtfType::Int {
+                    name,
+                    size,
+                    encoding,
+                    offset,
+                    bits,
+                } => {
+                    put(&mut types, strings.add(name));
+                    put(&mut types, info(BTF_KIND_INT, 0, false));
+                    put(&mut types, *size);
+                    put(
+                        &mut types,
+                        (*encoding as u32) << 24 | (*offset as u32) << 16 | *bits as u32,
+                    );
+                }
+                BtfType::Ptr { ty } => {
+                    put(&mut types, 0);
+                    put(&mut types, info(BTF_KIND_PTR, 0, false));
+                    put(&mut types, *ty);
+                }
+                BtfType::Array {
+                    elem_ty,
+                    index_ty,
+                    nelems,
+                } => {
+                    put(&mut types, 0);
+                    put(&mut types, info(BTF_KIND_ARRAY, 0, false));
+                    put(&mut types, 0);
+                    put(&mut types, *elem_ty);
+                    put(&mut types, *index_ty);
+                    put(&mut types, *nelems);
+                }
+                BtfType::Struct {
+                    name,
+                    size,
+                    members,
+                }
+                | BtfType::Union {
+                    name,
+                    size,
+                    members,
+                } => {
+                    let kind = if matches!(ty, BtfType::Struct { .. }) {
+                        BTF_KIND_STRUCT
+                    } else {
+                        BTF_KIND_UNION
+                    };
+                    put(&mut types, strings.add(name));
+                    put(&mut types, info(kind, members.len(), false));
+                    put(&mut types, *size);
+                    for member in members {
+                        put(&mut types, strings.add(&member.name));
+                        put(&mut types, member.ty);
+                        put(&mut types, member.bit_offset);
+                    }
+                }
+                BtfType::Enum {
+                    name,
+                    size,
+                    signed,
+                    values,
+                } => {
+                    put(&mut types, strings.add(name));
+                    put(&mut types, info(BTF_KIND_ENUM, values.len(), *signed));
+                    put(&mut types, *size);
+                    for value in values {
+                        put(&mut types, strings.add(&value.name));
+                        put(&mut types, value.value as u32);
+                    }
+                }
+                BtfType::Enum64 {
+                    name,
+                    size,
+                    signed,
+                    values,
+                } => {
+                    put(&mut types, strings.add(name));
+                    put(&mut types, info(BTF_KIND_ENUM64, values.len(), *signed));
+                    put(&mut types, *size);
+                    for value in values {
+                        put(&mut types, strings.add(&value.name));
+                        put(&mut types, value.value as u32);
+                        put(&mut types, (value.value as u64 >> 32) as u32);
+                    }
+                }
+                // the builder only creates the kinds above
+                _ => unreachable!(),
+            }
+        }
+
+        let mut btf = Vec::with_capacity(BTF_HEADER_LEN + types.len() + strings.data.len());
+        btf.extend_from_slice(&BTF_MAGIC.to_le_bytes());
+        btf.push(1); // version
+        btf.push(0); // flags
+        for value in [
+            BTF_HEADER_LEN as u32,
+            0,
+            types.len() as u32,
+            types.len() as u32,
+            strings.data.len() as u32,
+        ] {
+            put(&mut btf, value);
+        }
+        btf.extend_from_slice(&types);
+        btf.extend_from_slice(&strings.data);
+        btf
+    }
+}
+
+/// Strings of the blob, each one is stored once
+struct StringSection {
+    data: Vec<u8>,
+    offsets: BTreeMap<String, u32>,
+}
+
+impl Default for StringSection {
+    fn default() -> Self {
+        // offset 0 is the empty string
+        StringSection {
+            data: Vec::from([0]),
+            offsets: BTreeMap::from([(String::new(), 0)]),
+        }
+    }
+}
+
+impl StringSection {
+    fn add(&mut self, s: &str) -> u32 {
+        if let Some(offset) = self.offsets.get(s) {
+            return *offset;
+        }
+        let offset = self.data.len() as u32;
+        self.data.extend_from_slice(s.as_bytes());
+        self.data.push(0);
+        self.offsets.insert(s.to_string(), offset);
+        offset
+    }
+}
+
+macro_rules! impl_int {
+    ($($ty:ty => $signed:expr),* $(,)?) => {
+        $(
+            impl BtfTypeInfo for $ty {
+                fn add_btf_type(builder: &mut BtfBuilder) -> u32 {
+                    builder.add_int::<$ty>(stringify!($ty), $signed, false)
+                }
+            }
+        )*
+    };
+}
+
+impl_int!(
+    u8 => false, u16 => false, u32 => false, u64 => false, usize => false,
+    i8 => true, i16 => true, i32 => true, i64 => true, isize => true,
+);
+
+impl BtfTypeInfo for bool {
+    fn add_btf_type(builder: &mut BtfBuilder) -> u32 {
+        builder.add_int::<bool>("bool", false, true)
+    }
+}
+
+/// `void`, so that `*const c_void` is `void *`
+impl BtfTypeInfo for core::ffi::c_void {
+    fn add_btf_type(_builder: &mut BtfBuilder) -> u32 {
+        0
+    }
+}
+
+impl<T: BtfTypeInfo> BtfTypeInfo for *const T {
+    fn add_btf_type(builder: &mut BtfBuilder) -> u32 {
+        let ty = builder.add::<T>();
+        builder.add_ptr::<Self>(ty)
+    }
+}
+
+impl<T: BtfTypeInfo> BtfTypeInfo for *mut T {
+    fn add_btf_type(builder: &mut BtfBuilder) -> u32 {
+        let ty = builder.add::<T>();
+        builder.add_ptr::<Self>(ty)
+    }
+}
+
+impl<T: BtfTypeInfo, const N: usize> BtfTypeInfo for [T; N] {
+    fn add_btf_type(builder: &mut BtfBuilder) -> u32 {
+        builder.add_array::<Self, T>(N as u32)
+    }
+}
+
+/// The entries of the `btf_types` linker section as a
+/// `&'static [BtfTypeEntry]`, the section must contain at least one entry
+#[macro_export]
+macro_rules! linked_btf_types {
+    () => {{
+        extern "C" {
+            static __start_btf_types: $crate::btf::BtfTypeEntry;
+            static __stop_btf_types: $crate::btf::BtfTypeEntry;
+        }
+        // SAFETY: the linker places the entries between the two symbols
+        unsafe {
+            let start = core::ptr::addr_of!(__start_btf_types);
+            let stop = core::ptr::addr_of!(__stop_btf_types);
+            core::slice::from_raw_parts(start, stop.offset_from(start) as usize)
+        }
+    }};
+}
@@ -0,0 +1,664 @@
+//! CO-RE (Compile Once, Run Everywhere) relocations.
+//!
+//! `BPF_CORE_READ` and the `bpf_core_*` macros make clang record in `.BTF.ext`
+//! which field, type or enum value an instruction depends on, as an access
+//! string of indices into the local BTF. The same path is looked up by name in
+//! the BTF of the target kernel and the instruction is patched with the target
+//! offset, size or value.
+//!
+//! Target types match local types with the same kind and the same name, ignoring
+//! a `___flavor` suffix, so a program can describe several layouts of a struct.
+use alloc::{string::String, vec::Vec};
+
+use rbpf::ebpf::{self, Insn};
+
+use super::{Btf, BtfError, BtfResult, BtfType, BTF_INT_SIGNED, MAX_RESOLVE_DEPTH};
+
+pub const BPF_CORE_FIELD_BYTE_OFFSET: u32 = 0;
+pub const BPF_CORE_FIELD_BYTE_SIZE: u32 = 1;
+pub const BPF_CORE_FIELD_EXISTS: u32 = 2;
+pub const BPF_CORE_FIELD_SIGNED: u32 = 3;
+pub const BPF_CORE_FIELD_LSHIFT_U64: u32 = 4;
+pub const BPF_CORE_FIELD_RSHIFT_U64: u32 = 5;
+pub const BPF_CORE_TYPE_ID_LOCAL: u32 = 6;
+pub const BPF_CORE_TYPE_ID_TARGET: u32 = 7;
+pub const BPF_CORE_TYPE_EXISTS: u32 = 8;
+pub const BPF_CORE_TYPE_SIZE: u32 = 9;
+pub const BPF_CORE_ENUMVAL_EXISTS: u32 = 10;
+pub const BPF_CORE_ENUMVAL_VALUE: u32 = 11;
+pub const BPF_CORE_TYPE_MATCHES: u32 = 12;
+
+/// Helper id of the call replacing an instruction whose relocation can not be
+/// resolved, as libbpf does, so that the failure shows up if it is executed
+pub const BPF_CORE_POISON_HELPER: i32 = 0xbad2310;
+
+/// A CO-RE relocation record of `.BTF.ext`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoreRelo {
+    /// Offset in bytes of the instruction in its section
+    pub insn_off: u32,
+    /// The local root type
+    pub type_id: u32,
+    /// Indices separated by `:`, e.g. `0:1:2`
+    pub access: String,
+    pub kind: u32,
+}
+
+/// The value of a relocation in the local and the target BTF, `target` is
+/// `None` when the target has no match
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CoreValue {
+    pub local: u64,
+    pub target: Option<u64>,
+}
+
+/// A step of a field access once the anonymous members are skipped
+enum Accessor {
+    Field(String),
+    Index(u32),
+}
+
+/// The field reached by an access string
+struct FieldSpec {
+    ty: u32,
+    bit_offset: u32,
+}
+
+fn parse_access(access: &str) -> BtfResult<Vec<u32>> {
+    access
+        .split(':')
+        .map(|index| {
+            index
+                .parse()
+                .map_err(|_| BtfError::InvalidAccessString(access.into()))
+        })
+        .collect()
+}
+
+/// The name without the `___flavor` suffix
+fn essential_name(name: &str) -> &str {
+    match name.find("___") {
+        Some(end) => &name[..end],
+        None => name,
+    }
+}
+
+/// The types of the target with the kind and the essential name of `local_id`
+fn candidates(local: &Btf, local_id: u32, target: &Btf) -> BtfResult<Vec<u32>> {
+    let local_type = local.type_by_id(local_id)?;
+    let name = essential_name(local_type.name());
+    if name.is_empty() {
+        return Ok(Vec::new());
+    }
+    Ok(target
+        .types()
+        .filter(|(_, ty)| ty.kind() == local_type.kind() && essential_name(ty.name()) == name)
+        .map(|(id, _)| id)
+        .collect())
+}
+
+impl FieldSpec {
+    /// Move to a member at `bit_offset` from the current field
+    fn member(&mut self, ty: u32, bit_offset: u32) -> BtfResult<()> {
+        self.bit_offset = self
+            .bit_offset
+            .checked_add(bit_offset)
+            .ok_or(BtfError::Overflow(self.ty))?;
+        self.ty = ty;
+        Ok(())
+    }
+
+    /// Move to the element `index` of an array of `elem_ty`
+    fn element(&mut self, btf: &Btf, elem_ty: u32, index: u32) -> BtfResult<()> {
+        let bit_offset = btf
+            .resolve_size(elem_ty)?
+            .checked_mul(index)
+            .and_then(|offset| offset.checked_mul(8))
+            .ok_or(BtfError::Overflow(elem_ty))?;
+        self.member(elem_ty, bit_offset)
+    }
+}
+
+/// Walk the access string in the local BTF
+fn local_field(
+    btf: &Btf,
+    root: u32,
+    access: &str,
+    indices: &[u32],
+) -> BtfResult<(FieldSpec, Vec<Accessor>)> {
+    let mut spec = FieldSpec {
+        ty: root,
+        bit_offset: 0,
+    };
+    spec.element(btf, root, indices[0])?;
+    let mut accessors = Vec::new();
+    for index in &indices[1..] {
+        let id = btf.skip_mods_and_typedefs(spec.ty)?;
+        match btf.type_by_id(id)? {
+            BtfType::Struct { members, .. } | BtfType::Union { members, .. } => {
+                let member = members
+                    .get(*index as usize)
+                    .ok_or_else(|| BtfError::InvalidAccessString(access.into()))?;
+                spec.member(member.ty, member.bit_offset)?;
+                if !member.name.is_empty() {
+                    accessors.push(Accessor::Field(member.name.clone()));
+                }
+            }
+            BtfType::Array { elem_ty, .. } => {
+                spec.element(btf, *elem_ty, *index)?;
+                accessors.push(Accessor::Index(*index));
+            }
+            _ => return Err(BtfError::InvalidTypeId(id)),
+        }
+    }
+    Ok((spec, accessors))
+}
+
+/// Find a member by name, looking into anonymous struct and union members
+fn find_member(btf: &Btf, id: u32, name: &str, depth: usize) -> BtfResult<Option<FieldSpec>> {
+    if depth >= MAX_RESOLVE_DEPTH {
+        return Err(BtfError::TypeLoop(id));
+    }
+    let members = match btf.type_by_id(btf.skip_mods_and_typedefs(id)?)? {
+        BtfType::Struct { members, .. } | BtfType::Union { members, .. } => members,
+        _ => return Ok(None),
+    };
+    for member in members {
+        if member.name == name {
+            return Ok(Some(FieldSpec {
+                ty: member.ty,
+                bit_offset: member.bit_offset,
+            }));
+        }
+        if member.name.is_empty() {
+            if let Some(inner) = find_member(btf, member.ty, name, depth + 1)? {
+                let mut spec = FieldSpec {
+                    ty: id,
+                    bit_offset: member.bit_offset,
+                };
+                spec.member(inner.ty, inner.bit_offset)?;
+                return Ok(Some(spec));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Follow the accessors in the target BTF from a candidate root type
+fn target_field(
+    btf: &Btf,
+    root: u32,
+    root_index: u32,
+    accessors: &[Accessor],
+) -> BtfResult<Option<FieldSpec>> {
+    let mut spec = FieldSpec {
+        ty: root,
+        bit_offset: 0,
+    };
+    spec.element(btf, root, root_index)?;
+    for accessor in accessors {
+        match accessor {
+            Accessor::Field(name) => match find_member(btf, spec.ty, name, 0)? {
+                Some(member) => spec.member(member.ty, member.bit_offset)?,
+                None => return Ok(None),
+            },
+            Accessor::Index(index) => {
+                let id = btf.skip_mods_and_typedefs(spec.ty)?;
+                match btf.type_by_id(id)? {
+                    // a zero-sized array is a flexible array member
+                    BtfType::Array {
+                        elem_ty, nelems, ..
+                    } if *index < *nelems || *nelems == 0 => {
+                        spec.element(btf, *elem_ty, *index)?;
+                    }
+                    _ => return Ok(None),
+                }
+            }
+        }
+    }
+    Ok(Some(spec))
+}
+
+/// Whether two field types can be read the same way
+fn fields_compatible(
+    local: &Btf,
+    local_id: u32,
+    target: &Btf,
+    target_id: u32,
+    depth: usize,
+) -> BtfResult<bool> {
+    if depth >= MAX_RESOLVE_DEPTH {
+        return Err(BtfError::TypeLoop(local_id));
+    }
+    let local_type = local.type_by_id(local.skip_mods_and_typedefs(local_id)?)?;
+    let target_type = target.type_by_id(target.skip_mods_and_typedefs(target_id)?)?;
+    Ok(match (local_type, target_type) {
+        (
+            BtfType::Int { .. } | BtfType::Enum { .. } | BtfType::Enum64 { .. },
+            BtfType::Int { .. } | BtfType::Enum { .. } | BtfType::Enum64 { .. },
+        ) => true,
+        (
+            BtfType::Array {
+                elem_ty: local_elem,
+                ..
+            },
+            BtfType::Array {
+                elem_ty: target_elem,
+                ..
+            },
+        ) => fields_compatible(local, *local_elem, target, *target_elem, depth + 1)?,
+        (local_type, target_type) => local_type.kind() == target_type.kind(),
+    })
+}
+
+fn is_signed(btf: &Btf, id: u32) -> BtfResult<bool> {
+    Ok(match btf.type_by_id(btf.skip_mods_and_typedefs(id)?)? {
+        BtfType::Int { encoding, .. } => encoding & BTF_INT_SIGNED != 0,
+        BtfType::Enum { signed, .. } | BtfType::Enum64 { signed, .. } => *signed,
+        _ => false,
+    })
+}
+
+fn field_value(btf: &Btf, kind: u32, spec: &FieldSpec) -> BtfResult<u64> {
+    Ok(match kind {
+        BPF_CORE_FIELD_BYTE_OFFSET => (spec.bit_offset / 8) as u64,
+        BPF_CORE_FIELD_BYTE_SIZE => btf.resolve_size(spec.ty)? as u64,
+        BPF_CORE_FIELD_EXISTS => 1,
+        BPF_CORE_FIELD_SIGNED => is_signed(btf, spec.ty)? as u64,
+        _ => return Err(BtfError::UnsupportedCoreRelo(kind)),
+    })
+}
+
+/// Compute the local and target values of a relocation
+pub fn resolve(local: &Btf, target: &Btf, relo: &CoreRelo) -> BtfResult<CoreValue> {
+    let indices = parse_access(&relo.access)?;
+    let root = local.skip_mods_and_typedefs(relo.type_id)?;
+    let candidates = candidates(local, root, target)?;
+    match relo.kind {
+        BPF_CORE_FIELD_BYTE_OFFSET
+        | BPF_CORE_FIELD_BYTE_SIZE
+        | BPF_CORE_FIELD_EXISTS
+        | BPF_CORE_FIELD_SIGNED => {
+            let (local_spec, accessors) = local_field(local, root, &relo.access, &indices)?;
+            let local_value = field_value(local, relo.kind, &local_spec)?;
+            let mut target_value = None;
+            for candidate in candidates {
+                if let Some(spec) = target_field(target, candidate, indices[0], &accessors)? {
+                    if fields_compatible(local, local_spec.ty, target, spec.ty, 0)? {
+                        target_value = Some(field_value(target, relo.kind, &spec)?);
+                        break;
+                    }
+                }
+            }
+            if relo.kind == BPF_CORE_FIELD_EXISTS {
+                target_value = Some(target_value.unwrap_or(0));
+            }
+            Ok(CoreValue {
+                local: local_value,
+                target: target_value,
+            })
+        }
+        BPF_CORE_TYPE_ID_LOCAL => Ok(CoreValue {
+            local: relo.type_id as u64,
+            target: Some(relo.type_id as u64),
+        }),
+        BPF_CORE_TYPE_ID_TARGET | BPF_CORE_TYPE_EXISTS | BPF_CORE_TYPE_SIZE => {
+            let candidate = candidates.first().copied();
+            let value = |btf: &Btf, id: u32| -> BtfResult<u64> {
+                Ok(match relo.kind {
+                    BPF_CORE_TYPE_ID_TARGET => id as u64,
+                    BPF_CORE_TYPE_EXISTS => 1,
+                    _ => btf.resolve_size(id)? as u64,
+                })
+            };
+            let mut target_value = candidate.map(|id| value(target, id)).transpose()?;
+            if relo.kind == BPF_CORE_TYPE_EXISTS {
+                target_value = Some(target_value.unwrap_or(0));
+            }
+            Ok(CoreValue {
+                local: value(local, root)?,
+                target: target_value,
+            })
+        }
+        BPF_CORE_ENUMVAL_EXISTS | BPF_CORE_ENUMVAL_VALUE => {
+            let enumerator = match local.type_by_id(root)? {
+                BtfType::Enum { values, .. } | BtfType::Enum64 { values, .. } => values
+                    .get(indices[0] as usize)
+                    .ok_or_else(|| BtfError::InvalidAccessString(relo.access.clone()))?,
+                _ => return Err(BtfError::InvalidTypeId(root)),
+            };
+            let value = |value: i64| match relo.kind {
+                BPF_CORE_ENUMVAL_EXISTS => 1,
+                _ => value as u64,
+            };
+            let mut target_value = None;
+            for candidate in candidates {
+                if let BtfType::Enum { values, .. } | BtfType::Enum64 { values, .. } =
+                    target.type_by_id(candidate)?
+                {
+                    if let Some(v) = values.iter().find(|v| v.name == enumerator.name) {
+                        target_value = Some(value(v.value));
+                        break;
+                    }
+                }
+            }
+            if relo.kind == BPF_CORE_ENUMVAL_EXISTS {
+                target_value = Some(target_value.unwrap_or(0));
+            }
+            Ok(CoreValue {
+                local: value(enumerator.value),
+                target: target_value,
+            })
+        }
+        kind => Err(BtfError::UnsupportedCoreRelo(kind)),
+    }
+}
+
+/// Replace the instruction with a call to [`BPF_CORE_POISON_HELPER`]
+fn poison(prog: &mut [Insn], index: usize) {
+    let call = Insn {
+        opc: ebpf::CALL,
+        dst: 0,
+        src: 0,
+        off: 0,
+        imm: BPF_CORE_POISON_HELPER,
+    };
+    if prog[index].opc == ebpf::LD_DW_IMM && index + 1 < prog.len() {
+        prog[index + 1] = call.clone();
+    }
+    prog[index] = call;
+}
+
+/// Patch the instruction at `index` with the target value. The instruction
+/// must hold the local value, in the immediate of an ALU or 64-bit load
+/// instruction or in the offset of a memory access.
+pub fn patch_insn(prog: &mut [Insn], index: usize, value: CoreValue) -> Result<(), &'static str> {
+    let insn = prog.get(index).ok_or("instruction is out of the program")?;
+    let target = match value.target {
+        Some(target) => target,
+        None => {
+            log::warn!(
+                "CO-RE relocation of instruction {} has no target, poison it",
+                index
+            );
+            poison(prog, index);
+            return Ok(());
+        }
+    };
+    match insn.opc & ebpf::BPF_CLS_MASK {
+        ebpf::BPF_ALU | ebpf::BPF_ALU64 if insn.opc & ebpf::BPF_X == 0 => {
+            if insn.imm as u64 != value.local {
+                return Err("immediate does not hold the local value");
+            }
+            prog[index].imm = i32::try_from(target).map_err(|_| "value does not fit in 32 bits")?;
+        }
+        ebpf::BPF_LDX | ebpf::BPF_ST | ebpf::BPF_STX => {
+            if insn.off as u64 != value.local {
+                return Err("offset does not hold the local value");
+            }
+            prog[index].off =
+                i16::try_from(target).map_err(|_| "offset does not fit in 16 bits")?;
+        }
+        ebpf::BPF_LD if insn.opc == ebpf::LD_DW_IMM && index + 1 < prog.len() => {
+            let local = (insn.imm as u32 as u64) | ((prog[index + 1].imm as u32 as u64) << 32);
+            if local != value.local {
+                return Err("immediate does not hold the local value");
+            }
+            prog[index].imm = target as u32 as i32;
+            prog[index + 1].imm = (target >> 32) as u32 as i32;
+        }
+        _ => return Err("instruction can not be relocated"),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{vec, vec::Vec};
+
+    use super::*;
+    use crate::btf::{BtfEnumValue, BtfMember};
+
+    const INT: u32 = 1;
+    const CHAR: u32 = 2;
+    const COMM: u32 = 3;
+    const TASK: u32 = 4;
+    const STATE: u32 = 5;
+    const LOOP: u32 = 6;
+
+    fn int(name: &str, size: u32, encoding: u8) -> BtfType {
+        BtfType::Int {
+            name: name.into(),
+            size,
+            encoding,
+            offset: 0,
+            bits: size as u8 * 8,
+        }
+    }
+
+    fn member(name: &str, ty: u32, bit_offset: u32) -> BtfMember {
+        BtfMember {
+            name: name.into(),
+            ty,
+            bit_offset,
+            bitfield_size: 0,
+        }
+    }
+
+    fn state(values: &[(&str, i64)]) -> BtfType {
+        BtfType::Enum {
+            name: "state".into(),
+            size: 4,
+            signed: false,
+            values: values
+                .iter()
+                .map(|(name, value)| BtfEnumValue {
+                    name: (*name).into(),
+                    value: *value,
+                })
+                .collect(),
+        }
+    }
+
+    /// The types shared by both sides, then the task struct and the state enum
+    fn btf(task: BtfType, state: BtfType) -> Btf {
+        let looped = BtfType::Struct {
+            name: "loop".into(),
+            size: 4,
+            members: vec![member("", LOOP, 0), member("x", INT, 0)],
+        };
+        Btf {
+            types: vec![
+                BtfType::Void,
+                int("int", 4, BTF_INT_SIGNED),
+                int("char", 1, 0),
+                BtfType::Array {
+                    elem_ty: CHAR,
+                    index_ty: INT,
+                    nelems: 16,
+                },
+                task,
+                state,
+                looped,
+            ],
+            strings: vec![0],
+        }
+    }
+
+    fn local() -> Btf {
+        let task = BtfType::Struct {
+            name: "task".into(),
+            size: 24,
+            members: vec![
+                member("pid", INT, 0),
+                member("comm", COMM, 32),
+                member("gone", INT, 160),
+            ],
+        };
+        btf(task, state(&[("RUNNING", 0), ("STOPPED", 4)]))
+    }
+
+    /// `comm` moved to the front, a flavored name and no `gone` or `RUNNING`
+    fn target() -> Btf {
+        let task = BtfType::Struct {
+            name: "task___new".into(),
+            size: 28,
+            members: vec![
+                member("comm", COMM, 0),
+                member("flags", INT, 128),
+                member("pid", INT, 160),
+            ],
+        };
+        btf(task, state(&[("STOPPED", 8)]))
+    }
+
+    fn resolve_relo(type_id: u32, access: &str, kind: u32) -> BtfResult<CoreValue> {
+        let relo = CoreRelo {
+            insn_off: 0,
+            type_id,
+            access: access.into(),
+            kind,
+        };
+        resolve(&local(), &target(), &relo)
+    }
+
+    fn value(local: u64, target: Option<u64>) -> BtfResult<CoreValue> {
+        Ok(CoreValue { local, target })
+    }
+
+    #[test]
+    fn fields() {
+        use resolve_relo as r;
+        assert_eq!(
+            r(TASK, "0:0", BPF_CORE_FIELD_BYTE_OFFSET),
+            value(0, Some(20))
+        );
+        assert_eq!(
+            r(TASK, "0:1:3", BPF_CORE_FIELD_BYTE_OFFSET),
+            value(7, Some(3))
+        );
+        assert_eq!(
+            r(TASK, "1:0", BPF_CORE_FIELD_BYTE_OFFSET),
+            value(24, Some(48))
+        );
+        assert_eq!(
+            r(TASK, "0:1", BPF_CORE_FIELD_BYTE_SIZE),
+            value(16, Some(16))
+        );
+        assert_eq!(r(TASK, "0:0", BPF_CORE_FIELD_SIGNED), value(1, Some(1)));
+        assert_eq!(r(TASK, "0:1:0", BPF_CORE_FIELD_SIGNED), value(0, Some(0)));
+        assert_eq!(r(TASK, "0:0", BPF_CORE_FIELD_EXISTS), value(1, Some(1)));
+        assert_eq!(r(TASK, "0:2", BPF_CORE_FIELD_EXISTS), value(1, Some(0)));
+        assert_eq!(r(TASK, "0:2", BPF_CORE_FIELD_BYTE_OFFSET), value(20, None));
+    }
+
+    #[test]
+    fn types_and_enums() {
+        use resolve_relo as r;
+        assert_eq!(r(TASK, "0", BPF_CORE_TYPE_SIZE), value(24, Some(28)));
+        assert_eq!(r(TASK, "0", BPF_CORE_TYPE_EXISTS), value(1, Some(1)));
+        assert_eq!(r(TASK, "0", BPF_CORE_TYPE_ID_TARGET), value(4, Some(4)));
+        assert_eq!(r(STATE, "1", BPF_CORE_ENUMVAL_VALUE), value(4, Some(8)));
+        assert_eq!(r(STATE, "0", BPF_CORE_ENUMVAL_EXISTS), value(1, Some(0)));
+    }
+
+    #[test]
+    fn malformed() {
+        use resolve_relo as r;
+        let invalid = |access: &str| Err(BtfError::InvalidAccessString(access.into()));
+        assert_eq!(r(TASK, "0:x", BPF_CORE_FIELD_BYTE_OFFSET), invalid("0:x"));
+        assert_eq!(r(TASK, "0:3", BPF_CORE_FIELD_BYTE_OFFSET), invalid("0:3"));
+        assert_eq!(r(STATE, "2", BPF_CORE_ENUMVAL_VALUE), invalid("2"));
+        assert_eq!(
+            r(TASK, "0:0", BPF_CORE_TYPE_MATCHES),
+            Err(BtfError::UnsupportedCoreRelo(BPF_CORE_TYPE_MATCHES))
+        );
+        assert_eq!(
+            r(TASK, "4294967295:0", BPF_CORE_FIELD_BYTE_OFFSET),
+            Err(BtfError::Overflow(TASK))
+        );
+        assert_eq!(
+            r(TASK, "0:1:4294967295", BPF_CORE_FIELD_BYTE_OFFSET),
+            Err(BtfError::Overflow(CHAR))
+        );
+        // `x` is looked up through the anonymous member, which is the struct itself
+        assert_eq!(
+            r(LOOP, "0:1", BPF_CORE_FIELD_BYTE_OFFSET),
+            Err(BtfError::TypeLoop(LOOP))
+        );
+    }
+
+    fn insn(opc: u8, off: i16, imm: i32) -> Insn {
+        Insn {
+            opc,
+            dst: 1,
+            src: 0,
+            off,
+            imm,
+        }
+    }
+
+    #[test]
+    fn patch() {
+        let mut prog = vec![
+            insn(ebpf::MOV64_IMM, 0, 4),
+            insn(ebpf::LD_W_REG, 4, 0),
+            insn(ebpf::LD_DW_IMM, 0, 7),
+            insn(0, 0, 0),
+        ];
+        patch_insn(
+            &mut prog,
+            0,
+            CoreValue {
+                local: 4,
+                target: Some(20),
+            },
+        )
+        .unwrap();
+        patch_insn(
+            &mut prog,
+            1,
+            CoreValue {
+                local: 4,
+                target: Some(20),
+            },
+        )
+        .unwrap();
+        patch_insn(
+            &mut prog,
+            2,
+            CoreValue {
+                local: 7,
+                target: Some(1 << 33 | 5),
+            },
+        )
+        .unwrap();
+        let patched = prog.iter().map(|i| (i.off, i.imm)).collect::<Vec<_>>();
+        assert_eq!(patched, [(0, 20), (20, 0), (0, 5), (0, 2)]);
+
+        let value = CoreValue {
+            local: 1,
+            target: Some(2),
+        };
+        assert!(patch_insn(&mut prog, 0, value).is_err());
+        assert!(patch_insn(&mut prog, 4, value).is_err());
+        let value = CoreValue {
+            local: 20,
+            target: Some(1 << 20),
+        };
+        assert!(patch_insn(&mut prog, 1, value).is_err());
+
+        patch_insn(
+            &mut prog,
+            2,
+            CoreValue {
+                local: 1 << 33 | 5,
+                target: None,
+            },
+        )
+        .unwrap();
+        let poison = insn(ebpf::CALL, 0, BPF_CORE_POISON_HELPER);
+        let poison = Insn { dst: 0, ..poison };
+        assert_eq!(prog[2..], [poison.clone(), poison]);
+    }
+}
@@ -0,0 +1,190 @@
+//! Format raw values, such as map keys and values, with their BTF type.
+//!
+//! The output follows C initializers: `{.pid = 42, .comm = "bash", .flags = [0, 1]}`.
+use core::fmt::{Display, Formatter, Result, Write};
+
+use super::{Btf, BtfType, BTF_INT_BOOL, BTF_INT_CHAR, BTF_INT_SIGNED};
+
+/// A value with its BTF type, see [`Btf::display`]
+pub struct BtfDisplay<'a> {
+    btf: &'a Btf,
+    ty: u32,
+    data: &'a [u8],
+}
+
+impl<'a> BtfDisplay<'a> {
+    pub(super) fn new(btf: &'a Btf, ty: u32, data: &'a [u8]) -> Self {
+        BtfDisplay { btf, ty, data }
+    }
+}
+
+impl Display for BtfDisplay<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write_value(self.btf, f, self.ty, self.data, 0, 0)
+    }
+}
+
+/// Read `bits` bits (all of `size` bytes if 0) starting `bit_offset` bits into
+/// `data`, little endian
+fn read_bits(data: &[u8], size: usize, bit_offset: u32, bits: u32) -> Option<u128> {
+    let start = (bit_offset / 8) as usize;
+    let shift = bit_offset % 8;
+    let bits = if bits == 0 { size as u32 * 8 } else { bits };
+    let len = ((shift + bits + 7) / 8) as usize;
+    if len > 16 {
+        return None;
+    }
+    let bytes = data.get(start..start + len)?;
+    let mut value = 0u128;
+    for (i, byte) in bytes.iter().enumerate() {
+        value |= (*byte as u128) << (i * 8);
+    }
+    value >>= shift;
+    if bits < 128 {
+        value &= (1u128 << bits) - 1;
+    }
+    Some(value)
+}
+
+fn sign_extend(value: u128, bits: u32) -> i128 {
+    let shift = 128 - bits;
+    ((value << shift) as i128) >> shift
+}
+
+fn write_value(
+    btf: &Btf,
+    f: &mut Formatter<'_>,
+    ty: u32,
+    data: &[u8],
+    bit_offset: u32,
+    bitfield_size: u32,
+) -> Result {
+    let id = match btf.skip_mods_and_typedefs(ty) {
+        Ok(id) => id,
+        Err(_) => return write!(f, "<invalid type {}>", ty),
+    };
+    let btf_type = match btf.type_by_id(id) {
+        Ok(btf_type) => btf_type,
+        Err(_) => return write!(f, "<invalid type {}>", id),
+    };
+    // aggregates start on a byte boundary
+    let bytes = data.get((bit_offset / 8) as usize..).unwrap_or(&[]);
+    match btf_type {
+        BtfType::Int {
+            size,
+            encoding,
+            offset,
+            bits,
+            ..
+        } => {
+            let bits = if bitfield_size != 0 {
+                bitfield_size
+            } else {
+                *bits as u32
+            };
+            let value = match read_bits(data, *size as usize, bit_offset + *offset as u32, bits) {
+                Some(value) => value,
+                None => return write!(f, "<truncated>"),
+            };
+            if encoding & BTF_INT_BOOL != 0 {
+                write!(f, "{}", value != 0)
+            } else if encoding & BTF_INT_CHAR != 0 && (0x20..0x7f).contains(&value) {
+                write!(f, "'{}'", value as u8 as char)
+            } else if encoding & BTF_INT_SIGNED != 0 {
+                write!(f, "{}", sign_extend(value, bits))
+            } else {
+                write!(f, "{}", value)
+            }
+        }
+        BtfType::Enum { size, values, .. } | BtfType::Enum64 { size, values, .. } => {
+            let value = match read_bits(data, *size as usize, bit_offset, bitfield_size) {
+                Some(value) => value,
+                None => return write!(f, "<truncated>"),
+            };
+            match values.iter().find(|v| v.value as u64 as u128 == value) {
+                Some(v) => write!(f, "{}", v.name),
+                None => write!(f, "{}", value),
+            }
+        }
+        BtfType::Ptr { .. } => match read_bits(data, 8, bit_offset, 0) {
+            Some(value) => write!(f, "{:#x}", value),
+            None => write!(f, "<truncated>"),
+        },
+        BtfType::Float { size, .. } => match (size, bytes) {
+            (4, [a, b, c, d, ..]) => write!(f, "{}", f32::from_le_bytes([*a, *b, *c, *d])),
+            (8, [a, b, c, d, e, g, h, i, ..]) => {
+                write!(
+                    f,
+                    "{}",
+                    f64::from_le_bytes([*a, *b, *c, *d, *e, *g, *h, *i])
+                )
+            }
+            _ => write!(f, "<float{}>", size * 8),
+        },
+        BtfType::Array {
+            elem_ty, nelems, ..
+        } => {
+            let elem_size = match btf.resolve_size(*elem_ty) {
+                Ok(size) => size as usize,
+                Err(_) => return write!(f, "<unsized array>"),
+            };
+            let is_char = matches!(
+                btf.skip_mods_and_typedefs(*elem_ty).and_then(|id| btf.type_by_id(id)),
+                Ok(BtfType::Int { size: 1, encoding, .. }) if encoding & BTF_INT_CHAR != 0
+            );
+            if is_char {
+                let len = (*nelems as usize).min(bytes.len());
+                let end = bytes[..len].iter().position(|b| *b == 0).unwrap_or(len);
+                f.write_char('"')?;
+                for b in &bytes[..end] {
+                    write!(f, "{}", core::ascii::escape_default(*b))?;
+                }
+                return f.write_char('"');
+            }
+            f.write_char('[')?;
+            for i in 0..*nelems as usize {
+                if i != 0 {
+                    f.write_str(", ")?;
+                }
+                write_value(btf, f, *elem_ty, bytes, (i * elem_size * 8) as u32, 0)?;
+            }
+            f.write_char(']')
+        }
+        BtfType::Struct { members, .. } | BtfType::Union { members, .. } => {
+            f.write_char('{')?;
+            for (i, member) in members.iter().enumerate() {
+                if i != 0 {
+                    f.write_str(", ")?;
+                }
+                if !member.name.is_empty() {
+                    write!(f, ".{} = ", member.name)?;
+                }
+                write_value(
+                    btf,
+                    f,
+                    member.ty,
+                    bytes,
+                    member.bit_offset,
+                    member.bitfield_size,
+                )?;
+            }
+            f.write_char('}')
+        }
+        BtfType::Datasec { vars, .. } => {
+            f.write_char('{')?;
+            for (i, var) in vars.iter().enumerate() {
+                if i != 0 {
+                    f.write_str(", ")?;
+                }
+                if let Ok(BtfType::Var { name, .. }) = btf.type_by_id(var.ty) {
+                    write!(f, ".{} = ", name)?;
+                }
+                write_value(btf, f, var.ty, bytes, var.offset * 8, 0)?;
+            }
+            f.write_char('}')
+        }
+        BtfType::Var { ty, .. } => write_value(btf, f, *ty, data, bit_offset, bitfield_size),
+        BtfType::Void => write!(f, "<void>"),
+        other => write!(f, "<{}>", other.name()),
+    }
+}
@@ -0,0 +1,293 @@
+//! Parser for the `.BTF.ext` section, which annotates the instructions of each
+//! program section with the BTF of its functions and its source lines.
+//!
+//! The instruction offsets in an object file are in bytes from the start of
+//! the section.
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use super::{core_relo::CoreRelo, Btf, BtfError, BtfResult, Reader, BTF_MAGIC};
+
+/// Size of the header up to the line info, newer headers append fields
+const BTF_EXT_HEADER_LEN: usize = 24;
+/// Size of the header with the CO-RE relocations
+const BTF_EXT_CORE_RELO_HEADER_LEN: usize = 32;
+
+/// The BTF `FUNC` type of the function starting at an instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BtfFuncInfo {
+    pub insn_off: u32,
+    pub type_id: u32,
+}
+
+/// The source line of an instruction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BtfLineInfo {
+    pub insn_off: u32,
+    pub file_name: String,
+    /// The text of the source line
+    pub line: String,
+    pub line_num: u32,
+    pub line_col: u32,
+}
+
+/// Records of one program section
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct BtfExtSection<T> {
+    section: String,
+    records: Vec<T>,
+}
+
+/// Parsed `.BTF.ext` section, the strings are resolved with the `.BTF` section
+#[derive(Debug, Clone, Default)]
+pub struct BtfExt {
+    func_info: Vec<BtfExtSection<BtfFuncInfo>>,
+    line_info: Vec<BtfExtSection<BtfLineInfo>>,
+    core_relos: Vec<BtfExtSection<CoreRelo>>,
+}
+
+/// Parse an info subsection: the record size, then for each program section
+/// its name, the number of records and the records
+fn parse_info<T>(
+    btf: &Btf,
+    data: &[u8],
+    min_record_size: usize,
+    mut record: impl FnMut(&mut Reader) -> BtfResult<T>,
+) -> BtfResult<Vec<BtfExtSection<T>>> {
+    let mut sections = Vec::new();
+    if data.is_empty() {
+        return Ok(sections);
+    }
+    let mut reader = Reader::new(data);
+    let record_size = reader.u32()? as usize;
+    if record_size < min_record_size {
+        return Err(BtfError::InvalidHeader);
+    }
+    while !reader.is_empty() {
+        let section = btf.string_at(reader.u32()?)?.to_string();
+        let num_info = reader.u32()?;
+        // the count is untrusted, do not reserve more records than the data holds
+        let mut records = Vec::with_capacity((num_info as usize).min(data.len() / record_size));
+        for _ in 0..num_info {
+            records.push(record(&mut reader)?);
+            // newer versions may append fields to the records
+            reader.skip(record_size - min_record_size)?;
+        }
+        sections.push(BtfExtSection { section, records });
+    }
+    Ok(sections)
+}
+
+impl BtfExt {
+    pub fn parse(data: &[u8], btf: &Btf) -> BtfResult<BtfExt> {
+        let mut header = Reader::new(data);
+        if header.u16()? != BTF_MAGIC {
+            return Err(BtfError::InvalidHeader);
+        }
+        let _version = header.u8()?;
+        let _flags = header.u8()?;
+        let hdr_len = header.u32()? as usize;
+        if hdr_len < BTF_EXT_HEADER_LEN {
+            return Err(BtfError::InvalidHeader);
+        }
+        let func_info_off = header.u32()? as usize;
+        let func_info_len = header.u32()? as usize;
+        let line_info_off = header.u32()? as usize;
+        let line_info_len = header.u32()? as usize;
+        let section = |off: usize, len: usize| {
+            data.get(hdr_len + off..hdr_len + off + len)
+                .ok_or(BtfError::Truncated)
+        };
+
+        let func_info = parse_info(btf, section(func_info_off, func_info_len)?, 8, |r| {
+            Ok(BtfFuncInfo {
+                insn_off: r.u32()?,
+                type_id: r.u32()?,
+            })
+        })?;
+        let line_info = parse_info(btf, section(line_info_off, line_info_len)?, 16, |r| {
+            let insn_off = r.u32()?;
+            let file_name = btf.string_at(r.u32()?)?.to_string();
+            let line = btf.string_at(r.u32()?)?.to_string();
+            let line_col = r.u32()?;
+            Ok(BtfLineInfo {
+                insn_off,
+                file_name,
+                line,
+                line_num: line_col >> 10,
+                line_col: line_col & 0x3ff,
+            })
+        })?;
+        let core_relos = if hdr_len >= BTF_EXT_CORE_RELO_HEADER_LEN {
+            let core_relo_off = header.u32()? as usize;
+            let core_relo_len = header.u32()? as usize;
+            parse_info(btf, section(core_relo_off, core_relo_len)?, 16, |r| {
+                Ok(CoreRelo {
+                    insn_off: r.u32()?,
+                    type_id: r.u32()?,
+                    access: btf.string_at(r.u32()?)?.to_string(),
+                    kind: r.u32()?,
+                })
+            })?
+        } else {
+            Vec::new()
+        };
+        Ok(BtfExt {
+            func_info,
+            line_info,
+            core_relos,
+        })
+    }
+
+    /// The function info of a program section
+    pub fn func_info(&self, section: &str) -> &[BtfFuncInfo] {
+        self.func_info
+            .iter()
+            .find(|info| info.section == section)
+            .map_or(&[], |info| &info.records)
+    }
+
+    /// The line info of a program section
+    pub fn line_info(&self, section: &str) -> &[BtfLineInfo] {
+        self.line_info
+            .iter()
+            .find(|info| info.section == section)
+            .map_or(&[], |info| &info.records)
+    }
+
+    /// The CO-RE relocations of a program section
+    pub fn core_relos(&self, section: &str) -> &[CoreRelo] {
+        self.core_relos
+            .iter()
+            .find(|info| info.section == section)
+            .map_or(&[], |info| &info.records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{vec, vec::Vec};
+
+    use super::*;
+    use crate::btf::BtfType;
+
+    const STRINGS: &[u8] = b"\0.text\0a.c\0int x;\x000:1\0";
+    const TEXT: u32 = 1;
+    const FILE: u32 = 7;
+    const LINE: u32 = 11;
+    const ACCESS: u32 = 18;
+
+    fn btf() -> Btf {
+        Btf {
+            types: vec![BtfType::Void],
+            strings: STRINGS.to_vec(),
+        }
+    }
+
+    /// A subsection with one `.text` record made of `words`
+    fn info(words: &[u32]) -> Vec<u32> {
+        let mut info = vec![words.len() as u32 * 4, TEXT, 1];
+        info.extend_from_slice(words);
+        info
+    }
+
+    fn ext(func_info: &[u32], line_info: &[u32], core_relo: &[u32]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&BTF_MAGIC.to_le_bytes());
+        data.extend_from_slice(&[1, 0]);
+        let mut offset = 0;
+        let mut header = vec![BTF_EXT_CORE_RELO_HEADER_LEN as u32];
+        for sub in [func_info, line_info, core_relo] {
+            header.extend_from_slice(&[offset, sub.len() as u32 * 4]);
+            offset += sub.len() as u32 * 4;
+        }
+        let words = header
+            .iter()
+            .chain(func_info)
+            .chain(line_info)
+            .chain(core_relo);
+        for word in words {
+            data.extend_from_slice(&word.to_le_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn parse() {
+        let data = ext(
+            &info(&[8, 1]),
+            &info(&[0, FILE, LINE, 12 << 10 | 5]),
+            &info(&[16, 1, ACCESS, 0]),
+        );
+        let ext = BtfExt::parse(&data, &btf()).unwrap();
+        assert_eq!(
+            ext.func_info(".text"),
+            &[BtfFuncInfo {
+                insn_off: 8,
+                type_id: 1
+            }]
+        );
+        assert_eq!(
+            ext.line_info(".text"),
+            &[BtfLineInfo {
+                insn_off: 0,
+                file_name: "a.c".into(),
+                line: "int x;".into(),
+                line_num: 12,
+                line_col: 5,
+            }]
+        );
+        assert_eq!(
+            ext.core_relos(".text"),
+            &[CoreRelo {
+                insn_off: 16,
+                type_id: 1,
+                access: "0:1".into(),
+                kind: 0,
+            }]
+        );
+        assert!(ext.func_info("xdp").is_empty());
+    }
+
+    #[test]
+    fn larger_records() {
+        // a newer producer appending a field to the func info records
+        let data = ext(&info(&[8, 1, 0xdead]), &[], &[]);
+        let ext = BtfExt::parse(&data, &btf()).unwrap();
+        assert_eq!(ext.func_info(".text").len(), 1);
+        assert!(ext.core_relos(".text").is_empty());
+    }
+
+    #[test]
+    fn malformed() {
+        let btf = btf();
+        let mut data = ext(&info(&[8, 1]), &[], &[]);
+        data[0] = 0;
+        assert_eq!(
+            BtfExt::parse(&data, &btf).err(),
+            Some(BtfError::InvalidHeader)
+        );
+
+        let data = ext(&[4, TEXT, 1, 8], &[], &[]);
+        assert_eq!(
+            BtfExt::parse(&data, &btf).err(),
+            Some(BtfError::InvalidHeader)
+        );
+
+        let mut data = ext(&info(&[8, 1]), &[], &[]);
+        data.truncate(data.len() - 4);
+        assert_eq!(BtfExt::parse(&data, &btf).err(), Some(BtfError::Truncated));
+
+        // a huge record count runs out of data instead of allocating
+        let data = ext(&[8, TEXT, u32::MAX, 8, 1], &[], &[]);
+        assert_eq!(BtfExt::parse(&data, &btf).err(), Some(BtfError::Truncated));
+
+        let data = ext(&[8, 100, 1, 8, 1], &[], &[]);
+        assert_eq!(
+            BtfExt::parse(&data, &btf).err(),
+            Some(BtfError::InvalidString(100))
+        );
+    }
+}
@@ -0,0 +1,836 @@
+//! Parser for the BPF Type Format (`.BTF` and `.BTF.ext` sections).
+//!
+//! See <https://docs.kernel.org/bpf/btf.html> for the encoding.
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::fmt::{Display, Formatter};
+
+mod builder;
+pub mod core_relo;
+mod dump;
+pub mod ext;
+
+pub use btf_derive::Btf;
+pub use builder::{BtfBuilder, BtfField, BtfTypeEntry, BtfTypeInfo};
+pub use dump::BtfDisplay;
+
+pub const BTF_MAGIC: u16 = 0xeb9f;
+
+pub const BTF_KIND_INT: u8 = 1;
+pub const BTF_KIND_PTR: u8 = 2;
+pub const BTF_KIND_ARRAY: u8 = 3;
+pub const BTF_KIND_STRUCT: u8 = 4;
+pub const BTF_KIND_UNION: u8 = 5;
+pub const BTF_KIND_ENUM: u8 = 6;
+pub const BTF_KIND_FWD: u8 = 7;
+pub const BTF_KIND_TYPEDEF: u8 = 8;
+pub const BTF_KIND_VOLATILE: u8 = 9;
+pub const BTF_KIND_CONST: u8 = 10;
+pub const BTF_KIND_RESTRICT: u8 = 11;
+pub const BTF_KIND_FUNC: u8 = 12;
+pub const BTF_KIND_FUNC_PROTO: u8 = 13;
+pub const BTF_KIND_VAR: u8 = 14;
+pub const BTF_KIND_DATASEC: u8 = 15;
+pub const BTF_KIND_FLOAT: u8 = 16;
+pub const BTF_KIND_DECL_TAG: u8 = 17;
+pub const BTF_KIND_TYPE_TAG: u8 = 18;
+pub const BTF_KIND_ENUM64: u8 = 19;
+
+/// `BTF_INT_SIGNED` in the encoding of an integer
+pub const BTF_INT_SIGNED: u8 = 1 << 0;
+pub const BTF_INT_CHAR: u8 = 1 << 1;
+pub const BTF_INT_BOOL: u8 = 1 << 2;
+
+const BTF_HEADER_LEN: usize = 24;
+
+/// Longest chain of typedefs, modifiers and array elements followed when
+/// resolving a type, longer chains are taken for loops
+pub const MAX_RESOLVE_DEPTH: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BtfError {
+    /// The data does not start with a valid BTF header
+    InvalidHeader,
+    /// A type or string record runs past the end of its section
+    Truncated,
+    /// The kind of a type record is unknown
+    UnknownKind(u8),
+    /// A string offset is outside of the string section
+    InvalidString(u32),
+    /// A type id does not exist
+    InvalidTypeId(u32),
+    /// The size of a type can not be computed
+    UnsizedType(u32),
+    /// A CO-RE relocation kind the loader does not handle
+    UnsupportedCoreRelo(u32),
+    /// A CO-RE access string does not match its type
+    InvalidAccessString(String),
+    /// Resolving the type follows more than [`MAX_RESOLVE_DEPTH`] types
+    TypeLoop(u32),
+    /// The size or an offset computed for the type does not fit in 32 bits
+    Overflow(u32),
+}
+
+impl Display for BtfError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            BtfError::InvalidHeader => write!(f, "invalid BTF header"),
+            BtfError::Truncated => write!(f, "BTF data is truncated"),
+            BtfError::UnknownKind(kind) => write!(f, "unknown BTF kind {}", kind),
+            BtfError::InvalidString(offset) => write!(f, "invalid BTF string offset {}", offset),
+            BtfError::InvalidTypeId(id) => write!(f, "invalid BTF type id {}", id),
+            BtfError::UnsizedType(id) => write!(f, "BTF type {} has no size", id),
+            BtfError::UnsupportedCoreRelo(kind) => {
+                write!(f, "unsupported CO-RE relocation kind {}", kind)
+            }
+            BtfError::InvalidAccessString(access) => {
+                write!(f, "invalid CO-RE access string {}", access)
+            }
+            BtfError::TypeLoop(id) => write!(f, "BTF type {} is nested too deep", id),
+            BtfError::Overflow(id) => write!(f, "BTF type {} is too large", id),
+        }
+    }
+}
+
+pub type BtfResult<T> = Result<T, BtfError>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BtfMember {
+    pub name: String,
+    pub ty: u32,
+    /// Offset of the member in bits
+    pub bit_offset: u32,
+    /// Size of a bitfield member in bits, 0 for regular members
+    pub bitfield_size: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BtfEnumValue {
+    pub name: String,
+    pub value: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BtfParam {
+    pub name: String,
+    pub ty: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BtfVarSecinfo {
+    pub ty: u32,
+    pub offset: u32,
+    pub size: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BtfType {
+    /// Type id 0
+    Void,
+    Int {
+        name: String,
+        size: u32,
+        encoding: u8,
+        offset: u8,
+        bits: u8,
+    },
+    Ptr {
+        ty: u32,
+    },
+    Array {
+        elem_ty: u32,
+        index_ty: u32,
+        nelems: u32,
+    },
+    Struct {
+        name: String,
+        size: u32,
+        members: Vec<BtfMember>,
+    },
+    Union {
+        name: String,
+        size: u32,
+        members: Vec<BtfMember>,
+    },
+    Enum {
+        name: String,
+        size: u32,
+        signed: bool,
+        values: Vec<BtfEnumValue>,
+    },
+    Fwd {
+        name: String,
+        is_union: bool,
+    },
+    Typedef {
+        name: String,
+        ty: u32,
+    },
+    Volatile {
+        ty: u32,
+    },
+    Const {
+        ty: u32,
+    },
+    Restrict {
+        ty: u32,
+    },
+    Func {
+        name: String,
+        proto: u32,
+        linkage: u32,
+    },
+    FuncProto {
+        ret_ty: u32,
+        params: Vec<BtfParam>,
+    },
+    Var {
+        name: String,
+        ty: u32,
+        linkage: u32,
+    },
+    Datasec {
+        name: String,
+        size: u32,
+        vars: Vec<BtfVarSecinfo>,
+    },
+    Float {
+        name: String,
+        size: u32,
+    },
+    DeclTag {
+        name: String,
+        ty: u32,
+        component_idx: i32,
+    },
+    TypeTag {
+        name: String,
+        ty: u32,
+    },
+    Enum64 {
+        name: String,
+        size: u32,
+        signed: bool,
+        values: Vec<BtfEnumValue>,
+    },
+}
+
+impl BtfType {
+    pub fn name(&self) -> &str {
+        match self {
+            BtfType::Int { name, .. }
+            | BtfType::Struct { name, .. }
+            | BtfType::Union { name, .. }
+            | BtfType::Enum { name, .. }
+            | BtfType::Fwd { name, .. }
+            | BtfType::Typedef { name, .. }
+            | BtfType::Func { name, .. }
+            | BtfType::Var { name, .. }
+            | BtfType::Datasec { name, .. }
+            | BtfType::Float { name, .. }
+            | BtfType::DeclTag { name, .. }
+            | BtfType::TypeTag { name, .. }
+            | BtfType::Enum64 { name, .. } => name,
+            _ => "",
+        }
+    }
+
+    pub fn kind(&self) -> u8 {
+        match self {
+            BtfType::Void => 0,
+            BtfType::Int { .. } => BTF_KIND_INT,
+            BtfType::Ptr { .. } => BTF_KIND_PTR,
+            BtfType::Array { .. } => BTF_KIND_ARRAY,
+            BtfType::Struct { .. } => BTF_KIND_STRUCT,
+            BtfType::Union { .. } => BTF_KIND_UNION,
+            BtfType::Enum { .. } => BTF_KIND_ENUM,
+            BtfType::Fwd { .. } => BTF_KIND_FWD,
+            BtfType::Typedef { .. } => BTF_KIND_TYPEDEF,
+            BtfType::Volatile { .. } => BTF_KIND_VOLATILE,
+            BtfType::Const { .. } => BTF_KIND_CONST,
+            BtfType::Restrict { .. } => BTF_KIND_RESTRICT,
+            BtfType::Func { .. } => BTF_KIND_FUNC,
+            BtfType::FuncProto { .. } => BTF_KIND_FUNC_PROTO,
+            BtfType::Var { .. } => BTF_KIND_VAR,
+            BtfType::Datasec { .. } => BTF_KIND_DATASEC,
+            BtfType::Float { .. } => BTF_KIND_FLOAT,
+            BtfType::DeclTag { .. } => BTF_KIND_DECL_TAG,
+            BtfType::TypeTag { .. } => BTF_KIND_TYPE_TAG,
+            BtfType::Enum64 { .. } => BTF_KIND_ENUM64,
+        }
+    }
+
+    /// The type referenced by modifiers, typedefs, pointers and variables
+    pub fn referenced_type(&self) -> Option<u32> {
+        match self {
+            BtfType::Ptr { ty }
+            | BtfType::Typedef { ty, .. }
+            | BtfType::Volatile { ty }
+            | BtfType::Const { ty }
+            | BtfType::Restrict { ty }
+            | BtfType::Var { ty, .. }
+            | BtfType::DeclTag { ty, .. }
+            | BtfType::TypeTag { ty, .. } => Some(*ty),
+            _ => None,
+        }
+    }
+}
+
+/// Little endian cursor over a byte slice
+pub(crate) struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Self {
+        Reader { data, pos: 0 }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    pub(crate) fn u16(&mut self) -> BtfResult<u16> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + 2)
+            .ok_or(BtfError::Truncated)?;
+        self.pos += 2;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub(crate) fn u32(&mut self) -> BtfResult<u32> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + 4)
+            .ok_or(BtfError::Truncated)?;
+        self.pos += 4;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub(crate) fn skip(&mut self, len: usize) -> BtfResult<()> {
+        if self.pos + len > self.data.len() {
+            return Err(BtfError::Truncated);
+        }
+        self.pos += len;
+        Ok(())
+    }
+
+    pub(crate) fn u8(&mut self) -> BtfResult<u8> {
+        let byte = *self.data.get(self.pos).ok_or(BtfError::Truncated)?;
+        self.pos += 1;
+        Ok(byte)
+    }
+}
+
+/// A variable of a data section and where it is placed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BtfDatasecVar {
+    pub name: String,
+    /// Type of the variable, not of the [`BtfType::Var`]
+    pub ty: u32,
+    pub offset: u32,
+    pub size: u32,
+}
+
+/// Parsed BTF type and string sections
+#[derive(Debug, Clone)]
+pub struct Btf {
+    /// Types indexed by type id, id 0 is [`BtfType::Void`]
+    types: Vec<BtfType>,
+    /// The string section, also referenced by `.BTF.ext`
+    strings: Vec<u8>,
+}
+
+impl Btf {
+    pub fn parse(data: &[u8]) -> BtfResult<Btf> {
+        let mut header = Reader::new(data);
+        if header.u16()? != BTF_MAGIC {
+            return Err(BtfError::InvalidHeader);
+        }
+        let _version = header.u8()?;
+        let _flags = header.u8()?;
+        let hdr_len = header.u32()? as usize;
+        let type_off = header.u32()? as usize;
+        let type_len = header.u32()? as usize;
+        let str_off = header.u32()? as usize;
+        let str_len = header.u32()? as usize;
+        if hdr_len < BTF_HEADER_LEN {
+            return Err(BtfError::InvalidHeader);
+        }
+        let section = |off: usize, len: usize| {
+            data.get(hdr_len + off..hdr_len + off + len)
+                .ok_or(BtfError::Truncated)
+        };
+        let type_data = section(type_off, type_len)?;
+        let strings = section(str_off, str_len)?;
+
+        let name = |offset: u32| string_at(strings, offset).map(|s| s.to_string());
+
+        let mut types = Vec::from([BtfType::Void]);
+        let mut reader = Reader::new(type_data);
+        while !reader.is_empty() {
+            let name_off = reader.u32()?;
+            let info = reader.u32()?;
+            let size_or_type = reader.u32()?;
+            let vlen = (info & 0xffff) as usize;
+            let kind = ((info >> 24) & 0x1f) as u8;
+            let kind_flag = info >> 31 == 1;
+            let ty = match kind {
+                BTF_KIND_INT => {
+                    let int = reader.u32()?;
+                    BtfType::Int {
+                        name: name(name_off)?,
+                        size: size_or_type,
+                        encoding: ((int >> 24) & 0x0f) as u8,
+                        offset: ((int >> 16) & 0xff) as u8,
+                        bits: (int & 0xff) as u8,
+                    }
+                }
+                BTF_KIND_PTR => BtfType::Ptr { ty: size_or_type },
+                BTF_KIND_ARRAY => BtfType::Array {
+                    elem_ty: reader.u32()?,
+                    index_ty: reader.u32()?,
+                    nelems: reader.u32()?,
+                },
+                BTF_KIND_STRUCT | BTF_KIND_UNION => {
+                    let mut members = Vec::with_capacity(vlen);
+                    for _ in 0..vlen {
+                        let member_name = name(reader.u32()?)?;
+                        let member_ty = reader.u32()?;
+                        let offset = reader.u32()?;
+                        let (bit_offset, bitfield_size) = if kind_flag {
+                            (offset & 0xff_ffff, offset >> 24)
+                        } else {
+                            (offset, 0)
+                        };
+                        members.push(BtfMember {
+                            name: member_name,
+                            ty: member_ty,
+                            bit_offset,
+                            bitfield_size,
+                        });
+                    }
+                    let name = name(name_off)?;
+                    if kind == BTF_KIND_STRUCT {
+                        BtfType::Struct {
+                            name,
+                            size: size_or_type,
+                            members,
+                        }
+                    } else {
+                        BtfType::Union {
+                            name,
+                            size: size_or_type,
+                            members,
+                        }
+                    }
+                }
+                BTF_KIND_ENUM => {
+                    let mut values = Vec::with_capacity(vlen);
+                    for _ in 0..vlen {
+                        let value_name = name(reader.u32()?)?;
+                        let value = reader.u32()?;
+                        let value = if kind_flag {
+                            value as i32 as i64
+                        } else {
+                            value as i64
+                        };
+                        values.push(BtfEnumValue {
+                            name: value_name,
+                            value,
+                        });
+                    }
+                    BtfType::Enum {
+                        name: name(name_off)?,
+                        size: size_or_type,
+                        signed: kind_flag,
+                        values,
+                    }
+                }
+                BTF_KIND_FWD => BtfType::Fwd {
+                    name: name(name_off)?,
+                    is_union: kind_flag,
+                },
+                BTF_KIND_TYPEDEF => BtfType::Typedef {
+                    name: name(name_off)?,
+                    ty: size_or_type,
+                },
+                BTF_KIND_VOLATILE => BtfType::Volatile { ty: size_or_type },
+                BTF_KIND_CONST => BtfType::Const { ty: size_or_type },
+                BTF_KIND_RESTRICT => BtfType::Restrict { ty: size_or_type },
+                BTF_KIND_FUNC => BtfType::Func {
+                    name: name(name_off)?,
+                    proto: size_or_type,
+                    linkage: vlen as u32,
+                },
+                BTF_KIND_FUNC_PROTO => {
+                    let mut params = Vec::with_capacity(vlen);
+                    for _ in 0..vlen {
+                        params.push(BtfParam {
+                            name: name(reader.u32()?)?,
+                            ty: reader.u32()?,
+                        });
+                    }
+                    BtfType::FuncProto {
+                        ret_ty: size_or_type,
+                        params,
+                    }
+                }
+                BTF_KIND_VAR => BtfType::Var {
+                    name: name(name_off)?,
+                    ty: size_or_type,
+                    linkage: reader.u32()?,
+                },
+                BTF_KIND_DATASEC => {
+                    let mut vars = Vec::with_capacity(vlen);
+                    for _ in 0..vlen {
+                        vars.push(BtfVarSecinfo {
+                            ty: reader.u32()?,
+                            offset: reader.u32()?,
+                            size: reader.u32()?,
+                        });
+                    }
+                    BtfType::Datasec {
+                        name: name(name_off)?,
+                        size: size_or_type,
+                        vars,
+                    }
+                }
+                BTF_KIND_FLOAT => BtfType::Float {
+                    name: name(name_off)?,
+                    size: size_or_type,
+                },
+                BTF_KIND_DECL_TAG => BtfType::DeclTag {
+                    name: name(name_off)?,
+                    ty: size_or_type,
+                    component_idx: reader.u32()? as i32,
+                },
+                BTF_KIND_TYPE_TAG => BtfType::TypeTag {
+                    name: name(name_off)?,
+                    ty: size_or_type,
+                },
+                BTF_KIND_ENUM64 => {
+                    let mut values = Vec::with_capacity(vlen);
+                    for _ in 0..vlen {
+                        let value_name = name(reader.u32()?)?;
+                        let lo = reader.u32()? as u64;
+                        let hi = reader.u32()? as u64;
+                        values.push(BtfEnumValue {
+                            name: value_name,
+                            value: (hi << 32 | lo) as i64,
+                        });
+                    }
+                    BtfType::Enum64 {
+                        name: name(name_off)?,
+                        size: size_or_type,
+                        signed: kind_flag,
+                        values,
+                    }
+                }
+                kind => return Err(BtfError::UnknownKind(kind)),
+            };
+            types.push(ty);
+        }
+        Ok(Btf {
+            types,
+            strings: strings.to_vec(),
+        })
+    }
+
+    /// The string at `offset` in the string section
+    pub fn string_at(&self, offset: u32) -> BtfResult<&str> {
+        string_at(&self.strings, offset)
+    }
+
+    /// All types with their ids, starting from [`BtfType::Void`]
+    pub fn types(&self) -> impl Iterator<Item = (u32, &BtfType)> {
+        self.types
+            .iter()
+            .enumerate()
+            .map(|(id, ty)| (id as u32, ty))
+    }
+
+    pub fn type_by_id(&self, id: u32) -> BtfResult<&BtfType> {
+        self.types
+            .get(id as usize)
+            .ok_or(BtfError::InvalidTypeId(id))
+    }
+
+    /// Find the first type with the name and kind
+    pub fn type_by_name(&self, name: &str, kind: u8) -> Option<(u32, &BtfType)> {
+        self.types()
+            .find(|(_, ty)| ty.kind() == kind && ty.name() == name)
+    }
+
+    /// Follow typedefs and type modifiers until a concrete type
+    pub fn skip_mods_and_typedefs(&self, start: u32) -> BtfResult<u32> {
+        let mut id = start;
+        for _ in 0..MAX_RESOLVE_DEPTH {
+            match self.type_by_id(id)? {
+                BtfType::Typedef { ty, .. }
+                | BtfType::Volatile { ty }
+                | BtfType::Const { ty }
+                | BtfType::Restrict { ty }
+                | BtfType::TypeTag { ty, .. } => id = *ty,
+                _ => return Ok(id),
+            }
+        }
+        Err(BtfError::TypeLoop(start))
+    }
+
+    /// The size in bytes of a type
+    pub fn resolve_size(&self, start: u32) -> BtfResult<u32> {
+        let mut id = start;
+        // elements of the arrays walked so far
+        let mut count = 1u32;
+        for _ in 0..MAX_RESOLVE_DEPTH {
+            id = self.skip_mods_and_typedefs(id)?;
+            let size = match self.type_by_id(id)? {
+                BtfType::Int { size, .. }
+                | BtfType::Struct { size, .. }
+                | BtfType::Union { size, .. }
+                | BtfType::Enum { size, .. }
+                | BtfType::Enum64 { size, .. }
+                | BtfType::Datasec { size, .. }
+                | BtfType::Float { size, .. } => *size,
+                BtfType::Ptr { .. } => core::mem::size_of::<u64>() as u32,
+                BtfType::Array {
+                    elem_ty, nelems, ..
+                } => {
+                    count = count
+                        .checked_mul(*nelems)
+                        .ok_or(BtfError::Overflow(start))?;
+                    id = *elem_ty;
+                    continue;
+                }
+                BtfType::Var { ty, .. } => {
+                    id = *ty;
+                    continue;
+                }
+                _ => return Err(BtfError::UnsizedType(id)),
+            };
+            return size.checked_mul(count).ok_or(BtfError::Overflow(start));
+        }
+        Err(BtfError::TypeLoop(start))
+    }
+
+    /// The variables of the data section `name` (`.data`, `.bss`, `.maps`, ...)
+    pub fn datasec_layout(&self, name: &str) -> BtfResult<Vec<BtfDatasecVar>> {
+        let vars = match self.type_by_name(name, BTF_KIND_DATASEC) {
+            Some((_, BtfType::Datasec { vars, .. })) => vars,
+            _ => return Ok(Vec::new()),
+        };
+        let mut layout = Vec::with_capacity(vars.len());
+        for var in vars {
+            match self.type_by_id(var.ty)? {
+                BtfType::Var { name, ty, .. } => layout.push(BtfDatasecVar {
+                    name: name.clone(),
+                    ty: *ty,
+                    offset: var.offset,
+                    size: var.size,
+                }),
+                _ => return Err(BtfError::InvalidTypeId(var.ty)),
+            }
+        }
+        Ok(layout)
+    }
+
+    /// Complete the data sections of an object file. The compiler leaves their
+    /// size and the offsets of their variables to the linker, the loader fills
+    /// them from the ELF section sizes and symbol values.
+    pub fn fixup_datasec(
+        &mut self,
+        section_size: impl Fn(&str) -> Option<u32>,
+        symbol_offset: impl Fn(&str, &str) -> Option<u32>,
+    ) {
+        let var_names = self
+            .types
+            .iter()
+            .map(|ty| match ty {
+                BtfType::Var { name, .. } => Some(name.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        for ty in self.types.iter_mut() {
+            if let BtfType::Datasec { name, size, vars } = ty {
+                if *size == 0 {
+                    *size = section_size(name).unwrap_or(0);
+                }
+                for var in vars.iter_mut() {
+                    let var_name = var_names
+                        .get(var.ty as usize)
+                        .and_then(|name| name.as_ref());
+                    if let Some(offset) =
+                        var_name.and_then(|var_name| symbol_offset(name, var_name))
+                    {
+                        var.offset = offset;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Format `data` as a value of type `ty`, e.g. a map key or value
+    pub fn display<'a>(&'a self, ty: u32, data: &'a [u8]) -> BtfDisplay<'a> {
+        BtfDisplay::new(self, ty, data)
+    }
+}
+
+fn string_at(strings: &[u8], offset: u32) -> BtfResult<&str> {
+    let bytes = strings
+        .get(offset as usize..)
+        .ok_or(BtfError::InvalidString(offset))?;
+    let end = bytes
+        .iter()
+        .position(|b| *b == 0)
+        .ok_or(BtfError::InvalidString(offset))?;
+    core::str::from_utf8(&bytes[..end]).map_err(|_| BtfError::InvalidString(offset))
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{vec, vec::Vec};
+
+    use super::*;
+    use crate::btf::BtfField;
+
+    fn btf(types: Vec<BtfType>) -> Btf {
+        Btf {
+            types: [BtfType::Void].into_iter().chain(types).collect(),
+            strings: vec![0],
+        }
+    }
+
+    fn int(size: u32) -> BtfType {
+        BtfType::Int {
+            name: String::new(),
+            size,
+            encoding: 0,
+            offset: 0,
+            bits: size as u8 * 8,
+        }
+    }
+
+    fn array(elem_ty: u32, nelems: u32) -> BtfType {
+        BtfType::Array {
+            elem_ty,
+            index_ty: 1,
+            nelems,
+        }
+    }
+
+    #[repr(C)]
+    struct Pair {
+        a: u32,
+        b: [u16; 3],
+    }
+
+    #[test]
+    fn parse_built_types() {
+        let mut builder = BtfBuilder::new();
+        builder.add_struct::<Pair>(
+            "pair",
+            &[
+                BtfField {
+                    name: "a",
+                    offset: 0,
+                    ty: |b| b.add::<u32>(),
+                },
+                BtfField {
+                    name: "b",
+                    offset: 4,
+                    ty: |b| b.add::<[u16; 3]>(),
+                },
+            ],
+        );
+        let btf = Btf::parse(&builder.build()).unwrap();
+        let (id, ty) = btf.type_by_name("pair", BTF_KIND_STRUCT).unwrap();
+        assert_eq!(btf.resolve_size(id), Ok(12));
+        let BtfType::Struct { members, .. } = ty else {
+            panic!("not a struct: {:?}", ty);
+        };
+        let names = members.iter().map(|m| m.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["a", "b"]);
+        assert_eq!(members[1].bit_offset, 32);
+        assert_eq!(btf.resolve_size(members[1].ty), Ok(6));
+        assert_eq!(btf.type_by_id(members[0].ty).unwrap().name(), "u32");
+    }
+
+    #[test]
+    fn parse_malformed() {
+        let mut builder = BtfBuilder::new();
+        builder.add::<u64>();
+        let data = builder.build();
+
+        let mut bad = data.clone();
+        bad[0] = 0;
+        assert_eq!(Btf::parse(&bad).err(), Some(BtfError::InvalidHeader));
+        assert_eq!(
+            Btf::parse(&data[..data.len() - 1]).err(),
+            Some(BtfError::Truncated)
+        );
+        let mut bad = data.clone();
+        // the kind of the first type
+        bad[BTF_HEADER_LEN + 7] = 0x1f;
+        assert_eq!(Btf::parse(&bad).err(), Some(BtfError::UnknownKind(0x1f)));
+    }
+
+    #[test]
+    fn skip_mods() {
+        let btf = btf(vec![
+            int(4),
+            BtfType::Const { ty: 1 },
+            BtfType::Typedef {
+                name: "t".into(),
+                ty: 2,
+            },
+            BtfType::Volatile { ty: 3 },
+        ]);
+        assert_eq!(btf.skip_mods_and_typedefs(4), Ok(1));
+        assert_eq!(btf.resolve_size(4), Ok(4));
+        assert_eq!(
+            btf.skip_mods_and_typedefs(5),
+            Err(BtfError::InvalidTypeId(5))
+        );
+    }
+
+    #[test]
+    fn type_loops() {
+        let btf = btf(vec![
+            BtfType::Typedef {
+                name: "a".into(),
+                ty: 2,
+            },
+            BtfType::Const { ty: 1 },
+            array(3, 1),
+            BtfType::Var {
+                name: "v".into(),
+                ty: 4,
+                linkage: 0,
+            },
+        ]);
+        assert_eq!(btf.skip_mods_and_typedefs(2), Err(BtfError::TypeLoop(2)));
+        assert_eq!(btf.resolve_size(1), Err(BtfError::TypeLoop(1)));
+        assert_eq!(btf.resolve_size(3), Err(BtfError::TypeLoop(3)));
+    }
+
+    #[test]
+    fn array_sizes() {
+        let btf = btf(vec![
+            int(4),
+            array(1, 3),
+            array(2, 5),
+            array(1, u32::MAX),
+            array(2, 1 << 30),
+            array(0, 2),
+        ]);
+        assert_eq!(btf.resolve_size(3), Ok(60));
+        assert_eq!(btf.resolve_size(4), Err(BtfError::Overflow(4)));
+        assert_eq!(btf.resolve_size(5), Err(BtfError::Overflow(5)));
+        assert_eq!(btf.resolve_size(6), Err(BtfError::UnsizedType(0)));
+    }
+}
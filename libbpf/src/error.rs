@@ -0,0 +1,147 @@
+use alloc::string::String;
+use core::fmt::{Display, Formatter};
+
+use crate::btf::BtfError;
+
+/// Errors of [`BpfLoader::load`](crate::loader::BpfLoader::load), the object
+/// comes from the user so none of them panics
+#[derive(Debug)]
+pub enum LoadError {
+    /// No ELF data was given or it can not be parsed
+    InvalidElf(String),
+    /// A section needed by the object does not exist
+    MissingSection(String),
+    /// The content of a section is malformed
+    InvalidSection {
+        section: String,
+        reason: &'static str,
+    },
+    /// A relocation type the loader does not handle
+    UnsupportedRelocation {
+        section: String,
+        ty: u32,
+    },
+    /// A symbol does not resolve to a map, a variable or a function
+    UnknownSymbol {
+        section: String,
+        symbol: String,
+    },
+    /// The host value of a read-only global does not have its size
+    RodataSize {
+        symbol: String,
+        size: usize,
+        value_size: usize,
+    },
+    /// The section name of a program has an invalid attach target
+    InvalidAttachTarget(String),
+    /// The host failed to create or initialize a map
+    MapCreation {
+        map: String,
+        error: anyhow::Error,
+    },
+    /// The host failed to give a handle to a program
+    ProgramCreation {
+        program: String,
+        error: anyhow::Error,
+    },
+    /// The linked program has more instructions than the executor accepts
+    OversizeProgram {
+        program: String,
+        insns: usize,
+    },
+    /// A CO-RE relocation can not be applied, or the object has CO-RE
+    /// relocations and no target BTF was given
+    CoreRelocation {
+        section: String,
+        insn_off: u32,
+        reason: &'static str,
+    },
+    /// A program without a GPL-compatible license calls a GPL-only helper
+    GplOnlyHelper {
+        program: String,
+        helper: u32,
+        license: String,
+    },
+    /// The object does not contain any program
+    NoProgram,
+    Btf(BtfError),
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            LoadError::InvalidElf(reason) => write!(f, "invalid ELF file: {}", reason),
+            LoadError::MissingSection(section) => write!(f, "missing section {}", section),
+            LoadError::InvalidSection { section, reason } => {
+                write!(f, "invalid section {}: {}", section, reason)
+            }
+            LoadError::UnsupportedRelocation { section, ty } => {
+                write!(
+                    f,
+                    "unsupported relocation type {} in section {}",
+                    ty, section
+                )
+            }
+            LoadError::UnknownSymbol { section, symbol } => {
+                write!(
+                    f,
+                    "unknown symbol {} referenced in section {}",
+                    symbol, section
+                )
+            }
+            LoadError::RodataSize {
+                symbol,
+                size,
+                value_size,
+            } => write!(
+                f,
+                "rodata {} has size {}, got a value of {} bytes",
+                symbol, size, value_size
+            ),
+            LoadError::InvalidAttachTarget(section) => {
+                write!(f, "invalid attach target in section {}", section)
+            }
+            LoadError::MapCreation { map, error } => {
+                write!(f, "failed to create map {}: {}", map, error)
+            }
+            LoadError::ProgramCreation { program, error } => {
+                write!(f, "failed to create program {}: {}", program, error)
+            }
+            LoadError::OversizeProgram { program, insns } => {
+                write!(
+                    f,
+                    "program {} has too many instructions: {}",
+                    program, insns
+                )
+            }
+            LoadError::CoreRelocation {
+                section,
+                insn_off,
+                reason,
+            } => write!(
+                f,
+                "failed to apply CO-RE relocation at offset {} in section {}: {}",
+                insn_off, section, reason
+            ),
+            LoadError::GplOnlyHelper {
+                program,
+                helper,
+                license,
+            } => write!(
+                f,
+                "program {} calls GPL-only helper {} with license \"{}\"",
+                program, helper, license
+            ),
+            LoadError::NoProgram => write!(f, "no program found in the object"),
+            LoadError::Btf(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl From<BtfError> for LoadError {
+    fn from(error: BtfError) -> Self {
+        LoadError::Btf(error)
+    }
+}
+
+pub type LoadResult<T> = Result<T, LoadError>;
@@ -1,40 +1,67 @@
 use alloc::vec::Vec;
+use core::marker::PhantomData;
 
-use crate::loader::Relocation;
+use crate::helpers::{Helper, HelperEnv, BPF_FUNC_TAIL_CALL};
+use crate::loader::{ProgFd, Relocation, BPF_PSEUDO_CALL, BPF_PSEUDO_MAP_VALUE};
+use crate::map::MapRegistry;
+use crate::relocation::{DataRelocation, DataTarget};
 use crate::INS_SIZE;
-use anyhow::Result;
-use rbpf::{ebpf, ebpf::to_insn_vec};
+use anyhow::{anyhow, Result};
+use rbpf::{
+    ebpf,
+    ebpf::{to_insn_vec, Insn},
+    EbpfVmRaw,
+};
 
-pub struct BpfExecutor<F> {
-    _f: core::marker::PhantomData<F>,
+/// Prepare loaded programs for execution against the maps of a registry
+pub struct BpfExecutor<'a, R: ?Sized> {
+    registry: &'a mut R,
 }
 
-pub trait FindMapOps {
-    fn map_data_len(map_fd: usize) -> usize;
-    fn map_data_ptr(map_fd: usize) -> *mut u8;
-}
+impl<'a, R: MapRegistry + ?Sized> BpfExecutor<'a, R> {
+    pub fn new(registry: &'a mut R) -> Self {
+        BpfExecutor { registry }
+    }
 
-impl<F: FindMapOps> BpfExecutor<F> {
-    pub fn process(prog: &[u8], relocations: &[Relocation]) -> Result<Vec<u8>> {
+    pub fn process(&mut self, prog: &[u8], relocations: &[Relocation]) -> Result<Vec<u8>> {
+        if prog.len() % INS_SIZE != 0 {
+            return Err(anyhow!(
+                "program size {} is not a multiple of {}",
+                prog.len(),
+                INS_SIZE
+            ));
+        }
         let mut instructions = to_insn_vec(prog);
-        // we need update the LD_IMM64 instruction
+        // we need update the LD_IMM64 instruction which refers to global data,
+        // the ones loading a map fd are kept for the map helpers
         for relocation in relocations {
             let index = relocation.offset / INS_SIZE;
-            let mut insn = instructions[index].clone();
-            if insn.opc == ebpf::LD_DW_IMM {
-                let mut next_insn = instructions[index + 1].clone();
-                // Now the imm is the map_fd
-                let imm = insn.imm as usize;
-                let map_data_ptr = F::map_data_ptr(imm) as usize;
-                let map_data_offset = next_insn.imm as usize;
+            let insn = instructions.get(index).ok_or_else(|| {
+                anyhow!("relocation at {} is out of the program", relocation.offset)
+            })?;
+            if insn.opc == ebpf::LD_DW_IMM && insn.src == BPF_PSEUDO_MAP_VALUE as u8 {
+                // Now the imm is the map_fd, the next imm the offset of the variable
+                let map_fd = insn.imm as usize;
+                let map_data_offset = instructions
+                    .get(index + 1)
+                    .ok_or_else(|| anyhow!("64-bit load at {} is truncated", relocation.offset))?
+                    .imm as usize;
+                let map_data_ptr = self.registry.map_data_ptr(map_fd);
+                if map_data_ptr.is_null() {
+                    return Err(anyhow!(
+                        "map {} referenced at {} has no data",
+                        map_fd,
+                        relocation.offset
+                    ));
+                }
+                let address = map_data_ptr as usize + map_data_offset;
                 // The current ins store the map_data_ptr low 32 bits,
                 // the next ins store the map_data_ptr high 32 bits
-                insn.imm = (map_data_ptr + map_data_offset) as i32;
-                next_insn.imm = ((map_data_ptr + map_data_offset) >> 32) as i32;
-                instructions[index] = insn;
-                instructions[index + 1] = next_insn;
+                instructions[index].imm = address as i32;
+                instructions[index + 1].imm = (address >> 32) as i32;
             }
         }
+        let instructions = expand_tail_calls(instructions)?;
         let prog = instructions
             .iter()
             .map(|ins| ins.to_vec())
@@ -42,4 +69,364 @@ impl<F: FindMapOps> BpfExecutor<F> {
             .collect();
         Ok(prog)
     }
+
+    /// Store the addresses of variables in the global data maps which refer to
+    /// them. Function addresses are left to the host.
+    pub fn relocate_data(&mut self, relocations: &[DataRelocation]) -> Result<()> {
+        for relocation in relocations {
+            let target = match relocation.target {
+                DataTarget::Map { map_fd, offset } => {
+                    self.registry.map_data_ptr(map_fd) as u64 + offset
+                }
+                DataTarget::Function { ref symbol, .. } => {
+                    log::warn!("the address of function {} is left to the host", symbol);
+                    continue;
+                }
+            };
+            let size = relocation.kind.data_size().unwrap_or_default();
+            if relocation.offset + size > self.registry.map_data_len(relocation.map_fd) {
+                return Err(anyhow!(
+                    "relocation at {} is out of map {}",
+                    relocation.offset,
+                    relocation.map_fd
+                ));
+            }
+            let ptr = self.registry.map_data_ptr(relocation.map_fd);
+            // the addend is the value stored in the object
+            unsafe {
+                let location = ptr.add(relocation.offset);
+                if size == 8 {
+                    let addend = (location as *const u64).read_unaligned();
+                    (location as *mut u64).write_unaligned(target + addend);
+                } else {
+                    let addend = (location as *const u32).read_unaligned();
+                    (location as *mut u32).write_unaligned((target as u32).wrapping_add(addend));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The most tail calls a program and its targets make, like in Linux
+pub const MAX_TAIL_CALL_CNT: usize = 33;
+
+/// The helper called after a successful `bpf_tail_call`, there is none with
+/// this id so the VM stops, in a BPF function too
+const TAIL_CALL_STOP: i32 = -1;
+
+/// The instructions following a tail call, the program stops after a
+/// successful call and goes on after a failed one
+const TAIL_CALL_EXIT: [Insn; 2] = [
+    Insn {
+        opc: ebpf::JNE_IMM,
+        dst: 0,
+        src: 0,
+        off: 1,
+        imm: 0,
+    },
+    Insn {
+        opc: ebpf::CALL,
+        dst: 0,
+        src: 0,
+        off: 0,
+        imm: TAIL_CALL_STOP,
+    },
+];
+
+/// `bpf_tail_call` does not return when it succeeds, but the VM has no tail
+/// calls: the helper leaves the target pending, the program stops at
+/// [`TAIL_CALL_STOP`] and [`run_program`] starts the target. The jumps and
+/// calls crossing the added instructions are moved.
+fn expand_tail_calls(instructions: Vec<Insn>) -> Result<Vec<Insn>> {
+    let is_tail_call = |insn: &Insn| {
+        insn.opc == ebpf::CALL && insn.src == 0 && insn.imm == BPF_FUNC_TAIL_CALL as i32
+    };
+    let is_pseudo_call = |insn: &Insn| insn.opc == ebpf::CALL && insn.src == BPF_PSEUDO_CALL as u8;
+    // the position of each instruction once the exits are added
+    let mut positions = Vec::with_capacity(instructions.len() + 1);
+    let mut added = 0;
+    for (index, insn) in instructions.iter().enumerate() {
+        positions.push(index + added);
+        if is_tail_call(insn) {
+            added += TAIL_CALL_EXIT.len();
+        }
+    }
+    if added == 0 {
+        return Ok(instructions);
+    }
+    positions.push(instructions.len() + added);
+    // the new offset of the jump or call at `index`
+    let moved = |index: usize, offset: i64| {
+        let target = usize::try_from(index as i64 + 1 + offset)
+            .ok()
+            .and_then(|target| positions.get(target))
+            .ok_or_else(|| anyhow!("jump at {} is out of the program", index))?;
+        Ok::<_, anyhow::Error>(*target as i64 - positions[index] as i64 - 1)
+    };
+
+    let mut expanded = Vec::with_capacity(instructions.len() + added);
+    for (index, mut insn) in instructions.into_iter().enumerate() {
+        let class = insn.opc & ebpf::BPF_CLS_MASK;
+        let op = insn.opc & ebpf::BPF_ALU_OP_MASK;
+        if is_pseudo_call(&insn) {
+            insn.imm = i32::try_from(moved(index, insn.imm as i64)?)
+                .map_err(|_| anyhow!("call at {} is too far once expanded", index))?;
+        } else if (class == ebpf::BPF_JMP || class == ebpf::BPF_JMP32)
+            && op != ebpf::BPF_CALL
+            && op != ebpf::BPF_EXIT
+        {
+            insn.off = i16::try_from(moved(index, insn.off as i64)?)
+                .map_err(|_| anyhow!("jump at {} is too far once expanded", index))?;
+        }
+        let tail_call = is_tail_call(&insn);
+        expanded.push(insn);
+        if tail_call {
+            expanded.extend_from_slice(&TAIL_CALL_EXIT);
+        }
+    }
+    Ok(expanded)
+}
+
+/// The context address the VM gives a program in r1, which the program passes
+/// to `bpf_tail_call`. An empty context is a null pointer.
+fn ctx_address(ctx: &[u8]) -> u64 {
+    if ctx.is_empty() {
+        0
+    } else {
+        ctx.as_ptr() as u64
+    }
+}
+
+/// Ends the run of a context in the registry of `E` when dropped
+struct Run<E: HelperEnv> {
+    ctx: u64,
+    env: PhantomData<E>,
+}
+
+impl<E: HelperEnv> Run<E> {
+    fn start(ctx: u64) -> Result<Self> {
+        E::maps()
+            .start_run(ctx)
+            .map_err(|error| anyhow!("context {:#x} is in use: {}", ctx, error))?;
+        Ok(Run {
+            ctx,
+            env: PhantomData,
+        })
+    }
+}
+
+impl<E: HelperEnv> Drop for Run<E> {
+    fn drop(&mut self) {
+        E::maps().end_run(self.ctx);
+    }
+}
+
+/// Run a program processed by [`BpfExecutor::process`], then the programs it
+/// tail calls with the same context. `programs` gives the processed program
+/// of a handle stored in the program arrays.
+///
+/// `bpf_tail_call` leaves its target pending in the registry of `E` under the
+/// address of the context, and the program stops right after the call. A
+/// program stopped with a pending target is followed by the target, other
+/// errors of the VM are returned. After [`MAX_TAIL_CALL_CNT`] tail calls,
+/// `bpf_tail_call` fails and the program goes on.
+///
+/// Programs running at the same time need distinct contexts.
+pub fn run_program<'p, E: HelperEnv>(
+    entry: &'p [u8],
+    programs: impl Fn(ProgFd) -> Option<&'p [u8]>,
+    helpers: &[(u32, Helper)],
+    ctx: &mut [u8],
+) -> Result<u64> {
+    let run = Run::<E>::start(ctx_address(ctx))?;
+    let mut prog = entry;
+    loop {
+        let mut vm =
+            EbpfVmRaw::new(Some(prog)).map_err(|error| anyhow!("invalid program: {:?}", error))?;
+        for (id, helper) in helpers {
+            vm.register_helper(*id, *helper)
+                .map_err(|error| anyhow!("failed to register helper {}: {:?}", id, error))?;
+        }
+        let result = vm.execute_program(ctx);
+        match E::maps().take_tail_call(run.ctx) {
+            Some(fd) => {
+                prog = programs(fd).ok_or_else(|| anyhow!("tail call to unknown program {}", fd))?
+            }
+            None => return result.map_err(|error| anyhow!("program failed: {:?}", error)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{string::String, vec, vec::Vec};
+
+    use super::*;
+    use crate::loader::{BpfMapAttr, MapFd};
+
+    /// Map 1 has data, the others have none
+    struct Registry {
+        data: Vec<u8>,
+    }
+
+    impl MapRegistry for Registry {
+        fn create_map(&mut self, _attr: BpfMapAttr) -> Result<MapFd> {
+            Err(anyhow!("not supported"))
+        }
+        fn update_map_element(&mut self, _map_fd: MapFd, _key: &[u8], _value: &[u8]) -> Result<()> {
+            Err(anyhow!("not supported"))
+        }
+        fn create_prog(&mut self) -> Result<ProgFd> {
+            Err(anyhow!("not supported"))
+        }
+        fn map_data_len(&self, map_fd: MapFd) -> usize {
+            if map_fd == 1 {
+                self.data.len()
+            } else {
+                0
+            }
+        }
+        fn map_data_ptr(&mut self, map_fd: MapFd) -> *mut u8 {
+            if map_fd == 1 {
+                self.data.as_mut_ptr()
+            } else {
+                core::ptr::null_mut()
+            }
+        }
+    }
+
+    fn insn(opc: u8, src: u8, imm: i32) -> Insn {
+        Insn {
+            opc,
+            dst: 1,
+            src,
+            off: 0,
+            imm,
+        }
+    }
+
+    fn bytes(insns: &[Insn]) -> Vec<u8> {
+        insns.iter().flat_map(|insn| insn.to_vec()).collect()
+    }
+
+    fn relocation(offset: usize) -> Relocation {
+        Relocation {
+            offset,
+            symbol: String::new(),
+            section_index: 0,
+            section_name: String::new(),
+            section_addr: 0,
+            symbol_value: 0,
+            ty: 0,
+        }
+    }
+
+    /// `r1 = &map[map_fd] + offset; exit`
+    fn load_map_value(map_fd: i32, offset: i32) -> Vec<u8> {
+        bytes(&[
+            insn(ebpf::LD_DW_IMM, BPF_PSEUDO_MAP_VALUE as u8, map_fd),
+            insn(0, 0, offset),
+            insn(ebpf::EXIT, 0, 0),
+        ])
+    }
+
+    #[test]
+    fn process_map_value() {
+        let mut registry = Registry { data: vec![0; 16] };
+        let address = registry.data.as_ptr() as usize + 8;
+        let prog = BpfExecutor::new(&mut registry)
+            .process(&load_map_value(1, 8), &[relocation(0)])
+            .unwrap();
+        let insns = to_insn_vec(&prog);
+        assert_eq!(insns.len(), 3);
+        assert_eq!(insns[0].imm, address as i32);
+        assert_eq!(insns[1].imm, (address >> 32) as i32);
+    }
+
+    #[test]
+    fn process_errors() {
+        let mut registry = Registry { data: vec![0; 16] };
+        let mut executor = BpfExecutor::new(&mut registry);
+        // a map without data
+        assert!(executor
+            .process(&load_map_value(2, 0), &[relocation(0)])
+            .is_err());
+        assert!(executor
+            .process(&load_map_value(1, 0), &[relocation(24)])
+            .is_err());
+        let prog = load_map_value(1, 0);
+        assert!(executor.process(&prog[..8], &[relocation(0)]).is_err());
+        assert!(executor.process(&prog[..12], &[]).is_err());
+    }
+
+    fn tail_call() -> Insn {
+        insn(ebpf::CALL, 0, BPF_FUNC_TAIL_CALL as i32)
+    }
+
+    fn jump(opc: u8, off: i16) -> Insn {
+        Insn {
+            opc,
+            dst: 0,
+            src: 0,
+            off,
+            imm: 0,
+        }
+    }
+
+    #[test]
+    fn expand_without_tail_call() {
+        let insns = vec![jump(ebpf::JA, 0), insn(ebpf::EXIT, 0, 0)];
+        assert_eq!(expand_tail_calls(insns.clone()).unwrap(), insns);
+    }
+
+    #[test]
+    fn expand_moves_jumps_and_calls() {
+        let insns = vec![
+            // over the tail call
+            jump(ebpf::JA, 2),
+            tail_call(),
+            // back to the tail call
+            jump(ebpf::JNE_IMM, -2),
+            // to the function after the tail call
+            insn(ebpf::CALL, BPF_PSEUDO_CALL as u8, 1),
+            insn(ebpf::EXIT, 0, 0),
+            insn(ebpf::EXIT, 0, 0),
+        ];
+        let expanded = expand_tail_calls(insns).unwrap();
+        let added = TAIL_CALL_EXIT.len();
+        assert_eq!(expanded.len(), 6 + added);
+        assert_eq!(expanded[0].off, 2 + added as i16);
+        assert_eq!(expanded[2..2 + added], TAIL_CALL_EXIT);
+        assert_eq!(expanded[2 + added].off, -2 - added as i16);
+        assert_eq!(expanded[3 + added].imm, 1);
+    }
+
+    #[test]
+    fn expand_tail_call_in_function() {
+        let insns = vec![
+            insn(ebpf::CALL, BPF_PSEUDO_CALL as u8, 1),
+            insn(ebpf::EXIT, 0, 0),
+            tail_call(),
+            insn(ebpf::EXIT, 0, 0),
+        ];
+        let expanded = expand_tail_calls(insns).unwrap();
+        assert_eq!(expanded[0].imm, 1);
+        assert_eq!(expanded[3..5], TAIL_CALL_EXIT);
+        assert_eq!(expanded[5].opc, ebpf::EXIT);
+    }
+
+    #[test]
+    fn expand_errors() {
+        // a jump out of the program
+        let insns = vec![tail_call(), jump(ebpf::JA, 5), insn(ebpf::EXIT, 0, 0)];
+        assert!(expand_tail_calls(insns).is_err());
+        let insns = vec![tail_call(), jump(ebpf::JA, -3), insn(ebpf::EXIT, 0, 0)];
+        assert!(expand_tail_calls(insns).is_err());
+        // a jump whose offset no longer fits once expanded
+        let mut insns = vec![jump(ebpf::JA, i16::MAX), tail_call()];
+        insns.resize(i16::MAX as usize + 2, insn(ebpf::MOV64_IMM, 0, 0));
+        insns.push(insn(ebpf::EXIT, 0, 0));
+        assert!(expand_tail_calls(insns).is_err());
+    }
 }
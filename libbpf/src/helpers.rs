@@ -0,0 +1,563 @@
+//! Helpers callable from the programs, registered into the VM by their id.
+//!
+//! Helpers are plain functions without a context, so the map registry they use
+//! is given by a type implementing [`HelperEnv`]:
+//!
+//! ```ignore
+//! static MAPS: BpfMapRegistry<SpinMutex<()>> = BpfMapRegistry::new();
+//!
+//! struct Env;
+//! impl HelperEnv for Env {
+//!     type Lock = SpinMutex<()>;
+//!     fn maps() -> &'static BpfMapRegistry<Self::Lock> {
+//!         &MAPS
+//!     }
+//! }
+//!
+//! for (id, helper) in map_helpers::<Env>() {
+//!     vm.register_helper(id, helper)?;
+//! }
+//! ```
+//!
+//! The map arguments are the fds the loader stores in the `LD_IMM64`
+//! instructions with `BPF_PSEUDO_MAP_FD`, keys and values are read with the
+//! sizes of the map. Errors are returned as negative errnos like in Linux.
+use lock_api::RawMutex;
+
+use crate::loader::MapFd;
+use crate::map::{
+    ringbuf_commit, BpfMapRegistry, MapError, MapResult, BPF_F_FAST_STACK_CMP, BPF_F_REUSE_STACKID,
+    BPF_F_SKIP_FIELD_MASK, BPF_F_USER_STACK,
+};
+
+/// The signature of the helpers in the VM
+pub type Helper = fn(u64, u64, u64, u64, u64) -> u64;
+
+pub const BPF_FUNC_MAP_LOOKUP_ELEM: u32 = 1;
+pub const BPF_FUNC_MAP_UPDATE_ELEM: u32 = 2;
+pub const BPF_FUNC_MAP_DELETE_ELEM: u32 = 3;
+pub const BPF_FUNC_TAIL_CALL: u32 = 12;
+pub const BPF_FUNC_PERF_EVENT_OUTPUT: u32 = 25;
+pub const BPF_FUNC_GET_STACKID: u32 = 27;
+pub const BPF_FUNC_GET_STACK: u32 = 67;
+pub const BPF_FUNC_MAP_PUSH_ELEM: u32 = 87;
+pub const BPF_FUNC_MAP_POP_ELEM: u32 = 88;
+pub const BPF_FUNC_MAP_PEEK_ELEM: u32 = 89;
+pub const BPF_FUNC_RINGBUF_OUTPUT: u32 = 130;
+pub const BPF_FUNC_RINGBUF_RESERVE: u32 = 131;
+pub const BPF_FUNC_RINGBUF_SUBMIT: u32 = 132;
+pub const BPF_FUNC_RINGBUF_DISCARD: u32 = 133;
+pub const BPF_FUNC_RINGBUF_QUERY: u32 = 134;
+
+/// The most addresses of a stack walked by the helpers, skipped ones included
+pub const PERF_MAX_STACK_DEPTH: usize = 127;
+
+/// The host state used by the helpers
+pub trait HelperEnv: 'static {
+    type Lock: RawMutex + 'static;
+    /// The registry holding the maps of the running programs
+    fn maps() -> &'static BpfMapRegistry<Self::Lock>;
+    /// Fill `ips` with the kernel stack of the program context `ctx`, innermost
+    /// first, and return the number of addresses. Hosts running programs on
+    /// probes unwind the registers of the probe, e.g. with `kprobe::unwind`.
+    fn stack_trace(_ctx: u64, _ips: &mut [u64]) -> usize {
+        0
+    }
+}
+
+/// The map helpers with their ids
+pub fn map_helpers<E: HelperEnv>() -> [(u32, Helper); 7] {
+    [
+        (BPF_FUNC_MAP_LOOKUP_ELEM, bpf_map_lookup_elem::<E>),
+        (BPF_FUNC_MAP_UPDATE_ELEM, bpf_map_update_elem::<E>),
+        (BPF_FUNC_MAP_DELETE_ELEM, bpf_map_delete_elem::<E>),
+        (BPF_FUNC_TAIL_CALL, bpf_tail_call::<E>),
+        (BPF_FUNC_MAP_PUSH_ELEM, bpf_map_push_elem::<E>),
+        (BPF_FUNC_MAP_POP_ELEM, bpf_map_pop_elem::<E>),
+        (BPF_FUNC_MAP_PEEK_ELEM, bpf_map_peek_elem::<E>),
+    ]
+}
+
+/// The ring buffer helpers with their ids
+pub fn ringbuf_helpers<E: HelperEnv>() -> [(u32, Helper); 5] {
+    [
+        (BPF_FUNC_RINGBUF_OUTPUT, bpf_ringbuf_output::<E>),
+        (BPF_FUNC_RINGBUF_RESERVE, bpf_ringbuf_reserve::<E>),
+        (BPF_FUNC_RINGBUF_SUBMIT, bpf_ringbuf_submit),
+        (BPF_FUNC_RINGBUF_DISCARD, bpf_ringbuf_discard),
+        (BPF_FUNC_RINGBUF_QUERY, bpf_ringbuf_query::<E>),
+    ]
+}
+
+/// The perf event helpers with their ids
+pub fn perf_event_helpers<E: HelperEnv>() -> [(u32, Helper); 1] {
+    [(BPF_FUNC_PERF_EVENT_OUTPUT, bpf_perf_event_output::<E>)]
+}
+
+/// The stack helpers with their ids
+pub fn stack_helpers<E: HelperEnv>() -> [(u32, Helper); 2] {
+    [
+        (BPF_FUNC_GET_STACKID, bpf_get_stackid::<E>),
+        (BPF_FUNC_GET_STACK, bpf_get_stack::<E>),
+    ]
+}
+
+/// The return value of a helper failing with `error`
+pub(crate) fn errno(error: MapError) -> u64 {
+    -error.errno() as u64
+}
+
+pub(crate) fn ret(result: MapResult<()>) -> u64 {
+    result.map_or_else(errno, |_| 0)
+}
+
+/// The memory of the program at `ptr`
+///
+/// # Safety
+///
+/// There is no verifier, the program has to pass a buffer of `len` bytes.
+pub(crate) unsafe fn program_slice<'a>(ptr: u64, len: usize) -> &'a [u8] {
+    if len == 0 {
+        return &[];
+    }
+    core::slice::from_raw_parts(ptr as *const u8, len)
+}
+
+/// The writable memory of the program at `ptr`
+///
+/// # Safety
+///
+/// See [`program_slice`].
+pub(crate) unsafe fn program_slice_mut<'a>(ptr: u64, len: usize) -> &'a mut [u8] {
+    if len == 0 {
+        return &mut [];
+    }
+    core::slice::from_raw_parts_mut(ptr as *mut u8, len)
+}
+
+/// `void *bpf_map_lookup_elem(struct bpf_map *map, const void *key)`, the
+/// address of the value or NULL.
+///
+/// The program keeps the address after the registry is unlocked, it stays valid
+/// until the element is deleted. This is why the maps preallocate their
+/// elements: updates write the value in place and never move it.
+pub fn bpf_map_lookup_elem<E: HelperEnv>(map: u64, key: u64, _: u64, _: u64, _: u64) -> u64 {
+    E::maps()
+        .with_map(map as MapFd, |map, attr| {
+            let key = unsafe { program_slice(key, attr.key_size as usize) };
+            Ok(map
+                .lookup_elem(key)?
+                .map_or(0, |value| value.as_mut_ptr() as u64))
+        })
+        .unwrap_or(0)
+}
+
+/// `long bpf_map_update_elem(struct bpf_map *map, const void *key, const void *value, u64 flags)`
+pub fn bpf_map_update_elem<E: HelperEnv>(
+    map: u64,
+    key: u64,
+    value: u64,
+    flags: u64,
+    _: u64,
+) -> u64 {
+    ret(E::maps().with_map(map as MapFd, |map, attr| {
+        let key = unsafe { program_slice(key, attr.key_size as usize) };
+        let value = unsafe { program_slice(value, attr.value_size as usize) };
+        map.update_elem(key, value, flags)
+    }))
+}
+
+/// `long bpf_map_delete_elem(struct bpf_map *map, const void *key)`
+pub fn bpf_map_delete_elem<E: HelperEnv>(map: u64, key: u64, _: u64, _: u64, _: u64) -> u64 {
+    ret(E::maps().with_map(map as MapFd, |map, attr| {
+        let key = unsafe { program_slice(key, attr.key_size as usize) };
+        map.delete_elem(key)
+    }))
+}
+
+/// `long bpf_tail_call(void *ctx, struct bpf_map *prog_array_map, u32 index)`,
+/// the target becomes the next program of the run of `ctx` and the program
+/// stops, see [`run_program`](crate::executor::run_program). Failures return
+/// to the program like in Linux.
+pub fn bpf_tail_call<E: HelperEnv>(ctx: u64, map: u64, index: u64, _: u64, _: u64) -> u64 {
+    let maps = E::maps();
+    ret(maps
+        .with_map(map as MapFd, |map, _| map.prog_array_get(index as u32))
+        .and_then(|prog| maps.tail_call(ctx, prog)))
+}
+
+/// `long bpf_map_push_elem(struct bpf_map *map, const void *value, u64 flags)`
+pub fn bpf_map_push_elem<E: HelperEnv>(map: u64, value: u64, flags: u64, _: u64, _: u64) -> u64 {
+    ret(E::maps().with_map(map as MapFd, |map, attr| {
+        let value = unsafe { program_slice(value, attr.value_size as usize) };
+        map.push_elem(value, flags)
+    }))
+}
+
+/// `long bpf_map_pop_elem(struct bpf_map *map, void *value)`
+pub fn bpf_map_pop_elem<E: HelperEnv>(map: u64, value: u64, _: u64, _: u64, _: u64) -> u64 {
+    ret(E::maps().with_map(map as MapFd, |map, attr| {
+        let value = unsafe { program_slice_mut(value, attr.value_size as usize) };
+        map.pop_elem(value)
+    }))
+}
+
+/// `long bpf_map_peek_elem(struct bpf_map *map, void *value)`
+pub fn bpf_map_peek_elem<E: HelperEnv>(map: u64, value: u64, _: u64, _: u64, _: u64) -> u64 {
+    ret(E::maps().with_map(map as MapFd, |map, attr| {
+        let value = unsafe { program_slice_mut(value, attr.value_size as usize) };
+        map.peek_elem(value)
+    }))
+}
+
+/// `long bpf_ringbuf_output(void *ringbuf, void *data, u64 size, u64 flags)`,
+/// the wakeup flags are ignored since the host polls
+pub fn bpf_ringbuf_output<E: HelperEnv>(map: u64, data: u64, size: u64, _: u64, _: u64) -> u64 {
+    ret(E::maps().with_map(map as MapFd, |map, _| {
+        let data = unsafe { program_slice(data, size as usize) };
+        let record = map.ringbuf_reserve(data.len())?;
+        record.copy_from_slice(data);
+        unsafe { ringbuf_commit(record.as_mut_ptr(), false) };
+        Ok(())
+    }))
+}
+
+/// `void *bpf_ringbuf_reserve(void *ringbuf, u64 size, u64 flags)`, the record
+/// or NULL if there is no room for it
+pub fn bpf_ringbuf_reserve<E: HelperEnv>(map: u64, size: u64, flags: u64, _: u64, _: u64) -> u64 {
+    if flags != 0 {
+        return 0;
+    }
+    E::maps()
+        .with_map(map as MapFd, |map, _| {
+            Ok(map.ringbuf_reserve(size as usize)?.as_mut_ptr() as u64)
+        })
+        .unwrap_or(0)
+}
+
+/// `void bpf_ringbuf_submit(void *data, u64 flags)`
+pub fn bpf_ringbuf_submit(data: u64, _: u64, _: u64, _: u64, _: u64) -> u64 {
+    unsafe { ringbuf_commit(data as *mut u8, false) };
+    0
+}
+
+/// `void bpf_ringbuf_discard(void *data, u64 flags)`
+pub fn bpf_ringbuf_discard(data: u64, _: u64, _: u64, _: u64, _: u64) -> u64 {
+    unsafe { ringbuf_commit(data as *mut u8, true) };
+    0
+}
+
+/// `u64 bpf_ringbuf_query(void *ringbuf, u64 flags)`
+pub fn bpf_ringbuf_query<E: HelperEnv>(map: u64, flags: u64, _: u64, _: u64, _: u64) -> u64 {
+    E::maps()
+        .with_map(map as MapFd, |map, _| map.ringbuf_query(flags))
+        .unwrap_or(0)
+}
+
+/// `long bpf_perf_event_output(void *ctx, struct bpf_map *map, u64 flags, void *data, u64 size)`,
+/// the CPU index is in the low 32 bits of `flags`, `BPF_F_CURRENT_CPU` for the
+/// running CPU
+pub fn bpf_perf_event_output<E: HelperEnv>(
+    _ctx: u64,
+    map: u64,
+    flags: u64,
+    data: u64,
+    size: u64,
+) -> u64 {
+    ret(E::maps().with_map(map as MapFd, |map, _| {
+        let data = unsafe { program_slice(data, size as usize) };
+        map.perf_event_output(flags, data)
+    }))
+}
+
+/// The kernel stack of `ctx` without the skipped entries of `flags`
+fn stack_trace<E: HelperEnv>(ctx: u64, flags: u64, ips: &mut [u64]) -> MapResult<usize> {
+    if flags & BPF_F_USER_STACK != 0 {
+        return Err(MapError::NotSupported);
+    }
+    let skip = (flags & BPF_F_SKIP_FIELD_MASK) as usize;
+    let len = E::stack_trace(ctx, ips).min(ips.len());
+    if skip >= len {
+        return Ok(0);
+    }
+    ips.copy_within(skip..len, 0);
+    Ok(len - skip)
+}
+
+/// `long bpf_get_stackid(void *ctx, struct bpf_map *map, u64 flags)`, the id
+/// of the kernel stack in a stack trace map
+pub fn bpf_get_stackid<E: HelperEnv>(ctx: u64, map: u64, flags: u64, _: u64, _: u64) -> u64 {
+    let known =
+        BPF_F_SKIP_FIELD_MASK | BPF_F_USER_STACK | BPF_F_FAST_STACK_CMP | BPF_F_REUSE_STACKID;
+    if flags & !known != 0 {
+        return errno(MapError::Invalid);
+    }
+    let mut ips = [0; PERF_MAX_STACK_DEPTH];
+    let len = match stack_trace::<E>(ctx, flags, &mut ips) {
+        Ok(len) => len,
+        Err(error) => return errno(error),
+    };
+    E::maps()
+        .with_map(map as MapFd, |map, _| map.get_stackid(&ips[..len], flags))
+        .map_or_else(errno, |id| id as u64)
+}
+
+/// `long bpf_get_stack(void *ctx, void *buf, u32 size, u64 flags)`, copy the
+/// kernel stack of `ctx` to `buf` and return the number of bytes copied, the
+/// rest of `buf` is zeroed. Invalid flags or sizes leave `buf` untouched.
+pub fn bpf_get_stack<E: HelperEnv>(ctx: u64, buf: u64, size: u64, flags: u64, _: u64) -> u64 {
+    if flags & !(BPF_F_SKIP_FIELD_MASK | BPF_F_USER_STACK) != 0 || size % 8 != 0 {
+        return errno(MapError::Invalid);
+    }
+    let buf = unsafe { program_slice_mut(buf, size as usize) };
+    buf.fill(0);
+    let mut ips = [0; PERF_MAX_STACK_DEPTH];
+    let len = match stack_trace::<E>(ctx, flags, &mut ips) {
+        Ok(len) => len.min(buf.len() / 8),
+        Err(error) => return errno(error),
+    };
+    for (entry, ip) in buf.chunks_exact_mut(8).zip(&ips[..len]) {
+        entry.copy_from_slice(&ip.to_ne_bytes());
+    }
+    (len * 8) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{string::ToString, vec::Vec};
+
+    use super::*;
+    use crate::loader::BpfMapAttr;
+    use crate::map::{
+        BPF_ANY, BPF_EXIST, BPF_MAP_TYPE_HASH, BPF_MAP_TYPE_PROG_ARRAY, BPF_MAP_TYPE_QUEUE,
+        BPF_MAP_TYPE_STACK, BPF_MAP_TYPE_STACK_TRACE, BPF_NOEXIST,
+    };
+
+    type Lock = spin::Mutex<()>;
+
+    static MAPS: BpfMapRegistry<Lock> = BpfMapRegistry::new();
+
+    /// The stack of every context, innermost first
+    const STACK: [u64; 5] = [0x10, 0x20, 0x30, 0x40, 0x50];
+
+    /// The maps of [`MAPS`] and a fake unwinder returning [`STACK`]
+    struct Env;
+
+    impl HelperEnv for Env {
+        type Lock = Lock;
+        fn maps() -> &'static BpfMapRegistry<Lock> {
+            &MAPS
+        }
+        fn stack_trace(_ctx: u64, ips: &mut [u64]) -> usize {
+            let len = STACK.len().min(ips.len());
+            ips[..len].copy_from_slice(&STACK[..len]);
+            len
+        }
+    }
+
+    fn create(map_type: u32, key_size: u32, value_size: u32, max_entries: u32) -> u64 {
+        MAPS.create(BpfMapAttr {
+            map_type,
+            key_size,
+            value_size,
+            max_entries,
+            map_flags: 0,
+            name: "map".to_string(),
+            btf_key_type_id: 0,
+            btf_value_type_id: 0,
+        })
+        .unwrap() as u64
+    }
+
+    fn addr(bytes: &[u8]) -> u64 {
+        bytes.as_ptr() as u64
+    }
+
+    /// The value at an address returned by `bpf_map_lookup_elem`
+    fn value(ptr: u64) -> u64 {
+        assert_ne!(ptr, 0);
+        unsafe { (ptr as *const u64).read_unaligned() }
+    }
+
+    #[test]
+    fn lookup_update_delete() {
+        let map = create(BPF_MAP_TYPE_HASH, 4, 8, 2);
+        let key = 1u32.to_ne_bytes();
+        let update = |value: u64, flags| {
+            bpf_map_update_elem::<Env>(map, addr(&key), addr(&value.to_ne_bytes()), flags, 0)
+        };
+        assert_eq!(bpf_map_lookup_elem::<Env>(map, addr(&key), 0, 0, 0), 0);
+        assert_eq!(update(1, BPF_EXIST), errno(MapError::NotFound));
+        assert_eq!(update(1, BPF_NOEXIST), 0);
+        assert_eq!(update(2, BPF_NOEXIST), errno(MapError::Exists));
+        assert_eq!(update(2, BPF_EXIST | BPF_NOEXIST), errno(MapError::Invalid));
+        let ptr = bpf_map_lookup_elem::<Env>(map, addr(&key), 0, 0, 0);
+        assert_eq!(value(ptr), 1);
+        // the program sees updates through the address it looked up
+        assert_eq!(update(2, BPF_ANY), 0);
+        assert_eq!(value(ptr), 2);
+        unsafe { (ptr as *mut u64).write_unaligned(3) };
+        let mut host = [0; 8];
+        MAPS.lookup_elem(map as MapFd, &key, &mut host).unwrap();
+        assert_eq!(u64::from_ne_bytes(host), 3);
+        assert_eq!(bpf_map_delete_elem::<Env>(map, addr(&key), 0, 0, 0), 0);
+        assert_eq!(
+            bpf_map_delete_elem::<Env>(map, addr(&key), 0, 0, 0),
+            errno(MapError::NotFound)
+        );
+        assert_eq!(bpf_map_lookup_elem::<Env>(map, addr(&key), 0, 0, 0), 0);
+    }
+
+    #[test]
+    fn unknown_map() {
+        let key = 1u32.to_ne_bytes();
+        let map = MapFd::MAX as u64;
+        assert_eq!(bpf_map_lookup_elem::<Env>(map, addr(&key), 0, 0, 0), 0);
+        assert_eq!(
+            bpf_map_delete_elem::<Env>(map, addr(&key), 0, 0, 0),
+            errno(MapError::BadFd)
+        );
+    }
+
+    #[test]
+    fn push_pop_peek() {
+        let queue = create(BPF_MAP_TYPE_QUEUE, 0, 8, 2);
+        let stack = create(BPF_MAP_TYPE_STACK, 0, 8, 2);
+        for map in [queue, stack] {
+            let push = |value: u64, flags| {
+                bpf_map_push_elem::<Env>(map, addr(&value.to_ne_bytes()), flags, 0, 0)
+            };
+            let mut out = [0u8; 8];
+            let out_addr = out.as_mut_ptr() as u64;
+            assert_eq!(
+                bpf_map_peek_elem::<Env>(map, out_addr, 0, 0, 0),
+                errno(MapError::NotFound)
+            );
+            assert_eq!(push(1, BPF_ANY), 0);
+            assert_eq!(push(2, BPF_ANY), 0);
+            assert_eq!(push(3, BPF_ANY), errno(MapError::TooBig));
+            assert_eq!(push(3, BPF_NOEXIST), errno(MapError::Invalid));
+            // a full map drops its oldest value
+            assert_eq!(push(3, BPF_EXIST), 0);
+            let (first, second) = if map == queue { (2, 3) } else { (3, 2) };
+            assert_eq!(bpf_map_peek_elem::<Env>(map, out_addr, 0, 0, 0), 0);
+            assert_eq!(u64::from_ne_bytes(out), first);
+            assert_eq!(bpf_map_pop_elem::<Env>(map, out_addr, 0, 0, 0), 0);
+            assert_eq!(u64::from_ne_bytes(out), first);
+            assert_eq!(bpf_map_pop_elem::<Env>(map, out_addr, 0, 0, 0), 0);
+            assert_eq!(u64::from_ne_bytes(out), second);
+            assert_eq!(
+                bpf_map_pop_elem::<Env>(map, out_addr, 0, 0, 0),
+                errno(MapError::NotFound)
+            );
+        }
+    }
+
+    #[test]
+    fn tail_call() {
+        let map = create(BPF_MAP_TYPE_PROG_ARRAY, 4, 4, 2);
+        MAPS.update_elem(
+            map as MapFd,
+            &0u32.to_ne_bytes(),
+            &5u32.to_ne_bytes(),
+            BPF_ANY,
+        )
+        .unwrap();
+        // the address of the program context, unique among the tests
+        let ctx = 0x1000;
+        // no program runs with the context
+        assert_eq!(
+            bpf_tail_call::<Env>(ctx, map, 0, 0, 0),
+            errno(MapError::Invalid)
+        );
+        MAPS.start_run(ctx).unwrap();
+        assert_eq!(
+            bpf_tail_call::<Env>(ctx, map, 1, 0, 0),
+            errno(MapError::NotFound)
+        );
+        assert_eq!(
+            bpf_tail_call::<Env>(ctx, map, 2, 0, 0),
+            errno(MapError::TooBig)
+        );
+        assert_eq!(MAPS.take_tail_call(ctx), None);
+        assert_eq!(bpf_tail_call::<Env>(ctx, map, 0, 0, 0), 0);
+        assert_eq!(MAPS.take_tail_call(ctx), Some(5));
+        MAPS.end_run(ctx);
+    }
+
+    fn stack(buf: &mut [u64], size: u64, flags: u64) -> u64 {
+        bpf_get_stack::<Env>(0, buf.as_mut_ptr() as u64, size, flags, 0)
+    }
+
+    #[test]
+    fn get_stack() {
+        let mut buf = [u64::MAX; 8];
+        assert_eq!(stack(&mut buf, 64, 0), 40);
+        assert_eq!(buf, [0x10, 0x20, 0x30, 0x40, 0x50, 0, 0, 0]);
+        // skip two entries
+        assert_eq!(stack(&mut buf, 64, 2), 24);
+        assert_eq!(buf[..4], [0x30, 0x40, 0x50, 0]);
+        // the buffer is shorter than the stack
+        assert_eq!(stack(&mut buf, 16, 1), 16);
+        // only the given size is written
+        assert_eq!(buf[..3], [0x20, 0x30, 0x50]);
+        assert_eq!(stack(&mut buf, 64, 5), 0);
+        assert_eq!(buf, [0; 8]);
+    }
+
+    #[test]
+    fn get_stack_errors() {
+        let mut buf = [u64::MAX; 4];
+        // invalid arguments are refused before the buffer is written
+        assert_eq!(
+            stack(&mut buf, 32, BPF_F_REUSE_STACKID),
+            errno(MapError::Invalid)
+        );
+        assert_eq!(stack(&mut buf, 12, 0), errno(MapError::Invalid));
+        assert_eq!(buf, [u64::MAX; 4]);
+        assert_eq!(
+            stack(&mut buf, 32, BPF_F_USER_STACK),
+            errno(MapError::NotSupported)
+        );
+        assert_eq!(buf, [0; 4]);
+    }
+
+    #[test]
+    fn get_stackid() {
+        // a single bucket, depth 4
+        let map = create(BPF_MAP_TYPE_STACK_TRACE, 4, 32, 1);
+        let lookup = |id: u64| {
+            let mut value = [0; 32];
+            MAPS.lookup_elem(map as MapFd, &(id as u32).to_ne_bytes(), &mut value)
+                .unwrap();
+            value
+                .chunks_exact(8)
+                .map(|ip| u64::from_ne_bytes(ip.try_into().unwrap()))
+                .collect::<Vec<_>>()
+        };
+        let id = bpf_get_stackid::<Env>(0, map, 0, 0, 0);
+        assert_eq!(id, 0);
+        // truncated to the depth of the map
+        assert_eq!(lookup(id), [0x10, 0x20, 0x30, 0x40]);
+        assert_eq!(bpf_get_stackid::<Env>(0, map, 0, 0, 0), id);
+        // another stack in the same bucket
+        assert_eq!(
+            bpf_get_stackid::<Env>(0, map, 3, 0, 0),
+            errno(MapError::Exists)
+        );
+        assert_eq!(
+            bpf_get_stackid::<Env>(0, map, 3 | BPF_F_REUSE_STACKID, 0, 0),
+            id
+        );
+        assert_eq!(lookup(id), [0x40, 0x50, 0, 0]);
+        // nothing left once skipped
+        assert_eq!(
+            bpf_get_stackid::<Env>(0, map, 5, 0, 0),
+            errno(MapError::NotFound)
+        );
+        assert_eq!(
+            bpf_get_stackid::<Env>(0, map, 1 << 11, 0, 0),
+            errno(MapError::Invalid)
+        );
+    }
+}
@@ -2,11 +2,15 @@
 #![no_std]
 extern crate alloc;
 
+pub mod btf;
+pub mod error;
 pub mod loader;
 pub mod print;
 
 pub mod executor;
+pub mod helpers;
 pub mod map;
-mod relocation;
+pub mod relocation;
+pub mod section;
 
 pub const INS_SIZE: usize = 8;
@@ -1,23 +1,49 @@
 use alloc::{
-    collections::BTreeMap,
+    collections::{btree_map::Entry, BTreeMap, BTreeSet},
     format,
     string::{String, ToString},
     vec::Vec,
 };
-use core::marker::PhantomData;
 
-use crate::INS_SIZE;
-use anyhow::Result;
-use elf::{endian::AnyEndian, ElfBytes};
-use rbpf::ebpf::{to_insn_vec, Insn};
+use crate::{
+    btf::{
+        core_relo,
+        ext::{BtfExt, BtfFuncInfo, BtfLineInfo},
+        Btf, BtfType, BTF_KIND_DATASEC,
+    },
+    error::{LoadError, LoadResult},
+    map::{MapRegistry, BPF_MAP_TYPE_ARRAY, BPF_MAP_TYPE_PROG_ARRAY, BPF_MAP_TYPE_UNSPEC},
+    relocation::{DataRelocation, DataTarget, RelocationKind, R_BPF_NONE},
+    section::{parse_section, AttachTarget, ProgramType},
+    INS_SIZE,
+};
+use anyhow::{anyhow, Result};
+use elf::{
+    abi::{SHF_EXECINSTR, SHT_REL, STB_GLOBAL, STT_FUNC, STT_OBJECT, STT_SECTION},
+    endian::{AnyEndian, EndianParse},
+    section::{SectionHeader, SectionHeaderTable},
+    string_table::StringTable,
+    symbol::{Symbol, SymbolTable},
+    ElfBytes,
+};
+use rbpf::ebpf::{self, to_insn_vec, Insn};
 
 #[derive(Debug)]
-pub struct BpfLoader<'data, C> {
+pub struct BpfLoader<'data> {
+    elf: Option<&'data [u8]>,
     elf_data: Option<ElfBytes<'data, AnyEndian>>,
     text_section_name: Option<String>,
-    _c: PhantomData<C>,
+    /// Initial values of read-only globals, set by the host before loading
+    rodata: BTreeMap<String, Vec<u8>>,
+    btf: Option<Btf>,
+    btf_ext: Option<BtfExt>,
+    /// BTF of the kernel the programs run on, for CO-RE relocations
+    target_btf: Option<&'data [u8]>,
+    /// Helpers only callable by GPL-compatible programs
+    gpl_only_helpers: BTreeSet<u32>,
 }
 
+#[derive(Debug, Clone)]
 pub struct BpfMapAttr {
     pub map_type: u32,
     pub key_size: u32,
@@ -25,154 +51,787 @@ pub struct BpfMapAttr {
     pub max_entries: u32,
     pub map_flags: u32,
     pub name: String,
+    /// BTF type of the keys, 0 if unknown
+    pub btf_key_type_id: u32,
+    /// BTF type of the values, 0 if unknown
+    pub btf_value_type_id: u32,
 }
 
-pub trait CreateMapOps {
-    fn create_map(attr: BpfMapAttr) -> Result<MapFd>;
-    fn map_data_len(map_fd: MapFd) -> usize;
-    fn update_map_element(map_fd: MapFd, key: &[u8], value: &[u8]) -> Result<()>;
-}
-
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Relocation {
     pub offset: usize,
     pub symbol: String,
     pub section_index: usize,
     pub section_name: String,
     pub section_addr: u64,
+    pub symbol_value: u64,
     pub ty: u32,
 }
 
+impl Relocation {
+    /// The kind of the relocation, `None` for unknown types
+    pub fn kind(&self) -> Option<RelocationKind> {
+        RelocationKind::from_type(self.ty)
+    }
+}
+
 type SecIndex = usize;
+/// An executable section with the name, offset and size of its programs
+type ProgramSection = (SecIndex, String, Vec<(String, usize, usize)>);
 pub type MapFd = usize;
+/// The handle of a loaded program, given by [`MapRegistry::create_prog`] and
+/// stored in program arrays
+pub type ProgFd = u32;
+
+/// Size of the legacy `struct bpf_map_def` fields we read
+/// (type, key_size, value_size, max_entries, map_flags)
+const BPF_MAP_DEF_SIZE: usize = 20;
 
-impl<'data, C: CreateMapOps> BpfLoader<'data, C> {
+/// A program with its subprograms appended
+struct LinkedProgram {
+    insns: Vec<Insn>,
+    relocations: Vec<Relocation>,
+    /// The functions in the program with the index of their first instruction
+    functions: Vec<(Function, usize)>,
+}
+
+/// A function in an executable section, `start` and `end` are byte offsets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Function {
+    section_index: SecIndex,
+    start: usize,
+    end: usize,
+}
+
+/// A map created for a global data section or a map definition
+#[derive(Debug)]
+pub struct LoadedMap {
+    section_index: SecIndex,
+    /// Offset of the map symbol in its section, 0 for global data sections
+    offset: u64,
+    /// Global data is referenced by value, map definitions by fd
+    is_data: bool,
+    fd: MapFd,
+    attr: BpfMapAttr,
+}
+
+impl LoadedMap {
+    /// The name of the map, or of the section for global data
+    pub fn name(&self) -> &str {
+        &self.attr.name
+    }
+    pub fn fd(&self) -> MapFd {
+        self.fd
+    }
+    pub fn map_type(&self) -> u32 {
+        self.attr.map_type
+    }
+    pub fn key_size(&self) -> u32 {
+        self.attr.key_size
+    }
+    pub fn value_size(&self) -> u32 {
+        self.attr.value_size
+    }
+    pub fn max_entries(&self) -> u32 {
+        self.attr.max_entries
+    }
+    pub fn attr(&self) -> &BpfMapAttr {
+        &self.attr
+    }
+}
+
+/// A global variable, stored in the value of the map of its data section
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GlobalVar {
+    pub section: String,
+    pub map_fd: MapFd,
+    /// Offset of the variable in the map value
+    pub offset: usize,
+    pub size: usize,
+}
+
+impl<'data> BpfLoader<'data> {
     pub fn new() -> Self {
         BpfLoader {
+            elf: None,
             elf_data: None,
             text_section_name: None,
-            _c: PhantomData,
+            rodata: BTreeMap::new(),
+            btf: None,
+            btf_ext: None,
+            target_btf: None,
+            gpl_only_helpers: BTreeSet::new(),
         }
     }
 
+    /// Only load the programs of the section `name`
     pub fn text_section_name(mut self, name: &str) -> Self {
         self.text_section_name = Some(name.to_string());
         self
     }
 
+    /// Set the initial value of a read-only global (`const volatile` in C) before
+    /// loading, the value must have the size of the variable
+    pub fn rodata(mut self, symbol: &str, value: &[u8]) -> Self {
+        self.rodata.insert(symbol.to_string(), value.to_vec());
+        self
+    }
+
+    /// Set the object to load, it is parsed by [`BpfLoader::load`]
     pub fn elf(mut self, elf: &'data [u8]) -> Self {
-        self.elf_data =
-            Some(ElfBytes::<AnyEndian>::minimal_parse(elf).expect("The elf file is not valid"));
+        self.elf = Some(elf);
         self
     }
 
-    fn create_map(&mut self) -> Result<BTreeMap<SecIndex, MapFd>> {
-        let elf = self.elf_data.as_ref().unwrap();
-        let (section_headers, section_headers_name_table) =
-            elf.section_headers_with_strtab().unwrap();
-        let (section_headers, section_headers_name_table) = (
-            section_headers.unwrap(),
-            section_headers_name_table.unwrap(),
+    /// Set the BTF of the target kernel, e.g. the content of
+    /// `/sys/kernel/btf/vmlinux`, used to apply the CO-RE relocations
+    pub fn target_btf(mut self, btf: &'data [u8]) -> Self {
+        self.target_btf = Some(btf);
+        self
+    }
+
+    /// Only let programs with a GPL-compatible license call the helper `id`,
+    /// see [`license_is_gpl_compatible`]
+    pub fn gpl_only_helper(mut self, id: u32) -> Self {
+        self.gpl_only_helpers.insert(id);
+        self
+    }
+
+    /// The parsed object, only valid once [`BpfLoader::load`] has parsed it
+    fn elf_file(&self) -> &ElfBytes<'data, AnyEndian> {
+        self.elf_data
+            .as_ref()
+            .expect("the ELF file is parsed by load")
+    }
+
+    fn section_headers(
+        &self,
+    ) -> LoadResult<(SectionHeaderTable<'data, AnyEndian>, StringTable<'data>)> {
+        match self.elf_file().section_headers_with_strtab() {
+            Ok((Some(section_headers), Some(names))) => Ok((section_headers, names)),
+            Ok(_) => Err(LoadError::MissingSection(".shstrtab".to_string())),
+            Err(e) => Err(LoadError::InvalidElf(e.to_string())),
+        }
+    }
+
+    fn symbols(&self) -> LoadResult<(SymbolTable<'data, AnyEndian>, StringTable<'data>)> {
+        self.elf_file()
+            .symbol_table()
+            .map_err(|e| LoadError::InvalidElf(e.to_string()))?
+            .ok_or_else(|| LoadError::MissingSection(".symtab".to_string()))
+    }
+
+    fn section_name(names: &StringTable<'data>, section: &SectionHeader) -> LoadResult<&'data str> {
+        names.get(section.sh_name as usize).map_err(|_| {
+            LoadError::InvalidElf(format!("invalid section name offset {}", section.sh_name))
+        })
+    }
+
+    /// The name of a section for error messages
+    fn section_name_by_index(&self, section_index: SecIndex) -> String {
+        self.section_headers()
+            .ok()
+            .and_then(|(section_headers, names)| {
+                let section = section_headers.get(section_index).ok()?;
+                Self::section_name(&names, &section).ok()
+            })
+            .map_or_else(|| format!("#{}", section_index), |name| name.to_string())
+    }
+
+    fn symbol_name(strings: &StringTable<'data>, symbol: &Symbol) -> LoadResult<&'data str> {
+        strings.get(symbol.st_name as usize).map_err(|_| {
+            LoadError::InvalidElf(format!("invalid symbol name offset {}", symbol.st_name))
+        })
+    }
+
+    /// The data of an optional section, e.g. `license` or `version`
+    fn optional_section_data(&self, name: &str) -> LoadResult<Option<&'data [u8]>> {
+        match self.elf_file().section_header_by_name(name) {
+            Ok(Some(section)) => self.section_data(name, &section).map(Some),
+            Ok(None) => Ok(None),
+            Err(e) => Err(LoadError::InvalidElf(e.to_string())),
+        }
+    }
+
+    /// The content of the `license` section, up to its NUL
+    fn license(&self) -> LoadResult<Option<String>> {
+        let data = match self.optional_section_data("license")? {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+        let end = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+        core::str::from_utf8(&data[..end])
+            .map(|license| Some(license.to_string()))
+            .map_err(|_| LoadError::InvalidSection {
+                section: "license".to_string(),
+                reason: "license is not valid UTF-8",
+            })
+    }
+
+    /// The kernel version of the `version` section, used by old kprobe programs
+    fn kernel_version(&self) -> LoadResult<Option<u32>> {
+        let data = match self.optional_section_data("version")? {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+        let endian = self.elf_file().ehdr.endianness;
+        endian
+            .parse_u32_at(&mut 0, data)
+            .map(Some)
+            .map_err(|_| LoadError::InvalidSection {
+                section: "version".to_string(),
+                reason: "version is not a 32-bit integer",
+            })
+    }
+
+    /// Reject the calls to GPL-only helpers of a program without a
+    /// GPL-compatible license
+    fn check_helpers(&self, name: &str, prog: &[Insn], license: Option<&str>) -> LoadResult<()> {
+        if license.map_or(false, license_is_gpl_compatible) {
+            return Ok(());
+        }
+        let helper = prog.iter().find(|insn| {
+            insn.opc == ebpf::CALL
+                && insn.src == 0
+                && self.gpl_only_helpers.contains(&(insn.imm as u32))
+        });
+        match helper {
+            Some(insn) => Err(LoadError::GplOnlyHelper {
+                program: name.to_string(),
+                helper: insn.imm as u32,
+                license: license.unwrap_or_default().to_string(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    fn section_data(&self, name: &str, section: &SectionHeader) -> LoadResult<&'data [u8]> {
+        self.elf_file()
+            .section_data(section)
+            .map(|(data, _)| data)
+            .map_err(|_| LoadError::InvalidSection {
+                section: name.to_string(),
+                reason: "data is out of the file",
+            })
+    }
+
+    fn new_map<R: MapRegistry + ?Sized>(
+        registry: &mut R,
+        section_index: SecIndex,
+        offset: u64,
+        is_data: bool,
+        attr: BpfMapAttr,
+    ) -> LoadResult<LoadedMap> {
+        let fd = registry
+            .create_map(attr.clone())
+            .map_err(|error| LoadError::MapCreation {
+                map: attr.name.clone(),
+                error,
+            })?;
+        log::info!(
+            "create map {} with key size: {}, value size: {}, max entries: {}, map_fd: {}",
+            attr.name,
+            attr.key_size,
+            attr.value_size,
+            attr.max_entries,
+            fd
         );
-        let mut map_fds = BTreeMap::new();
+        Ok(LoadedMap {
+            section_index,
+            offset,
+            is_data,
+            fd,
+            attr,
+        })
+    }
+
+    /// Store the initial content of a global data map
+    fn init_map<R: MapRegistry + ?Sized>(
+        registry: &mut R,
+        map: &LoadedMap,
+        data: &[u8],
+        freeze: bool,
+    ) -> LoadResult<()> {
+        let key = [0; 4];
+        let map_error = |error| LoadError::MapCreation {
+            map: map.attr.name.clone(),
+            error,
+        };
+        registry
+            .update_map_element(map.fd, &key, data)
+            .map_err(map_error)?;
+        if freeze {
+            registry.freeze_map(map.fd).map_err(map_error)?;
+        }
+        Ok(())
+    }
+
+    fn create_map<R: MapRegistry + ?Sized>(&self, registry: &mut R) -> LoadResult<Vec<LoadedMap>> {
+        let (section_headers, section_headers_name_table) = self.section_headers()?;
+        let mut maps = Vec::new();
         for (idx, section) in section_headers.iter().enumerate() {
-            let name_index = section.sh_name;
-            let name = section_headers_name_table.get(name_index as usize).unwrap();
+            let name = Self::section_name(&section_headers_name_table, &section)?;
             let section_size = section.sh_size;
+            let is_data = [".bss", ".data", ".rodata"]
+                .iter()
+                .any(|prefix| name.starts_with(prefix));
+            if is_data && section_size == 0 {
+                // nothing in an empty section can be referenced, and a map needs a value
+                log::warn!("section {} is empty, skip it", name);
+                continue;
+            }
+            let value_size = || {
+                u32::try_from(section_size).map_err(|_| LoadError::InvalidSection {
+                    section: name.to_string(),
+                    reason: "section is too large for a map value",
+                })
+            };
             if name.starts_with(".bss") {
                 let map_attr = BpfMapAttr {
-                    map_type: 1,
+                    map_type: BPF_MAP_TYPE_ARRAY,
                     key_size: 4,
-                    value_size: section_size as u32,
+                    value_size: value_size()?,
                     max_entries: 1,
-                    map_flags: 0,
+                    map_flags: BPF_F_MMAPABLE,
                     name: name.to_string(),
+                    btf_key_type_id: 0,
+                    btf_value_type_id: self.datasec_type_id(name),
                 };
-                let map_fd = C::create_map(map_attr)?;
-                map_fds.insert(idx, map_fd);
-                log::info!(
-                    "create map for section: {} with size: {}, map_fd: {}",
-                    name,
-                    section_size,
-                    map_fd
-                );
+                maps.push(Self::new_map(registry, idx, 0, true, map_attr)?);
             } else if name.starts_with(".data") {
-                let section_size = section.sh_size;
                 let map_attr = BpfMapAttr {
-                    map_type: 2,
+                    map_type: BPF_MAP_TYPE_ARRAY,
                     key_size: 4,
-                    value_size: section_size as u32,
+                    value_size: value_size()?,
                     max_entries: 1,
-                    map_flags: 0,
+                    map_flags: BPF_F_MMAPABLE,
                     name: name.to_string(),
+                    btf_key_type_id: 0,
+                    btf_value_type_id: self.datasec_type_id(name),
                 };
-                let map_fd = C::create_map(map_attr)?;
-                map_fds.insert(idx, map_fd);
-                log::info!(
-                    "create map for section: {} with size: {}, map_fd: {}",
-                    name,
-                    section_size,
-                    map_fd
-                );
+                let map = Self::new_map(registry, idx, 0, true, map_attr)?;
                 log::info!("The section is .Data, we need update the map data");
-                let (data, _) = elf.section_data(&section).unwrap();
-                assert_eq!(data.len(), section_size as usize);
-                let key = [0; 4];
-                C::update_map_element(map_fd, &key, data)?;
+                let data = self.section_data(name, &section)?;
+                if data.len() != section_size as usize {
+                    return Err(LoadError::InvalidSection {
+                        section: name.to_string(),
+                        reason: "compressed data is not supported",
+                    });
+                }
+                Self::init_map(registry, &map, data, false)?;
+                maps.push(map);
+            } else if name.starts_with(".rodata") {
+                let map_attr = BpfMapAttr {
+                    map_type: BPF_MAP_TYPE_ARRAY,
+                    key_size: 4,
+                    value_size: value_size()?,
+                    max_entries: 1,
+                    map_flags: BPF_F_RDONLY_PROG | BPF_F_MMAPABLE,
+                    name: name.to_string(),
+                    btf_key_type_id: 0,
+                    btf_value_type_id: self.datasec_type_id(name),
+                };
+                let map = Self::new_map(registry, idx, 0, true, map_attr)?;
+                let data = self.rodata_data(idx, name, &section)?;
+                Self::init_map(registry, &map, &data, true)?;
+                maps.push(map);
+            } else if name == "maps" {
+                maps.extend(self.legacy_maps(registry, idx, &section)?);
+            } else if name == ".maps" {
+                maps.extend(self.btf_maps(registry, idx)?);
+            }
+        }
+        Ok(maps)
+    }
+
+    /// The content of a `.rodata` section with the values set by [`BpfLoader::rodata`]
+    fn rodata_data(
+        &self,
+        section_index: SecIndex,
+        section_name: &str,
+        section: &SectionHeader,
+    ) -> LoadResult<Vec<u8>> {
+        let mut data = self.section_data(section_name, section)?.to_vec();
+        let (symbol_table, string_table) = self.symbols()?;
+        for symbol in symbol_table
+            .iter()
+            .filter(|sym| sym.st_shndx as usize == section_index && sym.st_name != 0)
+        {
+            let name = Self::symbol_name(&string_table, &symbol)?;
+            if let Some(value) = self.rodata.get(name) {
+                if value.len() as u64 != symbol.st_size {
+                    return Err(LoadError::RodataSize {
+                        symbol: name.to_string(),
+                        size: symbol.st_size as usize,
+                        value_size: value.len(),
+                    });
+                }
+                let start = symbol.st_value as usize;
+                data.get_mut(start..start.saturating_add(value.len()))
+                    .ok_or_else(|| LoadError::InvalidSection {
+                        section: section_name.to_string(),
+                        reason: "symbol is out of the section",
+                    })?
+                    .copy_from_slice(value);
+                log::info!("set rodata {} to {:?}", name, value);
             }
         }
-        Ok(map_fds)
+        Ok(data)
     }
 
-    fn find_prog(&self) -> Result<Vec<Insn>> {
-        let elf = self.elf_data.as_ref().unwrap();
-        let text_section_name = self.text_section_name.as_ref().unwrap();
-        let text = elf
-            .section_header_by_name(text_section_name)
-            .expect("section table should be parseable")
-            .expect("file should have a xdp section");
-        let (prog_data, _) = elf.section_data(&text).unwrap();
-        let insn = to_insn_vec(prog_data);
-        Ok(insn)
+    /// The named variables of the global data sections
+    fn globals(&self, maps: &[LoadedMap]) -> LoadResult<BTreeMap<String, GlobalVar>> {
+        let (symbol_table, string_table) = self.symbols()?;
+        let mut globals = BTreeMap::new();
+        for symbol in symbol_table.iter() {
+            if symbol.st_symtype() != STT_OBJECT {
+                continue;
+            }
+            let map = match maps
+                .iter()
+                .find(|map| map.is_data && map.section_index == symbol.st_shndx as usize)
+            {
+                Some(map) => map,
+                None => continue,
+            };
+            let name = Self::symbol_name(&string_table, &symbol)?;
+            if name.is_empty() {
+                continue;
+            }
+            globals.insert(
+                name.to_string(),
+                GlobalVar {
+                    section: map.attr.name.clone(),
+                    map_fd: map.fd,
+                    offset: symbol.st_value as usize,
+                    size: symbol.st_size as usize,
+                },
+            );
+        }
+        Ok(globals)
     }
 
-    fn relocations(&self, section_name: &str) -> Result<Vec<Relocation>> {
-        let elf = self.elf_data.as_ref().unwrap();
-        let section = elf
-            .section_header_by_name(&section_name)
-            .expect("section table should be parseable")
-            .expect(&format!("file should have a {} section", section_name));
+    fn is_rodata_symbol(&self, name: &str) -> LoadResult<bool> {
+        let (section_headers, section_headers_name_table) = self.section_headers()?;
+        let (symbol_table, string_table) = self.symbols()?;
+        Ok(symbol_table.iter().any(|sym| {
+            string_table
+                .get(sym.st_name as usize)
+                .map_or(false, |sym| sym == name)
+                && section_headers
+                    .get(sym.st_shndx as usize)
+                    .ok()
+                    .and_then(|section| {
+                        Self::section_name(&section_headers_name_table, &section).ok()
+                    })
+                    .map_or(false, |section| section.starts_with(".rodata"))
+        }))
+    }
 
-        let relocation_section = elf
-            .section_data_as_rels(&section)
-            .expect("Failed to parse relocations");
+    /// Create the maps declared as `struct bpf_map_def` in the `maps` section
+    fn legacy_maps<R: MapRegistry + ?Sized>(
+        &self,
+        registry: &mut R,
+        section_index: SecIndex,
+        section: &SectionHeader,
+    ) -> LoadResult<Vec<LoadedMap>> {
+        let data = self.section_data("maps", section)?;
+        let (symbol_table, string_table) = self.symbols()?;
+        let symbols = symbol_table
+            .iter()
+            .filter(|sym| {
+                sym.st_shndx as usize == section_index
+                    && sym.st_name != 0
+                    && sym.st_symtype() != STT_SECTION
+            })
+            .collect::<Vec<_>>();
+        if symbols.is_empty() {
+            return Ok(Vec::new());
+        }
+        let invalid = |reason| LoadError::InvalidSection {
+            section: "maps".to_string(),
+            reason,
+        };
+        // all definitions in the section have the same size
+        let def_size = data.len() / symbols.len();
+        if def_size < BPF_MAP_DEF_SIZE {
+            return Err(invalid("map definitions are too small"));
+        }
+        let endian = self.elf_file().ehdr.endianness;
+        let mut maps = Vec::new();
+        for symbol in symbols {
+            let name = Self::symbol_name(&string_table, &symbol)?;
+            let mut offset = symbol.st_value as usize;
+            let mut field = || {
+                endian
+                    .parse_u32_at(&mut offset, data)
+                    .map_err(|_| invalid("map definition is out of the section"))
+            };
+            let map_attr = BpfMapAttr {
+                map_type: field()?,
+                key_size: field()?,
+                value_size: field()?,
+                max_entries: field()?,
+                map_flags: field()?,
+                name: name.to_string(),
+                btf_key_type_id: 0,
+                btf_value_type_id: 0,
+            };
+            maps.push(Self::new_map(
+                registry,
+                section_index,
+                symbol.st_value,
+                false,
+                map_attr,
+            )?);
+        }
+        Ok(maps)
+    }
 
-        let (section_headers, section_headers_name_table) =
-            elf.section_headers_with_strtab().unwrap();
-        let (section_headers, section_headers_name_table) = (
-            section_headers.unwrap(),
-            section_headers_name_table.unwrap(),
+    /// Parse `.BTF` and `.BTF.ext` if the object has them, the data sections are
+    /// completed with the ELF layout
+    fn load_btf(&mut self) -> LoadResult<()> {
+        let (section_headers, section_headers_name_table) = self.section_headers()?;
+        let (symbol_table, string_table) = self.symbols()?;
+        let mut sections = BTreeMap::new();
+        for (idx, section) in section_headers.iter().enumerate() {
+            let name = Self::section_name(&section_headers_name_table, &section)?;
+            sections.insert(name, (idx, section));
+        }
+        let mut btf = match sections.get(".BTF") {
+            Some((_, section)) => Btf::parse(self.section_data(".BTF", section)?)?,
+            None => return Ok(()),
+        };
+        btf.fixup_datasec(
+            |name| {
+                sections
+                    .get(name)
+                    .map(|(_, section)| section.sh_size as u32)
+            },
+            |section_name, var_name| {
+                let (idx, _) = sections.get(section_name)?;
+                symbol_table
+                    .iter()
+                    .find(|sym| {
+                        sym.st_shndx as usize == *idx
+                            && string_table
+                                .get(sym.st_name as usize)
+                                .map_or(false, |name| name == var_name)
+                    })
+                    .map(|sym| sym.st_value as u32)
+            },
         );
+        if let Some((_, section)) = sections.get(".BTF.ext") {
+            self.btf_ext = Some(BtfExt::parse(
+                self.section_data(".BTF.ext", section)?,
+                &btf,
+            )?);
+        }
+        self.btf = Some(btf);
+        Ok(())
+    }
+
+    /// The BTF type of a global data section, 0 if unknown
+    fn datasec_type_id(&self, name: &str) -> u32 {
+        self.btf
+            .as_ref()
+            .and_then(|btf| btf.type_by_name(name, BTF_KIND_DATASEC))
+            .map_or(0, |(id, _)| id)
+    }
+
+    /// The function and line info of a linked program, the instruction offsets
+    /// are moved from the sections to the program
+    fn program_ext_info(
+        &self,
+        linked: &[(Function, usize)],
+    ) -> (Vec<BtfFuncInfo>, Vec<BtfLineInfo>) {
+        let mut func_info = Vec::new();
+        let mut line_info = Vec::new();
+        let btf_ext = match &self.btf_ext {
+            Some(btf_ext) => btf_ext,
+            None => return (func_info, line_info),
+        };
+        for (function, base) in linked {
+            let section_name = self.section_name_by_index(function.section_index);
+            let range = function.start as u32..function.end as u32;
+            let rebase =
+                |insn_off: u32| insn_off - function.start as u32 + (base * INS_SIZE) as u32;
+            for info in btf_ext.func_info(&section_name) {
+                if range.contains(&info.insn_off) {
+                    func_info.push(BtfFuncInfo {
+                        insn_off: rebase(info.insn_off),
+                        ..*info
+                    });
+                }
+            }
+            for info in btf_ext.line_info(&section_name) {
+                if range.contains(&info.insn_off) {
+                    line_info.push(BtfLineInfo {
+                        insn_off: rebase(info.insn_off),
+                        ..info.clone()
+                    });
+                }
+            }
+        }
+        line_info.sort_by_key(|info| info.insn_off);
+        (func_info, line_info)
+    }
+
+    /// Apply the CO-RE relocations of the functions of a linked program against
+    /// the target BTF
+    fn relocate_core(
+        &self,
+        prog: &mut [Insn],
+        linked: &[(Function, usize)],
+        target: Option<&Btf>,
+    ) -> LoadResult<()> {
+        let (btf, btf_ext) = match (&self.btf, &self.btf_ext) {
+            (Some(btf), Some(btf_ext)) => (btf, btf_ext),
+            _ => return Ok(()),
+        };
+        for (function, base) in linked {
+            let section_name = self.section_name_by_index(function.section_index);
+            let range = function.start as u32..function.end as u32;
+            for relo in btf_ext.core_relos(&section_name) {
+                if !range.contains(&relo.insn_off) {
+                    continue;
+                }
+                let error = |reason| LoadError::CoreRelocation {
+                    section: section_name.clone(),
+                    insn_off: relo.insn_off,
+                    reason,
+                };
+                let target = target.ok_or_else(|| error("no target BTF"))?;
+                let value = core_relo::resolve(btf, target, relo)?;
+                let index = (relo.insn_off as usize - function.start) / INS_SIZE + base;
+                log::debug!(
+                    "relocate_core: {}+{} kind {} access {}: {:?}",
+                    section_name,
+                    relo.insn_off,
+                    relo.kind,
+                    relo.access,
+                    value
+                );
+                core_relo::patch_insn(prog, index, value).map_err(error)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Create the maps declared with BTF in the `.maps` section
+    fn btf_maps<R: MapRegistry + ?Sized>(
+        &self,
+        registry: &mut R,
+        section_index: SecIndex,
+    ) -> LoadResult<Vec<LoadedMap>> {
+        let btf = self
+            .btf
+            .as_ref()
+            .ok_or_else(|| LoadError::MissingSection(".BTF".to_string()))?;
+        if btf.type_by_name(".maps", BTF_KIND_DATASEC).is_none() {
+            return Err(LoadError::InvalidSection {
+                section: ".maps".to_string(),
+                reason: "no BTF description of the section",
+            });
+        }
+        let mut maps = Vec::new();
+        for var in btf.datasec_layout(".maps")? {
+            let map_attr = btf_map_attr(btf, &var.name, var.ty)?;
+            maps.push(Self::new_map(
+                registry,
+                section_index,
+                var.offset as u64,
+                false,
+                map_attr,
+            )?);
+        }
+        Ok(maps)
+    }
 
-        let (symbol_table, string_table) = elf.symbol_table().unwrap().unwrap();
+    /// The executable sections with their global functions, each global function
+    /// is a program. `.text` only holds the subprograms called by them.
+    fn program_symbols(&self) -> LoadResult<Vec<ProgramSection>> {
+        let (section_headers, section_headers_name_table) = self.section_headers()?;
+        let (symbol_table, string_table) = self.symbols()?;
+        let mut programs = Vec::new();
+        for (idx, section) in section_headers.iter().enumerate() {
+            let name = Self::section_name(&section_headers_name_table, &section)?;
+            if section.sh_flags & SHF_EXECINSTR as u64 == 0 || name == ".text" {
+                continue;
+            }
+            if let Some(text_section_name) = &self.text_section_name {
+                if name != text_section_name {
+                    continue;
+                }
+            }
+            let mut symbols = Vec::new();
+            for sym in symbol_table.iter().filter(|sym| {
+                sym.st_shndx as usize == idx
+                    && sym.st_symtype() == STT_FUNC
+                    && sym.st_bind() == STB_GLOBAL
+            }) {
+                symbols.push((
+                    Self::symbol_name(&string_table, &sym)?.to_string(),
+                    sym.st_value as usize,
+                    sym.st_size as usize,
+                ));
+            }
+            if symbols.is_empty() {
+                log::warn!("section {} has no global function, skip it", name);
+                continue;
+            }
+            programs.push((idx, name.to_string(), symbols));
+        }
+        if let Some(text_section_name) = &self.text_section_name {
+            if programs.is_empty() {
+                return Err(LoadError::MissingSection(text_section_name.clone()));
+            }
+        }
+        Ok(programs)
+    }
+
+    /// The relocations of the section `section_index`
+    fn relocations(&self, section_index: SecIndex) -> LoadResult<Vec<Relocation>> {
+        let (section_headers, section_headers_name_table) = self.section_headers()?;
+        let section = match section_headers
+            .iter()
+            .find(|section| section.sh_type == SHT_REL && section.sh_info as usize == section_index)
+        {
+            Some(section) => section,
+            None => return Ok(Vec::new()),
+        };
+        let relocation_section_name = Self::section_name(&section_headers_name_table, &section)?;
+
+        let relocation_section = self
+            .elf_file()
+            .section_data_as_rels(&section)
+            .map_err(|_| LoadError::InvalidSection {
+                section: relocation_section_name.to_string(),
+                reason: "relocations can not be parsed",
+            })?;
+
+        let (symbol_table, string_table) = self.symbols()?;
 
         let mut relocations = Vec::new();
-        relocation_section.for_each(|item| {
+        for item in relocation_section {
             log::info!("{:?}", item);
-            let symbol = symbol_table.get(item.r_sym as usize).unwrap();
-            let name = string_table.get(symbol.st_name as usize).unwrap();
+            let symbol =
+                symbol_table
+                    .get(item.r_sym as usize)
+                    .map_err(|_| LoadError::UnknownSymbol {
+                        section: relocation_section_name.to_string(),
+                        symbol: format!("#{}", item.r_sym),
+                    })?;
+            let name = Self::symbol_name(&string_table, &symbol)?;
             let section_index = symbol.st_shndx;
-            let section_header = section_headers.get(section_index as usize).unwrap();
-            let section_header_name_index = section_header.sh_name;
-            let section_header_name = section_headers_name_table
-                .get(section_header_name_index as usize)
-                .expect(
-                    format!(
-                        "section header name index {} is invalid",
-                        section_header_name_index
-                    )
-                    .as_str(),
-                );
+            let section_header = section_headers.get(section_index as usize).map_err(|_| {
+                LoadError::UnknownSymbol {
+                    section: relocation_section_name.to_string(),
+                    symbol: name.to_string(),
+                }
+            })?;
+            let section_header_name =
+                Self::section_name(&section_headers_name_table, &section_header)?;
             log::info!(
                 "name: {} -> [{}] {:?} ",
                 name,
@@ -185,74 +844,475 @@ impl<'data, C: CreateMapOps> BpfLoader<'data, C> {
                 section_index: section_index as usize,
                 section_name: section_header_name.to_string(),
                 section_addr: section_header.sh_addr,
+                symbol_value: symbol.st_value,
                 ty: item.r_type,
             };
             relocations.push(relocation);
-        });
+        }
         Ok(relocations)
     }
 
-    pub fn load(mut self) -> Result<Bpf> {
-        let bpf_map = self.create_map()?;
-        let mut prog = self.find_prog()?;
+    /// All functions of the executable sections, the candidates of BPF-to-BPF calls
+    fn functions(&self) -> LoadResult<Vec<Function>> {
+        let (section_headers, _) = self.section_headers()?;
+        let (symbol_table, _) = self.symbols()?;
+        Ok(symbol_table
+            .iter()
+            .filter(|sym| {
+                sym.st_symtype() == STT_FUNC
+                    && section_headers
+                        .get(sym.st_shndx as usize)
+                        .map_or(false, |section| {
+                            section.sh_flags & SHF_EXECINSTR as u64 != 0
+                        })
+            })
+            .map(|sym| Function {
+                section_index: sym.st_shndx as usize,
+                start: sym.st_value as usize,
+                end: sym.st_value.saturating_add(sym.st_size) as usize,
+            })
+            .collect())
+    }
 
-        let text_section_name = self.text_section_name.as_ref().unwrap();
-        let text_relocation_name = format!(".rel{}", text_section_name);
+    /// Build a program from its main function and append the subprograms it
+    /// calls, recursively. The relative offset of every `call` with
+    /// `BPF_PSEUDO_CALL` is fixed up to the position of its callee, either from
+    /// its [`R_BPF_64_32`](crate::relocation::R_BPF_64_32) relocation or, without relocation, relative to the
+    /// call in the same section.
+    ///
+    /// The offsets of the relocations of the map references are relative to the
+    /// linked program.
+    fn link_program(
+        &self,
+        main: Function,
+        functions: &[Function],
+        section_relocations: &BTreeMap<SecIndex, Vec<Relocation>>,
+    ) -> LoadResult<LinkedProgram> {
+        let (section_headers, _) = self.section_headers()?;
+        let function_insns = |function: &Function| -> LoadResult<Vec<Insn>> {
+            let section_name = self.section_name_by_index(function.section_index);
+            let section = section_headers
+                .get(function.section_index)
+                .map_err(|_| LoadError::MissingSection(section_name.clone()))?;
+            let invalid = |reason| LoadError::InvalidSection {
+                section: section_name.clone(),
+                reason,
+            };
+            // rbpf panics on a partial instruction, and calls and relocations
+            // are found by instruction index
+            if function.start % INS_SIZE != 0 || (function.end - function.start) % INS_SIZE != 0 {
+                return Err(invalid("function is not made of whole instructions"));
+            }
+            self.section_data(&section_name, &section)?
+                .get(function.start..function.end)
+                .map(to_insn_vec)
+                .ok_or_else(|| invalid("function is out of the section"))
+        };
 
-        let relocations = self.relocations(&text_relocation_name)?;
+        let mut prog = function_insns(&main)?;
+        // the functions in the program with the index of their first instruction
+        let mut linked = Vec::from([(main, 0)]);
+        let mut relocations = Vec::new();
+        let mut next = 0;
+        while next < linked.len() {
+            let (function, base) = linked[next];
+            next += 1;
+            let mut call_relocations = BTreeMap::new();
+            for relocation in section_relocations
+                .get(&function.section_index)
+                .into_iter()
+                .flatten()
+                .filter(|relocation| (function.start..function.end).contains(&relocation.offset))
+            {
+                let index = (relocation.offset - function.start) / INS_SIZE;
+                let invalid = |reason| LoadError::InvalidSection {
+                    section: self.section_name_by_index(function.section_index),
+                    reason,
+                };
+                match relocation.kind() {
+                    None if relocation.ty == R_BPF_NONE => {}
+                    Some(kind @ RelocationKind::Call) => {
+                        if !kind.matches_insn(&prog, base + index) {
+                            return Err(invalid("relocation does not target a BPF call"));
+                        }
+                        call_relocations.insert(index, relocation);
+                    }
+                    Some(kind @ RelocationKind::Load64) => {
+                        if !kind.matches_insn(&prog, base + index) {
+                            return Err(invalid("relocation does not target a 64-bit load"));
+                        }
+                        relocations.push(Relocation {
+                            offset: (base + index) * INS_SIZE,
+                            ..relocation.clone()
+                        });
+                    }
+                    _ => {
+                        return Err(LoadError::UnsupportedRelocation {
+                            section: self.section_name_by_index(function.section_index),
+                            ty: relocation.ty,
+                        })
+                    }
+                }
+            }
+            for index in 0..(function.end - function.start) / INS_SIZE {
+                let insn = &prog[base + index];
+                if insn.opc != ebpf::CALL || insn.src != BPF_PSEUDO_CALL as u8 {
+                    continue;
+                }
+                let next_insn = (insn.imm as i64 + 1) * INS_SIZE as i64;
+                let (section_index, offset, symbol) = match call_relocations.get(&index) {
+                    Some(relocation) => (
+                        relocation.section_index,
+                        relocation.symbol_value as i64 + next_insn,
+                        relocation.symbol.clone(),
+                    ),
+                    None => {
+                        let offset = (function.start + index * INS_SIZE) as i64 + next_insn;
+                        (function.section_index, offset, format!("{:#x}", offset))
+                    }
+                };
+                let callee = *functions
+                    .iter()
+                    .find(|f| {
+                        f.section_index == section_index
+                            && (f.start as i64..f.end as i64).contains(&offset)
+                    })
+                    .ok_or_else(|| LoadError::UnknownSymbol {
+                        section: self.section_name_by_index(section_index),
+                        symbol,
+                    })?;
+                let callee_base = match linked.iter().find(|(f, _)| *f == callee) {
+                    Some((_, callee_base)) => *callee_base,
+                    None => {
+                        let callee_base = prog.len();
+                        prog.extend(function_insns(&callee)?);
+                        linked.push((callee, callee_base));
+                        log::info!(
+                            "link subprogram at {:#x} of section {} to {}",
+                            callee.start,
+                            callee.section_index,
+                            callee_base
+                        );
+                        callee_base
+                    }
+                };
+                let target = callee_base + (offset as usize - callee.start) / INS_SIZE;
+                prog[base + index].imm = target as i32 - (base + index) as i32 - 1;
+            }
+        }
+        Ok(LinkedProgram {
+            insns: prog,
+            relocations,
+            functions: linked,
+        })
+    }
 
-        for relocation in &relocations {
+    /// The addresses stored in the global data sections, they are resolved by
+    /// the executor once the map values exist
+    fn data_relocations(
+        &self,
+        maps: &[LoadedMap],
+        functions: &[Function],
+    ) -> LoadResult<Vec<DataRelocation>> {
+        let mut data_relocations = Vec::new();
+        // global data maps are named after their section
+        for map in maps.iter().filter(|map| map.is_data) {
+            for relocation in self.relocations(map.section_index)? {
+                let kind = match relocation.kind() {
+                    None if relocation.ty == R_BPF_NONE => continue,
+                    Some(kind) if kind.data_size().is_some() => kind,
+                    _ => {
+                        return Err(LoadError::UnsupportedRelocation {
+                            section: map.attr.name.clone(),
+                            ty: relocation.ty,
+                        })
+                    }
+                };
+                let size = kind.data_size().unwrap_or_default();
+                if relocation.offset.saturating_add(size) > map.attr.value_size as usize {
+                    return Err(LoadError::InvalidSection {
+                        section: map.attr.name.clone(),
+                        reason: "relocation is out of the section",
+                    });
+                }
+                let target = if let Some(target) = maps.iter().find(|target| {
+                    target.is_data && target.section_index == relocation.section_index
+                }) {
+                    DataTarget::Map {
+                        map_fd: target.fd,
+                        offset: relocation.symbol_value,
+                    }
+                } else if functions
+                    .iter()
+                    .any(|f| f.section_index == relocation.section_index)
+                {
+                    DataTarget::Function {
+                        symbol: relocation.symbol.clone(),
+                        section_name: relocation.section_name.clone(),
+                        offset: relocation.symbol_value,
+                    }
+                } else {
+                    return Err(LoadError::UnknownSymbol {
+                        section: map.attr.name.clone(),
+                        symbol: relocation.symbol.clone(),
+                    });
+                };
+                data_relocations.push(DataRelocation {
+                    map_fd: map.fd,
+                    offset: relocation.offset,
+                    kind,
+                    target,
+                });
+            }
+        }
+        Ok(data_relocations)
+    }
+
+    /// Point the instructions loading a map to the created map, the relocations
+    /// have been checked to target an `ld_imm64`
+    fn relocate_maps(
+        &self,
+        prog: &mut [Insn],
+        relocations: &[Relocation],
+        maps: &[LoadedMap],
+    ) -> LoadResult<()> {
+        for relocation in relocations {
             let ins_index = relocation.offset / INS_SIZE;
 
-            let map_fd = bpf_map.get(&relocation.section_index).expect(
-                format!(
-                    "map not found for section index: {}",
-                    relocation.section_index
-                )
-                .as_str(),
-            );
-            let map_data_len = C::map_data_len(*map_fd);
+            let map = maps
+                .iter()
+                .find(|map| {
+                    map.section_index == relocation.section_index
+                        && (map.is_data || map.offset == relocation.symbol_value)
+                })
+                .ok_or_else(|| LoadError::UnknownSymbol {
+                    section: relocation.section_name.clone(),
+                    symbol: relocation.symbol.clone(),
+                })?;
 
-            let instructions = prog.as_mut_slice();
-            if map_data_len != 0 {
+            if map.is_data {
+                // the variable is at the symbol offset in its section, plus the
+                // addend clang leaves in the immediate for section symbols
+                let offset = prog[ins_index].imm as i64 + relocation.symbol_value as i64;
+                if !(0..map.attr.value_size as i64).contains(&offset) {
+                    return Err(LoadError::InvalidSection {
+                        section: relocation.section_name.clone(),
+                        reason: "variable is out of the section",
+                    });
+                }
                 log::error!(
-                    "relocate_maps: map data is not empty, set src_reg to BPF_PSEUDO_MAP_VALUE: {}",
+                    "relocate_maps: map is global data, set src_reg to BPF_PSEUDO_MAP_VALUE: {}",
                     BPF_PSEUDO_MAP_VALUE
                 );
                 log::error!(
-                    "relocate_maps: set next imm to sym.address:{} + ins.imm:{} = {}",
-                    relocation.section_addr,
-                    instructions[ins_index].imm,
-                    relocation.section_addr + instructions[ins_index].imm as u64
+                    "relocate_maps: set next imm to sym.value:{} + ins.imm:{} = {}",
+                    relocation.symbol_value,
+                    prog[ins_index].imm,
+                    offset
                 );
-                instructions[ins_index].set_src_reg(BPF_PSEUDO_MAP_VALUE as u8);
-                instructions[ins_index + 1].imm =
-                    instructions[ins_index].imm + relocation.section_addr as i32;
+                prog[ins_index].set_src_reg(BPF_PSEUDO_MAP_VALUE as u8);
+                prog[ins_index + 1].imm = offset as i32;
             } else {
                 log::error!(
-                    "relocate_maps: map data is empty, set src_reg to BPF_PSEUDO_MAP_FD: {}",
+                    "relocate_maps: map {} is defined, set src_reg to BPF_PSEUDO_MAP_FD: {}",
+                    map.attr.name,
                     BPF_PSEUDO_MAP_FD
                 );
-                instructions[ins_index].set_src_reg(BPF_PSEUDO_MAP_FD as u8);
+                prog[ins_index].set_src_reg(BPF_PSEUDO_MAP_FD as u8);
             }
-            log::error!("relocate_maps: set imm to fd: {}", map_fd);
-            instructions[ins_index].imm = *map_fd as i32;
+            log::error!("relocate_maps: set imm to fd: {}", map.fd);
+            prog[ins_index].imm = map.fd as i32;
         }
+        Ok(())
+    }
 
-        let prog = prog.iter().map(|ins| ins.to_vec()).flatten().collect();
+    /// Load every program of the object, all of them share the same maps which
+    /// are created in `registry`
+    pub fn load<R: MapRegistry + ?Sized>(mut self, registry: &mut R) -> LoadResult<Bpf> {
+        let elf = self
+            .elf
+            .ok_or_else(|| LoadError::InvalidElf("no ELF data".to_string()))?;
+        self.elf_data = Some(
+            ElfBytes::<AnyEndian>::minimal_parse(elf)
+                .map_err(|e| LoadError::InvalidElf(e.to_string()))?,
+        );
+        for name in self.rodata.keys() {
+            if !self.is_rodata_symbol(name)? {
+                return Err(LoadError::UnknownSymbol {
+                    section: ".rodata".to_string(),
+                    symbol: name.clone(),
+                });
+            }
+        }
+        let license = self.license()?;
+        let kernel_version = self.kernel_version()?;
+        self.load_btf()?;
+        let target_btf = self.target_btf.map(Btf::parse).transpose()?;
+        let mut registry = MapGuard::new(registry);
+        let maps = self.create_map(&mut registry)?;
+        let functions = self.functions()?;
+        let mut section_relocations = BTreeMap::new();
+        for function in &functions {
+            if let Entry::Vacant(entry) = section_relocations.entry(function.section_index) {
+                entry.insert(self.relocations(function.section_index)?);
+            }
+        }
 
+        let data_relocations = self.data_relocations(&maps, &functions)?;
+        let globals = self.globals(&maps)?;
+
+        let mut programs = Vec::new();
+        for (section_index, section_name, symbols) in self.program_symbols()? {
+            let (program_type, attach_target) = parse_section(&section_name)?;
+            for (name, start, size) in symbols {
+                let main = Function {
+                    section_index,
+                    start,
+                    end: start.saturating_add(size),
+                };
+                let LinkedProgram {
+                    insns: mut prog,
+                    relocations,
+                    functions: linked,
+                } = self.link_program(main, &functions, &section_relocations)?;
+                if prog.len() > ebpf::PROG_MAX_INSNS {
+                    return Err(LoadError::OversizeProgram {
+                        program: name,
+                        insns: prog.len(),
+                    });
+                }
+                self.check_helpers(&name, &prog, license.as_deref())?;
+                self.relocate_core(&mut prog, &linked, target_btf.as_ref())?;
+                self.relocate_maps(&mut prog, &relocations, &maps)?;
+                let (func_info, line_info) = self.program_ext_info(&linked);
+                let fd = registry
+                    .create_prog()
+                    .map_err(|error| LoadError::ProgramCreation {
+                        program: name.clone(),
+                        error,
+                    })?;
+                log::info!("load program {} in section {}", name, section_name);
+                programs.push(BpfProgram {
+                    fd,
+                    name,
+                    section_name: section_name.clone(),
+                    program_type,
+                    attach_target: attach_target.clone(),
+                    text: prog.iter().map(|ins| ins.to_vec()).flatten().collect(),
+                    relocation: relocations,
+                    func_info,
+                    line_info,
+                });
+            }
+        }
+        if programs.is_empty() {
+            return Err(LoadError::NoProgram);
+        }
+
+        registry.keep();
         Ok(Bpf {
-            text: prog,
-            bpf_map,
-            relocation: relocations,
+            programs,
+            maps,
+            data_relocations,
+            globals,
+            btf: self.btf,
+            license,
+            kernel_version,
         })
     }
 }
 
+/// Forwards to a [`MapRegistry`] and releases the maps created through it
+/// when dropped, so that a failing [`BpfLoader::load`] does not leak them
+struct MapGuard<'r, R: MapRegistry + ?Sized> {
+    registry: &'r mut R,
+    created: Vec<MapFd>,
+}
+
+impl<'r, R: MapRegistry + ?Sized> MapGuard<'r, R> {
+    fn new(registry: &'r mut R) -> Self {
+        Self {
+            registry,
+            created: Vec::new(),
+        }
+    }
+
+    /// The load succeeded, the maps are now owned by the [`Bpf`]
+    fn keep(mut self) {
+        self.created.clear();
+    }
+}
+
+impl<R: MapRegistry + ?Sized> MapRegistry for MapGuard<'_, R> {
+    fn create_map(&mut self, attr: BpfMapAttr) -> Result<MapFd> {
+        let fd = self.registry.create_map(attr)?;
+        self.created.push(fd);
+        Ok(fd)
+    }
+
+    fn update_map_element(&mut self, map_fd: MapFd, key: &[u8], value: &[u8]) -> Result<()> {
+        self.registry.update_map_element(map_fd, key, value)
+    }
+
+    fn freeze_map(&mut self, map_fd: MapFd) -> Result<()> {
+        self.registry.freeze_map(map_fd)
+    }
+
+    fn release_map(&mut self, map_fd: MapFd) -> Result<()> {
+        self.created.retain(|&fd| fd != map_fd);
+        self.registry.release_map(map_fd)
+    }
+
+    fn create_prog(&mut self) -> Result<ProgFd> {
+        self.registry.create_prog()
+    }
+
+    fn map_data_len(&self, map_fd: MapFd) -> usize {
+        self.registry.map_data_len(map_fd)
+    }
+
+    fn map_data_ptr(&mut self, map_fd: MapFd) -> *mut u8 {
+        self.registry.map_data_ptr(map_fd)
+    }
+}
+
+impl<R: MapRegistry + ?Sized> Drop for MapGuard<'_, R> {
+    fn drop(&mut self) {
+        for fd in self.created.drain(..).rev() {
+            if let Err(e) = self.registry.release_map(fd) {
+                log::warn!("failed to release map {}: {}", fd, e);
+            }
+        }
+    }
+}
+
 pub const BPF_PSEUDO_MAP_FD: u32 = 1;
 pub const BPF_PSEUDO_MAP_IDX: u32 = 5;
+/// The `call` targets a BPF function, its immediate is a relative offset
+pub const BPF_PSEUDO_CALL: u32 = 1;
 pub const BPF_PSEUDO_MAP_VALUE: u32 = 2;
 
+/// Each CPU of an LRU hash map has its own LRU list
+pub const BPF_F_NO_COMMON_LRU: u32 = 1 << 1;
+/// The map is read-only from the program side
+pub const BPF_F_RDONLY_PROG: u32 = 1 << 7;
+/// The map data can be mapped by the host, set for global data
+pub const BPF_F_MMAPABLE: u32 = 1 << 10;
+
+/// Whether a license lets a program call GPL-only helpers, the same list as
+/// `license_is_gpl_compatible` of Linux
+pub fn license_is_gpl_compatible(license: &str) -> bool {
+    matches!(
+        license,
+        "GPL"
+            | "GPL v2"
+            | "GPL and additional rights"
+            | "Dual BSD/GPL"
+            | "Dual MIT/GPL"
+            | "Dual MPL/GPL"
+    )
+}
+
 pub trait InsExt {
     fn set_src_reg(&mut self, src: u8);
 }
@@ -263,18 +1323,336 @@ impl InsExt for Insn {
     }
 }
 
+/// A program of the object, named after its function
 #[derive(Debug)]
-pub struct Bpf {
+pub struct BpfProgram {
+    fd: ProgFd,
+    name: String,
+    section_name: String,
+    program_type: ProgramType,
+    attach_target: AttachTarget,
     text: Vec<u8>,
-    bpf_map: BTreeMap<SecIndex, MapFd>,
     relocation: Vec<Relocation>,
+    /// Offsets in bytes from the start of the program
+    func_info: Vec<BtfFuncInfo>,
+    line_info: Vec<BtfLineInfo>,
 }
 
-impl Bpf {
+impl BpfProgram {
+    /// The handle of the program, unique in the registry it was loaded in
+    pub fn fd(&self) -> ProgFd {
+        self.fd
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn section_name(&self) -> &str {
+        &self.section_name
+    }
+    pub fn program_type(&self) -> ProgramType {
+        self.program_type
+    }
+    pub fn attach_target(&self) -> &AttachTarget {
+        &self.attach_target
+    }
     pub fn text(&self) -> &[u8] {
         &self.text
     }
     pub fn relocation(&self) -> &[Relocation] {
         &self.relocation
     }
+    /// The BTF functions of the program and their start
+    pub fn func_info(&self) -> &[BtfFuncInfo] {
+        &self.func_info
+    }
+    /// The source lines of the program, sorted by instruction offset
+    pub fn line_info(&self) -> &[BtfLineInfo] {
+        &self.line_info
+    }
+}
+
+#[derive(Debug)]
+pub struct Bpf {
+    programs: Vec<BpfProgram>,
+    maps: Vec<LoadedMap>,
+    data_relocations: Vec<DataRelocation>,
+    globals: BTreeMap<String, GlobalVar>,
+    btf: Option<Btf>,
+    license: Option<String>,
+    kernel_version: Option<u32>,
+}
+
+impl Bpf {
+    pub fn programs(&self) -> impl Iterator<Item = &BpfProgram> {
+        self.programs.iter()
+    }
+    /// The addresses in global data sections, see [`BpfExecutor::relocate_data`](crate::executor::BpfExecutor::relocate_data)
+    pub fn data_relocations(&self) -> &[DataRelocation] {
+        &self.data_relocations
+    }
+    /// The BTF of the object, with the layout of its data sections
+    pub fn btf(&self) -> Option<&Btf> {
+        self.btf.as_ref()
+    }
+    /// The content of the `license` section
+    pub fn license(&self) -> Option<&str> {
+        self.license.as_deref()
+    }
+    /// The content of the `version` section
+    pub fn kernel_version(&self) -> Option<u32> {
+        self.kernel_version
+    }
+    /// The attributes of a map, with the BTF types to format its keys and values
+    pub fn map_attr(&self, name: &str) -> Option<&BpfMapAttr> {
+        self.map_by_name(name).map(|map| &map.attr)
+    }
+    /// Release the maps of the object, they are freed once no other object
+    /// uses them
+    pub fn unload<R: MapRegistry + ?Sized>(self, registry: &mut R) -> Result<()> {
+        for map in &self.maps {
+            registry.release_map(map.fd)?;
+        }
+        Ok(())
+    }
+    /// The maps of the object, including those of the global data sections
+    pub fn maps(&self) -> impl Iterator<Item = &LoadedMap> {
+        self.maps.iter()
+    }
+    /// Find a map by name, `.bss` or `.data` for global data
+    pub fn map_by_name(&self, name: &str) -> Option<&LoadedMap> {
+        self.maps.iter().find(|map| map.attr.name == name)
+    }
+    /// Find a global variable by its symbol name
+    pub fn global(&self, name: &str) -> Option<&GlobalVar> {
+        self.globals.get(name)
+    }
+    /// The address of a global variable of `size` bytes in its map
+    fn global_ptr<R: MapRegistry + ?Sized>(
+        &self,
+        registry: &mut R,
+        name: &str,
+        size: usize,
+    ) -> Result<*mut u8> {
+        let global = self
+            .global(name)
+            .ok_or_else(|| anyhow!("global variable {} not found", name))?;
+        if global.size != size {
+            return Err(anyhow!(
+                "global variable {} has size {}, not {}",
+                name,
+                global.size,
+                size
+            ));
+        }
+        if global.offset + size > registry.map_data_len(global.map_fd) {
+            return Err(anyhow!("global variable {} is out of its map", name));
+        }
+        Ok(registry
+            .map_data_ptr(global.map_fd)
+            .wrapping_add(global.offset))
+    }
+    /// Read a global variable, `T` must be plain data such as an integer or a
+    /// `#[repr(C)]` struct of integers
+    pub fn read_global<R: MapRegistry + ?Sized, T: Copy>(
+        &self,
+        registry: &mut R,
+        name: &str,
+    ) -> Result<T> {
+        let ptr = self.global_ptr(registry, name, core::mem::size_of::<T>())?;
+        // SAFETY: the variable is in the map value and has the size of T
+        Ok(unsafe { (ptr as *const T).read_unaligned() })
+    }
+    /// Write a global variable of `.data` or `.bss`, `.rodata` is set with
+    /// [`BpfLoader::rodata`] before loading
+    pub fn write_global<R: MapRegistry + ?Sized, T: Copy>(
+        &self,
+        registry: &mut R,
+        name: &str,
+        value: T,
+    ) -> Result<()> {
+        if self
+            .global(name)
+            .map_or(false, |global| global.section.starts_with(".rodata"))
+        {
+            return Err(anyhow!("global variable {} is read-only", name));
+        }
+        let ptr = self.global_ptr(registry, name, core::mem::size_of::<T>())?;
+        // SAFETY: the variable is in the map value and has the size of T
+        unsafe { (ptr as *mut T).write_unaligned(value) };
+        Ok(())
+    }
+    /// Find a program by its function name
+    pub fn program(&self, name: &str) -> Option<&BpfProgram> {
+        self.programs.iter().find(|prog| prog.name == name)
+    }
+    /// The handle of a program, stored in program arrays
+    pub fn program_fd(&self, name: &str) -> Option<ProgFd> {
+        self.program(name).map(BpfProgram::fd)
+    }
+    /// Set `index` of the program array `map` to the program named `program`
+    /// of this object, the target of `bpf_tail_call(ctx, &map, index)`
+    pub fn update_prog_array<R: MapRegistry + ?Sized>(
+        &self,
+        registry: &mut R,
+        map: &str,
+        index: u32,
+        program: &str,
+    ) -> Result<()> {
+        let prog_array = self
+            .map_by_name(map)
+            .ok_or_else(|| anyhow!("no map named {}", map))?;
+        if prog_array.map_type() != BPF_MAP_TYPE_PROG_ARRAY {
+            return Err(anyhow!("map {} is not a program array", map));
+        }
+        let prog = self
+            .program_fd(program)
+            .ok_or_else(|| anyhow!("no program named {}", program))?;
+        registry.update_map_element(prog_array.fd(), &index.to_ne_bytes(), &prog.to_ne_bytes())
+    }
+    /// The programs in the section `section_name`
+    pub fn programs_in_section<'a>(
+        &'a self,
+        section_name: &'a str,
+    ) -> impl Iterator<Item = &'a BpfProgram> {
+        self.programs
+            .iter()
+            .filter(move |prog| prog.section_name == section_name)
+    }
+}
+
+/// Derive the map attributes from a BTF map definition such as
+///
+/// ```c
+/// struct {
+///     __uint(type, BPF_MAP_TYPE_HASH);
+///     __uint(max_entries, 1024);
+///     __type(key, u32);
+///     __type(value, u64);
+/// } counts SEC(".maps");
+/// ```
+///
+/// `__uint(name, val)` is encoded as a pointer to an array of `val` elements,
+/// `__type(name, val)` as a pointer to `val`.
+fn btf_map_attr(btf: &Btf, name: &str, ty: u32) -> crate::btf::BtfResult<BpfMapAttr> {
+    let mut attr = BpfMapAttr {
+        map_type: BPF_MAP_TYPE_UNSPEC,
+        key_size: 0,
+        value_size: 0,
+        max_entries: 0,
+        map_flags: 0,
+        name: name.to_string(),
+        btf_key_type_id: 0,
+        btf_value_type_id: 0,
+    };
+    let ty = btf.skip_mods_and_typedefs(ty)?;
+    let members = match btf.type_by_id(ty)? {
+        BtfType::Struct { members, .. } => members,
+        _ => return Ok(attr),
+    };
+    for member in members {
+        let pointee = match btf.type_by_id(member.ty)? {
+            BtfType::Ptr { ty } => *ty,
+            _ => {
+                log::warn!("map {}: member {} is not a pointer", name, member.name);
+                continue;
+            }
+        };
+        let uint = || match btf.type_by_id(pointee)? {
+            BtfType::Array { nelems, .. } => Ok(*nelems),
+            _ => Err(crate::btf::BtfError::InvalidTypeId(pointee)),
+        };
+        match member.name.as_str() {
+            "type" => attr.map_type = uint()?,
+            "max_entries" => attr.max_entries = uint()?,
+            "map_flags" => attr.map_flags = uint()?,
+            "key_size" => attr.key_size = uint()?,
+            "value_size" => attr.value_size = uint()?,
+            "key" => {
+                attr.key_size = btf.resolve_size(pointee)?;
+                attr.btf_key_type_id = pointee;
+            }
+            "value" => {
+                attr.value_size = btf.resolve_size(pointee)?;
+                attr.btf_value_type_id = pointee;
+            }
+            other => log::warn!("map {}: member {} is ignored", name, other),
+        }
+    }
+    Ok(attr)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{vec, vec::Vec};
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Registry {
+        live: Vec<MapFd>,
+        next: MapFd,
+    }
+
+    impl MapRegistry for Registry {
+        fn create_map(&mut self, _attr: BpfMapAttr) -> Result<MapFd> {
+            self.next += 1;
+            self.live.push(self.next);
+            Ok(self.next)
+        }
+
+        fn update_map_element(&mut self, _map_fd: MapFd, _key: &[u8], _value: &[u8]) -> Result<()> {
+            Ok(())
+        }
+
+        fn release_map(&mut self, map_fd: MapFd) -> Result<()> {
+            self.live.retain(|&fd| fd != map_fd);
+            Ok(())
+        }
+
+        fn create_prog(&mut self) -> Result<ProgFd> {
+            Err(anyhow!("not supported"))
+        }
+
+        fn map_data_len(&self, _map_fd: MapFd) -> usize {
+            0
+        }
+
+        fn map_data_ptr(&mut self, _map_fd: MapFd) -> *mut u8 {
+            core::ptr::null_mut()
+        }
+    }
+
+    fn attr() -> BpfMapAttr {
+        BpfMapAttr {
+            map_type: BPF_MAP_TYPE_ARRAY,
+            key_size: 4,
+            value_size: 4,
+            max_entries: 1,
+            map_flags: 0,
+            name: "map".to_string(),
+            btf_key_type_id: 0,
+            btf_value_type_id: 0,
+        }
+    }
+
+    #[test]
+    fn map_guard_releases_on_error() {
+        let mut registry = Registry::default();
+        {
+            let mut guard = MapGuard::new(&mut registry);
+            guard.create_map(attr()).unwrap();
+            guard.create_map(attr()).unwrap();
+        }
+        assert!(registry.live.is_empty());
+    }
+
+    #[test]
+    fn map_guard_keeps_loaded_maps() {
+        let mut registry = Registry::default();
+        let mut guard = MapGuard::new(&mut registry);
+        guard.create_map(attr()).unwrap();
+        guard.create_map(attr()).unwrap();
+        guard.keep();
+        assert_eq!(registry.live, vec![1, 2]);
+    }
 }
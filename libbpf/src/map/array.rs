@@ -0,0 +1,416 @@
+//! `BPF_MAP_TYPE_ARRAY` and `BPF_MAP_TYPE_PERCPU_ARRAY`, fixed arrays indexed by
+//! a `u32` key.
+//!
+//! The elements are allocated once, zeroed, and never move: programs keep
+//! pointers to them and the global data sections are arrays of one element
+//! whose address is embedded in the instructions. With `BPF_F_MMAPABLE` the
+//! buffer is page aligned and exposed to the host with [`BpfMapOps::data`].
+use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+
+use super::{BpfMapOps, CpuOps, MapError, MapResult, BPF_EXIST, BPF_NOEXIST};
+use crate::loader::{BpfMapAttr, BPF_F_MMAPABLE};
+
+pub(super) const PAGE_SIZE: usize = 4096;
+
+/// A zeroed buffer that never moves
+#[derive(Debug)]
+pub(super) struct Buffer {
+    ptr: *mut u8,
+    layout: Layout,
+}
+
+// SAFETY: the buffer is owned, accesses go through the map
+unsafe impl Send for Buffer {}
+// SAFETY: shared buffers are only accessed through raw pointers, whose users
+// order their accesses
+unsafe impl Sync for Buffer {}
+
+impl Buffer {
+    pub(super) fn new(size: usize, align: usize) -> MapResult<Self> {
+        let layout = Layout::from_size_align(size.max(1), align).map_err(|_| MapError::Invalid)?;
+        // SAFETY: the layout has a non-zero size
+        let ptr = unsafe { alloc_zeroed(layout) };
+        if ptr.is_null() {
+            return Err(MapError::NoMemory);
+        }
+        Ok(Buffer { ptr, layout })
+    }
+
+    pub(super) fn as_ptr(&self) -> *mut u8 {
+        self.ptr
+    }
+
+    pub(super) fn slice_mut(&mut self, offset: usize, len: usize) -> &mut [u8] {
+        assert!(offset + len <= self.layout.size());
+        // SAFETY: the range is in the buffer
+        unsafe { core::slice::from_raw_parts_mut(self.ptr.add(offset), len) }
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        // SAFETY: allocated in Buffer::new with this layout
+        unsafe { dealloc(self.ptr, self.layout) }
+    }
+}
+
+/// The index of a key, `None` if it is out of the array
+fn index(key: &[u8], max_entries: usize) -> MapResult<Option<usize>> {
+    let key: [u8; 4] = key.try_into().map_err(|_| MapError::Invalid)?;
+    let index = u32::from_ne_bytes(key) as usize;
+    Ok((index < max_entries).then_some(index))
+}
+
+/// Checks of the update flags, the elements of an array always exist
+fn check_update(flags: u64) -> MapResult<()> {
+    match flags {
+        BPF_NOEXIST => Err(MapError::Exists),
+        flags if flags > BPF_EXIST => Err(MapError::Invalid),
+        _ => Ok(()),
+    }
+}
+
+/// Iterate over the indices, an unknown key restarts from the first one
+fn next_index(key: Option<&[u8]>, max_entries: usize, next_key: &mut [u8]) -> MapResult<()> {
+    let next = match key
+        .map(|key| index(key, max_entries))
+        .transpose()?
+        .flatten()
+    {
+        Some(index) => index + 1,
+        None => 0,
+    };
+    if next >= max_entries {
+        return Err(MapError::NotFound);
+    }
+    if next_key.len() != 4 {
+        return Err(MapError::Invalid);
+    }
+    next_key.copy_from_slice(&(next as u32).to_ne_bytes());
+    Ok(())
+}
+
+#[derive(Debug)]
+pub struct ArrayMap {
+    value_size: usize,
+    /// Distance between two elements, a multiple of 8 bytes
+    value_stride: usize,
+    max_entries: usize,
+    mmapable: bool,
+    buffer: Buffer,
+}
+
+impl ArrayMap {
+    pub fn new(attr: &BpfMapAttr) -> MapResult<Self> {
+        if attr.key_size != 4 {
+            return Err(MapError::Invalid);
+        }
+        let value_size = attr.value_size as usize;
+        let value_stride = (value_size + 7) & !7;
+        let max_entries = attr.max_entries as usize;
+        let size = value_stride
+            .checked_mul(max_entries)
+            .ok_or(MapError::TooBig)?;
+        let mmapable = attr.map_flags & BPF_F_MMAPABLE != 0;
+        let buffer = if mmapable {
+            Buffer::new((size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1), PAGE_SIZE)?
+        } else {
+            Buffer::new(size, 8)?
+        };
+        Ok(ArrayMap {
+            value_size,
+            value_stride,
+            max_entries,
+            mmapable,
+            buffer,
+        })
+    }
+}
+
+impl BpfMapOps for ArrayMap {
+    fn lookup_elem(&mut self, key: &[u8]) -> MapResult<Option<&mut [u8]>> {
+        Ok(match index(key, self.max_entries)? {
+            Some(index) => Some(
+                self.buffer
+                    .slice_mut(index * self.value_stride, self.value_size),
+            ),
+            None => None,
+        })
+    }
+
+    fn update_elem(&mut self, key: &[u8], value: &[u8], flags: u64) -> MapResult<()> {
+        check_update(flags)?;
+        if value.len() != self.value_size {
+            return Err(MapError::Invalid);
+        }
+        let index = index(key, self.max_entries)?.ok_or(MapError::TooBig)?;
+        self.buffer
+            .slice_mut(index * self.value_stride, self.value_size)
+            .copy_from_slice(value);
+        Ok(())
+    }
+
+    fn delete_elem(&mut self, _key: &[u8]) -> MapResult<()> {
+        Err(MapError::Invalid)
+    }
+
+    fn get_next_key(&self, key: Option<&[u8]>, next_key: &mut [u8]) -> MapResult<()> {
+        next_index(key, self.max_entries, next_key)
+    }
+
+    fn data(&mut self) -> Option<&mut [u8]> {
+        if !self.mmapable {
+            return None;
+        }
+        let len = self.value_stride * self.max_entries;
+        Some(self.buffer.slice_mut(0, len))
+    }
+}
+
+/// An array with one element per CPU for each index. Programs see the element
+/// of the CPU they run on, the host sees the elements of all CPUs one after
+/// the other, each rounded up to 8 bytes.
+#[derive(Debug)]
+pub struct PerCpuArrayMap {
+    value_size: usize,
+    value_stride: usize,
+    max_entries: usize,
+    cpus: &'static dyn CpuOps,
+    num_cpus: usize,
+    buffer: Buffer,
+}
+
+impl PerCpuArrayMap {
+    pub fn new(attr: &BpfMapAttr, cpus: &'static dyn CpuOps) -> MapResult<Self> {
+        if attr.key_size != 4 || attr.map_flags & BPF_F_MMAPABLE != 0 {
+            return Err(MapError::Invalid);
+        }
+        let value_size = attr.value_size as usize;
+        let value_stride = (value_size + 7) & !7;
+        let max_entries = attr.max_entries as usize;
+        let num_cpus = cpus.num_cpus().max(1);
+        let size = value_stride
+            .checked_mul(max_entries)
+            .and_then(|size| size.checked_mul(num_cpus))
+            .ok_or(MapError::TooBig)?;
+        Ok(PerCpuArrayMap {
+            value_size,
+            value_stride,
+            max_entries,
+            cpus,
+            num_cpus,
+            buffer: Buffer::new(size, 8)?,
+        })
+    }
+
+    /// Size of the values exchanged with the host
+    fn host_value_size(&self) -> usize {
+        self.value_stride * self.num_cpus
+    }
+
+    /// The values of all CPUs for an index
+    fn values_mut(&mut self, index: usize) -> &mut [u8] {
+        let len = self.value_stride * self.num_cpus;
+        self.buffer.slice_mut(index * len, len)
+    }
+}
+
+impl BpfMapOps for PerCpuArrayMap {
+    fn lookup_elem(&mut self, key: &[u8]) -> MapResult<Option<&mut [u8]>> {
+        let cpu = self.cpus.current_cpu();
+        if cpu >= self.num_cpus {
+            return Err(MapError::Invalid);
+        }
+        let (stride, size) = (self.value_stride, self.value_size);
+        Ok(match index(key, self.max_entries)? {
+            Some(index) => Some(&mut self.values_mut(index)[cpu * stride..cpu * stride + size]),
+            None => None,
+        })
+    }
+
+    fn update_elem(&mut self, key: &[u8], value: &[u8], flags: u64) -> MapResult<()> {
+        check_update(flags)?;
+        if value.len() != self.value_size {
+            return Err(MapError::Invalid);
+        }
+        let value_slot = self.lookup_elem(key)?.ok_or(MapError::TooBig)?;
+        value_slot.copy_from_slice(value);
+        Ok(())
+    }
+
+    fn delete_elem(&mut self, _key: &[u8]) -> MapResult<()> {
+        Err(MapError::Invalid)
+    }
+
+    fn get_next_key(&self, key: Option<&[u8]>, next_key: &mut [u8]) -> MapResult<()> {
+        next_index(key, self.max_entries, next_key)
+    }
+
+    fn lookup_elem_host(&mut self, key: &[u8], value: &mut [u8]) -> MapResult<()> {
+        if value.len() != self.host_value_size() {
+            return Err(MapError::Invalid);
+        }
+        let index = index(key, self.max_entries)?.ok_or(MapError::NotFound)?;
+        value.copy_from_slice(self.values_mut(index));
+        Ok(())
+    }
+
+    fn update_elem_host(&mut self, key: &[u8], value: &[u8], flags: u64) -> MapResult<()> {
+        check_update(flags)?;
+        if value.len() != self.host_value_size() {
+            return Err(MapError::Invalid);
+        }
+        let index = index(key, self.max_entries)?.ok_or(MapError::TooBig)?;
+        self.values_mut(index).copy_from_slice(value);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::map::{BPF_ANY, BPF_MAP_TYPE_ARRAY, BPF_MAP_TYPE_PERCPU_ARRAY};
+
+    /// Two CPUs, the per-CPU test switches the current one
+    struct TwoCpus(AtomicUsize);
+
+    impl CpuOps for TwoCpus {
+        fn num_cpus(&self) -> usize {
+            2
+        }
+        fn current_cpu(&self) -> usize {
+            self.0.load(Ordering::Relaxed)
+        }
+    }
+
+    static CPUS: TwoCpus = TwoCpus(AtomicUsize::new(0));
+
+    fn attr(map_type: u32, value_size: u32, max_entries: u32, map_flags: u32) -> BpfMapAttr {
+        BpfMapAttr {
+            map_type,
+            key_size: 4,
+            value_size,
+            max_entries,
+            map_flags,
+            name: "array".to_string(),
+            btf_key_type_id: 0,
+            btf_value_type_id: 0,
+        }
+    }
+
+    fn key(index: u32) -> [u8; 4] {
+        index.to_ne_bytes()
+    }
+
+    #[test]
+    fn array() {
+        let mut map = ArrayMap::new(&attr(BPF_MAP_TYPE_ARRAY, 4, 2, 0)).unwrap();
+        let value = map.lookup_elem(&key(1)).unwrap().unwrap();
+        assert_eq!(value, [0; 4]);
+        let ptr = value.as_ptr();
+        map.update_elem(&key(1), &7u32.to_ne_bytes(), BPF_ANY)
+            .unwrap();
+        map.update_elem(&key(1), &8u32.to_ne_bytes(), BPF_EXIST)
+            .unwrap();
+        // programs keep the address of the value
+        let value = map.lookup_elem(&key(1)).unwrap().unwrap();
+        assert_eq!(value.as_ptr(), ptr);
+        assert_eq!(value, 8u32.to_ne_bytes());
+        assert_eq!(map.lookup_elem(&key(0)).unwrap().unwrap(), [0; 4]);
+        assert!(map.data().is_none());
+    }
+
+    #[test]
+    fn array_errors() {
+        let mut map = ArrayMap::new(&attr(BPF_MAP_TYPE_ARRAY, 4, 2, 0)).unwrap();
+        assert!(map.lookup_elem(&key(2)).unwrap().is_none());
+        assert!(map.lookup_elem(&[0; 8]).is_err());
+        let value = 1u32.to_ne_bytes();
+        assert_eq!(
+            map.update_elem(&key(2), &value, BPF_ANY),
+            Err(MapError::TooBig)
+        );
+        assert_eq!(
+            map.update_elem(&key(0), &value, BPF_NOEXIST),
+            Err(MapError::Exists)
+        );
+        assert_eq!(
+            map.update_elem(&key(0), &value, BPF_EXIST + 1),
+            Err(MapError::Invalid)
+        );
+        assert_eq!(
+            map.update_elem(&key(0), &[0; 8], BPF_ANY),
+            Err(MapError::Invalid)
+        );
+        assert_eq!(map.delete_elem(&key(0)), Err(MapError::Invalid));
+        let mut next = [0; 4];
+        map.get_next_key(Some(&key(0)), &mut next).unwrap();
+        assert_eq!(next, key(1));
+        assert_eq!(
+            map.get_next_key(Some(&key(1)), &mut next),
+            Err(MapError::NotFound)
+        );
+        assert!(ArrayMap::new(&BpfMapAttr {
+            key_size: 8,
+            ..attr(BPF_MAP_TYPE_ARRAY, 4, 2, 0)
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn mmapable_array() {
+        let mut map = ArrayMap::new(&attr(BPF_MAP_TYPE_ARRAY, 12, 3, BPF_F_MMAPABLE)).unwrap();
+        map.update_elem(&key(1), &[1; 12], BPF_ANY).unwrap();
+        let data = map.data().unwrap();
+        assert_eq!(data.as_ptr() as usize % PAGE_SIZE, 0);
+        // the values are 8 byte aligned
+        assert_eq!(data.len(), 48);
+        assert_eq!(data[16..28], [1; 12]);
+        assert_eq!(data[28..32], [0; 4]);
+    }
+
+    #[test]
+    fn percpu_array() {
+        let mut map =
+            PerCpuArrayMap::new(&attr(BPF_MAP_TYPE_PERCPU_ARRAY, 4, 2, 0), &CPUS).unwrap();
+        CPUS.0.store(1, Ordering::Relaxed);
+        map.update_elem(&key(0), &7u32.to_ne_bytes(), BPF_ANY)
+            .unwrap();
+        assert_eq!(
+            map.lookup_elem(&key(0)).unwrap().unwrap(),
+            7u32.to_ne_bytes()
+        );
+        CPUS.0.store(0, Ordering::Relaxed);
+        assert_eq!(map.lookup_elem(&key(0)).unwrap().unwrap(), [0; 4]);
+
+        // the host sees the value of each CPU rounded up to 8 bytes
+        let mut values = [0; 16];
+        map.lookup_elem_host(&key(0), &mut values).unwrap();
+        assert_eq!(values[..4], [0; 4]);
+        assert_eq!(values[8..12], 7u32.to_ne_bytes());
+        let values = [[1, 0, 0, 0, 0, 0, 0, 0], [2, 0, 0, 0, 0, 0, 0, 0]].concat();
+        map.update_elem_host(&key(1), &values, BPF_ANY).unwrap();
+        assert_eq!(
+            map.lookup_elem(&key(1)).unwrap().unwrap(),
+            1u32.to_ne_bytes()
+        );
+
+        assert_eq!(
+            map.lookup_elem_host(&key(2), &mut [0; 16]),
+            Err(MapError::NotFound)
+        );
+        assert_eq!(
+            map.lookup_elem_host(&key(0), &mut [0; 8]),
+            Err(MapError::Invalid)
+        );
+        assert_eq!(map.delete_elem(&key(0)), Err(MapError::Invalid));
+        assert!(PerCpuArrayMap::new(
+            &attr(BPF_MAP_TYPE_PERCPU_ARRAY, 4, 2, BPF_F_MMAPABLE),
+            &CPUS
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,299 @@
+//! `BPF_MAP_TYPE_HASH`, a hash table preallocated for `max_entries` elements.
+//!
+//! Like the preallocated hash maps of Linux, keys and values live in fixed
+//! buffers so the address of a value never changes while its element exists,
+//! and the chains are indices into these buffers so that updates never
+//! allocate.
+use alloc::vec::Vec;
+
+use super::{alloc_zeroed, BpfMapOps, MapError, MapResult, BPF_EXIST, BPF_NOEXIST};
+use crate::loader::BpfMapAttr;
+
+/// End of a chain
+const NONE: u32 = u32::MAX;
+
+/// FNV-1a, the keys come from programs and hosts of the same system
+fn hash(key: &[u8]) -> u64 {
+    key.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+#[derive(Debug)]
+pub struct HashMap {
+    key_size: usize,
+    value_size: usize,
+    /// Distance between two values in `values`, a multiple of 8 bytes
+    value_stride: usize,
+    keys: Vec<u8>,
+    values: Vec<u64>,
+    /// The first element of each bucket
+    buckets: Vec<u32>,
+    /// The next element in the bucket or in the free list
+    next: Vec<u32>,
+    free: u32,
+    len: usize,
+}
+
+impl HashMap {
+    pub fn new(attr: &BpfMapAttr) -> MapResult<Self> {
+        let max_entries = attr.max_entries as usize;
+        let key_size = attr.key_size as usize;
+        let value_size = attr.value_size as usize;
+        let value_stride = (value_size + 7) & !7;
+        let mut next = alloc_zeroed::<u32>(max_entries)?;
+        // every element starts in the free list
+        for (index, next) in next.iter_mut().enumerate() {
+            *next = if index + 1 < max_entries {
+                index as u32 + 1
+            } else {
+                NONE
+            };
+        }
+        let buckets = max_entries.next_power_of_two();
+        let mut heads = alloc_zeroed::<u32>(buckets)?;
+        heads.fill(NONE);
+        Ok(HashMap {
+            key_size,
+            value_size,
+            value_stride,
+            keys: alloc_zeroed(max_entries * key_size)?,
+            values: alloc_zeroed(max_entries * value_stride / 8)?,
+            buckets: heads,
+            next,
+            free: 0,
+            len: 0,
+        })
+    }
+
+    /// The number of elements
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub(super) fn value_size(&self) -> usize {
+        self.value_size
+    }
+
+    fn bucket(&self, key: &[u8]) -> usize {
+        hash(key) as usize & (self.buckets.len() - 1)
+    }
+
+    pub(super) fn key(&self, index: u32) -> &[u8] {
+        let start = index as usize * self.key_size;
+        &self.keys[start..start + self.key_size]
+    }
+
+    pub(super) fn value_mut(&mut self, index: u32) -> &mut [u8] {
+        let start = index as usize * self.value_stride / 8;
+        let words = &mut self.values[start..start + self.value_stride / 8];
+        // SAFETY: the words of an element are at least value_size bytes
+        unsafe { core::slice::from_raw_parts_mut(words.as_mut_ptr() as *mut u8, self.value_size) }
+    }
+
+    /// The element of `key` and the one before it in its bucket
+    pub(super) fn find(&self, key: &[u8]) -> Option<(u32, u32)> {
+        let mut prev = NONE;
+        let mut index = self.buckets[self.bucket(key)];
+        while index != NONE {
+            if self.key(index) == key {
+                return Some((index, prev));
+            }
+            prev = index;
+            index = self.next[index as usize];
+        }
+        None
+    }
+
+    pub(super) fn check_key(&self, key: &[u8]) -> MapResult<()> {
+        if key.len() != self.key_size {
+            return Err(MapError::Invalid);
+        }
+        Ok(())
+    }
+
+    /// Take an element from the free list and link it in the bucket of `key`
+    pub(super) fn insert(&mut self, key: &[u8]) -> MapResult<u32> {
+        let index = self.free;
+        if index == NONE {
+            return Err(MapError::TooBig);
+        }
+        self.free = self.next[index as usize];
+        let start = index as usize * self.key_size;
+        self.keys[start..start + self.key_size].copy_from_slice(key);
+        let bucket = self.bucket(key);
+        self.next[index as usize] = self.buckets[bucket];
+        self.buckets[bucket] = index;
+        self.len += 1;
+        Ok(index)
+    }
+
+    /// Unlink an element from its bucket and give it back to the free list
+    pub(super) fn remove(&mut self, index: u32, prev: u32) {
+        let next = self.next[index as usize];
+        if prev == NONE {
+            let bucket = self.bucket(self.key(index));
+            self.buckets[bucket] = next;
+        } else {
+            self.next[prev as usize] = next;
+        }
+        self.next[index as usize] = self.free;
+        self.free = index;
+        self.len -= 1;
+    }
+}
+
+impl BpfMapOps for HashMap {
+    fn lookup_elem(&mut self, key: &[u8]) -> MapResult<Option<&mut [u8]>> {
+        self.check_key(key)?;
+        Ok(match self.find(key) {
+            Some((index, _)) => Some(self.value_mut(index)),
+            None => None,
+        })
+    }
+
+    fn update_elem(&mut self, key: &[u8], value: &[u8], flags: u64) -> MapResult<()> {
+        self.check_key(key)?;
+        if value.len() != self.value_size || flags > BPF_EXIST {
+            return Err(MapError::Invalid);
+        }
+        let index = match (self.find(key), flags) {
+            (Some(_), BPF_NOEXIST) => return Err(MapError::Exists),
+            (None, BPF_EXIST) => return Err(MapError::NotFound),
+            (Some((index, _)), _) => index,
+            (None, _) => self.insert(key)?,
+        };
+        self.value_mut(index).copy_from_slice(value);
+        Ok(())
+    }
+
+    fn delete_elem(&mut self, key: &[u8]) -> MapResult<()> {
+        self.check_key(key)?;
+        let (index, prev) = self.find(key).ok_or(MapError::NotFound)?;
+        self.remove(index, prev);
+        Ok(())
+    }
+
+    /// Walk the buckets in order, an unknown key restarts from the first one
+    fn get_next_key(&self, key: Option<&[u8]>, next_key: &mut [u8]) -> MapResult<()> {
+        if next_key.len() != self.key_size {
+            return Err(MapError::Invalid);
+        }
+        let (mut index, mut bucket) = match key.and_then(|key| self.find(key).map(|f| (key, f))) {
+            Some((key, (index, _))) => (self.next[index as usize], self.bucket(key) + 1),
+            None => (NONE, 0),
+        };
+        while index == NONE && bucket < self.buckets.len() {
+            index = self.buckets[bucket];
+            bucket += 1;
+        }
+        if index == NONE {
+            return Err(MapError::NotFound);
+        }
+        next_key.copy_from_slice(self.key(index));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{string::ToString, vec::Vec};
+
+    use super::*;
+    use crate::map::{BPF_ANY, BPF_MAP_TYPE_HASH};
+
+    fn map(max_entries: u32) -> HashMap {
+        HashMap::new(&BpfMapAttr {
+            map_type: BPF_MAP_TYPE_HASH,
+            key_size: 4,
+            value_size: 4,
+            max_entries,
+            map_flags: 0,
+            name: "hash".to_string(),
+            btf_key_type_id: 0,
+            btf_value_type_id: 0,
+        })
+        .unwrap()
+    }
+
+    fn lookup(map: &mut HashMap, key: u32) -> Option<u32> {
+        let value = map.lookup_elem(&key.to_ne_bytes()).unwrap()?;
+        Some(u32::from_ne_bytes(value.try_into().unwrap()))
+    }
+
+    fn update(map: &mut HashMap, key: u32, value: u32, flags: u64) -> MapResult<()> {
+        map.update_elem(&key.to_ne_bytes(), &value.to_ne_bytes(), flags)
+    }
+
+    #[test]
+    fn update_flags() {
+        let mut map = map(4);
+        assert_eq!(update(&mut map, 1, 10, BPF_EXIST), Err(MapError::NotFound));
+        update(&mut map, 1, 10, BPF_NOEXIST).unwrap();
+        assert_eq!(update(&mut map, 1, 11, BPF_NOEXIST), Err(MapError::Exists));
+        update(&mut map, 1, 12, BPF_EXIST).unwrap();
+        update(&mut map, 2, 20, BPF_ANY).unwrap();
+        assert_eq!(
+            update(&mut map, 3, 30, BPF_EXIST + 1),
+            Err(MapError::Invalid)
+        );
+        assert_eq!(lookup(&mut map, 1), Some(12));
+        assert_eq!(lookup(&mut map, 2), Some(20));
+        assert_eq!(lookup(&mut map, 3), None);
+        assert_eq!(map.lookup_elem(&[0; 3]), Err(MapError::Invalid));
+        assert_eq!(map.len(), 2);
+    }
+
+    #[test]
+    fn capacity() {
+        let mut map = map(2);
+        update(&mut map, 1, 10, BPF_ANY).unwrap();
+        update(&mut map, 2, 20, BPF_ANY).unwrap();
+        assert_eq!(update(&mut map, 3, 30, BPF_ANY), Err(MapError::TooBig));
+        // updating an existing element does not need room
+        update(&mut map, 2, 21, BPF_ANY).unwrap();
+        let address = map
+            .lookup_elem(&1u32.to_ne_bytes())
+            .unwrap()
+            .unwrap()
+            .as_ptr();
+        map.delete_elem(&2u32.to_ne_bytes()).unwrap();
+        assert_eq!(
+            map.delete_elem(&2u32.to_ne_bytes()),
+            Err(MapError::NotFound)
+        );
+        update(&mut map, 3, 30, BPF_ANY).unwrap();
+        // values do not move while their element exists
+        let value = map.lookup_elem(&1u32.to_ne_bytes()).unwrap().unwrap();
+        assert_eq!(value.as_ptr(), address);
+        assert_eq!(lookup(&mut map, 3), Some(30));
+    }
+
+    #[test]
+    fn iterate() {
+        let mut map = map(16);
+        for key in 0..10 {
+            update(&mut map, key, key, BPF_ANY).unwrap();
+        }
+        let mut keys = Vec::new();
+        let mut key = None;
+        let mut next = [0; 4];
+        while map
+            .get_next_key(key.as_ref().map(|k: &[u8; 4]| &k[..]), &mut next)
+            .is_ok()
+        {
+            keys.push(u32::from_ne_bytes(next));
+            key = Some(next);
+        }
+        keys.sort();
+        assert_eq!(keys, (0..10).collect::<Vec<_>>());
+        // an unknown key restarts from the first one
+        assert!(map
+            .get_next_key(Some(&99u32.to_ne_bytes()), &mut next)
+            .is_ok());
+    }
+}
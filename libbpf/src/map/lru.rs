@@ -0,0 +1,379 @@
+//! `BPF_MAP_TYPE_LRU_HASH` and `BPF_MAP_TYPE_LRU_PERCPU_HASH`, hash maps which
+//! evict their least recently used element when they are full.
+//!
+//! The elements are those of a preallocated [`HashMap`], linked in a list
+//! ordered by their last use: updates and the lookups of the programs move an
+//! element to the front, the host lookups leave the order unchanged like in
+//! Linux. With `BPF_F_NO_COMMON_LRU` each CPU owns a share of the elements and
+//! evicts from its own list, otherwise all CPUs share one list.
+use alloc::vec::Vec;
+
+use super::{
+    alloc_zeroed, BpfMapOps, CpuOps, HashMap, MapError, MapResult, BPF_EXIST,
+    BPF_MAP_TYPE_LRU_PERCPU_HASH, BPF_NOEXIST,
+};
+use crate::loader::{BpfMapAttr, BPF_F_NO_COMMON_LRU};
+
+/// End of a list
+const NONE: u32 = u32::MAX;
+
+#[derive(Debug, Clone)]
+struct LruList {
+    /// The most recently used element
+    head: u32,
+    /// The element evicted next
+    tail: u32,
+    len: usize,
+    capacity: usize,
+}
+
+#[derive(Debug)]
+pub struct LruHashMap {
+    table: HashMap,
+    value_size: usize,
+    /// Distance between the values of two CPUs, 0 if the map is not per-CPU
+    value_stride: usize,
+    num_cpus: usize,
+    cpus: &'static dyn CpuOps,
+    /// Links of the elements in their list
+    prev: Vec<u32>,
+    next: Vec<u32>,
+    /// The list of each element
+    owner: Vec<u32>,
+    lists: Vec<LruList>,
+}
+
+impl LruHashMap {
+    pub fn new(attr: &BpfMapAttr, cpus: &'static dyn CpuOps) -> MapResult<Self> {
+        let num_cpus = cpus.num_cpus().max(1);
+        let max_entries = attr.max_entries as usize;
+        let value_size = attr.value_size as usize;
+        let percpu = attr.map_type == BPF_MAP_TYPE_LRU_PERCPU_HASH;
+        let value_stride = if percpu { (value_size + 7) & !7 } else { 0 };
+
+        // the table stores the values of all CPUs of an element together
+        let mut table_attr = attr.clone();
+        if percpu {
+            table_attr.value_size = value_stride
+                .checked_mul(num_cpus)
+                .and_then(|size| u32::try_from(size).ok())
+                .ok_or(MapError::TooBig)?;
+        }
+        let table = HashMap::new(&table_attr)?;
+
+        let lists = if attr.map_flags & BPF_F_NO_COMMON_LRU != 0 {
+            if max_entries < num_cpus {
+                return Err(MapError::Invalid);
+            }
+            // the first CPUs get the remaining elements
+            (0..num_cpus)
+                .map(|cpu| LruList {
+                    head: NONE,
+                    tail: NONE,
+                    len: 0,
+                    capacity: max_entries / num_cpus + usize::from(cpu < max_entries % num_cpus),
+                })
+                .collect()
+        } else {
+            alloc::vec![LruList {
+                head: NONE,
+                tail: NONE,
+                len: 0,
+                capacity: max_entries,
+            }]
+        };
+        Ok(LruHashMap {
+            table,
+            value_size,
+            value_stride,
+            num_cpus,
+            cpus,
+            prev: alloc_zeroed(max_entries)?,
+            next: alloc_zeroed(max_entries)?,
+            owner: alloc_zeroed(max_entries)?,
+            lists,
+        })
+    }
+
+    /// The number of elements
+    pub fn len(&self) -> usize {
+        self.table.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.table.is_empty()
+    }
+
+    fn percpu(&self) -> bool {
+        self.value_stride != 0
+    }
+
+    fn current_cpu(&self) -> MapResult<usize> {
+        let cpu = self.cpus.current_cpu();
+        if cpu >= self.num_cpus {
+            return Err(MapError::Invalid);
+        }
+        Ok(cpu)
+    }
+
+    /// The list new elements of the current CPU go to
+    fn current_list(&self) -> MapResult<usize> {
+        if self.lists.len() == 1 {
+            return Ok(0);
+        }
+        self.current_cpu()
+    }
+
+    /// The value of an element seen by the program running on this CPU
+    fn value_mut(&mut self, index: u32) -> MapResult<&mut [u8]> {
+        if !self.percpu() {
+            return Ok(self.table.value_mut(index));
+        }
+        let start = self.current_cpu()? * self.value_stride;
+        let size = self.value_size;
+        Ok(&mut self.table.value_mut(index)[start..start + size])
+    }
+
+    fn unlink(&mut self, index: u32) {
+        let list = &mut self.lists[self.owner[index as usize] as usize];
+        let (prev, next) = (self.prev[index as usize], self.next[index as usize]);
+        if prev == NONE {
+            list.head = next;
+        } else {
+            self.next[prev as usize] = next;
+        }
+        if next == NONE {
+            list.tail = prev;
+        } else {
+            self.prev[next as usize] = prev;
+        }
+        list.len -= 1;
+    }
+
+    fn push_front(&mut self, list_index: usize, index: u32) {
+        let list = &mut self.lists[list_index];
+        self.owner[index as usize] = list_index as u32;
+        self.prev[index as usize] = NONE;
+        self.next[index as usize] = list.head;
+        if list.head == NONE {
+            list.tail = index;
+        } else {
+            self.prev[list.head as usize] = index;
+        }
+        list.head = index;
+        list.len += 1;
+    }
+
+    /// Mark an element as the most recently used of its list
+    fn touch(&mut self, index: u32) {
+        let list = self.owner[index as usize] as usize;
+        if self.lists[list].head != index {
+            self.unlink(index);
+            self.push_front(list, index);
+        }
+    }
+
+    /// Remove the least recently used element of a list
+    fn evict(&mut self, list: usize) -> MapResult<()> {
+        let tail = self.lists[list].tail;
+        if tail == NONE {
+            return Err(MapError::TooBig);
+        }
+        self.unlink(tail);
+        let (index, prev) = self
+            .table
+            .find(self.table.key(tail))
+            .ok_or(MapError::NotFound)?;
+        self.table.remove(index, prev);
+        Ok(())
+    }
+
+    /// The element of `key`, a new one evicting an old one if needed.
+    /// Returns whether the element is new.
+    fn entry(&mut self, key: &[u8], flags: u64) -> MapResult<(u32, bool)> {
+        self.table.check_key(key)?;
+        if flags > BPF_EXIST {
+            return Err(MapError::Invalid);
+        }
+        match (self.table.find(key), flags) {
+            (Some(_), BPF_NOEXIST) => Err(MapError::Exists),
+            (None, BPF_EXIST) => Err(MapError::NotFound),
+            (Some((index, _)), _) => {
+                self.touch(index);
+                Ok((index, false))
+            }
+            (None, _) => {
+                let list = self.current_list()?;
+                if self.lists[list].len >= self.lists[list].capacity {
+                    self.evict(list)?;
+                }
+                let index = self.table.insert(key)?;
+                self.push_front(list, index);
+                Ok((index, true))
+            }
+        }
+    }
+}
+
+impl BpfMapOps for LruHashMap {
+    fn lookup_elem(&mut self, key: &[u8]) -> MapResult<Option<&mut [u8]>> {
+        self.table.check_key(key)?;
+        match self.table.find(key) {
+            Some((index, _)) => {
+                self.touch(index);
+                Ok(Some(self.value_mut(index)?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Updates the value of the current CPU, the other CPUs of a new element
+    /// of a per-CPU map start zeroed
+    fn update_elem(&mut self, key: &[u8], value: &[u8], flags: u64) -> MapResult<()> {
+        if value.len() != self.value_size {
+            return Err(MapError::Invalid);
+        }
+        if self.percpu() {
+            // fail before inserting the element
+            self.current_cpu()?;
+        }
+        let (index, new) = self.entry(key, flags)?;
+        if self.percpu() && new {
+            self.table.value_mut(index).fill(0);
+        }
+        self.value_mut(index)?.copy_from_slice(value);
+        Ok(())
+    }
+
+    fn delete_elem(&mut self, key: &[u8]) -> MapResult<()> {
+        self.table.check_key(key)?;
+        let (index, prev) = self.table.find(key).ok_or(MapError::NotFound)?;
+        self.unlink(index);
+        self.table.remove(index, prev);
+        Ok(())
+    }
+
+    fn get_next_key(&self, key: Option<&[u8]>, next_key: &mut [u8]) -> MapResult<()> {
+        self.table.get_next_key(key, next_key)
+    }
+
+    /// Copies the values of all CPUs for per-CPU maps, without refreshing the
+    /// element
+    fn lookup_elem_host(&mut self, key: &[u8], value: &mut [u8]) -> MapResult<()> {
+        self.table.lookup_elem_host(key, value)
+    }
+
+    /// Takes the values of all CPUs for per-CPU maps
+    fn update_elem_host(&mut self, key: &[u8], value: &[u8], flags: u64) -> MapResult<()> {
+        if value.len() != self.table.value_size() {
+            return Err(MapError::Invalid);
+        }
+        let (index, _) = self.entry(key, flags)?;
+        self.table.value_mut(index).copy_from_slice(value);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::map::{SingleCpu, BPF_ANY, BPF_MAP_TYPE_LRU_HASH};
+
+    /// Two CPUs, the tests using them switch the current one
+    struct TwoCpus(AtomicUsize);
+
+    impl CpuOps for TwoCpus {
+        fn num_cpus(&self) -> usize {
+            2
+        }
+        fn current_cpu(&self) -> usize {
+            self.0.load(Ordering::Relaxed)
+        }
+    }
+
+    static PERCPU_CPUS: TwoCpus = TwoCpus(AtomicUsize::new(0));
+    static LIST_CPUS: TwoCpus = TwoCpus(AtomicUsize::new(0));
+
+    fn map(
+        map_type: u32,
+        max_entries: u32,
+        map_flags: u32,
+        cpus: &'static dyn CpuOps,
+    ) -> LruHashMap {
+        LruHashMap::new(
+            &BpfMapAttr {
+                map_type,
+                key_size: 4,
+                value_size: 4,
+                max_entries,
+                map_flags,
+                name: "lru".to_string(),
+                btf_key_type_id: 0,
+                btf_value_type_id: 0,
+            },
+            cpus,
+        )
+        .unwrap()
+    }
+
+    fn update(map: &mut LruHashMap, key: u32, value: u32) {
+        map.update_elem(&key.to_ne_bytes(), &value.to_ne_bytes(), BPF_ANY)
+            .unwrap();
+    }
+
+    fn contains(map: &LruHashMap, key: u32) -> bool {
+        map.table.find(&key.to_ne_bytes()).is_some()
+    }
+
+    #[test]
+    fn evict_least_recently_used() {
+        let mut map = map(BPF_MAP_TYPE_LRU_HASH, 2, 0, &SingleCpu);
+        update(&mut map, 1, 10);
+        update(&mut map, 2, 20);
+        // a program lookup refreshes 1, a host lookup leaves 2 unchanged
+        map.lookup_elem(&1u32.to_ne_bytes()).unwrap().unwrap();
+        let mut value = [0; 4];
+        map.lookup_elem_host(&2u32.to_ne_bytes(), &mut value)
+            .unwrap();
+        update(&mut map, 3, 30);
+        assert!(contains(&map, 1));
+        assert!(!contains(&map, 2));
+        assert!(contains(&map, 3));
+        assert_eq!(map.len(), 2);
+        map.delete_elem(&1u32.to_ne_bytes()).unwrap();
+        update(&mut map, 4, 40);
+        assert!(contains(&map, 3));
+        assert!(contains(&map, 4));
+    }
+
+    #[test]
+    fn percpu_values() {
+        let mut map = map(BPF_MAP_TYPE_LRU_PERCPU_HASH, 2, 0, &PERCPU_CPUS);
+        PERCPU_CPUS.0.store(1, Ordering::Relaxed);
+        update(&mut map, 1, 11);
+        PERCPU_CPUS.0.store(0, Ordering::Relaxed);
+        let value = map.lookup_elem(&1u32.to_ne_bytes()).unwrap().unwrap();
+        assert_eq!(value, [0; 4]);
+        // the host sees the values of all CPUs, each rounded up to 8 bytes
+        let mut values = [0; 16];
+        map.lookup_elem_host(&1u32.to_ne_bytes(), &mut values)
+            .unwrap();
+        assert_eq!(values[8..12], 11u32.to_ne_bytes());
+    }
+
+    #[test]
+    fn per_cpu_lists() {
+        let mut map = map(BPF_MAP_TYPE_LRU_HASH, 2, BPF_F_NO_COMMON_LRU, &LIST_CPUS);
+        update(&mut map, 1, 10);
+        update(&mut map, 2, 20);
+        // each CPU has room for one element and evicts its own
+        assert!(!contains(&map, 1));
+        LIST_CPUS.0.store(1, Ordering::Relaxed);
+        update(&mut map, 3, 30);
+        assert!(contains(&map, 2));
+        assert!(contains(&map, 3));
+    }
+}
@@ -0,0 +1,271 @@
+//! BPF maps: the [`MapRegistry`] used by the loader and the executor, the
+//! [`BpfMapOps`] implemented by each map type and a ready-to-use registry,
+//! [`BpfMapRegistry`].
+use alloc::{boxed::Box, vec::Vec};
+use core::fmt::{Debug, Display, Formatter};
+
+use anyhow::Result;
+
+use crate::loader::{BpfMapAttr, MapFd, ProgFd};
+
+mod array;
+mod hash;
+mod lru;
+mod perf;
+mod prog_array;
+mod queue;
+mod registry;
+mod ringbuf;
+mod stack_trace;
+
+pub use array::{ArrayMap, PerCpuArrayMap};
+pub use hash::HashMap;
+pub use lru::LruHashMap;
+pub use perf::{PerfEventArray, PerfEventReader, PerfMmap, BPF_F_CURRENT_CPU, BPF_F_INDEX_MASK};
+pub use prog_array::ProgArray;
+pub use queue::QueueMap;
+pub use registry::BpfMapRegistry;
+pub use ringbuf::{ringbuf_commit, RingBuf, RingBufConsumer};
+pub use stack_trace::{
+    StackTraceMap, BPF_F_FAST_STACK_CMP, BPF_F_REUSE_STACKID, BPF_F_SKIP_FIELD_MASK,
+    BPF_F_USER_STACK,
+};
+
+pub const BPF_MAP_TYPE_UNSPEC: u32 = 0;
+pub const BPF_MAP_TYPE_HASH: u32 = 1;
+pub const BPF_MAP_TYPE_ARRAY: u32 = 2;
+pub const BPF_MAP_TYPE_PROG_ARRAY: u32 = 3;
+pub const BPF_MAP_TYPE_PERF_EVENT_ARRAY: u32 = 4;
+pub const BPF_MAP_TYPE_PERCPU_HASH: u32 = 5;
+pub const BPF_MAP_TYPE_PERCPU_ARRAY: u32 = 6;
+pub const BPF_MAP_TYPE_STACK_TRACE: u32 = 7;
+pub const BPF_MAP_TYPE_LRU_HASH: u32 = 9;
+pub const BPF_MAP_TYPE_LRU_PERCPU_HASH: u32 = 10;
+pub const BPF_MAP_TYPE_QUEUE: u32 = 22;
+pub const BPF_MAP_TYPE_STACK: u32 = 23;
+pub const BPF_MAP_TYPE_RINGBUF: u32 = 27;
+
+/// Create a new element or update an existing one
+pub const BPF_ANY: u64 = 0;
+/// Create a new element only if it does not exist
+pub const BPF_NOEXIST: u64 = 1;
+/// Update an existing element
+pub const BPF_EXIST: u64 = 2;
+
+/// Storage of the maps of a BPF environment, used by the loader to create them
+/// and by the executor to find their data. Each registry is isolated, a host can
+/// have one per container or per test.
+pub trait MapRegistry {
+    fn create_map(&mut self, attr: BpfMapAttr) -> Result<MapFd>;
+    fn update_map_element(&mut self, map_fd: MapFd, key: &[u8], value: &[u8]) -> Result<()>;
+    /// Make the map read-only for the host, used for `.rodata` once it is initialized
+    fn freeze_map(&mut self, _map_fd: MapFd) -> Result<()> {
+        Ok(())
+    }
+    /// Drop a reference to the map, taken by [`MapRegistry::create_map`], when
+    /// the object using it is unloaded
+    fn release_map(&mut self, _map_fd: MapFd) -> Result<()> {
+        Ok(())
+    }
+    /// A handle for a loaded program, unique in the registry, stored in the
+    /// program arrays
+    fn create_prog(&mut self) -> Result<ProgFd>;
+    /// Size in bytes of the data of a global data map
+    fn map_data_len(&self, map_fd: MapFd) -> usize;
+    /// The data of a global data map, referenced by the programs
+    fn map_data_ptr(&mut self, map_fd: MapFd) -> *mut u8;
+}
+
+/// Errors of the map operations, helpers return them to the programs as
+/// negative errno values
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// The key does not exist
+    NotFound,
+    /// The key exists and `BPF_NOEXIST` was given
+    Exists,
+    /// The map is full
+    TooBig,
+    /// Bad key or value size, flags or map attributes
+    Invalid,
+    /// The map type does not support the operation
+    NotSupported,
+    /// No memory left for the map
+    NoMemory,
+    /// The map is frozen or read-only
+    Permission,
+    /// No such map
+    BadFd,
+    /// No room left in a buffer, the caller can retry later
+    Again,
+}
+
+impl MapError {
+    /// The Linux errno of the error, positive
+    pub fn errno(&self) -> i64 {
+        match self {
+            MapError::NotFound => 2,      // ENOENT
+            MapError::Exists => 17,       // EEXIST
+            MapError::TooBig => 7,        // E2BIG
+            MapError::Invalid => 22,      // EINVAL
+            MapError::NotSupported => 95, // EOPNOTSUPP
+            MapError::NoMemory => 12,     // ENOMEM
+            MapError::Permission => 1,    // EPERM
+            MapError::BadFd => 9,         // EBADF
+            MapError::Again => 11,        // EAGAIN
+        }
+    }
+}
+
+impl Display for MapError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let reason = match self {
+            MapError::NotFound => "key not found",
+            MapError::Exists => "key already exists",
+            MapError::TooBig => "map is full",
+            MapError::Invalid => "invalid argument",
+            MapError::NotSupported => "operation not supported by the map type",
+            MapError::NoMemory => "out of memory",
+            MapError::Permission => "map is read-only",
+            MapError::BadFd => "no such map",
+            MapError::Again => "no room left in the buffer",
+        };
+        f.write_str(reason)
+    }
+}
+
+pub type MapResult<T> = Result<T, MapError>;
+
+/// The operations of a map type, called by [`BpfMapRegistry`] and the map
+/// helpers. Keys and values have the sizes of the map attributes.
+pub trait BpfMapOps: Debug + Send {
+    /// The value of `key`, its address stays valid until the element is
+    /// deleted since programs keep the pointers returned by lookups
+    fn lookup_elem(&mut self, key: &[u8]) -> MapResult<Option<&mut [u8]>>;
+    fn update_elem(&mut self, key: &[u8], value: &[u8], flags: u64) -> MapResult<()>;
+    fn delete_elem(&mut self, key: &[u8]) -> MapResult<()>;
+    /// The key following `key`, or the first key if `key` is `None`
+    fn get_next_key(&self, key: Option<&[u8]>, next_key: &mut [u8]) -> MapResult<()>;
+    /// The contiguous data of global data maps, referenced by the programs
+    fn data(&mut self) -> Option<&mut [u8]> {
+        None
+    }
+    /// Copy the value of `key` for the host, per-CPU maps override it to copy
+    /// the values of all CPUs
+    fn lookup_elem_host(&mut self, key: &[u8], value: &mut [u8]) -> MapResult<()> {
+        let found = self.lookup_elem(key)?.ok_or(MapError::NotFound)?;
+        if found.len() != value.len() {
+            return Err(MapError::Invalid);
+        }
+        value.copy_from_slice(found);
+        Ok(())
+    }
+    /// Update an element from the host
+    fn update_elem_host(&mut self, key: &[u8], value: &[u8], flags: u64) -> MapResult<()> {
+        self.update_elem(key, value, flags)
+    }
+    /// Push a value to a queue or a stack
+    fn push_elem(&mut self, _value: &[u8], _flags: u64) -> MapResult<()> {
+        Err(MapError::NotSupported)
+    }
+    /// Copy and remove the next value of a queue or a stack
+    fn pop_elem(&mut self, _value: &mut [u8]) -> MapResult<()> {
+        Err(MapError::NotSupported)
+    }
+    /// Copy the next value of a queue or a stack
+    fn peek_elem(&mut self, _value: &mut [u8]) -> MapResult<()> {
+        Err(MapError::NotSupported)
+    }
+    /// Reserve a record of `size` bytes in a ring buffer, the record is
+    /// committed with [`ringbuf_commit`]
+    fn ringbuf_reserve(&mut self, _size: usize) -> MapResult<&mut [u8]> {
+        Err(MapError::NotSupported)
+    }
+    /// A property of a ring buffer, one of the `BPF_RB_*` queries
+    fn ringbuf_query(&self, _flags: u64) -> MapResult<u64> {
+        Err(MapError::NotSupported)
+    }
+    /// Append a sample to the perf buffer of the CPU index in `flags`
+    fn perf_event_output(&mut self, _flags: u64, _data: &[u8]) -> MapResult<()> {
+        Err(MapError::NotSupported)
+    }
+    /// The memory of the perf buffer of a CPU index, for the host reader
+    fn perf_buffer(&mut self, _index: usize) -> MapResult<PerfMmap> {
+        Err(MapError::NotSupported)
+    }
+    /// The id of a stack given by its addresses, innermost first
+    fn get_stackid(&mut self, _ips: &[u64], _flags: u64) -> MapResult<u32> {
+        Err(MapError::NotSupported)
+    }
+    /// The program at `index` of a program array
+    fn prog_array_get(&self, _index: u32) -> MapResult<ProgFd> {
+        Err(MapError::NotSupported)
+    }
+}
+
+/// The CPUs of the OS, for per-CPU maps
+pub trait CpuOps: Sync {
+    fn num_cpus(&self) -> usize;
+    /// The CPU running the caller, less than [`CpuOps::num_cpus`]
+    fn current_cpu(&self) -> usize;
+}
+
+/// A single CPU, for hosts without per-CPU data
+#[derive(Debug, Default)]
+pub struct SingleCpu;
+
+impl CpuOps for SingleCpu {
+    fn num_cpus(&self) -> usize {
+        1
+    }
+    fn current_cpu(&self) -> usize {
+        0
+    }
+}
+
+impl Debug for dyn CpuOps {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "CpuOps({} CPUs)", self.num_cpus())
+    }
+}
+
+/// Create the map of `attr` with the implementation of its type
+pub fn new_map(attr: &BpfMapAttr, cpus: &'static dyn CpuOps) -> MapResult<Box<dyn BpfMapOps>> {
+    // queues and stacks have no keys, ring buffers neither keys nor values
+    let (keyless, valueless) = match attr.map_type {
+        BPF_MAP_TYPE_QUEUE | BPF_MAP_TYPE_STACK => (true, false),
+        BPF_MAP_TYPE_RINGBUF => (true, true),
+        _ => (false, false),
+    };
+    if (attr.key_size == 0 && !keyless)
+        || (attr.value_size == 0 && !valueless)
+        || attr.max_entries == 0
+    {
+        return Err(MapError::Invalid);
+    }
+    match attr.map_type {
+        BPF_MAP_TYPE_HASH => Ok(Box::new(HashMap::new(attr)?)),
+        BPF_MAP_TYPE_ARRAY => Ok(Box::new(ArrayMap::new(attr)?)),
+        BPF_MAP_TYPE_PERCPU_ARRAY => Ok(Box::new(PerCpuArrayMap::new(attr, cpus)?)),
+        BPF_MAP_TYPE_LRU_HASH | BPF_MAP_TYPE_LRU_PERCPU_HASH => {
+            Ok(Box::new(LruHashMap::new(attr, cpus)?))
+        }
+        BPF_MAP_TYPE_QUEUE | BPF_MAP_TYPE_STACK => Ok(Box::new(QueueMap::new(attr)?)),
+        BPF_MAP_TYPE_RINGBUF => Ok(Box::new(RingBuf::new(attr)?)),
+        BPF_MAP_TYPE_PERF_EVENT_ARRAY => Ok(Box::new(PerfEventArray::new(attr, cpus)?)),
+        BPF_MAP_TYPE_STACK_TRACE => Ok(Box::new(StackTraceMap::new(attr)?)),
+        BPF_MAP_TYPE_PROG_ARRAY => Ok(Box::new(ProgArray::new(attr)?)),
+        _ => Err(MapError::NotSupported),
+    }
+}
+
+/// A zeroed buffer of `len` elements, allocation failures are reported to the
+/// caller instead of aborting since map sizes come from the objects
+pub(crate) fn alloc_zeroed<T: Clone + Default>(len: usize) -> MapResult<Vec<T>> {
+    let mut buffer = Vec::new();
+    buffer
+        .try_reserve_exact(len)
+        .map_err(|_| MapError::NoMemory)?;
+    buffer.resize(len, T::default());
+    Ok(buffer)
+}
@@ -0,0 +1,472 @@
+//! `BPF_MAP_TYPE_PERF_EVENT_ARRAY`, a circular buffer per CPU written with
+//! `bpf_perf_event_output`.
+//!
+//! In Linux the host opens a perf event on each CPU and stores its fd in the
+//! map. Here the host opens the buffer of a CPU by updating its index with the
+//! number of data pages, and closes it by deleting the index, which is what
+//! [`PerfEventReader`] does. The reader shares the buffers it opened, so they
+//! outlive a host closing them. Each buffer has the layout of a perf mmap: a
+//! header page with `data_head` and `data_tail`, then the data pages holding
+//! `PERF_RECORD_SAMPLE` records. Samples which do not fit are counted and
+//! reported with a `PERF_RECORD_LOST` record once there is room again.
+use alloc::{sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use lock_api::RawMutex;
+
+use super::array::{Buffer, PAGE_SIZE};
+use super::{
+    BpfMapOps, BpfMapRegistry, CpuOps, MapError, MapResult, BPF_ANY, BPF_EXIST,
+    BPF_MAP_TYPE_PERF_EVENT_ARRAY, BPF_NOEXIST,
+};
+use crate::loader::{BpfMapAttr, MapFd};
+
+/// The index of the CPU running the program
+pub const BPF_F_CURRENT_CPU: u64 = 0xffff_ffff;
+pub const BPF_F_INDEX_MASK: u64 = 0xffff_ffff;
+
+pub const PERF_RECORD_LOST: u32 = 2;
+pub const PERF_RECORD_SAMPLE: u32 = 9;
+
+/// Offsets in the header page, those of `struct perf_event_mmap_page`
+const DATA_HEAD: usize = 1024;
+const DATA_TAIL: usize = 1032;
+/// `struct perf_event_header`
+const HEADER_SIZE: usize = 8;
+/// A sample is a header, the size of the raw data and the data
+const SAMPLE_SIZE: usize = HEADER_SIZE + 4;
+/// A lost record is a header, an id and the count
+const LOST_SIZE: usize = HEADER_SIZE + 16;
+
+fn round_up(len: usize) -> usize {
+    (len + 7) & !7
+}
+
+/// The position at `offset` in the header page at `base`
+///
+/// # Safety
+///
+/// `base` is the start of a perf buffer.
+unsafe fn position<'a>(base: *mut u8, offset: usize) -> &'a AtomicU64 {
+    &*(base.add(offset) as *const AtomicU64)
+}
+
+/// The memory of a perf buffer, shared by the map and the host reader
+#[derive(Debug, Clone)]
+pub struct PerfMmap {
+    buffer: Arc<Buffer>,
+    /// The size of the data, a power of 2
+    size: usize,
+}
+
+#[derive(Debug)]
+struct PerfBuffer {
+    buffer: Arc<Buffer>,
+    /// The size of the data, a power of 2
+    size: usize,
+    /// Samples dropped since the last lost record
+    lost: u64,
+}
+
+impl PerfBuffer {
+    fn new(pages: usize) -> MapResult<Self> {
+        if !pages.is_power_of_two() {
+            return Err(MapError::Invalid);
+        }
+        let size = pages.checked_mul(PAGE_SIZE).ok_or(MapError::TooBig)?;
+        Ok(PerfBuffer {
+            buffer: Arc::new(Buffer::new(PAGE_SIZE + size, PAGE_SIZE)?),
+            size,
+            lost: 0,
+        })
+    }
+
+    /// Copy `data` at `head` in the data pages, wrapping at the end
+    fn write(&mut self, head: u64, data: &[u8]) {
+        let offset = head as usize & (self.size - 1);
+        let first = data.len().min(self.size - offset);
+        let base = self.buffer.as_ptr();
+        // SAFETY: `data` fits in the data pages, the map is the only writer
+        // and the reader does not read past the head
+        unsafe {
+            core::ptr::copy_nonoverlapping(data.as_ptr(), base.add(PAGE_SIZE + offset), first);
+            core::ptr::copy_nonoverlapping(
+                data[first..].as_ptr(),
+                base.add(PAGE_SIZE),
+                data.len() - first,
+            );
+        }
+    }
+
+    fn record_header(kind: u32, size: usize) -> [u8; HEADER_SIZE] {
+        let mut header = [0; HEADER_SIZE];
+        header[..4].copy_from_slice(&kind.to_ne_bytes());
+        // misc is 0
+        header[6..].copy_from_slice(&(size as u16).to_ne_bytes());
+        header
+    }
+
+    /// Append a sample, after a lost record if samples were dropped
+    fn output(&mut self, data: &[u8]) -> MapResult<()> {
+        let len = round_up(SAMPLE_SIZE + data.len());
+        if len > u16::MAX as usize || len > self.size {
+            return Err(MapError::TooBig);
+        }
+        // SAFETY: the positions are in the header page
+        let (head_pos, tail_pos) = unsafe {
+            (
+                position(self.buffer.as_ptr(), DATA_HEAD),
+                position(self.buffer.as_ptr(), DATA_TAIL),
+            )
+        };
+        let tail = tail_pos.load(Ordering::Acquire);
+        let mut head = head_pos.load(Ordering::Relaxed);
+        let lost_len = if self.lost > 0 { LOST_SIZE } else { 0 };
+        if head + (lost_len + len) as u64 - tail > self.size as u64 {
+            self.lost += 1;
+            return Err(MapError::Again);
+        }
+        if self.lost > 0 {
+            let mut record = [0; LOST_SIZE];
+            record[..HEADER_SIZE]
+                .copy_from_slice(&Self::record_header(PERF_RECORD_LOST, LOST_SIZE));
+            // the id of the event is 0
+            record[16..].copy_from_slice(&self.lost.to_ne_bytes());
+            self.write(head, &record);
+            head += LOST_SIZE as u64;
+            self.lost = 0;
+        }
+        let mut sample = [0; SAMPLE_SIZE];
+        sample[..HEADER_SIZE].copy_from_slice(&Self::record_header(PERF_RECORD_SAMPLE, len));
+        sample[HEADER_SIZE..].copy_from_slice(&(data.len() as u32).to_ne_bytes());
+        self.write(head, &sample);
+        self.write(head + SAMPLE_SIZE as u64, data);
+        // the reader sees the records before the new head
+        head_pos.store(head + len as u64, Ordering::Release);
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct PerfEventArray {
+    /// The buffer of each CPU, if opened by the host
+    buffers: Vec<Option<PerfBuffer>>,
+    cpus: &'static dyn CpuOps,
+}
+
+impl PerfEventArray {
+    pub fn new(attr: &BpfMapAttr, cpus: &'static dyn CpuOps) -> MapResult<Self> {
+        if attr.key_size != 4 || attr.value_size != 4 {
+            return Err(MapError::Invalid);
+        }
+        let mut buffers = Vec::new();
+        buffers
+            .try_reserve_exact(attr.max_entries as usize)
+            .map_err(|_| MapError::NoMemory)?;
+        buffers.resize_with(attr.max_entries as usize, || None);
+        Ok(PerfEventArray { buffers, cpus })
+    }
+
+    fn index(&self, key: &[u8]) -> MapResult<usize> {
+        let key: [u8; 4] = key.try_into().map_err(|_| MapError::Invalid)?;
+        let index = u32::from_ne_bytes(key) as usize;
+        if index >= self.buffers.len() {
+            return Err(MapError::TooBig);
+        }
+        Ok(index)
+    }
+}
+
+impl BpfMapOps for PerfEventArray {
+    fn lookup_elem(&mut self, _key: &[u8]) -> MapResult<Option<&mut [u8]>> {
+        Err(MapError::NotSupported)
+    }
+
+    /// Programs can not open buffers
+    fn update_elem(&mut self, _key: &[u8], _value: &[u8], _flags: u64) -> MapResult<()> {
+        Err(MapError::NotSupported)
+    }
+
+    /// Closes the buffer of a CPU
+    fn delete_elem(&mut self, key: &[u8]) -> MapResult<()> {
+        let index = self.index(key)?;
+        self.buffers[index].take().ok_or(MapError::NotFound)?;
+        Ok(())
+    }
+
+    fn get_next_key(&self, key: Option<&[u8]>, next_key: &mut [u8]) -> MapResult<()> {
+        let next = match key.map(|key| self.index(key)) {
+            Some(Ok(index)) => index + 1,
+            _ => 0,
+        };
+        if next >= self.buffers.len() {
+            return Err(MapError::NotFound);
+        }
+        if next_key.len() != 4 {
+            return Err(MapError::Invalid);
+        }
+        next_key.copy_from_slice(&(next as u32).to_ne_bytes());
+        Ok(())
+    }
+
+    /// Opens the buffer of a CPU, the value is its number of data pages
+    fn update_elem_host(&mut self, key: &[u8], value: &[u8], flags: u64) -> MapResult<()> {
+        let index = self.index(key)?;
+        let pages: [u8; 4] = value.try_into().map_err(|_| MapError::Invalid)?;
+        match (flags, &self.buffers[index]) {
+            (BPF_NOEXIST, Some(_)) => return Err(MapError::Exists),
+            (BPF_EXIST, None) => return Err(MapError::NotFound),
+            (BPF_ANY | BPF_NOEXIST | BPF_EXIST, _) => {}
+            _ => return Err(MapError::Invalid),
+        }
+        self.buffers[index] = Some(PerfBuffer::new(u32::from_ne_bytes(pages) as usize)?);
+        Ok(())
+    }
+
+    fn perf_event_output(&mut self, flags: u64, data: &[u8]) -> MapResult<()> {
+        if flags & !BPF_F_INDEX_MASK != 0 {
+            return Err(MapError::Invalid);
+        }
+        let index = match flags & BPF_F_INDEX_MASK {
+            BPF_F_CURRENT_CPU => self.cpus.current_cpu(),
+            index => index as usize,
+        };
+        self.buffers
+            .get_mut(index)
+            .ok_or(MapError::TooBig)?
+            .as_mut()
+            .ok_or(MapError::NotFound)?
+            .output(data)
+    }
+
+    fn perf_buffer(&mut self, index: usize) -> MapResult<PerfMmap> {
+        let buffer = self
+            .buffers
+            .get(index)
+            .ok_or(MapError::TooBig)?
+            .as_ref()
+            .ok_or(MapError::NotFound)?;
+        Ok(PerfMmap {
+            buffer: buffer.buffer.clone(),
+            size: buffer.size,
+        })
+    }
+}
+
+/// Opens the buffers of all the CPUs of a perf event array and reads their
+/// records from the host. The buffers are closed when the reader is dropped.
+pub struct PerfEventReader<'a, L: RawMutex> {
+    maps: &'a BpfMapRegistry<L>,
+    fd: MapFd,
+    /// The buffer of each CPU index, kept alive by the reader
+    buffers: Vec<PerfMmap>,
+    /// A record crossing the end of a buffer
+    record: Vec<u8>,
+}
+
+impl<'a, L: RawMutex> PerfEventReader<'a, L> {
+    /// Open a buffer of `pages` data pages, a power of 2, for each CPU index.
+    /// Fails with [`MapError::Exists`] if a buffer of the map is already open.
+    pub fn new(maps: &'a BpfMapRegistry<L>, fd: MapFd, pages: u32) -> MapResult<Self> {
+        let attr = maps.attr(fd)?;
+        if attr.map_type != BPF_MAP_TYPE_PERF_EVENT_ARRAY {
+            return Err(MapError::Invalid);
+        }
+        maps.retain(fd)?;
+        let mut reader = PerfEventReader {
+            maps,
+            fd,
+            buffers: Vec::new(),
+            record: Vec::new(),
+        };
+        for index in 0..attr.max_entries {
+            let key = index.to_ne_bytes();
+            maps.update_elem(fd, &key, &pages.to_ne_bytes(), BPF_NOEXIST)?;
+            let buffer = maps.with_map(fd, |map, _| map.perf_buffer(index as usize));
+            match buffer {
+                Ok(buffer) => reader.buffers.push(buffer),
+                Err(e) => {
+                    let _ = maps.delete_elem(fd, &key);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(reader)
+    }
+
+    /// Drain the buffers, calling `sample` with the CPU index and the data of
+    /// each sample and `lost` with the number of samples dropped by a CPU.
+    /// Returns the number of samples read. The records of a buffer with a
+    /// corrupted record header are discarded.
+    pub fn poll(
+        &mut self,
+        mut sample: impl FnMut(usize, &[u8]),
+        mut lost: impl FnMut(usize, u64),
+    ) -> usize {
+        let mut count = 0;
+        for (cpu, buffer) in self.buffers.iter().enumerate() {
+            let (base, size) = (buffer.buffer.as_ptr(), buffer.size);
+            // SAFETY: the reader keeps the buffer alive, the records between
+            // the positions were written by the producer
+            let (head, tail_pos, data) = unsafe {
+                (
+                    position(base, DATA_HEAD).load(Ordering::Acquire),
+                    position(base, DATA_TAIL),
+                    core::slice::from_raw_parts(base.add(PAGE_SIZE), size),
+                )
+            };
+            let mut tail = tail_pos.load(Ordering::Relaxed);
+            while tail < head {
+                let offset = tail as usize & (size - 1);
+                let header = &data[offset..offset + HEADER_SIZE];
+                let kind = u32::from_ne_bytes(header[..4].try_into().unwrap());
+                let len = u16::from_ne_bytes(header[6..].try_into().unwrap()) as usize;
+                if len < HEADER_SIZE || len % 8 != 0 || len as u64 > head - tail {
+                    log::error!("corrupted perf record of length {} on cpu {}", len, cpu);
+                    tail = head;
+                    break;
+                }
+                let record = if offset + len <= size {
+                    &data[offset..offset + len]
+                } else {
+                    self.record.clear();
+                    self.record.extend_from_slice(&data[offset..]);
+                    self.record.extend_from_slice(&data[..offset + len - size]);
+                    &self.record
+                };
+                match kind {
+                    PERF_RECORD_SAMPLE if len >= SAMPLE_SIZE => {
+                        let raw_size = u32::from_ne_bytes(
+                            record[HEADER_SIZE..SAMPLE_SIZE].try_into().unwrap(),
+                        ) as usize;
+                        match record[SAMPLE_SIZE..].get(..raw_size) {
+                            Some(raw) => {
+                                sample(cpu, raw);
+                                count += 1;
+                            }
+                            None => log::error!("perf sample of {} bytes is truncated", raw_size),
+                        }
+                    }
+                    PERF_RECORD_LOST if len >= LOST_SIZE => {
+                        lost(cpu, u64::from_ne_bytes(record[16..24].try_into().unwrap()));
+                    }
+                    _ => log::warn!("unknown perf record type {}", kind),
+                }
+                tail += len as u64;
+            }
+            // give the space back to the program
+            tail_pos.store(tail, Ordering::Release);
+        }
+        count
+    }
+}
+
+impl<L: RawMutex> Drop for PerfEventReader<'_, L> {
+    fn drop(&mut self) {
+        for index in 0..self.buffers.len() as u32 {
+            let _ = self.maps.delete_elem(self.fd, &index.to_ne_bytes());
+        }
+        let _ = self.maps.release(self.fd);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{string::ToString, vec, vec::Vec};
+
+    use super::*;
+
+    type Registry = BpfMapRegistry<spin::Mutex<()>>;
+
+    fn perf_map(maps: &Registry) -> MapFd {
+        maps.create(BpfMapAttr {
+            map_type: BPF_MAP_TYPE_PERF_EVENT_ARRAY,
+            key_size: 4,
+            value_size: 4,
+            max_entries: 1,
+            map_flags: 0,
+            name: "events".to_string(),
+            btf_key_type_id: 0,
+            btf_value_type_id: 0,
+        })
+        .unwrap()
+    }
+
+    fn output(maps: &Registry, fd: MapFd, data: &[u8]) -> MapResult<()> {
+        maps.with_map(fd, |map, _| map.perf_event_output(BPF_F_CURRENT_CPU, data))
+    }
+
+    fn poll(reader: &mut PerfEventReader<'_, spin::Mutex<()>>) -> (Vec<Vec<u8>>, u64) {
+        let mut samples = Vec::new();
+        let mut lost = 0;
+        reader.poll(
+            |_, data| samples.push(data.to_vec()),
+            |_, count| lost += count,
+        );
+        (samples, lost)
+    }
+
+    #[test]
+    fn samples() {
+        let maps = Registry::new();
+        let fd = perf_map(&maps);
+        assert_eq!(output(&maps, fd, b"abc"), Err(MapError::NotFound));
+        let mut reader = PerfEventReader::new(&maps, fd, 1).unwrap();
+        output(&maps, fd, b"abc").unwrap();
+        output(&maps, fd, &[7; 100]).unwrap();
+        assert_eq!(poll(&mut reader), (vec![b"abc".to_vec(), vec![7; 100]], 0));
+        assert_eq!(poll(&mut reader), (vec![], 0));
+    }
+
+    #[test]
+    fn lost_samples() {
+        let maps = Registry::new();
+        let fd = perf_map(&maps);
+        let mut reader = PerfEventReader::new(&maps, fd, 1).unwrap();
+        let data = [1; 1000];
+        let mut written = 0;
+        while output(&maps, fd, &data).is_ok() {
+            written += 1;
+        }
+        assert_eq!(output(&maps, fd, &data), Err(MapError::Again));
+        assert_eq!(poll(&mut reader).0.len(), written);
+        output(&maps, fd, &data).unwrap();
+        assert_eq!(poll(&mut reader), (vec![data.to_vec()], 2));
+    }
+
+    #[test]
+    fn one_reader() {
+        let maps = Registry::new();
+        let fd = perf_map(&maps);
+        let mut reader = PerfEventReader::new(&maps, fd, 1).unwrap();
+        assert!(matches!(
+            PerfEventReader::new(&maps, fd, 1),
+            Err(MapError::Exists)
+        ));
+        output(&maps, fd, b"abc").unwrap();
+        // the host closes and reopens the buffer, the reader keeps the old one
+        maps.delete_elem(fd, &0u32.to_ne_bytes()).unwrap();
+        maps.update_elem(fd, &0u32.to_ne_bytes(), &1u32.to_ne_bytes(), BPF_ANY)
+            .unwrap();
+        assert_eq!(poll(&mut reader), (vec![b"abc".to_vec()], 0));
+        drop(reader);
+        assert!(PerfEventReader::new(&maps, fd, 1).is_ok());
+    }
+
+    #[test]
+    fn corrupted_header() {
+        let maps = Registry::new();
+        let fd = perf_map(&maps);
+        let mut reader = PerfEventReader::new(&maps, fd, 1).unwrap();
+        output(&maps, fd, b"abc").unwrap();
+        let mmap = maps.with_map(fd, |map, _| map.perf_buffer(0)).unwrap();
+        // SAFETY: the first record is in the data pages
+        unsafe {
+            let header = mmap.buffer.as_ptr().add(PAGE_SIZE);
+            header.add(6).write_bytes(0, 2);
+        }
+        assert_eq!(poll(&mut reader), (vec![], 0));
+        output(&maps, fd, b"def").unwrap();
+        assert_eq!(poll(&mut reader), (vec![b"def".to_vec()], 0));
+    }
+}
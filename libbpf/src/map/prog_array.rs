@@ -0,0 +1,191 @@
+//! `BPF_MAP_TYPE_PROG_ARRAY`, the targets of `bpf_tail_call`.
+//!
+//! The values are program handles, [`ProgFd`], set by the host, e.g. with
+//! [`Bpf::update_prog_array`](crate::loader::Bpf::update_prog_array). Programs
+//! can not read or change the array, they only jump to its programs.
+use alloc::vec::Vec;
+
+use super::{alloc_zeroed, BpfMapOps, MapError, MapResult, BPF_EXIST, BPF_NOEXIST};
+use crate::loader::{BpfMapAttr, ProgFd};
+
+/// An empty slot
+const NONE: ProgFd = ProgFd::MAX;
+
+#[derive(Debug)]
+pub struct ProgArray {
+    progs: Vec<ProgFd>,
+}
+
+impl ProgArray {
+    pub fn new(attr: &BpfMapAttr) -> MapResult<Self> {
+        if attr.key_size != 4 || attr.value_size != 4 {
+            return Err(MapError::Invalid);
+        }
+        let mut progs = alloc_zeroed(attr.max_entries as usize)?;
+        progs.fill(NONE);
+        Ok(ProgArray { progs })
+    }
+
+    fn index(&self, key: &[u8]) -> MapResult<usize> {
+        let key: [u8; 4] = key.try_into().map_err(|_| MapError::Invalid)?;
+        let index = u32::from_ne_bytes(key) as usize;
+        if index >= self.progs.len() {
+            return Err(MapError::TooBig);
+        }
+        Ok(index)
+    }
+}
+
+impl BpfMapOps for ProgArray {
+    fn lookup_elem(&mut self, _key: &[u8]) -> MapResult<Option<&mut [u8]>> {
+        Err(MapError::NotSupported)
+    }
+
+    fn update_elem(&mut self, _key: &[u8], _value: &[u8], _flags: u64) -> MapResult<()> {
+        Err(MapError::NotSupported)
+    }
+
+    fn delete_elem(&mut self, key: &[u8]) -> MapResult<()> {
+        let index = self.index(key)?;
+        if self.progs[index] == NONE {
+            return Err(MapError::NotFound);
+        }
+        self.progs[index] = NONE;
+        Ok(())
+    }
+
+    fn get_next_key(&self, key: Option<&[u8]>, next_key: &mut [u8]) -> MapResult<()> {
+        let next = match key.map(|key| self.index(key)) {
+            Some(Ok(index)) => index + 1,
+            _ => 0,
+        };
+        if next >= self.progs.len() {
+            return Err(MapError::NotFound);
+        }
+        if next_key.len() != 4 {
+            return Err(MapError::Invalid);
+        }
+        next_key.copy_from_slice(&(next as u32).to_ne_bytes());
+        Ok(())
+    }
+
+    /// The program handle of an index
+    fn lookup_elem_host(&mut self, key: &[u8], value: &mut [u8]) -> MapResult<()> {
+        let prog = self.prog_array_get(self.index(key)? as u32)?;
+        if value.len() != 4 {
+            return Err(MapError::Invalid);
+        }
+        value.copy_from_slice(&prog.to_ne_bytes());
+        Ok(())
+    }
+
+    /// Set the program handle of an index
+    fn update_elem_host(&mut self, key: &[u8], value: &[u8], flags: u64) -> MapResult<()> {
+        let index = self.index(key)?;
+        let prog: [u8; 4] = value.try_into().map_err(|_| MapError::Invalid)?;
+        let prog = ProgFd::from_ne_bytes(prog);
+        if prog == NONE || flags > BPF_EXIST {
+            return Err(MapError::Invalid);
+        }
+        match (self.progs[index] != NONE, flags) {
+            (true, BPF_NOEXIST) => return Err(MapError::Exists),
+            (false, BPF_EXIST) => return Err(MapError::NotFound),
+            _ => {}
+        }
+        self.progs[index] = prog;
+        Ok(())
+    }
+
+    fn prog_array_get(&self, index: u32) -> MapResult<ProgFd> {
+        match self.progs.get(index as usize) {
+            None => Err(MapError::TooBig),
+            Some(&NONE) => Err(MapError::NotFound),
+            Some(&prog) => Ok(prog),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+
+    use super::*;
+    use crate::map::{BPF_ANY, BPF_MAP_TYPE_PROG_ARRAY};
+
+    fn attr(key_size: u32, value_size: u32) -> BpfMapAttr {
+        BpfMapAttr {
+            map_type: BPF_MAP_TYPE_PROG_ARRAY,
+            key_size,
+            value_size,
+            max_entries: 2,
+            map_flags: 0,
+            name: "jmp_table".to_string(),
+            btf_key_type_id: 0,
+            btf_value_type_id: 0,
+        }
+    }
+
+    fn set(map: &mut ProgArray, index: u32, prog: ProgFd, flags: u64) -> MapResult<()> {
+        map.update_elem_host(&index.to_ne_bytes(), &prog.to_ne_bytes(), flags)
+    }
+
+    fn get(map: &mut ProgArray, index: u32) -> MapResult<ProgFd> {
+        let mut value = [0; 4];
+        map.lookup_elem_host(&index.to_ne_bytes(), &mut value)?;
+        Ok(ProgFd::from_ne_bytes(value))
+    }
+
+    #[test]
+    fn host_updates() {
+        let mut map = ProgArray::new(&attr(4, 4)).unwrap();
+        assert_eq!(get(&mut map, 0), Err(MapError::NotFound));
+        assert_eq!(set(&mut map, 0, 7, BPF_EXIST), Err(MapError::NotFound));
+        set(&mut map, 0, 7, BPF_NOEXIST).unwrap();
+        assert_eq!(set(&mut map, 0, 8, BPF_NOEXIST), Err(MapError::Exists));
+        set(&mut map, 0, 8, BPF_EXIST).unwrap();
+        set(&mut map, 1, 9, BPF_ANY).unwrap();
+        assert_eq!(get(&mut map, 0), Ok(8));
+        assert_eq!(map.prog_array_get(1), Ok(9));
+
+        map.delete_elem(&0u32.to_ne_bytes()).unwrap();
+        assert_eq!(map.prog_array_get(0), Err(MapError::NotFound));
+        assert_eq!(
+            map.delete_elem(&0u32.to_ne_bytes()),
+            Err(MapError::NotFound)
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!(ProgArray::new(&attr(8, 4)).err(), Some(MapError::Invalid));
+        assert_eq!(ProgArray::new(&attr(4, 8)).err(), Some(MapError::Invalid));
+        let mut map = ProgArray::new(&attr(4, 4)).unwrap();
+        // the empty slot marker is not a program
+        assert_eq!(set(&mut map, 0, NONE, BPF_ANY), Err(MapError::Invalid));
+        assert_eq!(set(&mut map, 0, 1, BPF_EXIST + 1), Err(MapError::Invalid));
+        assert_eq!(set(&mut map, 2, 1, BPF_ANY), Err(MapError::TooBig));
+        assert_eq!(map.prog_array_get(2), Err(MapError::TooBig));
+        // programs only jump to the programs of the array
+        let key = 0u32.to_ne_bytes();
+        assert!(matches!(map.lookup_elem(&key), Err(MapError::NotSupported)));
+        assert_eq!(
+            map.update_elem(&key, &1u32.to_ne_bytes(), BPF_ANY),
+            Err(MapError::NotSupported)
+        );
+    }
+
+    #[test]
+    fn iterate() {
+        let map = ProgArray::new(&attr(4, 4)).unwrap();
+        let mut key = [0; 4];
+        map.get_next_key(None, &mut key).unwrap();
+        assert_eq!(u32::from_ne_bytes(key), 0);
+        map.get_next_key(Some(&0u32.to_ne_bytes()), &mut key)
+            .unwrap();
+        assert_eq!(u32::from_ne_bytes(key), 1);
+        assert_eq!(
+            map.get_next_key(Some(&1u32.to_ne_bytes()), &mut key),
+            Err(MapError::NotFound)
+        );
+    }
+}
@@ -0,0 +1,195 @@
+//! `BPF_MAP_TYPE_QUEUE` and `BPF_MAP_TYPE_STACK`, maps without keys used with
+//! the push, pop and peek helpers.
+//!
+//! The values are kept in a ring of `max_entries` slots allocated when the map
+//! is created. A queue pops its oldest value, a stack its newest one, and a
+//! push with `BPF_EXIST` on a full map overwrites the oldest value.
+use alloc::vec::Vec;
+
+use super::{
+    alloc_zeroed, BpfMapOps, MapError, MapResult, BPF_EXIST, BPF_MAP_TYPE_STACK, BPF_NOEXIST,
+};
+use crate::loader::BpfMapAttr;
+
+#[derive(Debug)]
+pub struct QueueMap {
+    values: Vec<u8>,
+    value_size: usize,
+    max_entries: usize,
+    /// The slot of the oldest value
+    head: usize,
+    len: usize,
+    /// Pop the newest value instead of the oldest one
+    stack: bool,
+}
+
+impl QueueMap {
+    pub fn new(attr: &BpfMapAttr) -> MapResult<Self> {
+        if attr.key_size != 0 {
+            return Err(MapError::Invalid);
+        }
+        let value_size = attr.value_size as usize;
+        let max_entries = attr.max_entries as usize;
+        let size = value_size
+            .checked_mul(max_entries)
+            .ok_or(MapError::TooBig)?;
+        Ok(QueueMap {
+            values: alloc_zeroed(size)?,
+            value_size,
+            max_entries,
+            head: 0,
+            len: 0,
+            stack: attr.map_type == BPF_MAP_TYPE_STACK,
+        })
+    }
+
+    fn slot(&mut self, index: usize) -> &mut [u8] {
+        let offset = (index % self.max_entries) * self.value_size;
+        &mut self.values[offset..offset + self.value_size]
+    }
+
+    /// The slot popped next
+    fn top(&self) -> usize {
+        if self.stack {
+            self.head + self.len - 1
+        } else {
+            self.head
+        }
+    }
+}
+
+impl BpfMapOps for QueueMap {
+    fn lookup_elem(&mut self, _key: &[u8]) -> MapResult<Option<&mut [u8]>> {
+        Err(MapError::NotSupported)
+    }
+
+    /// Pushes the value, the key is empty
+    fn update_elem(&mut self, _key: &[u8], value: &[u8], flags: u64) -> MapResult<()> {
+        self.push_elem(value, flags)
+    }
+
+    fn delete_elem(&mut self, _key: &[u8]) -> MapResult<()> {
+        Err(MapError::NotSupported)
+    }
+
+    fn get_next_key(&self, _key: Option<&[u8]>, _next_key: &mut [u8]) -> MapResult<()> {
+        Err(MapError::NotSupported)
+    }
+
+    /// Copies the value popped next
+    fn lookup_elem_host(&mut self, _key: &[u8], value: &mut [u8]) -> MapResult<()> {
+        self.peek_elem(value)
+    }
+
+    fn push_elem(&mut self, value: &[u8], flags: u64) -> MapResult<()> {
+        if flags & BPF_NOEXIST != 0 || flags > BPF_EXIST || value.len() != self.value_size {
+            return Err(MapError::Invalid);
+        }
+        if self.len == self.max_entries {
+            if flags != BPF_EXIST {
+                return Err(MapError::TooBig);
+            }
+            // drop the oldest value
+            self.head = (self.head + 1) % self.max_entries;
+            self.len -= 1;
+        }
+        let index = self.head + self.len;
+        self.slot(index).copy_from_slice(value);
+        self.len += 1;
+        Ok(())
+    }
+
+    fn pop_elem(&mut self, value: &mut [u8]) -> MapResult<()> {
+        self.peek_elem(value)?;
+        if !self.stack {
+            self.head = (self.head + 1) % self.max_entries;
+        }
+        self.len -= 1;
+        Ok(())
+    }
+
+    fn peek_elem(&mut self, value: &mut [u8]) -> MapResult<()> {
+        if value.len() != self.value_size {
+            return Err(MapError::Invalid);
+        }
+        if self.len == 0 {
+            return Err(MapError::NotFound);
+        }
+        let top = self.top();
+        value.copy_from_slice(self.slot(top));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+
+    use super::*;
+    use crate::map::{BPF_ANY, BPF_MAP_TYPE_QUEUE};
+
+    fn map(map_type: u32, max_entries: u32) -> QueueMap {
+        QueueMap::new(&BpfMapAttr {
+            map_type,
+            key_size: 0,
+            value_size: 4,
+            max_entries,
+            map_flags: 0,
+            name: "queue".to_string(),
+            btf_key_type_id: 0,
+            btf_value_type_id: 0,
+        })
+        .unwrap()
+    }
+
+    fn push(map: &mut QueueMap, value: u32, flags: u64) -> MapResult<()> {
+        map.push_elem(&value.to_ne_bytes(), flags)
+    }
+
+    fn pop(map: &mut QueueMap) -> MapResult<u32> {
+        let mut value = [0; 4];
+        map.pop_elem(&mut value)?;
+        Ok(u32::from_ne_bytes(value))
+    }
+
+    #[test]
+    fn queue() {
+        let mut map = map(BPF_MAP_TYPE_QUEUE, 3);
+        assert_eq!(pop(&mut map), Err(MapError::NotFound));
+        for value in 1..=3 {
+            push(&mut map, value, BPF_ANY).unwrap();
+        }
+        assert_eq!(push(&mut map, 4, BPF_ANY), Err(MapError::TooBig));
+        assert_eq!(pop(&mut map), Ok(1));
+        push(&mut map, 4, BPF_ANY).unwrap();
+        // a full queue drops its oldest value on BPF_EXIST
+        push(&mut map, 5, BPF_EXIST).unwrap();
+        let mut value = [0; 4];
+        map.peek_elem(&mut value).unwrap();
+        assert_eq!(u32::from_ne_bytes(value), 3);
+        assert_eq!(pop(&mut map), Ok(3));
+        assert_eq!(pop(&mut map), Ok(4));
+        assert_eq!(pop(&mut map), Ok(5));
+        assert_eq!(pop(&mut map), Err(MapError::NotFound));
+    }
+
+    #[test]
+    fn stack() {
+        let mut map = map(BPF_MAP_TYPE_STACK, 2);
+        push(&mut map, 1, BPF_ANY).unwrap();
+        push(&mut map, 2, BPF_ANY).unwrap();
+        push(&mut map, 3, BPF_EXIST).unwrap();
+        assert_eq!(pop(&mut map), Ok(3));
+        assert_eq!(pop(&mut map), Ok(2));
+        assert_eq!(pop(&mut map), Err(MapError::NotFound));
+    }
+
+    #[test]
+    fn invalid() {
+        let mut map = map(BPF_MAP_TYPE_QUEUE, 2);
+        assert_eq!(push(&mut map, 1, BPF_NOEXIST), Err(MapError::Invalid));
+        assert_eq!(push(&mut map, 1, BPF_EXIST + 1), Err(MapError::Invalid));
+        assert_eq!(map.push_elem(&[0; 3], BPF_ANY), Err(MapError::Invalid));
+        assert_eq!(map.pop_elem(&mut [0; 8]), Err(MapError::Invalid));
+    }
+}
@@ -0,0 +1,355 @@
+//! A map registry owning its maps, for hosts without their own map storage.
+//!
+//! The registry is generic over a [`lock_api::RawMutex`] so that it can be
+//! shared between the loader, the executor and the helpers running on several
+//! CPUs, e.g. with `spin::mutex::SpinMutex<()>`:
+//!
+//! ```ignore
+//! static MAPS: BpfMapRegistry<spin::mutex::SpinMutex<()>> = BpfMapRegistry::with_cpus(&OS_CPUS);
+//! let bpf = BpfLoader::new().elf(obj).load(&mut &MAPS)?;
+//! ```
+use alloc::{boxed::Box, collections::BTreeMap};
+
+use anyhow::{anyhow, Result};
+use lock_api::{Mutex, RawMutex};
+
+use super::{new_map, BpfMapOps, CpuOps, MapError, MapRegistry, MapResult, SingleCpu, BPF_ANY};
+use crate::executor::MAX_TAIL_CALL_CNT;
+use crate::loader::{BpfMapAttr, MapFd, ProgFd};
+
+#[derive(Debug)]
+struct MapSlot {
+    attr: BpfMapAttr,
+    map: Box<dyn BpfMapOps>,
+    /// Objects and host handles using the map
+    refcount: usize,
+    /// Frozen maps are read-only for the host
+    frozen: bool,
+}
+
+#[derive(Debug)]
+struct Maps {
+    slots: BTreeMap<MapFd, MapSlot>,
+    next_fd: MapFd,
+    /// Program handles are never reused, a program array can not jump to
+    /// another program once its target is unloaded
+    next_prog_fd: ProgFd,
+    /// The running programs by the address of their context
+    runs: BTreeMap<u64, TailCalls>,
+}
+
+/// The tail calls of a running program, see
+/// [`run_program`](crate::executor::run_program)
+#[derive(Debug, Default)]
+struct TailCalls {
+    /// Tail calls made since the first program started
+    count: usize,
+    /// The target of a successful `bpf_tail_call`, started once the program
+    /// stopped
+    pending: Option<ProgFd>,
+}
+
+/// Maps allocated by fd and freed when their last reference is released
+pub struct BpfMapRegistry<L: RawMutex> {
+    maps: Mutex<L, Maps>,
+    cpus: &'static dyn CpuOps,
+}
+
+impl<L: RawMutex> Default for BpfMapRegistry<L> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<L: RawMutex> BpfMapRegistry<L> {
+    /// A registry for a single CPU
+    pub const fn new() -> Self {
+        Self::with_cpus(&SingleCpu)
+    }
+
+    /// A registry whose per-CPU maps have a value for each CPU of `cpus`
+    pub const fn with_cpus(cpus: &'static dyn CpuOps) -> Self {
+        BpfMapRegistry {
+            cpus,
+            maps: Mutex::const_new(
+                L::INIT,
+                Maps {
+                    slots: BTreeMap::new(),
+                    // fd 0 is never used, a null immediate is not a map
+                    next_fd: 1,
+                    next_prog_fd: 0,
+                    runs: BTreeMap::new(),
+                },
+            ),
+        }
+    }
+
+    /// Create a map with the implementation of its type, it has one reference
+    pub fn create(&self, attr: BpfMapAttr) -> MapResult<MapFd> {
+        let map = new_map(&attr, self.cpus)?;
+        let mut maps = self.maps.lock();
+        let fd = maps.next_fd;
+        maps.next_fd += 1;
+        maps.slots.insert(
+            fd,
+            MapSlot {
+                attr,
+                map,
+                refcount: 1,
+                frozen: false,
+            },
+        );
+        Ok(fd)
+    }
+
+    /// Take a reference to a map, e.g. to share it with another object
+    pub fn retain(&self, fd: MapFd) -> MapResult<()> {
+        let mut maps = self.maps.lock();
+        let slot = maps.slots.get_mut(&fd).ok_or(MapError::BadFd)?;
+        slot.refcount += 1;
+        Ok(())
+    }
+
+    /// Drop a reference to a map, the map is freed with its last reference
+    pub fn release(&self, fd: MapFd) -> MapResult<()> {
+        let mut maps = self.maps.lock();
+        let slot = maps.slots.get_mut(&fd).ok_or(MapError::BadFd)?;
+        slot.refcount -= 1;
+        if slot.refcount == 0 {
+            log::info!("free map {} with fd {}", slot.attr.name, fd);
+            maps.slots.remove(&fd);
+        }
+        Ok(())
+    }
+
+    /// Make the map read-only for the host
+    pub fn freeze(&self, fd: MapFd) -> MapResult<()> {
+        let mut maps = self.maps.lock();
+        maps.slots.get_mut(&fd).ok_or(MapError::BadFd)?.frozen = true;
+        Ok(())
+    }
+
+    /// The attributes of a map
+    pub fn attr(&self, fd: MapFd) -> MapResult<BpfMapAttr> {
+        let maps = self.maps.lock();
+        maps.slots
+            .get(&fd)
+            .map(|slot| slot.attr.clone())
+            .ok_or(MapError::BadFd)
+    }
+
+    /// The number of maps in the registry
+    pub fn len(&self) -> usize {
+        self.maps.lock().slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Run `f` on a map with the registry locked
+    pub fn with_map<T>(
+        &self,
+        fd: MapFd,
+        f: impl FnOnce(&mut dyn BpfMapOps, &BpfMapAttr) -> MapResult<T>,
+    ) -> MapResult<T> {
+        let mut maps = self.maps.lock();
+        let slot = maps.slots.get_mut(&fd).ok_or(MapError::BadFd)?;
+        f(slot.map.as_mut(), &slot.attr)
+    }
+
+    /// Copy the value of `key` into `value`, per-CPU maps have the values of
+    /// all CPUs each rounded up to 8 bytes
+    pub fn lookup_elem(&self, fd: MapFd, key: &[u8], value: &mut [u8]) -> MapResult<()> {
+        self.with_map(fd, |map, _| map.lookup_elem_host(key, value))
+    }
+
+    /// Update an element from the host, refused on frozen maps
+    pub fn update_elem(&self, fd: MapFd, key: &[u8], value: &[u8], flags: u64) -> MapResult<()> {
+        let mut maps = self.maps.lock();
+        let slot = maps.slots.get_mut(&fd).ok_or(MapError::BadFd)?;
+        if slot.frozen {
+            return Err(MapError::Permission);
+        }
+        slot.map.update_elem_host(key, value, flags)
+    }
+
+    /// Delete an element from the host, refused on frozen maps
+    pub fn delete_elem(&self, fd: MapFd, key: &[u8]) -> MapResult<()> {
+        let mut maps = self.maps.lock();
+        let slot = maps.slots.get_mut(&fd).ok_or(MapError::BadFd)?;
+        if slot.frozen {
+            return Err(MapError::Permission);
+        }
+        slot.map.delete_elem(key)
+    }
+
+    /// A handle for a loaded program, unique in the registry
+    pub fn create_prog(&self) -> MapResult<ProgFd> {
+        let mut maps = self.maps.lock();
+        let fd = maps.next_prog_fd;
+        // ProgFd::MAX marks the empty slots of the program arrays
+        if fd == ProgFd::MAX {
+            return Err(MapError::NoMemory);
+        }
+        maps.next_prog_fd += 1;
+        Ok(fd)
+    }
+
+    /// Track the tail calls of a program run with the context at `ctx`
+    pub(crate) fn start_run(&self, ctx: u64) -> MapResult<()> {
+        let mut maps = self.maps.lock();
+        if maps.runs.contains_key(&ctx) {
+            return Err(MapError::Again);
+        }
+        maps.runs.insert(ctx, TailCalls::default());
+        Ok(())
+    }
+
+    pub(crate) fn end_run(&self, ctx: u64) {
+        self.maps.lock().runs.remove(&ctx);
+    }
+
+    /// Make `prog` the next program of the run at `ctx`, a run makes at most
+    /// [`MAX_TAIL_CALL_CNT`] tail calls
+    pub(crate) fn tail_call(&self, ctx: u64, prog: ProgFd) -> MapResult<()> {
+        let mut maps = self.maps.lock();
+        let run = maps.runs.get_mut(&ctx).ok_or(MapError::Invalid)?;
+        if run.count >= MAX_TAIL_CALL_CNT {
+            return Err(MapError::TooBig);
+        }
+        run.count += 1;
+        run.pending = Some(prog);
+        Ok(())
+    }
+
+    /// The pending tail call of the run at `ctx`
+    pub(crate) fn take_tail_call(&self, ctx: u64) -> Option<ProgFd> {
+        let mut maps = self.maps.lock();
+        maps.runs.get_mut(&ctx)?.pending.take()
+    }
+
+    /// Copy and remove the next value of a queue or a stack
+    pub fn pop_elem(&self, fd: MapFd, value: &mut [u8]) -> MapResult<()> {
+        self.with_map(fd, |map, _| map.pop_elem(value))
+    }
+
+    pub fn get_next_key(
+        &self,
+        fd: MapFd,
+        key: Option<&[u8]>,
+        next_key: &mut [u8],
+    ) -> MapResult<()> {
+        self.with_map(fd, |map, _| map.get_next_key(key, next_key))
+    }
+}
+
+fn map_error(fd: MapFd, error: MapError) -> anyhow::Error {
+    anyhow!("map {}: {}", fd, error)
+}
+
+/// The loader and the executor use the registry through a shared reference, so
+/// that one registry can be used from several places
+impl<L: RawMutex> MapRegistry for &BpfMapRegistry<L> {
+    fn create_map(&mut self, attr: BpfMapAttr) -> Result<MapFd> {
+        let name = attr.name.clone();
+        self.create(attr)
+            .map_err(|error| anyhow!("map {}: {}", name, error))
+    }
+
+    fn update_map_element(&mut self, map_fd: MapFd, key: &[u8], value: &[u8]) -> Result<()> {
+        self.update_elem(map_fd, key, value, BPF_ANY)
+            .map_err(|error| map_error(map_fd, error))
+    }
+
+    fn freeze_map(&mut self, map_fd: MapFd) -> Result<()> {
+        self.freeze(map_fd)
+            .map_err(|error| map_error(map_fd, error))
+    }
+
+    fn release_map(&mut self, map_fd: MapFd) -> Result<()> {
+        self.release(map_fd)
+            .map_err(|error| map_error(map_fd, error))
+    }
+
+    fn create_prog(&mut self) -> Result<ProgFd> {
+        BpfMapRegistry::create_prog(self).map_err(|error| anyhow!("program: {}", error))
+    }
+
+    fn map_data_len(&self, map_fd: MapFd) -> usize {
+        self.with_map(map_fd, |map, _| Ok(map.data().map_or(0, |data| data.len())))
+            .unwrap_or(0)
+    }
+
+    fn map_data_ptr(&mut self, map_fd: MapFd) -> *mut u8 {
+        self.with_map(map_fd, |map, _| {
+            Ok(map
+                .data()
+                .map_or(core::ptr::null_mut(), |data| data.as_mut_ptr()))
+        })
+        .unwrap_or(core::ptr::null_mut())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+
+    use super::*;
+    use crate::map::BPF_MAP_TYPE_PROG_ARRAY;
+
+    type Registry = BpfMapRegistry<spin::Mutex<()>>;
+
+    #[test]
+    fn unique_programs() {
+        let maps = Registry::new();
+        let first = maps.create_prog().unwrap();
+        let second = maps.create_prog().unwrap();
+        assert_ne!(first, second);
+        let fd = maps
+            .create(BpfMapAttr {
+                map_type: BPF_MAP_TYPE_PROG_ARRAY,
+                key_size: 4,
+                value_size: 4,
+                max_entries: 2,
+                map_flags: 0,
+                name: "jmp_table".to_string(),
+                btf_key_type_id: 0,
+                btf_value_type_id: 0,
+            })
+            .unwrap();
+        maps.update_elem(fd, &1u32.to_ne_bytes(), &second.to_ne_bytes(), BPF_ANY)
+            .unwrap();
+        let get = |index: u32| maps.with_map(fd, |map, _| map.prog_array_get(index));
+        assert_eq!(get(0), Err(MapError::NotFound));
+        assert_eq!(get(1), Ok(second));
+        assert_eq!(get(2), Err(MapError::TooBig));
+    }
+
+    #[test]
+    fn tail_calls() {
+        let maps = Registry::new();
+        // not running
+        assert_eq!(maps.tail_call(8, 1), Err(MapError::Invalid));
+        maps.start_run(8).unwrap();
+        assert_eq!(maps.start_run(8), Err(MapError::Again));
+        assert_eq!(maps.take_tail_call(8), None);
+        maps.tail_call(8, 1).unwrap();
+        maps.tail_call(8, 2).unwrap();
+        assert_eq!(maps.take_tail_call(8), Some(2));
+        assert_eq!(maps.take_tail_call(8), None);
+        for _ in 2..MAX_TAIL_CALL_CNT {
+            maps.tail_call(8, 3).unwrap();
+        }
+        assert_eq!(maps.tail_call(8, 4), Err(MapError::TooBig));
+        assert_eq!(maps.take_tail_call(8), Some(3));
+        // another context has its own count
+        maps.start_run(16).unwrap();
+        maps.tail_call(16, 5).unwrap();
+        assert_eq!(maps.take_tail_call(16), Some(5));
+
+        maps.end_run(8);
+        assert_eq!(maps.tail_call(8, 1), Err(MapError::Invalid));
+        maps.start_run(8).unwrap();
+    }
+}
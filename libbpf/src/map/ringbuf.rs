@@ -0,0 +1,319 @@
+//! `BPF_MAP_TYPE_RINGBUF`, a buffer of variable sized records shared by all
+//! CPUs, written by the programs and read by the host.
+//!
+//! The memory has the layout of Linux: the consumer position in the first page,
+//! the producer position in the second one and the data after them. Each
+//! record starts with an 8 bytes header, its length with the busy and discard
+//! bits and its page offset, and is rounded up to 8 bytes. Linux maps the data
+//! pages twice so that a record crossing the end stays contiguous, here the
+//! data area is twice as large and such records continue past the end, which
+//! [`RingBufConsumer`] knows about.
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use lock_api::RawMutex;
+
+use super::array::{Buffer, PAGE_SIZE};
+use super::{BpfMapOps, BpfMapRegistry, MapError, MapResult, BPF_MAP_TYPE_RINGBUF};
+use crate::loader::{BpfMapAttr, MapFd};
+
+/// The record is reserved but not committed yet
+pub const BPF_RINGBUF_BUSY_BIT: u32 = 1 << 31;
+/// The record was discarded and is skipped by the consumer
+pub const BPF_RINGBUF_DISCARD_BIT: u32 = 1 << 30;
+pub const BPF_RINGBUF_HDR_SZ: usize = 8;
+
+/// Queries of `bpf_ringbuf_query`
+pub const BPF_RB_AVAIL_DATA: u64 = 0;
+pub const BPF_RB_RING_SIZE: u64 = 1;
+pub const BPF_RB_CONS_POS: u64 = 2;
+pub const BPF_RB_PROD_POS: u64 = 3;
+
+const CONSUMER_POS: usize = 0;
+const PRODUCER_POS: usize = PAGE_SIZE;
+const DATA: usize = 2 * PAGE_SIZE;
+
+fn round_up(len: usize) -> usize {
+    (len + 7) & !7
+}
+
+/// The position at `offset` in the buffer at `base`
+///
+/// # Safety
+///
+/// `base` is the start of a ring buffer.
+unsafe fn position<'a>(base: *mut u8, offset: usize) -> &'a AtomicU64 {
+    &*(base.add(offset) as *const AtomicU64)
+}
+
+#[derive(Debug)]
+pub struct RingBuf {
+    buffer: Buffer,
+    /// The size of the data, a power of 2
+    size: usize,
+}
+
+impl RingBuf {
+    pub fn new(attr: &BpfMapAttr) -> MapResult<Self> {
+        let size = attr.max_entries as usize;
+        if attr.key_size != 0
+            || attr.value_size != 0
+            || !size.is_power_of_two()
+            || size % PAGE_SIZE != 0
+        {
+            return Err(MapError::Invalid);
+        }
+        Ok(RingBuf {
+            buffer: Buffer::new(DATA + 2 * size, PAGE_SIZE)?,
+            size,
+        })
+    }
+
+    fn consumer_pos(&self) -> &AtomicU64 {
+        // SAFETY: the positions are in the first two pages of the buffer
+        unsafe { position(self.buffer.as_ptr(), CONSUMER_POS) }
+    }
+
+    fn producer_pos(&self) -> &AtomicU64 {
+        // SAFETY: see consumer_pos
+        unsafe { position(self.buffer.as_ptr(), PRODUCER_POS) }
+    }
+}
+
+impl BpfMapOps for RingBuf {
+    fn lookup_elem(&mut self, _key: &[u8]) -> MapResult<Option<&mut [u8]>> {
+        Err(MapError::NotSupported)
+    }
+
+    fn update_elem(&mut self, _key: &[u8], _value: &[u8], _flags: u64) -> MapResult<()> {
+        Err(MapError::NotSupported)
+    }
+
+    fn delete_elem(&mut self, _key: &[u8]) -> MapResult<()> {
+        Err(MapError::NotSupported)
+    }
+
+    fn get_next_key(&self, _key: Option<&[u8]>, _next_key: &mut [u8]) -> MapResult<()> {
+        Err(MapError::NotSupported)
+    }
+
+    /// The positions and the data, what Linux lets the host map
+    fn data(&mut self) -> Option<&mut [u8]> {
+        let len = DATA + 2 * self.size;
+        Some(self.buffer.slice_mut(0, len))
+    }
+
+    /// Records are reserved in order, the registry lock serializes the
+    /// producers
+    fn ringbuf_reserve(&mut self, size: usize) -> MapResult<&mut [u8]> {
+        let len = round_up(size + BPF_RINGBUF_HDR_SZ);
+        if size as u32 & (BPF_RINGBUF_BUSY_BIT | BPF_RINGBUF_DISCARD_BIT) != 0 || len > self.size {
+            return Err(MapError::TooBig);
+        }
+        let consumer = self.consumer_pos().load(Ordering::Acquire) as usize;
+        let producer = self.producer_pos().load(Ordering::Relaxed) as usize;
+        if producer + len - consumer > self.size {
+            return Err(MapError::Again);
+        }
+        let offset = DATA + (producer & (self.size - 1));
+        let header = self.buffer.slice_mut(offset, BPF_RINGBUF_HDR_SZ);
+        header[..4].copy_from_slice(&(size as u32 | BPF_RINGBUF_BUSY_BIT).to_ne_bytes());
+        header[4..].copy_from_slice(&((offset / PAGE_SIZE) as u32).to_ne_bytes());
+        // the consumer sees the busy header before the new position
+        self.producer_pos()
+            .store((producer + len) as u64, Ordering::Release);
+        Ok(self.buffer.slice_mut(offset + BPF_RINGBUF_HDR_SZ, size))
+    }
+
+    fn ringbuf_query(&self, flags: u64) -> MapResult<u64> {
+        let consumer = self.consumer_pos().load(Ordering::Acquire);
+        let producer = self.producer_pos().load(Ordering::Acquire);
+        Ok(match flags {
+            BPF_RB_AVAIL_DATA => producer - consumer,
+            BPF_RB_RING_SIZE => self.size as u64,
+            BPF_RB_CONS_POS => consumer,
+            BPF_RB_PROD_POS => producer,
+            _ => 0,
+        })
+    }
+}
+
+/// Submit or discard a record returned by [`BpfMapOps::ringbuf_reserve`],
+/// the consumer can then read or skip it
+///
+/// # Safety
+///
+/// `sample` is a reserved record which was not committed yet.
+pub unsafe fn ringbuf_commit(sample: *mut u8, discard: bool) {
+    let header = &*(sample.sub(BPF_RINGBUF_HDR_SZ) as *const AtomicU32);
+    let mut len = header.load(Ordering::Relaxed) & !BPF_RINGBUF_BUSY_BIT;
+    if discard {
+        len |= BPF_RINGBUF_DISCARD_BIT;
+    }
+    header.store(len, Ordering::Release);
+}
+
+/// Reads the records of a ring buffer from the host while programs write them.
+/// The consumer holds a reference to the map.
+pub struct RingBufConsumer<'a, L: RawMutex> {
+    maps: &'a BpfMapRegistry<L>,
+    fd: MapFd,
+    base: *mut u8,
+    size: usize,
+}
+
+impl<'a, L: RawMutex> RingBufConsumer<'a, L> {
+    pub fn new(maps: &'a BpfMapRegistry<L>, fd: MapFd) -> MapResult<Self> {
+        let (base, size) = maps.with_map(fd, |map, attr| {
+            if attr.map_type != BPF_MAP_TYPE_RINGBUF {
+                return Err(MapError::Invalid);
+            }
+            let data = map.data().ok_or(MapError::Invalid)?;
+            Ok((data.as_mut_ptr(), attr.max_entries as usize))
+        })?;
+        // the buffer stays allocated while the map has references
+        maps.retain(fd)?;
+        Ok(RingBufConsumer {
+            maps,
+            fd,
+            base,
+            size,
+        })
+    }
+
+    /// Bytes written by the producers and not consumed yet
+    pub fn available(&self) -> u64 {
+        // SAFETY: the map is alive, base is its buffer
+        unsafe {
+            position(self.base, PRODUCER_POS).load(Ordering::Acquire)
+                - position(self.base, CONSUMER_POS).load(Ordering::Relaxed)
+        }
+    }
+
+    /// Call `callback` on each committed record in order, until a record that
+    /// is still busy. Returns the number of records read.
+    pub fn poll(&mut self, mut callback: impl FnMut(&[u8])) -> usize {
+        // SAFETY: the map is alive, the records between the positions were
+        // reserved by the producers and are read once committed
+        unsafe {
+            let consumer_pos = position(self.base, CONSUMER_POS);
+            let producer_pos = position(self.base, PRODUCER_POS);
+            let mut consumer = consumer_pos.load(Ordering::Relaxed) as usize;
+            let mut count = 0;
+            loop {
+                let producer = producer_pos.load(Ordering::Acquire) as usize;
+                if consumer >= producer {
+                    break;
+                }
+                let record = self.base.add(DATA + (consumer & (self.size - 1)));
+                let len = (*(record as *const AtomicU32)).load(Ordering::Acquire);
+                if len & BPF_RINGBUF_BUSY_BIT != 0 {
+                    break;
+                }
+                let size = (len & !BPF_RINGBUF_DISCARD_BIT) as usize;
+                if len & BPF_RINGBUF_DISCARD_BIT == 0 {
+                    let sample = record.add(BPF_RINGBUF_HDR_SZ);
+                    callback(core::slice::from_raw_parts(sample, size));
+                    count += 1;
+                }
+                consumer += round_up(size + BPF_RINGBUF_HDR_SZ);
+                // give the space back to the producers
+                consumer_pos.store(consumer as u64, Ordering::Release);
+            }
+            count
+        }
+    }
+}
+
+impl<L: RawMutex> Drop for RingBufConsumer<'_, L> {
+    fn drop(&mut self) {
+        let _ = self.maps.release(self.fd);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{string::ToString, vec, vec::Vec};
+
+    use super::*;
+
+    type Registry = BpfMapRegistry<spin::Mutex<()>>;
+
+    fn ringbuf(maps: &Registry) -> MapFd {
+        maps.create(BpfMapAttr {
+            map_type: BPF_MAP_TYPE_RINGBUF,
+            key_size: 0,
+            value_size: 0,
+            max_entries: PAGE_SIZE as u32,
+            map_flags: 0,
+            name: "events".to_string(),
+            btf_key_type_id: 0,
+            btf_value_type_id: 0,
+        })
+        .unwrap()
+    }
+
+    /// Reserve a record filled with `byte`, returns its address
+    fn reserve(maps: &Registry, fd: MapFd, size: usize, byte: u8) -> MapResult<*mut u8> {
+        maps.with_map(fd, |map, _| {
+            let sample = map.ringbuf_reserve(size)?;
+            sample.fill(byte);
+            Ok(sample.as_mut_ptr())
+        })
+    }
+
+    fn output(maps: &Registry, fd: MapFd, size: usize, byte: u8) {
+        let sample = reserve(maps, fd, size, byte).unwrap();
+        // SAFETY: the record was just reserved
+        unsafe { ringbuf_commit(sample, false) };
+    }
+
+    fn poll(consumer: &mut RingBufConsumer<'_, spin::Mutex<()>>) -> Vec<Vec<u8>> {
+        let mut records = Vec::new();
+        consumer.poll(|record| records.push(record.to_vec()));
+        records
+    }
+
+    #[test]
+    fn records() {
+        let maps = Registry::new();
+        let fd = ringbuf(&maps);
+        let mut consumer = RingBufConsumer::new(&maps, fd).unwrap();
+        output(&maps, fd, 3, 1);
+        let discarded = reserve(&maps, fd, 5, 2).unwrap();
+        let busy = reserve(&maps, fd, 2, 3).unwrap();
+        output(&maps, fd, 1, 4);
+        // SAFETY: the record was reserved above
+        unsafe { ringbuf_commit(discarded, true) };
+        // the busy record stops the consumer
+        assert_eq!(poll(&mut consumer), vec![vec![1; 3]]);
+        // SAFETY: the record was reserved above
+        unsafe { ringbuf_commit(busy, false) };
+        assert_eq!(poll(&mut consumer), vec![vec![3; 2], vec![4; 1]]);
+        assert_eq!(consumer.available(), 0);
+    }
+
+    #[test]
+    fn full_and_wrap() {
+        let maps = Registry::new();
+        let fd = ringbuf(&maps);
+        let mut consumer = RingBufConsumer::new(&maps, fd).unwrap();
+        let size = 1000;
+        let record = round_up(size + BPF_RINGBUF_HDR_SZ);
+        for _ in 0..PAGE_SIZE / record {
+            output(&maps, fd, size, 5);
+        }
+        assert_eq!(reserve(&maps, fd, size, 6), Err(MapError::Again));
+        assert_eq!(reserve(&maps, fd, PAGE_SIZE, 6), Err(MapError::TooBig));
+        let query = |flags| maps.with_map(fd, |map, _| map.ringbuf_query(flags));
+        assert_eq!(
+            query(BPF_RB_AVAIL_DATA),
+            Ok((PAGE_SIZE / record * record) as u64)
+        );
+        assert_eq!(query(BPF_RB_RING_SIZE), Ok(PAGE_SIZE as u64));
+        assert_eq!(poll(&mut consumer).len(), PAGE_SIZE / record);
+        // this record crosses the end of the data and stays contiguous
+        output(&maps, fd, size, 6);
+        assert_eq!(poll(&mut consumer), vec![vec![6; size]]);
+        assert_eq!(query(BPF_RB_CONS_POS), query(BPF_RB_PROD_POS));
+    }
+}
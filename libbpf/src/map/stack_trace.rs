@@ -0,0 +1,221 @@
+//! `BPF_MAP_TYPE_STACK_TRACE`, the stacks recorded by `bpf_get_stackid`.
+//!
+//! The key is a stack id, the index of a bucket chosen by the hash of the
+//! addresses, and the value the addresses of the stack padded with zeroes. A
+//! stack whose bucket holds another one is refused with `EEXIST`, unless
+//! `BPF_F_REUSE_STACKID` replaces the old stack. The buckets are allocated
+//! once like in the other maps.
+use alloc::vec::Vec;
+
+use super::{alloc_zeroed, BpfMapOps, MapError, MapResult};
+use crate::loader::BpfMapAttr;
+
+/// The number of stack entries to skip, in the flags of the helpers
+pub const BPF_F_SKIP_FIELD_MASK: u64 = 0xff;
+/// Walk the user stack instead of the kernel one
+pub const BPF_F_USER_STACK: u64 = 1 << 8;
+/// Compare the stacks by their hashes only
+pub const BPF_F_FAST_STACK_CMP: u64 = 1 << 9;
+/// Replace the stack of the bucket if it is another one
+pub const BPF_F_REUSE_STACKID: u64 = 1 << 10;
+
+/// FNV-1a over the addresses
+fn hash(ips: &[u64]) -> u64 {
+    ips.iter()
+        .flat_map(|ip| ip.to_ne_bytes())
+        .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+        })
+}
+
+#[derive(Debug)]
+pub struct StackTraceMap {
+    /// The maximum number of addresses of a stack
+    depth: usize,
+    /// The addresses of each bucket, `depth` entries each
+    ips: Vec<u64>,
+    /// The number of addresses in each bucket, 0 if the bucket is empty
+    lens: Vec<u32>,
+    hashes: Vec<u64>,
+}
+
+impl StackTraceMap {
+    pub fn new(attr: &BpfMapAttr) -> MapResult<Self> {
+        if attr.key_size != 4 || attr.value_size % 8 != 0 {
+            return Err(MapError::Invalid);
+        }
+        let depth = attr.value_size as usize / 8;
+        let buckets = (attr.max_entries as usize).next_power_of_two();
+        let size = buckets.checked_mul(depth).ok_or(MapError::TooBig)?;
+        Ok(StackTraceMap {
+            depth,
+            ips: alloc_zeroed(size)?,
+            lens: alloc_zeroed(buckets)?,
+            hashes: alloc_zeroed(buckets)?,
+        })
+    }
+
+    /// The maximum number of addresses of a stack
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    fn bucket(&self, key: &[u8]) -> MapResult<usize> {
+        let key: [u8; 4] = key.try_into().map_err(|_| MapError::Invalid)?;
+        let id = u32::from_ne_bytes(key) as usize;
+        if id >= self.lens.len() {
+            return Err(MapError::NotFound);
+        }
+        Ok(id)
+    }
+
+    fn stack_mut(&mut self, bucket: usize) -> &mut [u64] {
+        &mut self.ips[bucket * self.depth..(bucket + 1) * self.depth]
+    }
+}
+
+impl BpfMapOps for StackTraceMap {
+    fn lookup_elem(&mut self, key: &[u8]) -> MapResult<Option<&mut [u8]>> {
+        let bucket = self.bucket(key)?;
+        if self.lens[bucket] == 0 {
+            return Ok(None);
+        }
+        let stack = self.stack_mut(bucket);
+        // SAFETY: the addresses are plain bytes
+        Ok(Some(unsafe {
+            core::slice::from_raw_parts_mut(stack.as_mut_ptr() as *mut u8, stack.len() * 8)
+        }))
+    }
+
+    /// Stacks are only added by `bpf_get_stackid`
+    fn update_elem(&mut self, _key: &[u8], _value: &[u8], _flags: u64) -> MapResult<()> {
+        Err(MapError::Invalid)
+    }
+
+    fn delete_elem(&mut self, key: &[u8]) -> MapResult<()> {
+        let bucket = self.bucket(key)?;
+        if self.lens[bucket] == 0 {
+            return Err(MapError::NotFound);
+        }
+        self.lens[bucket] = 0;
+        Ok(())
+    }
+
+    /// The ids of the recorded stacks in order
+    fn get_next_key(&self, key: Option<&[u8]>, next_key: &mut [u8]) -> MapResult<()> {
+        if next_key.len() != 4 {
+            return Err(MapError::Invalid);
+        }
+        let start = match key.map(|key| self.bucket(key)) {
+            Some(Ok(bucket)) => bucket + 1,
+            _ => 0,
+        };
+        let next = (start..self.lens.len())
+            .find(|bucket| self.lens[*bucket] != 0)
+            .ok_or(MapError::NotFound)?;
+        next_key.copy_from_slice(&(next as u32).to_ne_bytes());
+        Ok(())
+    }
+
+    fn get_stackid(&mut self, ips: &[u64], flags: u64) -> MapResult<u32> {
+        if ips.is_empty() {
+            return Err(MapError::NotFound);
+        }
+        let ips = &ips[..ips.len().min(self.depth)];
+        let hash = hash(ips);
+        let bucket = hash as usize & (self.lens.len() - 1);
+        let len = self.lens[bucket] as usize;
+        if len != 0 && self.hashes[bucket] == hash {
+            let same = flags & BPF_F_FAST_STACK_CMP != 0 || self.stack_mut(bucket)[..len] == *ips;
+            if same {
+                return Ok(bucket as u32);
+            }
+        }
+        if len != 0 && flags & BPF_F_REUSE_STACKID == 0 {
+            return Err(MapError::Exists);
+        }
+        let stack = self.stack_mut(bucket);
+        stack[..ips.len()].copy_from_slice(ips);
+        stack[ips.len()..].fill(0);
+        self.lens[bucket] = ips.len() as u32;
+        self.hashes[bucket] = hash;
+        Ok(bucket as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{string::ToString, vec};
+
+    use super::*;
+    use crate::map::BPF_MAP_TYPE_STACK_TRACE;
+
+    fn map(depth: u32, max_entries: u32) -> StackTraceMap {
+        StackTraceMap::new(&BpfMapAttr {
+            map_type: BPF_MAP_TYPE_STACK_TRACE,
+            key_size: 4,
+            value_size: depth * 8,
+            max_entries,
+            map_flags: 0,
+            name: "stacks".to_string(),
+            btf_key_type_id: 0,
+            btf_value_type_id: 0,
+        })
+        .unwrap()
+    }
+
+    fn stack(map: &mut StackTraceMap, id: u32) -> Option<Vec<u64>> {
+        let value = map.lookup_elem(&id.to_ne_bytes()).unwrap()?;
+        Some(
+            value
+                .chunks_exact(8)
+                .map(|ip| u64::from_ne_bytes(ip.try_into().unwrap()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn same_stack_same_id() {
+        let mut map = map(3, 4);
+        let id = map.get_stackid(&[1, 2, 3, 4], 0).unwrap();
+        // truncated to the depth
+        assert_eq!(stack(&mut map, id), Some(vec![1, 2, 3]));
+        assert_eq!(map.get_stackid(&[1, 2, 3], 0), Ok(id));
+        assert_eq!(map.get_stackid(&[1, 2, 3, 5], 0), Ok(id));
+        assert_eq!(map.get_stackid(&[], 0), Err(MapError::NotFound));
+    }
+
+    #[test]
+    fn bucket_collision() {
+        // every stack falls in the single bucket
+        let mut map = map(2, 1);
+        assert_eq!(map.get_stackid(&[1, 2], 0), Ok(0));
+        assert_eq!(map.get_stackid(&[3], 0), Err(MapError::Exists));
+        assert_eq!(stack(&mut map, 0), Some(vec![1, 2]));
+        assert_eq!(map.get_stackid(&[3], BPF_F_REUSE_STACKID), Ok(0));
+        // the old addresses are cleared
+        assert_eq!(stack(&mut map, 0), Some(vec![3, 0]));
+        assert_eq!(map.get_stackid(&[3], 0), Ok(0));
+    }
+
+    #[test]
+    fn ids() {
+        let mut map = map(2, 2);
+        let mut next = [0; 4];
+        assert_eq!(map.get_next_key(None, &mut next), Err(MapError::NotFound));
+        let id = map.get_stackid(&[1], 0).unwrap();
+        map.get_next_key(None, &mut next).unwrap();
+        assert_eq!(u32::from_ne_bytes(next), id);
+        assert_eq!(
+            map.update_elem(&id.to_ne_bytes(), &[0; 16], 0),
+            Err(MapError::Invalid)
+        );
+        map.delete_elem(&id.to_ne_bytes()).unwrap();
+        assert_eq!(stack(&mut map, id), None);
+        assert_eq!(map.delete_elem(&id.to_ne_bytes()), Err(MapError::NotFound));
+        assert_eq!(
+            map.delete_elem(&2u32.to_ne_bytes()),
+            Err(MapError::NotFound)
+        );
+    }
+}
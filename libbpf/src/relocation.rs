@@ -1 +1,99 @@
+//! Relocation types of BPF objects, as defined by LLVM in
+//! `llvm/BinaryFormat/ELFRelocs/BPF.def`.
+//!
+//! BPF objects use `REL` relocations, the addend is the value already stored
+//! at the relocated location.
+use alloc::string::String;
 
+use rbpf::ebpf::{self, Insn};
+
+use crate::loader::{MapFd, BPF_PSEUDO_CALL};
+
+pub const R_BPF_NONE: u32 = 0;
+/// `ld_imm64` of the address of a map or a variable
+pub const R_BPF_64_64: u32 = 1;
+/// 64-bit address stored in a data section
+pub const R_BPF_64_ABS64: u32 = 2;
+/// 32-bit address stored in a data section
+pub const R_BPF_64_ABS32: u32 = 3;
+/// 32-bit address the dynamic loader leaves alone, used by `.BTF` and `.BTF.ext`
+pub const R_BPF_64_NODYLD32: u32 = 4;
+/// `call` of a BPF function, the immediate is an offset in instructions
+pub const R_BPF_64_32: u32 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelocationKind {
+    /// [`R_BPF_64_64`]
+    Load64,
+    /// [`R_BPF_64_ABS64`]
+    Abs64,
+    /// [`R_BPF_64_ABS32`]
+    Abs32,
+    /// [`R_BPF_64_NODYLD32`]
+    NoDyld32,
+    /// [`R_BPF_64_32`]
+    Call,
+}
+
+impl RelocationKind {
+    /// The kind of a relocation type, `None` for `R_BPF_NONE` and unknown types
+    pub fn from_type(ty: u32) -> Option<Self> {
+        match ty {
+            R_BPF_64_64 => Some(RelocationKind::Load64),
+            R_BPF_64_ABS64 => Some(RelocationKind::Abs64),
+            R_BPF_64_ABS32 => Some(RelocationKind::Abs32),
+            R_BPF_64_NODYLD32 => Some(RelocationKind::NoDyld32),
+            R_BPF_64_32 => Some(RelocationKind::Call),
+            _ => None,
+        }
+    }
+
+    /// Size of the address patched by a relocation in a data section, `None` for
+    /// the kinds that only apply to instructions
+    pub fn data_size(&self) -> Option<usize> {
+        match self {
+            RelocationKind::Abs64 => Some(8),
+            RelocationKind::Abs32 | RelocationKind::NoDyld32 => Some(4),
+            RelocationKind::Load64 | RelocationKind::Call => None,
+        }
+    }
+
+    /// Whether the instruction at `index` can be patched by a relocation of this
+    /// kind
+    pub fn matches_insn(&self, prog: &[Insn], index: usize) -> bool {
+        let insn = match prog.get(index) {
+            Some(insn) => insn,
+            None => return false,
+        };
+        match self {
+            // the second half of the load holds the upper 32 bits
+            RelocationKind::Load64 => insn.opc == ebpf::LD_DW_IMM && index + 1 < prog.len(),
+            RelocationKind::Call => insn.opc == ebpf::CALL && insn.src == BPF_PSEUDO_CALL as u8,
+            _ => false,
+        }
+    }
+}
+
+/// What the address stored by a data relocation points to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DataTarget {
+    /// A variable at `offset` in the value of a global data map
+    Map { map_fd: MapFd, offset: u64 },
+    /// A function of the object, the host decides what its address is
+    Function {
+        symbol: String,
+        section_name: String,
+        offset: u64,
+    },
+}
+
+/// An address stored in a global data section, e.g. a function pointer in `.data`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataRelocation {
+    /// The map of the data section holding the address
+    pub map_fd: MapFd,
+    /// Offset of the address in the map value
+    pub offset: usize,
+    pub kind: RelocationKind,
+    pub target: DataTarget,
+}
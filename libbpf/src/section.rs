@@ -0,0 +1,394 @@
+//! Program types and attach points encoded in the section names, following
+//! the conventions of libbpf:
+//!
+//! | section                             | program type      | attach target                  |
+//! |-------------------------------------|-------------------|--------------------------------|
+//! | `kprobe/<sym>[+<off>]`              | `Kprobe`          | `Symbol`                       |
+//! | `kretprobe/<sym>`                   | `Kretprobe`       | `Symbol`                       |
+//! | `uprobe/<path>:<sym>[+<off>]`       | `Uprobe`          | `Uprobe`                       |
+//! | `uretprobe/<path>:<sym>[+<off>]`    | `Uretprobe`       | `Uprobe`                       |
+//! | `tracepoint/<cat>/<name>`           | `Tracepoint`      | `Tracepoint`                   |
+//! | `raw_tracepoint/<name>`             | `RawTracepoint`   | `RawTracepoint`                |
+//! | `xdp`                               | `Xdp`             | `None`                         |
+//! | `socket`                            | `SocketFilter`    | `None`                         |
+//! | `perf_event`                        | `PerfEvent`       | `None`                         |
+//!
+//! A program without target (`kprobe`, `xdp`, ...) can not be attached
+//! automatically, the host has to attach it by hand. `tp` and `raw_tp` are
+//! accepted as short forms of `tracepoint` and `raw_tracepoint`.
+use alloc::string::{String, ToString};
+use core::fmt::Display;
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    error::{LoadError, LoadResult},
+    loader::BpfProgram,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgramType {
+    Kprobe,
+    Kretprobe,
+    Uprobe,
+    Uretprobe,
+    Tracepoint,
+    RawTracepoint,
+    Xdp,
+    SocketFilter,
+    PerfEvent,
+    /// The section name does not follow any known convention
+    Unspec,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AttachTarget {
+    /// A kernel function and an offset in it
+    Symbol {
+        symbol: String,
+        offset: usize,
+    },
+    /// A function of a user binary, `symbol` is `None` when `offset` is an
+    /// address in the binary
+    Uprobe {
+        binary: String,
+        symbol: Option<String>,
+        offset: usize,
+    },
+    Tracepoint {
+        category: String,
+        name: String,
+    },
+    RawTracepoint(String),
+    /// The program is attached by hand
+    None,
+}
+
+impl Display for ProgramType {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let name = match self {
+            ProgramType::Kprobe => "kprobe",
+            ProgramType::Kretprobe => "kretprobe",
+            ProgramType::Uprobe => "uprobe",
+            ProgramType::Uretprobe => "uretprobe",
+            ProgramType::Tracepoint => "tracepoint",
+            ProgramType::RawTracepoint => "raw_tracepoint",
+            ProgramType::Xdp => "xdp",
+            ProgramType::SocketFilter => "socket",
+            ProgramType::PerfEvent => "perf_event",
+            ProgramType::Unspec => "unspec",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Parse a decimal or `0x` prefixed hexadecimal offset
+fn parse_offset(offset: &str) -> Option<usize> {
+    let (digits, radix) = match offset.strip_prefix("0x") {
+        Some(hex) => (hex, 16),
+        None => (offset, 10),
+    };
+    // `from_str_radix` also takes a leading sign
+    if !digits.starts_with(|c: char| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    usize::from_str_radix(digits, radix).ok()
+}
+
+/// Split `<sym>[+<off>]`
+fn parse_symbol(target: &str) -> Option<(String, usize)> {
+    let (symbol, offset) = match target.split_once('+') {
+        Some((symbol, offset)) => (symbol, parse_offset(offset)?),
+        None => (target, 0),
+    };
+    if symbol.is_empty() {
+        return None;
+    }
+    Some((symbol.to_string(), offset))
+}
+
+/// Split `<path>:<sym>[+<off>]` or `<path>:<addr>`
+fn parse_uprobe(target: &str) -> Option<AttachTarget> {
+    let (binary, func) = target.rsplit_once(':')?;
+    if binary.is_empty() || func.is_empty() {
+        return None;
+    }
+    let (symbol, offset) = match parse_offset(func) {
+        Some(offset) => (None, offset),
+        None => {
+            let (symbol, offset) = parse_symbol(func)?;
+            (Some(symbol), offset)
+        }
+    };
+    Some(AttachTarget::Uprobe {
+        binary: binary.to_string(),
+        symbol,
+        offset,
+    })
+}
+
+/// Infer the program type and the attach target from a section name
+pub fn parse_section(section_name: &str) -> LoadResult<(ProgramType, AttachTarget)> {
+    let (prefix, target) = match section_name.split_once('/') {
+        Some((prefix, target)) => (prefix, Some(target)),
+        None => (section_name, None),
+    };
+    let ty = match prefix {
+        "kprobe" => ProgramType::Kprobe,
+        "kretprobe" => ProgramType::Kretprobe,
+        "uprobe" => ProgramType::Uprobe,
+        "uretprobe" => ProgramType::Uretprobe,
+        "tracepoint" | "tp" => ProgramType::Tracepoint,
+        "raw_tracepoint" | "raw_tp" => ProgramType::RawTracepoint,
+        "xdp" => ProgramType::Xdp,
+        "socket" => ProgramType::SocketFilter,
+        "perf_event" => ProgramType::PerfEvent,
+        _ => return Ok((ProgramType::Unspec, AttachTarget::None)),
+    };
+    let target = match target {
+        None => return Ok((ty, AttachTarget::None)),
+        Some(target) => target,
+    };
+    let invalid = || LoadError::InvalidAttachTarget(section_name.to_string());
+    let target = match ty {
+        ProgramType::Kprobe => {
+            let (symbol, offset) = parse_symbol(target).ok_or_else(invalid)?;
+            AttachTarget::Symbol { symbol, offset }
+        }
+        ProgramType::Kretprobe => {
+            let (symbol, offset) = parse_symbol(target).ok_or_else(invalid)?;
+            // a return probe is placed at the function entry
+            if offset != 0 {
+                return Err(invalid());
+            }
+            AttachTarget::Symbol { symbol, offset }
+        }
+        ProgramType::Uprobe | ProgramType::Uretprobe => parse_uprobe(target).ok_or_else(invalid)?,
+        ProgramType::Tracepoint => match target.split_once('/') {
+            Some((category, name)) if !category.is_empty() && !name.is_empty() => {
+                AttachTarget::Tracepoint {
+                    category: category.to_string(),
+                    name: name.to_string(),
+                }
+            }
+            _ => return Err(invalid()),
+        },
+        ProgramType::RawTracepoint if !target.is_empty() => {
+            AttachTarget::RawTracepoint(target.to_string())
+        }
+        ProgramType::RawTracepoint => return Err(invalid()),
+        // `xdp/...`, `socket/...` carry no attach point we know of
+        _ => AttachTarget::None,
+    };
+    Ok((ty, target))
+}
+
+/// The hooks of the host, used by [`BpfProgram::attach`] to attach a program
+/// to the point described by its section name. The hooks the host does not
+/// support can be left out.
+pub trait AttachOps {
+    /// A handle that keeps the program attached
+    type Link;
+    fn attach_kprobe(
+        &mut self,
+        prog: &BpfProgram,
+        _symbol: &str,
+        _offset: usize,
+        _is_return: bool,
+    ) -> Result<Self::Link> {
+        Err(anyhow!("kprobe is not supported, program {}", prog.name()))
+    }
+    fn attach_uprobe(
+        &mut self,
+        prog: &BpfProgram,
+        _binary: &str,
+        _symbol: Option<&str>,
+        _offset: usize,
+        _is_return: bool,
+    ) -> Result<Self::Link> {
+        Err(anyhow!("uprobe is not supported, program {}", prog.name()))
+    }
+    fn attach_tracepoint(
+        &mut self,
+        prog: &BpfProgram,
+        _category: &str,
+        _name: &str,
+    ) -> Result<Self::Link> {
+        Err(anyhow!(
+            "tracepoint is not supported, program {}",
+            prog.name()
+        ))
+    }
+    fn attach_raw_tracepoint(&mut self, prog: &BpfProgram, _name: &str) -> Result<Self::Link> {
+        Err(anyhow!(
+            "raw tracepoint is not supported, program {}",
+            prog.name()
+        ))
+    }
+}
+
+impl BpfProgram {
+    /// Attach the program to the hook chosen from its section name, through
+    /// the hooks of `ops`
+    pub fn attach<A: AttachOps>(&self, ops: &mut A) -> Result<A::Link> {
+        let ty = self.program_type();
+        match (ty, self.attach_target()) {
+            (
+                ProgramType::Kprobe | ProgramType::Kretprobe,
+                AttachTarget::Symbol { symbol, offset },
+            ) => ops.attach_kprobe(self, symbol, *offset, ty == ProgramType::Kretprobe),
+            (
+                ProgramType::Uprobe | ProgramType::Uretprobe,
+                AttachTarget::Uprobe {
+                    binary,
+                    symbol,
+                    offset,
+                },
+            ) => ops.attach_uprobe(
+                self,
+                binary,
+                symbol.as_deref(),
+                *offset,
+                ty == ProgramType::Uretprobe,
+            ),
+            (ProgramType::Tracepoint, AttachTarget::Tracepoint { category, name }) => {
+                ops.attach_tracepoint(self, category, name)
+            }
+            (ProgramType::RawTracepoint, AttachTarget::RawTracepoint(name)) => {
+                ops.attach_raw_tracepoint(self, name)
+            }
+            _ => Err(anyhow!(
+                "{} program {} in section {} can not be attached automatically",
+                ty,
+                self.name(),
+                self.section_name()
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbol(symbol: &str, offset: usize) -> AttachTarget {
+        AttachTarget::Symbol {
+            symbol: symbol.to_string(),
+            offset,
+        }
+    }
+
+    fn uprobe(binary: &str, symbol: Option<&str>, offset: usize) -> AttachTarget {
+        AttachTarget::Uprobe {
+            binary: binary.to_string(),
+            symbol: symbol.map(ToString::to_string),
+            offset,
+        }
+    }
+
+    fn tracepoint(category: &str, name: &str) -> AttachTarget {
+        AttachTarget::Tracepoint {
+            category: category.to_string(),
+            name: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn sections() {
+        use AttachTarget::{None as Manual, RawTracepoint as Raw};
+        use ProgramType::*;
+        let cases = [
+            ("kprobe/do_sys_open", Kprobe, symbol("do_sys_open", 0)),
+            (
+                "kprobe/do_sys_open+0x10",
+                Kprobe,
+                symbol("do_sys_open", 0x10),
+            ),
+            ("kprobe", Kprobe, Manual),
+            ("kretprobe/do_sys_open", Kretprobe, symbol("do_sys_open", 0)),
+            (
+                "kretprobe/do_sys_open+0",
+                Kretprobe,
+                symbol("do_sys_open", 0),
+            ),
+            (
+                "uprobe/bin/sh:main",
+                Uprobe,
+                uprobe("bin/sh", Some("main"), 0),
+            ),
+            (
+                "uprobe//bin/sh:main+8",
+                Uprobe,
+                uprobe("/bin/sh", Some("main"), 8),
+            ),
+            (
+                "uprobe//bin/sh:0x1234",
+                Uprobe,
+                uprobe("/bin/sh", None, 0x1234),
+            ),
+            (
+                "uretprobe//bin/sh:main",
+                Uretprobe,
+                uprobe("/bin/sh", Some("main"), 0),
+            ),
+            (
+                "tracepoint/sched/sched_switch",
+                Tracepoint,
+                tracepoint("sched", "sched_switch"),
+            ),
+            (
+                "tp/sched/sched_switch",
+                Tracepoint,
+                tracepoint("sched", "sched_switch"),
+            ),
+            (
+                "raw_tracepoint/sched_switch",
+                RawTracepoint,
+                Raw("sched_switch".into()),
+            ),
+            (
+                "raw_tp/sched_switch",
+                RawTracepoint,
+                Raw("sched_switch".into()),
+            ),
+            ("xdp", Xdp, Manual),
+            ("xdp/frags", Xdp, Manual),
+            ("socket", SocketFilter, Manual),
+            ("perf_event", PerfEvent, Manual),
+            ("kprobes/do_sys_open", Unspec, Manual),
+            ("fentry/do_sys_open", Unspec, Manual),
+            (".text", Unspec, Manual),
+        ];
+        for (section, ty, target) in cases {
+            assert_eq!(parse_section(section).unwrap(), (ty, target), "{}", section);
+        }
+    }
+
+    #[test]
+    fn invalid_sections() {
+        for section in [
+            "kprobe/",
+            "kprobe/+4",
+            "kprobe/do_sys_open+x",
+            // a return probe is placed at the function entry
+            "kretprobe/do_sys_open+4",
+            "uprobe//bin/sh",
+            "uprobe/:main",
+            "uprobe//bin/sh:",
+            "uprobe//bin/sh:+4",
+            "tracepoint/sched_switch",
+            "tracepoint//sched_switch",
+            "tp/sched/",
+            "raw_tracepoint/",
+        ] {
+            assert!(
+                matches!(
+                    parse_section(section),
+                    Err(LoadError::InvalidAttachTarget(s)) if s == section
+                ),
+                "{}",
+                section
+            );
+        }
+    }
+}
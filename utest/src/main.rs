@@ -1,14 +1,26 @@
-use std::{collections::BTreeMap, fmt::Write, path::PathBuf};
+use std::{fmt::Write, path::PathBuf};
 
-use anyhow::Result;
 use libbpf::{
-    executor::{BpfExecutor, FindMapOps},
-    loader::{BpfLoader, BpfMapAttr, CreateMapOps, MapFd},
-    map::{BpfMap, MapEntry, MapKey},
+    btf::{Btf, BtfBuilder, BTF_KIND_STRUCT},
+    executor::{run_program, BpfExecutor},
+    helpers::{map_helpers, perf_event_helpers, ringbuf_helpers, stack_helpers, Helper, HelperEnv},
+    loader::BpfLoader,
+    map::BpfMapRegistry,
     print::printf_with,
 };
 use rbpf::{disassembler, helpers};
-use spin::Mutex;
+use spin::mutex::SpinMutex;
+
+static MAPS: BpfMapRegistry<SpinMutex<()>> = BpfMapRegistry::new();
+
+/// The helpers use the maps of the test
+struct Env;
+impl HelperEnv for Env {
+    type Lock = SpinMutex<()>;
+    fn maps() -> &'static BpfMapRegistry<Self::Lock> {
+        &MAPS
+    }
+}
 
 fn main() {
     env_logger::init_from_env(env_logger::Env::default().default_filter_or("info"));
@@ -16,32 +28,132 @@ fn main() {
     let file_data = std::fs::read(filename).expect("Could not read file.");
     let slice = file_data.as_slice();
 
-    let loader = BpfLoader::<CreateMapFdImpl>::new()
+    let target_btf = BtfBuilder::from_entries(libbpf::linked_btf_types!()).build();
+    dump_task(&target_btf);
+
+    let mut maps = &MAPS;
+    let bpf = BpfLoader::new()
         .elf(slice)
-        .text_section_name("xdp")
-        .load()
+        .target_btf(&target_btf)
+        .gpl_only_helper(helpers::BPF_TRACE_PRINTK_IDX)
+        .load(&mut maps)
         .unwrap();
+    log::info!(
+        "license: {:?}, kernel version: {:?}",
+        bpf.license(),
+        bpf.kernel_version()
+    );
+    let mut executor = BpfExecutor::new(&mut maps);
+    executor.relocate_data(bpf.data_relocations()).unwrap();
 
-    let prog = loader.text();
-    log::info!("After the pre-processing, the program is:");
+    let mut processed = Vec::new();
 
-    disassembler::disassemble(&prog);
+    for program in bpf.programs() {
+        log::info!(
+            "{} program {} in section {}, after the pre-processing, the program is:",
+            program.program_type(),
+            program.name(),
+            program.section_name()
+        );
+        let prog = program.text();
+        disassembler::disassemble(prog);
+        for line in program.line_info() {
+            log::info!(
+                "insn {}: {}:{} {}",
+                line.insn_off as usize / 8,
+                line.file_name,
+                line.line_num,
+                line.line
+            );
+        }
 
-    let new_prog = BpfExecutor::<FindMapImpl>::process(prog, loader.relocation()).unwrap();
+        let new_prog = executor.process(prog, program.relocation()).unwrap();
 
-    log::info!("After the post-processing, the program is:");
-    disassembler::disassemble(&new_prog);
+        log::info!("After the post-processing, the program is:");
+        disassembler::disassemble(&new_prog);
 
-    println!("*********************************");
+        processed.push(new_prog);
+    }
 
-    let hkey = helpers::BPF_TRACE_PRINTK_IDX as u8;
-    let mut vm = rbpf::EbpfVmRaw::new(Some(&new_prog)).unwrap();
-    vm.register_helper(hkey as u32, trace_printf).unwrap();
-    let res = vm.execute_program(&mut []).unwrap();
-    println!("Program returned: {res:?} ({res:#x})");
+    bpf.update_prog_array(&mut maps, "jmp_table", 0, "hello_tail")
+        .unwrap();
+    let helpers: Vec<_> = [(helpers::BPF_TRACE_PRINTK_IDX, trace_printf as Helper)]
+        .into_iter()
+        .chain(map_helpers::<Env>())
+        .chain(ringbuf_helpers::<Env>())
+        .chain(perf_event_helpers::<Env>())
+        .chain(stack_helpers::<Env>())
+        .collect();
+    for (program, prog) in bpf.programs().zip(&processed) {
+        println!("********************************* {}", program.name());
+        let res = run_program::<Env>(
+            prog,
+            |fd| {
+                bpf.programs()
+                    .zip(&processed)
+                    .find(|(program, _)| program.fd() == fd)
+                    .map(|(_, prog)| prog.as_slice())
+            },
+            &helpers,
+            &mut [],
+        )
+        .unwrap();
+        println!("Program returned: {res:?} ({res:#x})");
+    }
+    for map in bpf.maps() {
+        log::info!(
+            "map {}: fd {}, type {}, key size {}, value size {}, max entries {}",
+            map.name(),
+            map.fd(),
+            map.map_type(),
+            map.key_size(),
+            map.value_size(),
+            map.max_entries()
+        );
+    }
+    let counter: i32 = bpf.read_global(&mut maps, "counter").unwrap();
+    let counter2: i32 = bpf.read_global(&mut maps, "counter2").unwrap();
+    println!("counter: {counter}, counter2: {counter2}");
+    let calls = bpf.map_by_name("calls").unwrap();
+    let mut value = [0u8; 8];
+    MAPS.lookup_elem(calls.fd(), &0u32.to_ne_bytes(), &mut value)
+        .unwrap();
+    println!("calls: {}", u64::from_ne_bytes(value));
+    bpf.unload(&mut maps).unwrap();
+    assert!(MAPS.is_empty());
     println!("Test passed!");
 }
 
+/// A kernel type described to the BPF programs
+#[derive(libbpf::btf::Btf)]
+#[btf(name = "task_struct")]
+#[repr(C)]
+struct Task {
+    pid: i32,
+    tgid: i32,
+    comm: [u8; 16],
+    parent: *const Task,
+}
+
+fn dump_task(target_btf: &[u8]) {
+    let btf = Btf::parse(target_btf).unwrap();
+    let (id, _) = btf.type_by_name("task_struct", BTF_KIND_STRUCT).unwrap();
+    let task = Task {
+        pid: 1,
+        tgid: 1,
+        comm: *b"init\0\0\0\0\0\0\0\0\0\0\0\0",
+        parent: core::ptr::null(),
+    };
+    // Task has no padding
+    let data = unsafe {
+        core::slice::from_raw_parts(
+            &task as *const Task as *const u8,
+            core::mem::size_of::<Task>(),
+        )
+    };
+    log::info!("target BTF of task_struct: {}", btf.display(id, data));
+}
+
 struct FakeOut;
 impl Write for FakeOut {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
@@ -53,57 +165,3 @@ impl Write for FakeOut {
 pub fn trace_printf(fmt_ptr: u64, _fmt_len: u64, arg3: u64, arg4: u64, arg5: u64) -> u64 {
     unsafe { printf_with(&mut FakeOut, fmt_ptr as _, arg3, arg4, arg5) as u64 }
 }
-
-static BPF_MAP: Mutex<BTreeMap<MapFd, BpfMap>> = Mutex::new(BTreeMap::new());
-static BPF_MAP_ID: Mutex<MapFd> = Mutex::new(1);
-pub struct CreateMapFdImpl;
-pub struct FindMapImpl;
-
-impl CreateMapOps for CreateMapFdImpl {
-    fn create_map(attr: BpfMapAttr) -> Result<MapFd> {
-        let key_size = attr.key_size;
-        let value_size = attr.value_size;
-        let max_entries = attr.max_entries;
-        let _name = attr.name;
-        let mut map = BpfMap::new(key_size, value_size, max_entries);
-        if max_entries == 1 {
-            map.insert(
-                MapKey::new(vec![0; key_size as usize]),
-                MapEntry::new(vec![0; value_size as usize]),
-            );
-        }
-        let mut map_id = BPF_MAP_ID.lock();
-        let map_fd = *map_id;
-        *map_id += 1;
-        BPF_MAP.lock().insert(map_fd, map);
-        Ok(map_fd)
-    }
-    fn map_data_len(map_fd: MapFd) -> usize {
-        BPF_MAP.lock().get(&map_fd).unwrap().len()
-    }
-    fn update_map_element(map_fd: MapFd, key: &[u8], value: &[u8]) -> Result<()> {
-        log::info!("update map element: {:?} with value: {:?}", key, value);
-        let mut map = BPF_MAP.lock();
-        let map = map.get_mut(&map_fd).unwrap();
-        map.update(&MapKey::new(key.to_vec()), &MapEntry::new(value.to_vec()));
-        Ok(())
-    }
-}
-
-impl FindMapOps for FindMapImpl {
-    fn map_data_len(map_fd: usize) -> usize {
-        BPF_MAP.lock().get(&map_fd).unwrap().len()
-    }
-    fn map_data_ptr(map_fd: usize) -> *mut u8 {
-        let mut map = BPF_MAP.lock();
-        let map = map
-            .get_mut(&map_fd)
-            .expect(format!("map with fd {} not found", map_fd).as_str());
-        let value = map
-            .get_mut(&MapKey::new(vec![0; map.key_size() as usize]))
-            .unwrap();
-        let value_ptr = value.data_mut().as_mut_ptr();
-        log::info!("get map data ptr: {:p}", value_ptr);
-        value_ptr
-    }
-}